@@ -0,0 +1,49 @@
+/// How an anchor was found in a read by alignment.
+pub struct AnchorMatch {
+    /// The position in the read just after the aligned anchor.
+    pub end: usize,
+    pub distance: usize,
+    /// Whether the alignment needs an insertion or deletion, rather than only substitutions.
+    pub indel: bool,
+}
+
+/// Find the leftmost end of the best alignment of `anchor` within `seq` with at most
+/// `max_edits` edits, by semi-global (Sellers) dynamic programming.
+pub fn align_anchor(seq: &[u8], anchor: &[u8], max_edits: usize) -> Option<AnchorMatch> {
+    let m = anchor.len();
+    // The edit distances between each anchor prefix and the best read substring ending at
+    // the current position, with a free start anywhere in the read:
+    let mut column: Vec<usize> = (0..=m).collect();
+    let mut best: Option<(usize, usize)> = None;
+    for (j, base) in seq.iter().enumerate() {
+        let mut diagonal = column[0];
+        column[0] = 0;
+        for i in 1..=m {
+            let substitution = diagonal + usize::from(!anchor[i - 1].eq_ignore_ascii_case(base));
+            diagonal = column[i];
+            column[i] = substitution.min(column[i] + 1).min(column[i - 1] + 1);
+        }
+        if column[m] <= max_edits && best.is_none_or(|(distance, _)| column[m] < distance) {
+            best = Some((column[m], j + 1));
+        }
+    }
+    let (distance, end) = best?;
+    // The alignment only needs substitutions if the anchor fits just before the end with no
+    // more mismatches than the edit distance:
+    let indel = match end.checked_sub(m) {
+        Some(start) => {
+            seq[start..end]
+                .iter()
+                .zip(anchor.iter())
+                .filter(|(a, b)| !a.eq_ignore_ascii_case(b))
+                .count()
+                > distance
+        }
+        None => true,
+    };
+    Some(AnchorMatch {
+        end,
+        distance,
+        indel,
+    })
+}
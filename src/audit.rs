@@ -0,0 +1,61 @@
+use crate::index::CandidateIndex;
+use std::collections::BTreeMap;
+
+/// The pairwise edit distances within a list of barcodes, up to a maximum distance.
+pub struct Audit {
+    pub entries: usize,
+    pub max_distance: usize,
+    pub histogram: BTreeMap<usize, u64>,
+    pub min_distance: Option<usize>,
+    pub closest: Vec<(String, String)>,
+}
+
+/// Find all pairs of barcodes within `max_distance` of each other, using a candidate index so
+/// that large lists do not need every pair to be compared.
+pub fn audit_barcodes(barcodes: Vec<String>, max_distance: usize) -> Audit {
+    let index = CandidateIndex::new(barcodes, max_distance);
+    let mut audit = Audit {
+        entries: index.len(),
+        max_distance,
+        histogram: BTreeMap::new(),
+        min_distance: None,
+        closest: Vec::new(),
+    };
+    for i in 0..index.len() {
+        let barcode = index.get(i);
+        for (j, distance) in index.within(barcode) {
+            // Count each pair once:
+            if j <= i {
+                continue;
+            }
+            *audit.histogram.entry(distance).or_insert(0_u64) += 1_u64;
+            if audit.min_distance.is_none_or(|min| distance < min) {
+                audit.min_distance = Some(distance);
+                audit.closest.clear();
+            }
+            if audit.min_distance == Some(distance) {
+                audit
+                    .closest
+                    .push((barcode.to_owned(), index.get(j).to_owned()));
+            }
+        }
+    }
+    audit
+}
+
+impl Audit {
+    /// The number of pairs further apart than the maximum distance searched.
+    pub fn distant_pairs(&self) -> u64 {
+        let pairs = (self.entries as u64 * self.entries.saturating_sub(1) as u64) / 2;
+        pairs - self.histogram.values().sum::<u64>()
+    }
+
+    /// Whether correction at `threshold_distance` is unambiguous, i.e. every pair of barcodes
+    /// is more than twice the threshold apart.
+    pub fn is_safe(&self, threshold_distance: usize) -> bool {
+        match self.min_distance {
+            Some(min) => min > 2 * threshold_distance,
+            None => self.max_distance >= 2 * threshold_distance,
+        }
+    }
+}
@@ -0,0 +1,121 @@
+use crate::fastq::{Record, RecordReader};
+use crate::sequence;
+use simple_eyre::eyre::{eyre, Report};
+use std::io::{BufRead, ErrorKind, Read};
+
+/// The magic bytes that (decompressed) BAM files start with.
+pub const BAM_MAGIC: &[u8] = b"BAM\x01";
+
+/// The bases of the 4-bit BAM sequence encoding.
+const BASES: &[u8; 16] = b"=ACMGRSVTWYHKDBN";
+
+/// Records that are reverse complemented, or are secondary or supplementary alignments.
+const FLAG_REVERSE: u16 = 0x10;
+const FLAG_SECONDARY: u16 = 0x100;
+const FLAG_SUPPLEMENTARY: u16 = 0x800;
+
+/// Reads the records of a (decompressed) BAM file as reads, as in unaligned BAM from a
+/// basecaller. Aligned reverse-strand records are turned back into the orientation they were
+/// sequenced in, and secondary and supplementary alignments are skipped, so that each read is
+/// read once.
+pub struct BamReader<R> {
+    inner: R,
+    block: Vec<u8>,
+}
+
+fn read_u32(input: &mut impl Read) -> Result<u32, Report> {
+    let mut bytes = [0_u8; 4];
+    input.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn skip(input: &mut impl Read, length: u64) -> Result<(), Report> {
+    let skipped = std::io::copy(&mut input.take(length), &mut std::io::sink())?;
+    match skipped == length {
+        true => Ok(()),
+        false => Err(eyre!("truncated BAM header")),
+    }
+}
+
+impl<R: BufRead> BamReader<R> {
+    /// Start reading a BAM file, skipping its header.
+    pub fn new(mut inner: R) -> Result<Self, Report> {
+        let mut magic = [0_u8; 4];
+        inner.read_exact(&mut magic)?;
+        if magic != BAM_MAGIC {
+            return Err(eyre!("input is not BAM"));
+        }
+        let text_length = read_u32(&mut inner)?;
+        skip(&mut inner, text_length as u64)?;
+        for _ in 0..read_u32(&mut inner)? {
+            let name_length = read_u32(&mut inner)?;
+            skip(&mut inner, name_length as u64 + 4)?;
+        }
+        Ok(BamReader {
+            inner,
+            block: Vec::new(),
+        })
+    }
+}
+
+impl<R: BufRead> RecordReader for BamReader<R> {
+    fn read_record(&mut self, record: &mut Record) -> Result<bool, Report> {
+        loop {
+            let mut size = [0_u8; 4];
+            match self.inner.read_exact(&mut size) {
+                Ok(()) => (),
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(false),
+                Err(e) => return Err(e.into()),
+            }
+            self.block.resize(u32::from_le_bytes(size) as usize, 0_u8);
+            self.inner
+                .read_exact(&mut self.block)
+                .map_err(|_| eyre!("truncated BAM record"))?;
+            let block = &self.block;
+            if block.len() < 32 {
+                return Err(eyre!("invalid BAM record"));
+            }
+            let name_length = block[8] as usize;
+            let cigar_ops = u16::from_le_bytes([block[12], block[13]]) as usize;
+            let flag = u16::from_le_bytes([block[14], block[15]]);
+            let length = u32::from_le_bytes([block[16], block[17], block[18], block[19]]) as usize;
+            if flag & (FLAG_SECONDARY | FLAG_SUPPLEMENTARY) != 0 {
+                continue;
+            }
+            let name_start = 32;
+            let seq_start = name_start + name_length + 4 * cigar_ops;
+            let qual_start = seq_start + length.div_ceil(2);
+            let name = block
+                .get(name_start..name_start + name_length)
+                .ok_or_else(|| eyre!("invalid BAM record"))?;
+            let (seq, qual) = match (
+                block.get(seq_start..qual_start),
+                block.get(qual_start..qual_start + length),
+            ) {
+                (Some(seq), Some(qual)) => (seq, qual),
+                _ => return Err(eyre!("invalid BAM record")),
+            };
+            record.header.clear();
+            record.header.push('@');
+            record
+                .header
+                .push_str(String::from_utf8_lossy(name).trim_end_matches('\0'));
+            record.seq.clear();
+            record.seq.extend((0..length).map(|i| {
+                let code = seq[i / 2] >> (4 * (1 - i % 2));
+                BASES[(code & 0xf) as usize] as char
+            }));
+            // Missing qualities are stored as 0xff, and are given the lowest quality:
+            record.qual.clear();
+            record.qual.extend(qual.iter().map(|q| match q {
+                0xff => '!',
+                q => (q.min(&93) + 33) as char,
+            }));
+            if flag & FLAG_REVERSE != 0 {
+                record.seq = sequence::reverse_complement(&record.seq);
+                record.qual = record.qual.chars().rev().collect();
+            }
+            return Ok(true);
+        }
+    }
+}
@@ -0,0 +1,172 @@
+use crate::matrix;
+use crate::output::{self, Row};
+use crate::whitelist::open_text;
+use simple_eyre::eyre::{eyre, Report};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufRead, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+/// A sample of a batch sheet, with its input files and any search expression of its own.
+pub struct BatchSample {
+    pub name: String,
+    pub file_paths: Vec<PathBuf>,
+    pub expression: Option<String>,
+}
+
+/// Read a (sample, fastq path, [search expression]) batch sheet, either tab- or
+/// comma-separated, skipping blank lines, `#` comments and a `sample` header row. A sample
+/// given on several lines (such as the lanes of one sample) has its files counted together.
+pub fn read_batch(path: &Path) -> Result<Vec<BatchSample>, Report> {
+    let mut samples: Vec<BatchSample> = Vec::new();
+    for (i, line) in open_text(path)?.lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        // The expression is the last field, so that it may contain commas:
+        let fields: Vec<&str> = match line.contains('\t') {
+            true => line.splitn(3, '\t').map(str::trim).collect(),
+            false => line.splitn(3, ',').map(str::trim).collect(),
+        };
+        if i == 0 && fields[0] == "sample" {
+            continue;
+        }
+        let file_path = fields
+            .get(1)
+            .filter(|field| !field.is_empty())
+            .ok_or_else(|| eyre!("batch sheet line {} has no fastq path", i + 1))?;
+        let expression = fields.get(2).filter(|field| !field.is_empty());
+        match samples.iter_mut().find(|sample| sample.name == fields[0]) {
+            Some(sample) => {
+                if sample.expression.as_deref() != expression.copied() {
+                    return Err(eyre!(
+                        "batch sheet line {} gives sample {} a different search expression",
+                        i + 1,
+                        fields[0]
+                    ));
+                }
+                sample.file_paths.push(PathBuf::from(file_path));
+            }
+            None => samples.push(BatchSample {
+                name: fields[0].to_string(),
+                file_paths: vec![PathBuf::from(file_path)],
+                expression: expression.map(|expression| expression.to_string()),
+            }),
+        }
+    }
+    Ok(samples)
+}
+
+/// The outcome of counting one sample.
+#[derive(Default)]
+pub struct Counted {
+    pub rows: Vec<Row>,
+    pub total_reads: u64,
+    pub barcoded_reads: u64,
+    pub no_barcode: u64,
+    pub filtered_reads: u64,
+}
+
+/// The rows (of the first sample to have them) and per-sample counts of the final barcodes
+/// of every sample, in decreasing order of their total reads.
+fn matrix_rows(counted: &[Counted]) -> Vec<(&str, (&Row, Vec<u64>))> {
+    let mut barcodes: HashMap<&str, (&Row, Vec<u64>)> = HashMap::new();
+    for (i, sample) in counted.iter().enumerate() {
+        for row in sample.rows.iter() {
+            let (_, counts) = barcodes
+                .entry(&row.key)
+                .or_insert_with(|| (row, vec![0_u64; counted.len()]));
+            counts[i] += row.count;
+        }
+    }
+    let mut barcodes: Vec<(&str, (&Row, Vec<u64>))> = barcodes.into_iter().collect();
+    barcodes.sort_by(|(a, (_, a_counts)), (b, (_, b_counts))| {
+        let (a_total, b_total) = (a_counts.iter().sum::<u64>(), b_counts.iter().sum::<u64>());
+        b_total.cmp(&a_total).then(a.cmp(b))
+    });
+    barcodes
+}
+
+/// Write a barcode × sample matrix of the final barcodes of every sample, in decreasing order
+/// of their total reads.
+pub fn write_matrix(
+    writer: &mut impl Write,
+    samples: &[BatchSample],
+    counted: &[Counted],
+) -> io::Result<()> {
+    let barcodes = matrix_rows(counted);
+    write!(writer, "barcode")?;
+    for sample in samples.iter() {
+        write!(writer, "\t{}", sample.name)?;
+    }
+    writeln!(writer)?;
+    for (_, (row, counts)) in barcodes.iter() {
+        write!(writer, "{}", row.label)?;
+        for count in counts.iter() {
+            write!(writer, "\t{count}")?;
+        }
+        writeln!(writer)?;
+    }
+    writer.flush()
+}
+
+/// Write the barcode × sample matrix to a Matrix Market directory, as `matrix::write_mtx`.
+pub fn write_mtx(dir: &Path, samples: &[BatchSample], counted: &[Counted]) -> Result<(), Report> {
+    let rows = matrix_rows(counted);
+    let barcodes: Vec<(String, &[u64])> = rows
+        .iter()
+        .map(|(_, (row, counts))| (row.label.to_owned(), counts.as_slice()))
+        .collect();
+    let samples: Vec<String> = samples
+        .iter()
+        .map(|sample| sample.name.to_owned())
+        .collect();
+    matrix::write_mtx(dir, &barcodes, &samples)
+}
+
+/// Write the guide × sample matrix of a library screen as a MAGeCK count table.
+pub fn write_mageck(
+    writer: &mut impl Write,
+    samples: &[BatchSample],
+    counted: &[Counted],
+) -> io::Result<()> {
+    let rows = matrix_rows(counted);
+    let guides: Vec<(&str, &str, &[u64])> = rows
+        .iter()
+        .map(|(_, (row, counts))| {
+            let gene = row.columns.first().map_or("", |gene| gene.as_str());
+            (row.label.as_str(), gene, counts.as_slice())
+        })
+        .collect();
+    let samples: Vec<&str> = samples.iter().map(|sample| sample.name.as_str()).collect();
+    output::write_mageck(writer, &samples, &guides)
+}
+
+/// Write a summary of the reads of each sample.
+pub fn write_summary(
+    path: &Path,
+    samples: &[BatchSample],
+    counted: &[Counted],
+) -> Result<(), Report> {
+    let mut buffer = BufWriter::new(File::create(path)?);
+    writeln!(
+        buffer,
+        "sample\treads\tbarcoded_reads\tno_barcode\tfiltered_reads\tbarcodes"
+    )?;
+    for (sample, counted) in samples.iter().zip(counted.iter()) {
+        writeln!(
+            buffer,
+            "{}\t{}\t{}\t{}\t{}\t{}",
+            sample.name,
+            counted.total_reads,
+            counted.barcoded_reads,
+            counted.no_barcode,
+            counted.filtered_reads,
+            counted.rows.iter().filter(|row| row.count > 0).count()
+        )?;
+    }
+    buffer.flush()?;
+    Ok(())
+}
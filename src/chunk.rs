@@ -0,0 +1,110 @@
+use simple_eyre::eyre::Report;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+/// Per-chunk read composition, written as a long-format (chunk_index, metric, value) table.
+///
+/// The chunk statistics are calculated from the difference between running totals at each
+/// chunk boundary. The barcodes tracked are fixed as the `top` most frequent (pre-merging)
+/// barcodes at the end of the first chunk, so their fractions are comparable across chunks.
+pub struct ChunkTracker {
+    size: u64,
+    top: usize,
+    index: u64,
+    reads: u64,
+    unmatched: u64,
+    tracked: Option<Vec<(String, u64)>>,
+    buffer: BufWriter<File>,
+}
+
+impl ChunkTracker {
+    pub fn new(path: &Path, size: u64, top: usize) -> Result<ChunkTracker, Report> {
+        let mut buffer = BufWriter::new(File::create(path)?);
+        writeln!(buffer, "chunk_index\tmetric\tvalue")?;
+        Ok(ChunkTracker {
+            size: size.max(1),
+            top,
+            index: 0,
+            reads: 0,
+            unmatched: 0,
+            tracked: None,
+            buffer,
+        })
+    }
+
+    /// Record a chunk if `reads` (the number of reads processed so far) is at a boundary.
+    pub fn observe(
+        &mut self,
+        reads: u64,
+        unmatched: u64,
+        barcodes: &HashMap<String, u64>,
+    ) -> Result<(), Report> {
+        match reads > self.reads && reads.is_multiple_of(self.size) {
+            true => self.record(reads, unmatched, barcodes),
+            false => Ok(()),
+        }
+    }
+
+    /// Record the final (possibly partial) chunk and flush the table.
+    pub fn finish(
+        &mut self,
+        reads: u64,
+        unmatched: u64,
+        barcodes: &HashMap<String, u64>,
+    ) -> Result<(), Report> {
+        if reads > self.reads {
+            self.record(reads, unmatched, barcodes)?;
+        }
+        self.buffer.flush()?;
+        Ok(())
+    }
+
+    fn record(
+        &mut self,
+        reads: u64,
+        unmatched: u64,
+        barcodes: &HashMap<String, u64>,
+    ) -> Result<(), Report> {
+        let tracked = self.tracked.get_or_insert_with(|| {
+            let mut top: Vec<(String, u64)> = barcodes
+                .keys()
+                .map(|barcode| (barcode.to_owned(), 0_u64))
+                .collect();
+            top.sort_by_key(|(barcode, _)| std::cmp::Reverse(barcodes[barcode]));
+            top.truncate(self.top);
+            top
+        });
+        let chunk_reads = reads - self.reads;
+        let fraction = |n: u64| n as f64 / chunk_reads as f64;
+        let i = self.index;
+        writeln!(self.buffer, "{i}\treads\t{chunk_reads}")?;
+        writeln!(
+            self.buffer,
+            "{i}\tunmatched_rate\t{:0.6}",
+            fraction(unmatched - self.unmatched)
+        )?;
+        writeln!(self.buffer, "{i}\tdistinct_barcodes\t{}", barcodes.len())?;
+        let mut top_reads = 0_u64;
+        for (barcode, previous) in tracked.iter_mut() {
+            let count = barcodes.get(barcode).copied().unwrap_or(0);
+            top_reads += count - *previous;
+            writeln!(
+                self.buffer,
+                "{i}\tfraction:{barcode}\t{:0.6}",
+                fraction(count - *previous)
+            )?;
+            *previous = count;
+        }
+        writeln!(
+            self.buffer,
+            "{i}\ttop_fraction\t{:0.6}",
+            fraction(top_reads)
+        )?;
+        self.index += 1;
+        self.reads = reads;
+        self.unmatched = unmatched;
+        Ok(())
+    }
+}
@@ -0,0 +1,201 @@
+use crate::fastq::{self, Format};
+use flate2::read::GzDecoder;
+use rayon::prelude::*;
+use simple_eyre::eyre::Report;
+use std::io::{self, BufRead, BufReader, ErrorKind, Read};
+use std::path::Path;
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::thread;
+
+/// The number of BGZF blocks (of up to 64 KiB each) decompressed together per thread.
+const BLOCKS_PER_THREAD: usize = 16;
+
+/// The size of the chunks sent by a single background decompressor.
+const STREAM_CHUNK: usize = 1 << 20;
+
+/// The decompressed data, received in order from the background thread.
+struct ChannelReader {
+    receiver: Receiver<io::Result<Vec<u8>>>,
+    buffer: Vec<u8>,
+    position: usize,
+}
+
+impl Read for ChannelReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = self.fill_buf()?;
+        let n = available.len().min(buf.len());
+        buf[..n].copy_from_slice(&available[..n]);
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+impl BufRead for ChannelReader {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        while self.position == self.buffer.len() {
+            match self.receiver.recv() {
+                Ok(data) => {
+                    self.buffer = data?;
+                    self.position = 0;
+                }
+                // The sender finishes by hanging up:
+                Err(_) => return Ok(&[]),
+            }
+        }
+        Ok(&self.buffer[self.position..])
+    }
+
+    fn consume(&mut self, amount: usize) {
+        self.position = (self.position + amount).min(self.buffer.len());
+    }
+}
+
+/// The size of the BGZF block starting with `header`, if it is one: BGZF blocks are gzip
+/// members with a `BC` extra subfield giving the block size less one.
+fn bgzf_block_size(header: &[u8]) -> Option<usize> {
+    if header.len() < 12 || header[..4] != [0x1f, 0x8b, 8, 4] {
+        return None;
+    }
+    let extra_length = u16::from_le_bytes([header[10], header[11]]) as usize;
+    let mut extra = header.get(12..12 + extra_length)?;
+    while extra.len() >= 4 {
+        let length = u16::from_le_bytes([extra[2], extra[3]]) as usize;
+        if extra[..2] == *b"BC" && length == 2 {
+            return Some(u16::from_le_bytes([*extra.get(4)?, *extra.get(5)?]) as usize + 1);
+        }
+        extra = extra.get(4 + length..)?;
+    }
+    None
+}
+
+/// Read the next BGZF block, or `None` at the end of the input.
+fn read_block(input: &mut impl BufRead) -> io::Result<Option<Vec<u8>>> {
+    if input.fill_buf()?.is_empty() {
+        return Ok(None);
+    }
+    let mut header = [0_u8; 12];
+    input.read_exact(&mut header)?;
+    let extra_length = u16::from_le_bytes([header[10], header[11]]) as usize;
+    let mut block = header.to_vec();
+    block.resize(12 + extra_length, 0_u8);
+    input.read_exact(&mut block[12..])?;
+    let size = bgzf_block_size(&block)
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "not a BGZF block"))?;
+    let header_length = block.len();
+    if size < header_length {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            "invalid BGZF block size",
+        ));
+    }
+    block.resize(size, 0_u8);
+    input.read_exact(&mut block[header_length..])?;
+    Ok(Some(block))
+}
+
+fn decompress_block(block: &[u8]) -> io::Result<Vec<u8>> {
+    let mut data: Vec<u8> = Vec::new();
+    GzDecoder::new(block).read_to_end(&mut data)?;
+    Ok(data)
+}
+
+/// Decompress batches of BGZF blocks in parallel, sending them on in order.
+fn send_blocks(mut input: impl BufRead, threads: usize, sender: &SyncSender<io::Result<Vec<u8>>>) {
+    let pool = match rayon::ThreadPoolBuilder::new().num_threads(threads).build() {
+        Ok(pool) => pool,
+        Err(e) => {
+            let _ = sender.send(Err(io::Error::other(e)));
+            return;
+        }
+    };
+    loop {
+        let mut blocks: Vec<Vec<u8>> = Vec::new();
+        while blocks.len() < threads * BLOCKS_PER_THREAD {
+            match read_block(&mut input) {
+                Ok(Some(block)) => blocks.push(block),
+                Ok(None) => break,
+                Err(e) => {
+                    let _ = sender.send(Err(e));
+                    return;
+                }
+            }
+        }
+        let finished = blocks.len() < threads * BLOCKS_PER_THREAD;
+        let data: Vec<io::Result<Vec<u8>>> = pool.install(|| {
+            blocks
+                .par_iter()
+                .map(|block| decompress_block(block))
+                .collect()
+        });
+        for data in data {
+            let failed = data.is_err();
+            // Stop if the reader has gone away, or after passing on an error:
+            if sender.send(data).is_err() || failed {
+                return;
+            }
+        }
+        if finished {
+            return;
+        }
+    }
+}
+
+/// Decompress a gzip stream that cannot be split on a single thread, sending it on in chunks.
+fn send_stream(input: impl BufRead, sender: &SyncSender<io::Result<Vec<u8>>>) {
+    let mut decoder = fastq::gunzip(input);
+    loop {
+        let mut data: Vec<u8> = Vec::with_capacity(STREAM_CHUNK);
+        match (&mut decoder)
+            .take(STREAM_CHUNK as u64)
+            .read_to_end(&mut data)
+        {
+            Ok(0) => return,
+            Ok(_) => {
+                if sender.send(Ok(data)).is_err() {
+                    return;
+                }
+            }
+            Err(e) => {
+                // Pass on what was decompressed before the error, as with truncated input:
+                if !data.is_empty() && sender.send(Ok(data)).is_err() {
+                    return;
+                }
+                let _ = sender.send(Err(e));
+                return;
+            }
+        }
+    }
+}
+
+/// Open a FASTQ file for reading, decompressing it (if it is gzipped) on background threads.
+///
+/// BGZF files, made up of independently compressed blocks, are decompressed on `threads`
+/// threads. Other gzip files can only be decompressed from start to end, so they are
+/// decompressed on one background thread, alongside the processing of the reads. Inputs
+/// piped through a decompression program (or CRAM decoded with the `reference`) are
+/// decompressed alongside the reads anyway.
+pub fn open_reads_threaded(
+    path: &Path,
+    threads: usize,
+    reference: Option<&Path>,
+) -> Result<Box<dyn BufRead>, Report> {
+    let mut input = BufReader::with_capacity(STREAM_CHUNK, fastq::open_file(path)?);
+    let header = input.fill_buf()?;
+    match fastq::detect_format(header, path)? {
+        Format::Plain => return Ok(Box::new(input)),
+        Format::Piped(program) => return fastq::pipe_through(input, program),
+        Format::Cram => return fastq::pipe_cram(input, reference),
+        Format::Gzip => (),
+    }
+    let bgzf = bgzf_block_size(header).is_some();
+    let (sender, receiver) = sync_channel(threads.max(2) * BLOCKS_PER_THREAD);
+    thread::spawn(move || match bgzf {
+        true => send_blocks(input, threads, &sender),
+        false => send_stream(input, &sender),
+    });
+    Ok(Box::new(ChannelReader {
+        receiver,
+        buffer: Vec::new(),
+        position: 0,
+    }))
+}
@@ -0,0 +1,130 @@
+use crate::index::CandidateIndex;
+use crate::whitelist::open_text;
+use simple_eyre::eyre::{eyre, Report};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufWriter, Write};
+use std::path::Path;
+
+/// The samples of a sample sheet and their barcodes.
+pub struct SampleSheet {
+    pub samples: Vec<String>,
+    index: CandidateIndex,
+}
+
+/// Read a two-column (sample, barcode) sample sheet, indexing the barcodes for lookups within
+/// `radius` edits.
+pub fn read_sample_sheet(path: &Path, radius: usize) -> Result<SampleSheet, Report> {
+    let mut samples: Vec<String> = Vec::new();
+    let mut barcodes: Vec<String> = Vec::new();
+    for (i, line) in open_text(path)?.lines().enumerate() {
+        let line = line?;
+        let mut fields = line.split_ascii_whitespace();
+        let Some(sample) = fields.next().filter(|s| !s.starts_with('#')) else {
+            continue;
+        };
+        let barcode = fields
+            .next()
+            .ok_or_else(|| eyre!("sample sheet line {} has no barcode", i + 1))?;
+        samples.push(sample.to_string());
+        barcodes.push(barcode.to_string());
+    }
+    Ok(SampleSheet {
+        samples,
+        index: CandidateIndex::new(barcodes, radius),
+    })
+}
+
+/// The reads assigned to each sample, and how many of them are ambiguous with other samples.
+pub struct Crosstalk {
+    /// The reads assigned to each sample.
+    pub assigned: Vec<u64>,
+    /// For each pair of samples (a, b), the reads assigned to a whose barcode is within
+    /// distance 1 of the barcode of b.
+    pub matrix: Vec<Vec<u64>>,
+    pub unassigned: u64,
+}
+
+impl SampleSheet {
+    pub fn barcode(&self, sample: usize) -> &str {
+        self.index.get(sample)
+    }
+
+    /// Assign each observed barcode to the unique nearest sample within `threshold_distance`,
+    /// and count the bleed-through between samples.
+    pub fn crosstalk(
+        &self,
+        barcodes: &HashMap<String, u64>,
+        threshold_distance: usize,
+    ) -> Crosstalk {
+        let n = self.samples.len();
+        let mut crosstalk = Crosstalk {
+            assigned: vec![0_u64; n],
+            matrix: vec![vec![0_u64; n]; n],
+            unassigned: 0_u64,
+        };
+        for (barcode, count) in barcodes.iter() {
+            let near = self.index.within(barcode);
+            let nearest = near
+                .iter()
+                .filter(|(_, distance)| *distance <= threshold_distance)
+                .map(|(_, distance)| *distance)
+                .min();
+            let candidates: Vec<usize> = near
+                .iter()
+                .filter(|(_, distance)| Some(*distance) == nearest)
+                .map(|(sample, _)| *sample)
+                .collect();
+            match candidates[..] {
+                [a] => {
+                    crosstalk.assigned[a] += count;
+                    for (b, distance) in near.iter() {
+                        if *b != a && *distance <= 1 {
+                            crosstalk.matrix[a][*b] += count;
+                        }
+                    }
+                }
+                _ => crosstalk.unassigned += count,
+            }
+        }
+        crosstalk
+    }
+
+    /// The pairs of samples whose barcodes are within `max_distance` of each other.
+    pub fn close_pairs(&self, max_distance: usize) -> Vec<(usize, usize, usize)> {
+        let mut pairs: Vec<(usize, usize, usize)> = Vec::new();
+        for a in 0..self.index.len() {
+            for (b, distance) in self.index.within(self.index.get(a)) {
+                if b > a && distance <= max_distance {
+                    pairs.push((a, b, distance));
+                }
+            }
+        }
+        pairs.sort();
+        pairs
+    }
+}
+
+impl Crosstalk {
+    /// Write the crosstalk matrix, with the reads assigned to each sample on the diagonal.
+    pub fn write(&self, path: &Path, sheet: &SampleSheet) -> Result<(), Report> {
+        let mut buffer = BufWriter::new(File::create(path)?);
+        write!(buffer, "sample")?;
+        for sample in sheet.samples.iter() {
+            write!(buffer, "\t{sample}")?;
+        }
+        writeln!(buffer)?;
+        for (a, row) in self.matrix.iter().enumerate() {
+            write!(buffer, "{}", sheet.samples[a])?;
+            for (b, reads) in row.iter().enumerate() {
+                match a == b {
+                    true => write!(buffer, "\t{}", self.assigned[a])?,
+                    false => write!(buffer, "\t{reads}")?,
+                }
+            }
+            writeln!(buffer)?;
+        }
+        buffer.flush()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,92 @@
+use crate::merge::Merge;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+
+/// The distinct read sequences seen with a barcode, stored either in full or as 64-bit hashes.
+enum Distinct {
+    Sequences(HashSet<String>),
+    Hashes(HashSet<u64>),
+}
+
+impl Distinct {
+    fn len(&self) -> usize {
+        match self {
+            Distinct::Sequences(sequences) => sequences.len(),
+            Distinct::Hashes(hashes) => hashes.len(),
+        }
+    }
+
+    fn extend(&mut self, other: Distinct) {
+        match (self, other) {
+            (Distinct::Sequences(a), Distinct::Sequences(b)) => a.extend(b),
+            (Distinct::Hashes(a), Distinct::Hashes(b)) => a.extend(b),
+            _ => unreachable!("distinct read sets are all stored the same way"),
+        }
+    }
+}
+
+/// The distinct read sequences seen with each barcode, as a proxy for PCR duplication.
+///
+/// Every distinct read is kept in memory for each barcode, so storing 64-bit hashes rather
+/// than the sequences themselves saves memory at the cost of a small chance of collisions.
+pub struct DuplicateStats {
+    hash: bool,
+    barcodes: HashMap<String, Distinct>,
+}
+
+impl DuplicateStats {
+    pub fn new(hash: bool) -> Self {
+        DuplicateStats {
+            hash,
+            barcodes: HashMap::new(),
+        }
+    }
+
+    pub fn add(&mut self, barcode: &str, read: &str) {
+        if !self.barcodes.contains_key(barcode) {
+            let distinct = match self.hash {
+                true => Distinct::Hashes(HashSet::new()),
+                false => Distinct::Sequences(HashSet::new()),
+            };
+            self.barcodes.insert(barcode.to_owned(), distinct);
+        }
+        match self.barcodes.get_mut(barcode) {
+            Some(Distinct::Sequences(sequences)) if !sequences.contains(read) => {
+                sequences.insert(read.to_owned());
+            }
+            Some(Distinct::Hashes(hashes)) => {
+                let mut hasher = DefaultHasher::new();
+                read.hash(&mut hasher);
+                hashes.insert(hasher.finish());
+            }
+            _ => (),
+        }
+    }
+
+    /// Combine the distinct reads of merged barcodes with those of their endpoints.
+    pub fn merge(&mut self, merges: &[Merge]) {
+        for merge in merges.iter() {
+            if let Some(distinct) = self.barcodes.remove(&merge.barcode) {
+                match self.barcodes.get_mut(&merge.endpoint) {
+                    Some(endpoint) => endpoint.extend(distinct),
+                    None => {
+                        self.barcodes.insert(merge.endpoint.to_owned(), distinct);
+                    }
+                }
+            }
+        }
+    }
+
+    /// The number of distinct reads seen with a barcode.
+    pub fn distinct_reads(&self, barcode: &str) -> usize {
+        self.barcodes
+            .get(barcode)
+            .map_or(0, |distinct| distinct.len())
+    }
+
+    /// The total number of distinct reads across all barcodes.
+    pub fn total_distinct_reads(&self) -> u64 {
+        self.barcodes.values().map(|d| d.len() as u64).sum()
+    }
+}
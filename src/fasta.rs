@@ -0,0 +1,67 @@
+use crate::fastq::{self, Record, RecordReader};
+use simple_eyre::eyre::{eyre, Report};
+use std::io::BufRead;
+use std::path::Path;
+
+/// The extensions of FASTA files, before any compression extension.
+pub const FASTA_EXTENSIONS: [&str; 3] = [".fasta", ".fa", ".fna"];
+
+/// The quality given to every base of a FASTA record (Phred 40 at offset 33), so that FASTA
+/// reads pass any quality filters.
+const FASTA_QUALITY: char = 'I';
+
+/// Whether a file name has a FASTA extension, compressed or not.
+pub fn is_fasta_path(path: &Path) -> bool {
+    let name = path.to_string_lossy();
+    let name = fastq::strip_compression(fastq::strip_query(path, &name));
+    FASTA_EXTENSIONS
+        .iter()
+        .any(|extension| name.ends_with(extension))
+}
+
+/// Reads FASTA records, whose sequences may be wrapped over several lines. The headers are
+/// given a FASTQ `@` in place of the `>`, so that records are written out as FASTQ.
+pub struct FastaReader<R> {
+    inner: R,
+    line: String,
+}
+
+impl<R: BufRead> FastaReader<R> {
+    pub fn new(inner: R) -> Self {
+        FastaReader {
+            inner,
+            line: String::new(),
+        }
+    }
+}
+
+impl<R: BufRead> RecordReader for FastaReader<R> {
+    fn read_record(&mut self, record: &mut Record) -> Result<bool, Report> {
+        record.header.clear();
+        // Skip any blank lines before the header:
+        while record.header.trim().is_empty() {
+            record.header.clear();
+            if self.inner.read_line(&mut record.header)? == 0 {
+                return Ok(false);
+            }
+        }
+        let len = record.header.trim_end().len();
+        record.header.truncate(len);
+        if !record.header.starts_with('>') {
+            return Err(eyre!("invalid FASTA header {}", record.header));
+        }
+        record.header.replace_range(..1, "@");
+        // The sequence runs until the next header:
+        record.seq.clear();
+        while self.inner.fill_buf()?.first().is_some_and(|c| *c != b'>') {
+            self.line.clear();
+            self.inner.read_line(&mut self.line)?;
+            record.seq.push_str(self.line.trim_end());
+        }
+        record.qual.clear();
+        record
+            .qual
+            .extend(std::iter::repeat_n(FASTA_QUALITY, record.seq.len()));
+        Ok(true)
+    }
+}
@@ -0,0 +1,738 @@
+use crate::bam::{self, BamReader};
+use crate::fasta::{FastaReader, FASTA_EXTENSIONS};
+use flate2::read::MultiGzDecoder;
+use simple_eyre::eyre::{eyre, Report};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, ErrorKind, Read};
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdout, Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+/// A single FASTQ record, with line endings removed.
+#[derive(Default, Clone)]
+pub struct Record {
+    pub header: String,
+    pub seq: String,
+    pub qual: String,
+}
+
+/// A source of read records.
+pub trait RecordReader {
+    /// Read the next record into `record`, returning `false` at the end of the input.
+    fn read_record(&mut self, record: &mut Record) -> Result<bool, Report>;
+}
+
+/// A malformed record, with the reason it is malformed, so that it can be skipped.
+#[derive(Debug)]
+pub struct InvalidRecord {
+    pub reason: &'static str,
+    message: String,
+}
+
+impl std::fmt::Display for InvalidRecord {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for InvalidRecord {}
+
+fn invalid(reason: &'static str, message: String) -> Report {
+    InvalidRecord { reason, message }.into()
+}
+
+/// Reads FASTQ records from a buffered input, checking their structure.
+///
+/// Blank lines between records are skipped, and sequences and qualities wrapped over several
+/// lines are joined: the sequence runs until the `+` line, and the qualities until there are
+/// as many as there are bases (or are on one line, if the sequence is). After a malformed
+/// record, reading resumes from the next line starting with `@`.
+pub struct FastqReader<R> {
+    inner: R,
+    separator: String,
+    /// The number of the record being read.
+    number: u64,
+}
+
+impl<R: BufRead> FastqReader<R> {
+    pub fn new(inner: R) -> Self {
+        FastqReader {
+            inner,
+            separator: String::new(),
+            number: 0_u64,
+        }
+    }
+
+    /// Read a line onto the end of `line`, without its line ending, returning `false` at the
+    /// end of the input.
+    fn append_line(&mut self, line: &mut String) -> Result<bool, Report> {
+        if self.inner.read_line(line)? == 0 {
+            return Ok(false);
+        }
+        let len = line.trim_end_matches(['\n', '\r']).len();
+        line.truncate(len);
+        Ok(true)
+    }
+}
+
+impl<R: BufRead> RecordReader for FastqReader<R> {
+    fn read_record(&mut self, record: &mut Record) -> Result<bool, Report> {
+        record.header.clear();
+        while record.header.is_empty() {
+            if !self.append_line(&mut record.header)? {
+                return Ok(false);
+            }
+        }
+        self.number += 1_u64;
+        let number = self.number;
+        if !record.header.starts_with('@') {
+            while self.inner.fill_buf()?.first().is_some_and(|c| *c != b'@') {
+                self.separator.clear();
+                self.inner.read_line(&mut self.separator)?;
+            }
+            return Err(invalid(
+                "no_header",
+                format!(
+                    "FASTQ record {number} does not start with @: {}",
+                    record.header
+                ),
+            ));
+        }
+        // An incomplete record is reported as a truncated input:
+        let incomplete = |record: &Record| -> Report {
+            io::Error::new(
+                ErrorKind::UnexpectedEof,
+                format!("FASTQ record {number} ({}) is incomplete", record.header),
+            )
+            .into()
+        };
+        record.seq.clear();
+        let mut seq_lines = 0_usize;
+        loop {
+            match self.inner.fill_buf()?.first() {
+                None => return Err(incomplete(record)),
+                Some(b'+') => break,
+                Some(b'@') => {
+                    return Err(invalid(
+                        "no_separator",
+                        format!("FASTQ record {number} ({}) has no + line", record.header),
+                    ))
+                }
+                Some(_) => {
+                    self.append_line(&mut record.seq)?;
+                    seq_lines += 1;
+                }
+            }
+        }
+        self.separator.clear();
+        self.inner.read_line(&mut self.separator)?;
+        record.qual.clear();
+        let mut qual_lines = 0_usize;
+        while record.qual.len() < record.seq.len() && (seq_lines > 1 || qual_lines == 0) {
+            if !self.append_line(&mut record.qual)? {
+                return Err(incomplete(record));
+            }
+            qual_lines += 1;
+        }
+        if record.qual.len() != record.seq.len() {
+            return Err(invalid(
+                "quality_length",
+                format!(
+                    "FASTQ record {number} ({}) has {} quality scores for {} bases",
+                    record.header,
+                    record.qual.len(),
+                    record.seq.len()
+                ),
+            ));
+        }
+        Ok(true)
+    }
+}
+
+/// Whether two headers name the same read (pair), comparing the names up to any comment and
+/// ignoring any `/1` or `/2` suffixes.
+pub fn same_read(a: &str, b: &str) -> bool {
+    let name = |header: &str| {
+        let name = header.split_ascii_whitespace().next().unwrap_or_default();
+        let name = name.strip_suffix("/1").unwrap_or(name);
+        name.strip_suffix("/2").unwrap_or(name).to_owned()
+    };
+    name(a) == name(b)
+}
+
+/// An input that was found to be truncated before any of it could be read, which fails
+/// with the truncation when it is read.
+struct Truncated(Option<io::Error>);
+
+impl Read for Truncated {
+    fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+        match self.0.take() {
+            Some(e) => Err(e),
+            None => Ok(0),
+        }
+    }
+}
+
+/// Read the records of a (decompressed) input, which is BAM if it starts with the BAM magic
+/// bytes, FASTA if it is known to be or starts with `>`, and FASTQ otherwise.
+pub fn record_reader(
+    mut input: Box<dyn BufRead>,
+    fasta: bool,
+) -> Result<Box<dyn RecordReader>, Report> {
+    // An input truncated before its first record is reported as truncated when it is read:
+    let start = match input.fill_buf() {
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => {
+            let input = BufReader::new(Truncated(Some(e)));
+            return Ok(Box::new(FastqReader::new(input)));
+        }
+        start => start?,
+    };
+    Ok(if start.starts_with(bam::BAM_MAGIC) {
+        Box::new(BamReader::new(input)?)
+    } else if fasta || start.starts_with(b">") {
+        Box::new(FastaReader::new(input))
+    } else {
+        Box::new(FastqReader::new(input))
+    })
+}
+
+/// Whether an error is the input ending early, as with a partially transferred gzip file.
+fn is_truncation(e: &Report) -> bool {
+    e.downcast_ref::<io::Error>()
+        .is_some_and(|e| e.kind() == ErrorKind::UnexpectedEof)
+}
+
+/// Reads the records of several inputs in turn, as if they were one, counting the records
+/// of each.
+///
+/// A truncated input is an error, unless truncation is allowed, when its reads up to the
+/// truncation are kept and the input is noted in `truncated` before moving on to the next.
+pub struct FastqFiles {
+    readers: Vec<Box<dyn RecordReader>>,
+    names: Vec<String>,
+    current: usize,
+    allow_truncated: bool,
+    skip_invalid: bool,
+    pub counts: Vec<u64>,
+    pub truncated: Vec<usize>,
+    /// The number of malformed records skipped for each reason, if they are being skipped.
+    pub invalid: BTreeMap<&'static str, u64>,
+}
+
+impl FastqFiles {
+    pub fn new(
+        readers: Vec<Box<dyn RecordReader>>,
+        names: Vec<String>,
+        allow_truncated: bool,
+        skip_invalid: bool,
+    ) -> Self {
+        FastqFiles {
+            counts: vec![0_u64; readers.len()],
+            readers,
+            names,
+            current: 0,
+            allow_truncated,
+            skip_invalid,
+            truncated: Vec::new(),
+            invalid: BTreeMap::new(),
+        }
+    }
+
+    /// Whether to skip a record that was read, counting it if it is malformed and malformed
+    /// records are being skipped. Sequences of anything but `ACGTN` are malformed too.
+    fn skip(&mut self, read: &Result<bool, Report>, record: &Record) -> bool {
+        if !self.skip_invalid {
+            return false;
+        }
+        let reason = match read {
+            Err(e) => match e.downcast_ref::<InvalidRecord>() {
+                Some(invalid) => invalid.reason,
+                None => return false,
+            },
+            Ok(true) if !record.seq.bytes().all(|base| b"ACGTNacgtn".contains(&base)) => {
+                "invalid_base"
+            }
+            Ok(_) => return false,
+        };
+        *self.invalid.entry(reason).or_insert(0_u64) += 1_u64;
+        true
+    }
+
+    /// Pass on the outcome of reading from the current input, taking a truncation to be the
+    /// end of the input if that is allowed.
+    fn check_truncation(&mut self, read: Result<bool, Report>) -> Result<bool, Report> {
+        match read {
+            Err(e) if is_truncation(&e) => {
+                let (name, reads) = (&self.names[self.current], self.counts[self.current]);
+                if !self.allow_truncated {
+                    return Err(eyre!(
+                        "{name} is truncated after {reads} reads ({e}); use --allow-truncated \
+                        to count the reads before the truncation"
+                    ));
+                }
+                self.truncated.push(self.current);
+                Ok(false)
+            }
+            read => read,
+        }
+    }
+
+    /// The index of the input being read.
+    pub fn current(&self) -> usize {
+        self.current
+    }
+
+    /// Read the next record into `record`, returning `false` at the end of the last input.
+    pub fn read_record(&mut self, record: &mut Record) -> Result<bool, Report> {
+        while let Some(reader) = self.readers.get_mut(self.current) {
+            let read = reader.read_record(record);
+            if self.skip(&read, record) {
+                continue;
+            }
+            if self.check_truncation(read)? {
+                self.counts[self.current] += 1_u64;
+                return Ok(true);
+            }
+            self.current += 1;
+        }
+        Ok(false)
+    }
+
+    /// Read the next pair of records from interleaved input, in which each read 1 is
+    /// followed by its read 2, returning `false` at the end of the last input.
+    pub fn read_pair(&mut self, read1: &mut Record, read2: &mut Record) -> Result<bool, Report> {
+        while let Some(reader) = self.readers.get_mut(self.current) {
+            let read = match reader.read_record(read1) {
+                // A malformed or truncated read 2 is passed on, to drop the pair or end the input:
+                Ok(true) => match reader.read_record(read2) {
+                    Ok(true) => Ok(true),
+                    Ok(false) => Err(eyre!(
+                        "interleaved input ends with an unpaired read {}",
+                        read1.header
+                    )),
+                    Err(e) => Err(e),
+                },
+                Ok(false) => Ok(false),
+                // The mate of a malformed read 1 goes with it:
+                Err(e) => {
+                    if e.downcast_ref::<InvalidRecord>().is_some() {
+                        let _ = reader.read_record(read2);
+                    }
+                    Err(e)
+                }
+            };
+            if self.skip(&read, read1) || (read.is_ok() && self.skip(&read, read2)) {
+                continue;
+            }
+            if self.check_truncation(read)? {
+                self.counts[self.current] += 1_u64;
+                return Ok(true);
+            }
+            self.current += 1;
+        }
+        Ok(false)
+    }
+}
+
+/// The extensions of FASTQ (or BAM and CRAM) files, compressed or not.
+const FASTQ_EXTENSIONS: [&str; 4] = [".fastq", ".fq", ".bam", ".cram"];
+const COMPRESSION_EXTENSIONS: [&str; 4] = [".gz", ".zst", ".bz2", ".xz"];
+
+/// A file name without any compression extension.
+pub fn strip_compression(name: &str) -> &str {
+    COMPRESSION_EXTENSIONS
+        .iter()
+        .find_map(|extension| name.strip_suffix(extension))
+        .unwrap_or(name)
+}
+
+/// A file name without any compression extension and then any FASTQ extension.
+pub fn strip_extensions(name: &str) -> &str {
+    let name = strip_compression(name);
+    FASTQ_EXTENSIONS
+        .iter()
+        .chain(FASTA_EXTENSIONS.iter())
+        .find_map(|extension| name.strip_suffix(extension))
+        .unwrap_or(name)
+}
+
+/// Whether a file name matches a pattern of `*` (any run of characters) and `?` (any one
+/// character) wildcards.
+fn glob_match(pattern: &[u8], name: &[u8]) -> bool {
+    match (pattern.split_first(), name.split_first()) {
+        (None, None) => true,
+        (Some((b'*', rest)), _) => {
+            glob_match(rest, name) || (!name.is_empty() && glob_match(pattern, &name[1..]))
+        }
+        (Some((b'?', rest)), Some((_, name))) => glob_match(rest, name),
+        (Some((p, rest)), Some((n, name))) if p == n => glob_match(rest, name),
+        _ => false,
+    }
+}
+
+/// The files of a directory whose names are accepted, in name order.
+fn list_directory(directory: &Path, accept: impl Fn(&str) -> bool) -> Result<Vec<PathBuf>, Report> {
+    let mut paths: Vec<PathBuf> = Vec::new();
+    let entries = std::fs::read_dir(directory)
+        .map_err(|e| eyre!("cannot read directory {}: {e}", directory.to_string_lossy()))?;
+    for entry in entries {
+        let entry = entry?;
+        if entry.file_type()?.is_file() && accept(&entry.file_name().to_string_lossy()) {
+            paths.push(entry.path());
+        }
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+/// Expand the input paths: a directory stands for the FASTQ files in it, and a path whose
+/// file name has `*` or `?` wildcards (and does not exist as it is) for the files matching it.
+pub fn expand_inputs(paths: &[PathBuf]) -> Result<Vec<PathBuf>, Report> {
+    let mut expanded: Vec<PathBuf> = Vec::new();
+    for path in paths {
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let matched = if is_url(path) {
+            expanded.push(path.to_owned());
+            continue;
+        } else if path.is_dir() {
+            list_directory(path, |name| strip_extensions(name) != name)?
+        } else if !path.exists() && name.contains(['*', '?']) {
+            let directory = match path.parent() {
+                Some(parent) if !parent.as_os_str().is_empty() => parent,
+                _ => Path::new("."),
+            };
+            list_directory(directory, |file| {
+                glob_match(name.as_bytes(), file.as_bytes())
+            })?
+        } else {
+            expanded.push(path.to_owned());
+            continue;
+        };
+        if matched.is_empty() {
+            return Err(eyre!("no FASTQ files found for {}", path.to_string_lossy()));
+        }
+        expanded.extend(matched);
+    }
+    Ok(expanded)
+}
+
+/// Open a file, the standard input if the path is `-`, or the download of a URL.
+pub fn open_file(path: &Path) -> Result<Box<dyn Read + Send>, Report> {
+    if is_url(path) {
+        return open_url(&path.to_string_lossy());
+    }
+    Ok(match path == Path::new("-") {
+        true => Box::new(std::io::stdin()),
+        false => Box::new(
+            File::open(path).map_err(|e| eyre!("cannot open {}: {e}", path.to_string_lossy()))?,
+        ),
+    })
+}
+
+/// The compression of an input.
+pub enum Format {
+    Plain,
+    Gzip,
+    /// Compressed in a format that is decompressed by piping it through the named program.
+    Piped(&'static str),
+    /// CRAM, which is decoded to FASTQ by `samtools`.
+    Cram,
+}
+
+/// The format of an input starting with `start`, by its magic bytes. Anything that is not
+/// compressed must be plain FASTQ (or FASTA), so that other inputs are reported rather than being parsed
+/// as (no) reads.
+pub fn detect_format(start: &[u8], path: &Path) -> Result<Format, Report> {
+    Ok(match start {
+        [] | [b'@' | b'>', ..] => Format::Plain,
+        [0x1f, 0x8b, ..] => Format::Gzip,
+        [b'B', b'Z', b'h', ..] => Format::Piped("bzip2"),
+        [0x28, 0xb5, 0x2f, 0xfd, ..] => Format::Piped("zstd"),
+        [0xfd, b'7', b'z', b'X', b'Z', ..] => Format::Piped("xz"),
+        [b'C', b'R', b'A', b'M', ..] => Format::Cram,
+        _ => {
+            return Err(eyre!(
+                "{} is not FASTQ or FASTA, as it does not start with @ or >",
+                match path == Path::new("-") {
+                    true => "the standard input".to_string(),
+                    false => path.to_string_lossy().into_owned(),
+                }
+            ))
+        }
+    })
+}
+
+/// The output of a decompression (or download) program, which fails if the program does.
+struct PipedReader {
+    program: &'static str,
+    /// What the program does, for its failure message.
+    task: &'static str,
+    child: Child,
+    stdout: ChildStdout,
+    /// Set once the whole of the input fed to the program has been read, so that a failure
+    /// after then is taken to be the input's truncation (which zstd, bzip2 and xz all fail
+    /// on), as for gzip.
+    input_ended: Option<Arc<AtomicBool>>,
+}
+
+impl Read for PipedReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.stdout.read(buf)?;
+        if n == 0 && !buf.is_empty() {
+            let status = self.child.wait()?;
+            let ended = self
+                .input_ended
+                .as_ref()
+                .is_some_and(|ended| ended.load(Ordering::Relaxed));
+            if !status.success() && ended {
+                return Err(io::Error::new(
+                    ErrorKind::UnexpectedEof,
+                    format!("the {} data ends early ({status})", self.program),
+                ));
+            }
+            if !status.success() {
+                return Err(io::Error::other(format!(
+                    "{} failed to {} ({status})",
+                    self.program, self.task
+                )));
+            }
+        }
+        Ok(n)
+    }
+}
+
+/// Decompress an input by piping it through `program -dc`, which runs alongside the
+/// processing of the reads.
+pub fn pipe_through(
+    input: impl Read + Send + 'static,
+    program: &'static str,
+) -> Result<Box<dyn BufRead>, Report> {
+    let mut command = Command::new(program);
+    command.arg("-dc");
+    run_piped(input, program, command)
+}
+
+/// Decode a CRAM input to FASTQ with `samtools fastq`, which skips secondary and
+/// supplementary alignments and turns reverse-strand reads back around. Without a reference,
+/// samtools finds it from the CRAM header.
+pub fn pipe_cram(
+    input: impl Read + Send + 'static,
+    reference: Option<&Path>,
+) -> Result<Box<dyn BufRead>, Report> {
+    let mut command = Command::new("samtools");
+    command.args(["fastq", "-n"]);
+    if let Some(reference) = reference {
+        command.arg("--reference").arg(reference);
+    }
+    command.arg("-");
+    run_piped(input, "samtools", command)
+}
+
+/// Feed an input to a decoding program, reading its output.
+fn run_piped(
+    input: impl Read + Send + 'static,
+    program: &'static str,
+    mut command: Command,
+) -> Result<Box<dyn BufRead>, Report> {
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(|e| {
+            eyre!("cannot run {program} to decompress the input (is it installed?): {e}")
+        })?;
+    let mut stdin = child.stdin.take().expect("the child's input is piped");
+    let stdout = child.stdout.take().expect("the child's output is piped");
+    // Any failure to pass on the input shows up as a failure of the program:
+    let ended = Arc::new(AtomicBool::new(false));
+    let mut input = TrackEnd {
+        inner: input,
+        ended: ended.clone(),
+    };
+    thread::spawn(move || io::copy(&mut input, &mut stdin));
+    Ok(Box::new(BufReader::new(PipedReader {
+        program,
+        task: "decompress the input",
+        child,
+        stdout,
+        input_ended: Some(ended),
+    })))
+}
+
+/// Whether an input is an `http://`, `https://` or `s3://` URL rather than a file.
+pub fn is_url(path: &Path) -> bool {
+    let path = path.to_string_lossy();
+    ["http://", "https://", "s3://"]
+        .iter()
+        .any(|scheme| path.starts_with(scheme))
+}
+
+/// A name from a path without any URL query string (such as the signature of a presigned
+/// URL).
+pub fn strip_query<'a>(path: &Path, name: &'a str) -> &'a str {
+    match is_url(path) {
+        true => name.split('?').next().unwrap_or(name),
+        false => name,
+    }
+}
+
+/// Stream the contents of a URL, downloading with `curl` (or the AWS CLI for S3, which uses
+/// its usual credentials), so that nothing is stored locally.
+fn open_url(url: &str) -> Result<Box<dyn Read + Send>, Report> {
+    let (program, mut command) = match url.starts_with("s3://") {
+        true => {
+            let mut command = Command::new("aws");
+            command.args(["s3", "cp", url, "-"]);
+            ("aws", command)
+        }
+        false => {
+            let mut command = Command::new("curl");
+            command.args(["--fail", "--silent", "--show-error", "--location", url]);
+            ("curl", command)
+        }
+    };
+    let mut child = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(|e| eyre!("cannot run {program} to download {url} (is it installed?): {e}"))?;
+    let stdout = child.stdout.take().expect("the child's output is piped");
+    Ok(Box::new(PipedReader {
+        program,
+        task: "download the input",
+        child,
+        stdout,
+        input_ended: None,
+    }))
+}
+
+/// Notes when a compressed input has been read to its end.
+struct TrackEnd<R> {
+    inner: R,
+    ended: Arc<AtomicBool>,
+}
+
+impl<R: Read> Read for TrackEnd<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if n == 0 && !buf.is_empty() {
+            self.ended.store(true, Ordering::Relaxed);
+        }
+        Ok(n)
+    }
+}
+
+/// Decompressed gzip data, in which the decoder failing once the whole of the compressed input
+/// has been read means that the input is truncated, rather than corrupt.
+struct Gunzip<R: Read> {
+    decoder: MultiGzDecoder<TrackEnd<R>>,
+    ended: Arc<AtomicBool>,
+}
+
+impl<R: Read> Read for Gunzip<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.decoder.read(buf) {
+            Err(e) if self.ended.load(Ordering::Relaxed) => Err(io::Error::new(
+                ErrorKind::UnexpectedEof,
+                format!("the gzip data ends early: {e}"),
+            )),
+            read => read,
+        }
+    }
+}
+
+/// Decompress a gzip input, reporting truncation as an unexpected end of file.
+pub fn gunzip(input: impl Read) -> impl Read {
+    let ended = Arc::new(AtomicBool::new(false));
+    Gunzip {
+        decoder: MultiGzDecoder::new(TrackEnd {
+            inner: input,
+            ended: ended.clone(),
+        }),
+        ended,
+    }
+}
+
+/// Open a FASTQ file for reading, decompressing it according to its magic bytes. CRAM input
+/// is decoded with the `reference`, if given.
+pub fn open_reads(path: &Path, reference: Option<&Path>) -> Result<Box<dyn BufRead>, Report> {
+    let mut input = BufReader::new(open_file(path)?);
+    Ok(match detect_format(input.fill_buf()?, path)? {
+        Format::Plain => Box::new(input),
+        Format::Gzip => Box::new(BufReader::new(gunzip(input))),
+        Format::Piped(program) => pipe_through(input, program)?,
+        Format::Cram => pipe_cram(input, reference)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    /// Interleaved input of the given FASTQ text, as a single input.
+    fn interleaved(text: &'static str, allow_truncated: bool, skip_invalid: bool) -> FastqFiles {
+        let reader: Box<dyn RecordReader> =
+            Box::new(FastqReader::new(Cursor::new(text.as_bytes())));
+        FastqFiles::new(
+            vec![reader],
+            vec!["test.fq".to_string()],
+            allow_truncated,
+            skip_invalid,
+        )
+    }
+
+    /// The read 1 headers of the pairs read from some input, or the error that ended it.
+    fn read_pairs(files: &mut FastqFiles) -> Result<Vec<String>, Report> {
+        let (mut read1, mut read2) = (Record::default(), Record::default());
+        let mut headers: Vec<String> = Vec::new();
+        while files.read_pair(&mut read1, &mut read2)? {
+            headers.push(read1.header.clone());
+        }
+        Ok(headers)
+    }
+
+    #[test]
+    fn malformed_mate_2_drops_the_pair() {
+        let text = "@p1/1\nACGT\n+\nIIII\n@p1/2\nACGT\n+\nIII\n\
+            @p2/1\nACGT\n+\nIIII\n@p2/2\nACGT\n+\nIIII\n";
+        let mut files = interleaved(text, false, true);
+        assert_eq!(read_pairs(&mut files).unwrap(), vec!["@p2/1"]);
+        assert_eq!(files.invalid.get("quality_length"), Some(&1_u64));
+        assert_eq!(files.counts, vec![1_u64]);
+        let mut files = interleaved(text, false, false);
+        assert!(read_pairs(&mut files).is_err());
+    }
+
+    #[test]
+    fn truncated_mate_2_ends_the_input() {
+        let text = "@p1/1\nACGT\n+\nIIII\n@p1/2\nACGT\n+\nIIII\n@p2/1\nACGT\n+\nIIII\n@p2/2\nAC";
+        let mut files = interleaved(text, true, false);
+        assert_eq!(read_pairs(&mut files).unwrap(), vec!["@p1/1"]);
+        assert_eq!(files.truncated, vec![0_usize]);
+        let mut files = interleaved(text, false, false);
+        let e = read_pairs(&mut files).unwrap_err();
+        assert!(e.to_string().contains("--allow-truncated"));
+    }
+
+    #[test]
+    fn truncated_before_the_first_record() {
+        let truncated = || {
+            let e = io::Error::new(ErrorKind::UnexpectedEof, "the zstd data ends early");
+            let input: Box<dyn BufRead> = Box::new(BufReader::new(Truncated(Some(e))));
+            record_reader(input, false).unwrap()
+        };
+        let mut files = FastqFiles::new(vec![truncated()], vec!["t.fq.zst".into()], true, false);
+        assert!(!files.read_record(&mut Record::default()).unwrap());
+        assert_eq!(files.truncated, vec![0_usize]);
+        let mut files = FastqFiles::new(vec![truncated()], vec!["t.fq.zst".into()], false, false);
+        let e = files.read_record(&mut Record::default()).unwrap_err();
+        assert!(e.to_string().contains("truncated after 0 reads"));
+    }
+}
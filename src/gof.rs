@@ -0,0 +1,136 @@
+use crate::whitelist::open_text;
+use simple_eyre::eyre::{eyre, Report};
+use std::collections::HashMap;
+use std::io::BufRead;
+use std::path::Path;
+
+/// Read the expected barcode fractions from a (barcode, fraction) file, normalised to sum to
+/// one. Lines with only a barcode are given equal weight, so a plain whitelist gives a
+/// uniform expectation. A barcode may only be listed once.
+pub fn read_expected(path: &Path) -> Result<Vec<(String, f64)>, Report> {
+    let mut expected: Vec<(String, f64)> = Vec::new();
+    let mut lines_by_barcode: HashMap<String, usize> = HashMap::new();
+    for (i, line) in open_text(path)?.lines().enumerate() {
+        let line = line?;
+        let mut fields = line.split_ascii_whitespace();
+        let Some(barcode) = fields.next().filter(|b| !b.starts_with('#')) else {
+            continue;
+        };
+        let fraction = match fields.next() {
+            Some(fraction) => fraction
+                .parse::<f64>()
+                .map_err(|_| eyre!("invalid expected fraction {fraction} on line {}", i + 1))?,
+            None => 1_f64,
+        };
+        if !(fraction >= 0_f64 && fraction.is_finite()) {
+            return Err(eyre!(
+                "invalid expected fraction {fraction} on line {}",
+                i + 1
+            ));
+        }
+        if let Some(first) = lines_by_barcode.insert(barcode.to_string(), i + 1) {
+            return Err(eyre!(
+                "barcode {barcode} is expected on both lines {first} and {} of the expected file",
+                i + 1
+            ));
+        }
+        expected.push((barcode.to_string(), fraction));
+    }
+    let total: f64 = expected.iter().map(|(_, f)| f).sum();
+    if total <= 0_f64 {
+        return Err(eyre!("expected fractions do not sum to a positive value"));
+    }
+    for (_, fraction) in expected.iter_mut() {
+        *fraction /= total;
+    }
+    Ok(expected)
+}
+
+/// The representation of a single expected barcode.
+pub struct Representation {
+    pub barcode: String,
+    pub observed: u64,
+    pub expected: f64,
+    pub fold_change: f64,
+}
+
+/// A comparison of the observed barcode counts against an expected distribution.
+pub struct GoodnessOfFit {
+    pub chi_square: f64,
+    pub g: f64,
+    pub degrees_of_freedom: usize,
+    pub observed_reads: u64,
+    pub unexpected_reads: u64,
+    pub zero_expected_reads: u64,
+    /// The expected barcodes in descending order of fold change.
+    pub representation: Vec<Representation>,
+}
+
+/// Compare the observed counts of the expected barcodes with their expected fractions.
+///
+/// Barcodes with an expected fraction of zero are left out of the statistics (their reads are
+/// reported separately), as are observed barcodes not in the expected list. Fold changes are
+/// calculated with a pseudocount of 0.5 reads, so that unobserved barcodes have a finite
+/// fold change.
+pub fn goodness_of_fit(expected: &[(String, f64)], counts: &HashMap<String, u64>) -> GoodnessOfFit {
+    let observed = |barcode: &str| counts.get(barcode).copied().unwrap_or(0);
+    let zero_expected_reads: u64 = expected
+        .iter()
+        .filter(|(_, fraction)| *fraction == 0_f64)
+        .map(|(barcode, _)| observed(barcode))
+        .sum();
+    let listed: u64 = expected.iter().map(|(barcode, _)| observed(barcode)).sum();
+    let observed_reads = listed.saturating_sub(zero_expected_reads);
+    let mut gof = GoodnessOfFit {
+        chi_square: 0_f64,
+        g: 0_f64,
+        degrees_of_freedom: 0,
+        observed_reads,
+        unexpected_reads: counts.values().sum::<u64>().saturating_sub(listed),
+        zero_expected_reads,
+        representation: Vec::new(),
+    };
+    for (barcode, fraction) in expected.iter().filter(|(_, f)| *f > 0_f64) {
+        let o = observed(barcode);
+        let e = observed_reads as f64 * fraction;
+        if e > 0_f64 {
+            gof.chi_square += (o as f64 - e).powi(2) / e;
+            if o > 0 {
+                gof.g += 2_f64 * o as f64 * (o as f64 / e).ln();
+            }
+        }
+        gof.representation.push(Representation {
+            barcode: barcode.to_owned(),
+            observed: o,
+            expected: e,
+            fold_change: (o as f64 + 0.5) / (e + 0.5),
+        });
+    }
+    gof.degrees_of_freedom = gof.representation.len().saturating_sub(1);
+    gof.representation
+        .sort_by(|a, b| b.fold_change.total_cmp(&a.fold_change));
+    gof
+}
+
+impl GoodnessOfFit {
+    /// The ratio of the highest to the lowest fold change among the expected barcodes.
+    pub fn max_min_ratio(&self) -> Option<f64> {
+        match (self.representation.first(), self.representation.last()) {
+            (Some(max), Some(min)) => Some(max.fold_change / min.fold_change),
+            _ => None,
+        }
+    }
+
+    /// The expected barcodes with more reads than expected, most over-represented first.
+    pub fn over_represented(&self) -> impl Iterator<Item = &Representation> {
+        self.representation.iter().filter(|r| r.fold_change > 1_f64)
+    }
+
+    /// The expected barcodes with fewer reads than expected, most under-represented first.
+    pub fn under_represented(&self) -> impl Iterator<Item = &Representation> {
+        self.representation
+            .iter()
+            .rev()
+            .filter(|r| r.fold_change < 1_f64)
+    }
+}
@@ -0,0 +1,53 @@
+/// The fields of a Casava 1.8+ read header comment, e.g. `1:N:0:ACGTACGT+TTGCAACC`.
+pub struct CasavaComment {
+    pub filtered: bool,
+}
+
+/// Parse the Casava comment from a header line, returning `None` if it is not in that format.
+pub fn parse_casava_comment(header: &str) -> Option<CasavaComment> {
+    let comment = header.split_ascii_whitespace().nth(1)?;
+    let mut fields = comment.splitn(4, ':');
+    fields.next()?.parse::<u8>().ok()?;
+    let filtered = match fields.next()? {
+        "Y" | "y" => true,
+        "N" | "n" => false,
+        _ => return None,
+    };
+    fields.next()?.parse::<u32>().ok()?;
+    Some(CasavaComment { filtered })
+}
+
+/// A search expression for the index read sequences of a Casava 1.8+ header comment, which
+/// captures the i7 index, or the i7 and i5 indexes joined by `+` for dual-indexed runs.
+pub const ILLUMINA_INDEX_EXPRESSION: &str =
+    r"^\S+\s+\d+:[YNyn]:\d+:([ACGTN]+(?:\+[ACGTN]+)?)(?:\s|$)";
+
+/// The flow cell location of a read, from an Illumina read name.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ReadLocation {
+    pub lane: u32,
+    pub tile: u32,
+}
+
+/// Parse the lane and tile from an Illumina read name, in either the Casava 1.8+ style
+/// (`@instrument:run:flowcell:lane:tile:x:y`) or the older style
+/// (`@instrument:lane:tile:x:y#index/read`), returning `None` if it is in neither format.
+pub fn parse_read_location(header: &str) -> Option<ReadLocation> {
+    let name = header.split_ascii_whitespace().next()?;
+    let name = name.strip_prefix('@').unwrap_or(name);
+    // Remove any old-style index and read number suffix:
+    let name = name.split(['#', '/']).next()?;
+    let fields: Vec<&str> = name.split(':').collect();
+    let (lane, tile) = match fields.len() {
+        7 => (fields[3], fields[4]),
+        5 => (fields[1], fields[2]),
+        _ => return None,
+    };
+    for coordinate in &fields[fields.len() - 2..] {
+        coordinate.parse::<u32>().ok()?;
+    }
+    Some(ReadLocation {
+        lane: lane.parse().ok()?,
+        tile: tile.parse().ok()?,
+    })
+}
@@ -0,0 +1,67 @@
+use simple_eyre::eyre::Report;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+/// Frequency counts of observations binned to integers.
+#[derive(Default)]
+pub struct Histogram {
+    bins: BTreeMap<u64, u64>,
+    total: u64,
+}
+
+impl Histogram {
+    pub fn add(&mut self, bin: u64) {
+        *self.bins.entry(bin).or_insert(0_u64) += 1_u64;
+        self.total += 1_u64;
+    }
+
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    /// Iterate over the (bin, count) pairs in ascending bin order.
+    pub fn iter(&self) -> impl Iterator<Item = (u64, u64)> + '_ {
+        self.bins.iter().map(|(bin, count)| (*bin, *count))
+    }
+
+    /// The smallest bin at which the cumulative count reaches fraction `q` of the total.
+    pub fn quantile(&self, q: f64) -> Option<u64> {
+        let target = (q * self.total as f64).ceil().max(1_f64) as u64;
+        let mut cumulative = 0_u64;
+        for (bin, count) in self.iter() {
+            cumulative += count;
+            if cumulative >= target {
+                return Some(bin);
+            }
+        }
+        None
+    }
+
+    /// A one-line description of the distribution (min, quartiles and max).
+    pub fn describe(&self) -> String {
+        match self.total {
+            0 => "no observations".to_string(),
+            _ => format!(
+                "min {}, q1 {}, median {}, q3 {}, max {}",
+                self.quantile(0_f64).unwrap_or(0),
+                self.quantile(0.25).unwrap_or(0),
+                self.quantile(0.5).unwrap_or(0),
+                self.quantile(0.75).unwrap_or(0),
+                self.quantile(1_f64).unwrap_or(0),
+            ),
+        }
+    }
+
+    /// Write the histogram to a two-column tab-delimited file, with `label` naming the bins.
+    pub fn write(&self, path: &Path, label: &str) -> Result<(), Report> {
+        let mut buffer = BufWriter::new(File::create(path)?);
+        writeln!(buffer, "{label}\tcount")?;
+        for (bin, count) in self.iter() {
+            writeln!(buffer, "{bin}\t{count}")?;
+        }
+        buffer.flush()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,213 @@
+use crate::output::Row;
+use simple_eyre::eyre::Report;
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+/// The number of top barcodes listed in the report.
+const TOP_BARCODES: usize = 20;
+
+/// The size of the rank curve plot and its margins, in pixels.
+const WIDTH: f64 = 640_f64;
+const HEIGHT: f64 = 400_f64;
+const MARGIN: f64 = 60_f64;
+
+/// The run totals shown in the report.
+pub struct RunSummary<'a> {
+    pub sample: &'a str,
+    pub inputs: Vec<String>,
+    pub expression: &'a str,
+    pub total_reads: u64,
+    pub barcoded_reads: u64,
+    pub no_barcode: u64,
+    pub filtered_reads: u64,
+    pub barcodes_before_merging: usize,
+    pub barcodes_after_merging: usize,
+    pub threshold_count: u64,
+    pub threshold_distance: usize,
+    pub merges: usize,
+    pub merged_reads: u64,
+}
+
+/// Escape text for HTML.
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn percent(count: u64, total: u64) -> String {
+    match total {
+        0 => "-".to_string(),
+        total => format!("{:0.2}%", 100_f64 * count as f64 / total as f64),
+    }
+}
+
+/// Draw the barcode rank curve (the count of each barcode against its rank, both on log
+/// scales) as an SVG. Only the points that move the curve by a visible amount are drawn, so
+/// that the plot stays small for millions of barcodes.
+fn rank_curve(rows: &[Row]) -> String {
+    let counts: Vec<u64> = rows
+        .iter()
+        .map(|row| row.count)
+        .filter(|count| *count > 0)
+        .collect();
+    let mut svg = String::new();
+    let _ = write!(
+        svg,
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{WIDTH}\" height=\"{HEIGHT}\">"
+    );
+    if counts.is_empty() {
+        svg.push_str("<text x=\"60\" y=\"200\">No barcodes</text></svg>");
+        return svg;
+    }
+    // The axes run from 1 to the next power of ten above the largest rank and count:
+    let max_rank = (counts.len() as f64).log10().ceil().max(1_f64);
+    let max_count = (counts[0] as f64).log10().ceil().max(1_f64);
+    let x = |rank: f64| MARGIN + (WIDTH - 2_f64 * MARGIN) * rank.log10() / max_rank;
+    let y = |count: f64| HEIGHT - MARGIN - (HEIGHT - 2_f64 * MARGIN) * count.log10() / max_count;
+    let _ = write!(
+        svg,
+        "<g stroke=\"#444\" fill=\"none\"><line x1=\"{MARGIN}\" y1=\"{0}\" x2=\"{1}\" y2=\"{0}\"/>\
+        <line x1=\"{MARGIN}\" y1=\"{MARGIN}\" x2=\"{MARGIN}\" y2=\"{0}\"/></g>",
+        HEIGHT - MARGIN,
+        WIDTH - MARGIN
+    );
+    svg.push_str("<g font-size=\"12\" fill=\"#444\">");
+    for power in 0..=max_rank as i32 {
+        let _ = write!(
+            svg,
+            "<text x=\"{:0.1}\" y=\"{:0.1}\" text-anchor=\"middle\">1e{power}</text>",
+            x(10_f64.powi(power)),
+            HEIGHT - MARGIN + 18_f64
+        );
+    }
+    for power in 0..=max_count as i32 {
+        let _ = write!(
+            svg,
+            "<text x=\"{:0.1}\" y=\"{:0.1}\" text-anchor=\"end\">1e{power}</text>",
+            MARGIN - 6_f64,
+            y(10_f64.powi(power)) + 4_f64
+        );
+    }
+    let _ = write!(
+        svg,
+        "<text x=\"{:0.1}\" y=\"{:0.1}\" text-anchor=\"middle\">Barcode rank</text>\
+        <text x=\"16\" y=\"{:0.1}\" text-anchor=\"middle\" \
+        transform=\"rotate(-90 16 {:0.1})\">Reads</text></g>",
+        WIDTH / 2_f64,
+        HEIGHT - 16_f64,
+        HEIGHT / 2_f64,
+        HEIGHT / 2_f64
+    );
+    let mut points: Vec<String> = Vec::new();
+    let mut last: Option<(f64, f64)> = None;
+    for (i, count) in counts.iter().enumerate() {
+        let point = (x((i + 1) as f64), y(*count as f64));
+        let visible = match last {
+            Some((x, y)) => (point.0 - x).abs() >= 0.5 || (point.1 - y).abs() >= 0.5,
+            None => true,
+        };
+        if visible || i + 1 == counts.len() {
+            points.push(format!("{:0.1},{:0.1}", point.0, point.1));
+            last = Some(point);
+        }
+    }
+    let _ = write!(
+        svg,
+        "<polyline fill=\"none\" stroke=\"#1f77b4\" stroke-width=\"2\" points=\"{}\"/></svg>",
+        points.join(" ")
+    );
+    svg
+}
+
+/// Write a self-contained HTML report of a run, with its match rate, merge statistics,
+/// barcode rank curve and top barcodes, for reading without opening the tables.
+pub fn write_report(path: &Path, summary: &RunSummary, rows: &[Row]) -> Result<(), Report> {
+    let mut buffer = BufWriter::new(File::create(path)?);
+    let title = format!("fqbarcode report: {}", escape(summary.sample));
+    writeln!(
+        buffer,
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>{title}</title>\n\
+        <style>body {{ font-family: sans-serif; max-width: 60em; margin: 2em auto; color: #222 }} \
+        table {{ border-collapse: collapse }} th, td {{ padding: 0.2em 0.8em; text-align: left }} \
+        tr:nth-child(even) {{ background: #f2f2f2 }} td.n {{ text-align: right }} \
+        code {{ word-break: break-all }}</style>\n</head><body>\n<h1>{title}</h1>"
+    )?;
+    writeln!(
+        buffer,
+        "<p>fqbarcode {} searching <code>{}</code> in {}.</p>",
+        env!("CARGO_PKG_VERSION"),
+        escape(summary.expression),
+        escape(&summary.inputs.join(", "))
+    )?;
+    let total = summary.total_reads;
+    writeln!(buffer, "<h2>Reads</h2>\n<table>")?;
+    for (name, count) in [
+        ("Total", total),
+        ("Barcoded", summary.barcoded_reads),
+        ("No barcode", summary.no_barcode),
+        ("Filtered", summary.filtered_reads),
+    ] {
+        writeln!(
+            buffer,
+            "<tr><td>{name}</td><td class=\"n\">{count}</td><td class=\"n\">{}</td></tr>",
+            percent(count, total)
+        )?;
+    }
+    writeln!(buffer, "</table>\n<h2>Merging</h2>\n<table>")?;
+    for (name, value) in [
+        (
+            "Barcodes before merging",
+            summary.barcodes_before_merging.to_string(),
+        ),
+        (
+            "Barcodes after merging",
+            summary.barcodes_after_merging.to_string(),
+        ),
+        ("Barcodes merged", summary.merges.to_string()),
+        (
+            "Reads on merged barcodes",
+            format!(
+                "{} ({})",
+                summary.merged_reads,
+                percent(summary.merged_reads, summary.barcoded_reads)
+            ),
+        ),
+        (
+            "Merge threshold",
+            format!(
+                "counts of at most {}, within edit distance {}",
+                summary.threshold_count, summary.threshold_distance
+            ),
+        ),
+    ] {
+        writeln!(
+            buffer,
+            "<tr><td>{name}</td><td class=\"n\">{value}</td></tr>"
+        )?;
+    }
+    writeln!(buffer, "</table>\n<h2>Barcode rank curve</h2>")?;
+    writeln!(buffer, "{}", rank_curve(rows))?;
+    writeln!(
+        buffer,
+        "<h2>Top barcodes</h2>\n<table>\n<tr><th>Rank</th><th>Barcode</th><th>Reads</th>\
+        <th>Fraction</th></tr>"
+    )?;
+    for (i, row) in rows.iter().take(TOP_BARCODES).enumerate() {
+        writeln!(
+            buffer,
+            "<tr><td class=\"n\">{}</td><td><code>{}</code></td><td class=\"n\">{}</td>\
+            <td class=\"n\">{}</td></tr>",
+            i + 1,
+            escape(&row.label.replace('\t', " ")),
+            row.count,
+            percent(row.count, summary.barcoded_reads)
+        )?;
+    }
+    writeln!(buffer, "</table>\n</body></html>")?;
+    buffer.flush()?;
+    Ok(())
+}
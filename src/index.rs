@@ -0,0 +1,330 @@
+use crate::merge::Metric;
+use std::collections::{HashMap, HashSet};
+
+/// An index of barcodes for finding all entries within a small edit distance of a query
+/// without comparing against every entry.
+///
+/// This uses the pigeonhole principle: if two barcodes are within edit distance `r`, then of
+/// any `r + 1` non-overlapping q-grams of one, at least one occurs exactly in the other,
+/// shifted by at most `r` positions. Only entries sharing such a q-gram are compared in full.
+/// The same holds for the Hamming distance, with no shift. If an N matches any base, the
+/// entries and queries with an N are compared with everything instead.
+pub struct CandidateIndex {
+    barcodes: Vec<String>,
+    radius: usize,
+    metric: Metric,
+    wildcards: Vec<usize>,
+    q: usize,
+    qgrams: HashMap<String, Vec<(usize, usize)>>,
+}
+
+impl CandidateIndex {
+    pub fn new(barcodes: Vec<String>, radius: usize) -> Self {
+        CandidateIndex::with_metric(barcodes, radius, Metric::Levenshtein { wildcard: false })
+    }
+
+    /// An index of the barcodes within `radius` of a query by `metric`.
+    pub fn with_metric(barcodes: Vec<String>, radius: usize, metric: Metric) -> Self {
+        let min_length = barcodes.iter().map(|b| b.len()).min().unwrap_or(0);
+        let q = (min_length / (radius + 1)).max(1);
+        let mut qgrams: HashMap<String, Vec<(usize, usize)>> = HashMap::new();
+        for (i, barcode) in barcodes.iter().enumerate() {
+            for position in 0..=barcode.len().saturating_sub(q) {
+                if let Some(qgram) = barcode.get(position..position + q) {
+                    qgrams
+                        .entry(qgram.to_string())
+                        .or_default()
+                        .push((i, position));
+                }
+            }
+        }
+        CandidateIndex {
+            wildcards: wildcards(&barcodes, metric),
+            barcodes,
+            radius,
+            metric,
+            q,
+            qgrams,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.barcodes.len()
+    }
+
+    pub fn get(&self, index: usize) -> &str {
+        &self.barcodes[index]
+    }
+
+    /// All (index, distance) pairs of entries within the index radius of `query`.
+    pub fn within(&self, query: &str) -> Vec<(usize, usize)> {
+        let candidates: Vec<usize> = match query.len() >= self.q * (self.radius + 1)
+            && !(self.metric.wildcard() && has_wildcard(query))
+        {
+            true => {
+                let mut candidates: HashSet<usize> = self.wildcards.iter().copied().collect();
+                for segment in 0..=self.radius {
+                    let start = segment * self.q;
+                    let Some(entries) = query
+                        .get(start..start + self.q)
+                        .and_then(|qgram| self.qgrams.get(qgram))
+                    else {
+                        continue;
+                    };
+                    for (i, position) in entries.iter() {
+                        if position.abs_diff(start) <= self.radius {
+                            candidates.insert(*i);
+                        }
+                    }
+                }
+                candidates.into_iter().collect()
+            }
+            // Queries too short to be split into segments are compared with everything:
+            false => (0..self.barcodes.len()).collect(),
+        };
+        candidates
+            .into_iter()
+            .filter_map(|i| {
+                let distance = self.metric.within(query, &self.barcodes[i], self.radius)?;
+                Some((i, distance))
+            })
+            .collect()
+    }
+
+    /// The entry nearest to `query`, if it is within `max_distance` and unique.
+    pub fn nearest(&self, query: &str, max_distance: usize) -> Option<usize> {
+        let near = self.within(query);
+        let nearest = near
+            .iter()
+            .map(|(_, distance)| *distance)
+            .filter(|distance| *distance <= max_distance)
+            .min()?;
+        let mut candidates = near.iter().filter(|(_, distance)| *distance == nearest);
+        match (candidates.next(), candidates.next()) {
+            (Some((i, _)), None) => Some(*i),
+            _ => None,
+        }
+    }
+}
+
+/// Whether a barcode has an N.
+fn has_wildcard(barcode: &str) -> bool {
+    barcode.bytes().any(|base| base.eq_ignore_ascii_case(&b'N'))
+}
+
+/// The entries with an N, if an N matches any base by `metric`, which have to be compared
+/// with every query.
+fn wildcards(barcodes: &[String], metric: Metric) -> Vec<usize> {
+    match metric.wildcard() {
+        true => (0..barcodes.len())
+            .filter(|i| has_wildcard(&barcodes[*i]))
+            .collect(),
+        false => Vec::new(),
+    }
+}
+
+/// The hashes of the deletion neighbourhood of a barcode: every string left by deleting at
+/// most `radius` of its characters. The strings are hashed with FNV-1a as they are visited,
+/// (a character at a time) without being built, as there are many of them.
+fn deletion_neighbourhood(barcode: &str, radius: usize) -> Vec<u64> {
+    // Each set of deleted positions is visited once, by deleting them in increasing order:
+    fn visit(chars: &[char], deleted: &mut Vec<usize>, remaining: usize, hashes: &mut Vec<u64>) {
+        let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+        let mut next = deleted.iter().peekable();
+        for (i, c) in chars.iter().enumerate() {
+            if next.peek() == Some(&&i) {
+                next.next();
+                continue;
+            }
+            hash = (hash ^ *c as u64).wrapping_mul(0x0100_0000_01b3);
+        }
+        hashes.push(hash);
+        if remaining == 0 {
+            return;
+        }
+        let start = deleted.last().map_or(0, |last| last + 1);
+        for i in start..chars.len() {
+            deleted.push(i);
+            visit(chars, deleted, remaining - 1, hashes);
+            deleted.pop();
+        }
+    }
+    let chars: Vec<char> = barcode.chars().collect();
+    let mut hashes: Vec<u64> = Vec::new();
+    visit(&chars, &mut Vec::new(), radius, &mut hashes);
+    hashes.sort_unstable();
+    hashes.dedup();
+    hashes
+}
+
+/// An index of barcodes by their deletion neighbourhoods, for finding all entries within a
+/// small edit distance of a query in a few lookups, as in SymSpell.
+///
+/// If two barcodes are within edit distance `r`, deleting at most `r` characters from each
+/// makes them equal, so every entry within `r` of a query shares a string of its deletion
+/// neighbourhood with the query's. The neighbourhoods grow steeply with `r` (to about
+/// `C(L, r)` strings for barcodes of length `L`), so this is only practical for radii of one
+/// or two. Only the hashes of the strings are kept, as a collision only adds a candidate that
+/// is then compared in full. They are sorted with the entries they came from, which takes
+/// much less memory than a map of them, and found from the offsets of their leading bits.
+/// If an N matches any base, the entries and queries with an N are compared with everything
+/// instead.
+pub struct DeletionIndex {
+    barcodes: Vec<String>,
+    radius: usize,
+    metric: Metric,
+    wildcards: Vec<usize>,
+    deletions: Vec<(u64, u32)>,
+    /// The offset of the first hash with each value of the leading bits.
+    offsets: Vec<u32>,
+    shift: u32,
+}
+
+impl DeletionIndex {
+    pub fn new(barcodes: Vec<String>, radius: usize, metric: Metric) -> Self {
+        let mut deletions: Vec<(u64, u32)> = barcodes
+            .iter()
+            .enumerate()
+            .flat_map(|(i, barcode)| {
+                deletion_neighbourhood(barcode, radius)
+                    .into_iter()
+                    .map(move |deleted| (deleted, i as u32))
+            })
+            .collect();
+        deletions.sort_unstable();
+        // About as many values of the leading bits as hashes, so that each has one or two:
+        let bits = (usize::BITS - deletions.len().leading_zeros()).max(1);
+        let shift = u64::BITS - bits;
+        let mut offsets: Vec<u32> = Vec::with_capacity((1 << bits) + 1);
+        for (offset, (hash, _)) in deletions.iter().enumerate() {
+            while offsets.len() <= (hash >> shift) as usize {
+                offsets.push(offset as u32);
+            }
+        }
+        offsets.resize((1 << bits) + 1, deletions.len() as u32);
+        DeletionIndex {
+            wildcards: wildcards(&barcodes, metric),
+            barcodes,
+            radius,
+            metric,
+            deletions,
+            offsets,
+            shift,
+        }
+    }
+
+    /// All (index, distance) pairs of entries within the index radius of `query`.
+    pub fn within(&self, query: &str) -> Vec<(usize, usize)> {
+        let mut candidates: HashSet<usize> = match self.metric.wildcard() && has_wildcard(query) {
+            true => (0..self.barcodes.len()).collect(),
+            false => self.wildcards.iter().copied().collect(),
+        };
+        for deleted in deletion_neighbourhood(query, self.radius) {
+            let leading = (deleted >> self.shift) as usize;
+            let (start, end) = (self.offsets[leading], self.offsets[leading + 1]);
+            candidates.extend(
+                self.deletions[start as usize..end as usize]
+                    .iter()
+                    .filter(|(hash, _)| *hash == deleted)
+                    .map(|(_, i)| *i as usize),
+            );
+        }
+        candidates
+            .into_iter()
+            .filter_map(|i| {
+                let distance = self.metric.within(query, &self.barcodes[i], self.radius)?;
+                Some((i, distance))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    /// Barcodes of mixed lengths with some Ns, most of them a few edits from an earlier one so
+    /// that there are near neighbours to find.
+    fn barcodes(rng: &mut StdRng, count: usize) -> Vec<String> {
+        let mut barcodes: Vec<String> = Vec::new();
+        while barcodes.len() < count {
+            let mut barcode: Vec<u8> = match barcodes.len() {
+                n if n > 0 && rng.gen_bool(0.8) => barcodes[rng.gen_range(0..n)].clone().into(),
+                _ => (0..rng.gen_range(1..=10))
+                    .map(|_| b"ACGT"[rng.gen_range(0..4)])
+                    .collect(),
+            };
+            for _ in 0..rng.gen_range(0..=3) {
+                let i = rng.gen_range(0..=barcode.len());
+                let base = b"ACGTN"[rng.gen_range(0..5)];
+                match rng.gen_range(0..3) {
+                    0 if i < barcode.len() => barcode[i] = base,
+                    1 if i < barcode.len() => drop(barcode.remove(i)),
+                    _ => barcode.insert(i, base),
+                }
+            }
+            barcodes.push(String::from_utf8(barcode).unwrap());
+        }
+        barcodes
+    }
+
+    /// The (index, distance) pairs of the entries within `radius` of `query`, by comparing it
+    /// with every entry.
+    fn scan(entries: &[String], query: &str, radius: usize, metric: Metric) -> Vec<(usize, usize)> {
+        (0..entries.len())
+            .map(|i| (i, metric.distance(query, &entries[i])))
+            .filter(|(_, distance)| *distance <= radius)
+            .collect()
+    }
+
+    fn sorted(mut near: Vec<(usize, usize)>) -> Vec<(usize, usize)> {
+        near.sort_unstable();
+        near
+    }
+
+    const METRICS: [Metric; 4] = [
+        Metric::Levenshtein { wildcard: false },
+        Metric::Levenshtein { wildcard: true },
+        Metric::Hamming { wildcard: false },
+        Metric::Hamming { wildcard: true },
+    ];
+
+    #[test]
+    fn qgram_index_finds_every_barcode_within_the_radius() {
+        let mut rng = StdRng::seed_from_u64(5);
+        for metric in METRICS {
+            for radius in 0..=3 {
+                let entries = barcodes(&mut rng, 150);
+                let index = CandidateIndex::with_metric(entries.clone(), radius, metric);
+                // The queries include the entries, short barcodes and barcodes with an N:
+                for query in barcodes(&mut rng, 250).iter().chain(entries.iter()) {
+                    assert_eq!(
+                        sorted(index.within(query)),
+                        scan(&entries, query, radius, metric),
+                        "{query} within {radius}"
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn deletion_index_finds_every_barcode_within_the_radius() {
+        let mut rng = StdRng::seed_from_u64(6);
+        for metric in METRICS {
+            for radius in 0..=2 {
+                let entries = barcodes(&mut rng, 150);
+                let index = DeletionIndex::new(entries.clone(), radius, metric);
+                for query in barcodes(&mut rng, 250).iter().chain(entries.iter()) {
+                    assert_eq!(
+                        sorted(index.within(query)),
+                        scan(&entries, query, radius, metric),
+                        "{query} within {radius}"
+                    );
+                }
+            }
+        }
+    }
+}
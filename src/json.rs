@@ -0,0 +1,86 @@
+use simple_eyre::eyre::Report;
+use std::fmt::{self, Write as _};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+/// Quote and escape a string for JSON.
+pub fn json_string(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(quoted, "\\u{:04x}", c as u32);
+            }
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// A JSON object built up field by field, in order, for the summary files.
+#[derive(Default)]
+pub struct JsonObject {
+    fields: Vec<(String, String)>,
+}
+
+impl JsonObject {
+    pub fn integer(&mut self, name: &str, value: u64) {
+        self.fields.push((name.to_string(), value.to_string()));
+    }
+
+    /// Add a number, which is null if it is not finite (as JSON has no NaN or infinity).
+    pub fn number(&mut self, name: &str, value: f64) {
+        let json = match value.is_finite() {
+            true => value.to_string(),
+            false => "null".to_string(),
+        };
+        self.fields.push((name.to_string(), json));
+    }
+
+    pub fn string(&mut self, name: &str, value: &str) {
+        self.fields.push((name.to_string(), json_string(value)));
+    }
+
+    pub fn object(&mut self, name: &str, value: JsonObject) {
+        self.fields.push((name.to_string(), value.to_string()));
+    }
+
+    pub fn objects(&mut self, name: &str, values: Vec<JsonObject>) {
+        let values: Vec<String> = values.iter().map(|value| value.to_string()).collect();
+        self.fields
+            .push((name.to_string(), format!("[{}]", values.join(","))));
+    }
+
+    pub fn strings(&mut self, name: &str, values: &[String]) {
+        let values: Vec<String> = values.iter().map(|value| json_string(value)).collect();
+        self.fields
+            .push((name.to_string(), format!("[{}]", values.join(","))));
+    }
+
+    pub fn write(&self, path: &Path) -> Result<(), Report> {
+        let mut buffer = BufWriter::new(File::create(path)?);
+        writeln!(buffer, "{self}")?;
+        buffer.flush()?;
+        Ok(())
+    }
+}
+
+impl fmt::Display for JsonObject {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{{")?;
+        for (i, (name, value)) in self.fields.iter().enumerate() {
+            if i > 0 {
+                write!(f, ",")?;
+            }
+            write!(f, "{}:{value}", json_string(name))?;
+        }
+        write!(f, "}}")
+    }
+}
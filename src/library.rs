@@ -0,0 +1,133 @@
+use crate::whitelist::{open_text, pack, substitutions};
+use simple_eyre::eyre::{eyre, Report};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{BufRead, BufWriter, Write};
+use std::path::Path;
+
+/// A guide in a CRISPR library.
+pub struct Guide {
+    pub name: String,
+    pub gene: String,
+    pub sequence: String,
+}
+
+/// A CRISPR guide library, indexed by packed sequence.
+pub struct Library {
+    pub guides: Vec<Guide>,
+    /// The (first, later) indexes of guides with the same sequence.
+    pub duplicates: Vec<(usize, usize)>,
+    index: HashMap<u64, usize>,
+}
+
+impl Library {
+    /// Read a tab-delimited library with a header row, taking the guide name, gene and
+    /// sequence from the named columns.
+    pub fn read(path: &Path, columns: [&str; 3]) -> Result<Library, Report> {
+        let mut lines = open_text(path)?.lines();
+        let header = lines
+            .next()
+            .ok_or_else(|| eyre!("library file {} is empty", path.to_string_lossy()))??;
+        let header: Vec<&str> = header.split('\t').map(|f| f.trim()).collect();
+        let mut fields = [0_usize; 3];
+        for (field, column) in fields.iter_mut().zip(columns.iter()) {
+            *field = header
+                .iter()
+                .position(|f| f == column)
+                .ok_or_else(|| eyre!("library file has no {column} column"))?;
+        }
+        let mut library = Library {
+            guides: Vec::new(),
+            duplicates: Vec::new(),
+            index: HashMap::new(),
+        };
+        for (i, line) in lines.enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let row: Vec<&str> = line.split('\t').map(|f| f.trim()).collect();
+            let field = |j: usize| {
+                row.get(fields[j])
+                    .map(|f| f.to_string())
+                    .ok_or_else(|| eyre!("library line {} has too few columns", i + 2))
+            };
+            let guide = Guide {
+                name: field(0)?,
+                gene: field(1)?,
+                sequence: field(2)?.to_ascii_uppercase(),
+            };
+            let key = pack(guide.sequence.as_bytes()).ok_or_else(|| {
+                eyre!(
+                    "guide {} sequence is not made up of at most 31 A, C, G and T bases",
+                    guide.name
+                )
+            })?;
+            match library.index.get(&key) {
+                Some(j) => library.duplicates.push((*j, library.guides.len())),
+                None => {
+                    library.index.insert(key, library.guides.len());
+                }
+            }
+            library.guides.push(guide);
+        }
+        Ok(library)
+    }
+
+    /// Assign a sequence to a guide by exact match, or else by a unique single mismatch
+    /// (unless `rescue` is false).
+    pub fn assign(&self, sequence: &[u8], rescue: bool) -> Option<&Guide> {
+        if let Some(i) = pack(sequence).and_then(|key| self.index.get(&key)) {
+            return Some(&self.guides[*i]);
+        }
+        if !rescue {
+            return None;
+        }
+        match substitutions(sequence, |key| self.index.contains_key(&key))[..] {
+            [(i, base)] => {
+                let mut corrected = sequence.to_vec();
+                corrected[i] = base;
+                let key = pack(&corrected)?;
+                self.index.get(&key).map(|j| &self.guides[*j])
+            }
+            _ => None,
+        }
+    }
+
+    /// The count of each guide, from the counts of the guide sequences. A sequence shared by
+    /// several guides is counted against the first of them.
+    pub fn guide_counts(&self, counts: &HashMap<String, u64>) -> Vec<u64> {
+        self.guides
+            .iter()
+            .enumerate()
+            .map(|(i, guide)| {
+                let key = pack(guide.sequence.as_bytes());
+                match key.and_then(|key| self.index.get(&key)) == Some(&i) {
+                    true => counts.get(&guide.sequence).copied().unwrap_or(0),
+                    false => 0,
+                }
+            })
+            .collect()
+    }
+
+    /// Write the total counts of the guides of each gene to file.
+    pub fn write_gene_counts(
+        &self,
+        path: &Path,
+        counts: &HashMap<String, u64>,
+    ) -> Result<(), Report> {
+        let mut genes: BTreeMap<&str, (u64, u64)> = BTreeMap::new();
+        for (guide, count) in self.guides.iter().zip(self.guide_counts(counts)) {
+            let (guides, reads) = genes.entry(&guide.gene).or_insert((0_u64, 0_u64));
+            *guides += 1_u64;
+            *reads += count;
+        }
+        let mut buffer = BufWriter::new(File::create(path)?);
+        writeln!(buffer, "gene\tguides\tcount")?;
+        for (gene, (guides, reads)) in genes.iter() {
+            writeln!(buffer, "{gene}\t{guides}\t{reads}")?;
+        }
+        buffer.flush()?;
+        Ok(())
+    }
+}
@@ -1,18 +1,137 @@
-use clap::Parser;
-use flate2::read::MultiGzDecoder;
-use levenshtein::levenshtein;
+mod anchor;
+mod audit;
+mod bam;
+mod batch;
+mod chunk;
+mod decompress;
+mod demux;
+mod duplicate;
+mod fasta;
+mod fastq;
+mod gof;
+mod header;
+mod histogram;
+mod html;
+mod index;
+mod json;
+mod library;
+mod matrix;
+mod merge;
+mod multiqc;
+mod names;
+mod output;
+mod parquet;
+mod pattern;
+mod plate;
+mod quality;
+mod report;
+mod rescue;
+mod saturation;
+mod scan;
+mod search;
+mod sequence;
+mod sqlite;
+mod stats;
+mod strand;
+mod table;
+mod tile;
+mod trim;
+mod umi;
+mod warnings;
+mod whitelist;
+
+use clap::{ArgGroup, Parser, Subcommand};
+use fastq::{FastqFiles, Record};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use histogram::Histogram;
 use log::*;
-use rand::seq::SliceRandom;
-use rand::thread_rng;
+use output::{Columns, Row, TableFormat, TableWriter};
+use rand::SeedableRng;
+use rand_chacha::ChaCha12Rng;
+use rayon::prelude::*;
 use regex::Regex;
-use simple_eyre::eyre::Report;
-use std::collections::{HashMap, HashSet};
+use report::RunReport;
+use search::{Filtered, ReadFilter, Search, Searcher};
+use simple_eyre::eyre::{eyre, Report};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::fs::File;
-use std::io::{BufRead, BufReader, BufWriter, Write};
-use std::path::PathBuf;
+use std::io::{BufWriter, Write};
+use std::mem;
+use std::num::NonZeroU64;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+use warnings::{Code, Warnings};
+
+/// The number of reads read (and searched) at a time.
+const CHUNK_READS: usize = 16384;
 
+/// Count the barcodes of the reads and merge them, either in one run or (with a subcommand)
+/// as separate steps, so that the reads are only counted once.
 #[derive(Parser)]
-#[command(version)]
+#[command(version, args_conflicts_with_subcommands = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    #[command(flatten)]
+    args: Args,
+}
+
+/// The steps of a run that can be carried out on their own, each with the options of a run.
+#[derive(Subcommand)]
+enum Command {
+    /// Count the barcodes of the reads without merging them, writing a table for `merge` or
+    /// `stats`
+    Count(Args),
+    /// Merge the barcodes of count tables (given in place of REGEX and FILE, and counted
+    /// together) without reading the reads
+    Merge(Args),
+    /// Write the run report of count tables (given in place of REGEX and FILE) without
+    /// merging them
+    Stats(Args),
+    /// Join count tables (given in place of REGEX and FILE) on their barcodes, merging them
+    /// on their combined counts, and write a barcode × sample matrix of them
+    MergeTables(Args),
+    /// Count the barcodes of the reads without merging them, and write the barcodes above the
+    /// knee of the rank curve as a whitelist, as `umi_tools whitelist` does
+    Whitelist(Args),
+}
+
+/// Which steps a run carries out.
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+enum Mode {
+    /// Count the barcodes of the reads and merge them.
+    #[default]
+    Run,
+    /// Count the barcodes of the reads only.
+    Count,
+    /// Merge the barcodes of count tables.
+    Merge,
+    /// Report on the barcodes of count tables.
+    Stats,
+    /// Merge count tables into a barcode × sample matrix.
+    MergeTables,
+    /// Derive a whitelist from the barcodes of the reads.
+    Whitelist,
+}
+
+impl Mode {
+    /// Whether the barcodes are read from count tables rather than counted from the reads.
+    fn reads_tables(&self) -> bool {
+        matches!(self, Mode::Merge | Mode::Stats | Mode::MergeTables)
+    }
+
+    /// Whether the barcodes are merged.
+    fn merges(&self) -> bool {
+        matches!(self, Mode::Run | Mode::Merge | Mode::MergeTables)
+    }
+}
+
+#[derive(clap::Args)]
+#[command(group(ArgGroup::new("reference").args(["whitelist_10x_path", "whitelist_path", "library_path"]).multiple(true)))]
+#[command(group(ArgGroup::new("whitelists").args(["whitelist_10x_path", "whitelist_path"])))]
+#[command(group(ArgGroup::new("paired").args(["read2_paths", "interleaved"])))]
+#[command(group(ArgGroup::new("samples").args(["matrix", "batch_path"])))]
 struct Args {
     /// Show log messages. Multiple -v options increase the verbosity
     #[clap(short='v', long="verbose", action=clap::ArgAction::Count)]
@@ -20,9 +139,92 @@ struct Args {
     /// Write non-barcoded sequences to file
     #[clap(short = 'n', long = "unmatched", value_name = "FILE")]
     unmatched_path: Option<PathBuf>,
+    /// Write the reads matching the search expression as FASTQ instead of counting them
+    #[clap(long = "grep")]
+    grep: bool,
+    /// Write the count table (or the matrix, or the reads in grep mode) to this file, gzipped
+    /// if it ends in .gz, rather than the standard output
+    #[clap(short = 'o', long = "output", value_name = "FILE")]
+    output_path: Option<PathBuf>,
+    /// Read 2 files, read in step with the input files: the barcode is found in read 1
+    #[clap(
+        long = "read2",
+        value_name = "FILE",
+        num_args = 1..,
+        conflicts_with_all = ["grep", "batch_path"]
+    )]
+    read2_paths: Vec<PathBuf>,
+    /// The input files are interleaved, with each read 1 followed by its read 2
+    #[clap(long = "interleaved", conflicts_with = "grep")]
+    interleaved: bool,
+    /// Which read of each pair to search for the barcode: 1, 2, or both (read 1, then read 2
+    /// if read 1 has no barcode)
+    #[clap(
+        long = "barcode-read",
+        value_name = "READ",
+        default_value = "1",
+        value_parser = ["1", "2", "both"],
+        requires = "paired"
+    )]
+    barcode_read: String,
+    /// Write the mates of the barcoded reads (read 2, unless the barcode was found in read 2)
+    /// to file as FASTQ (gzipped if it ends in .gz), with their barcodes added to the headers
+    #[clap(long = "read2-output", value_name = "FILE", requires = "paired")]
+    read2_output_path: Option<PathBuf>,
+    /// Write the reads that do not match in grep mode
+    #[clap(long = "invert", requires = "grep")]
+    invert: bool,
+    /// Only write reads whose barcode is within the threshold distance of this one in grep mode
+    #[clap(long = "barcode-equals", value_name = "SEQ", requires = "grep")]
+    barcode_equals: Option<String>,
     /// Search expresion
-    #[clap(value_name = "REGEX")]
-    barcode_expression: String,
+    #[clap(
+        value_name = "REGEX",
+        required_unless_present_any = ["audit_whitelist_path", "batch_path", "illumina_index"]
+    )]
+    barcode_expression: Option<String>,
+    /// Further search expressions to try in order when REGEX does not match, one per line
+    #[clap(long = "patterns", value_name = "FILE")]
+    patterns_path: Option<PathBuf>,
+    /// Search the read headers (without their `@`) for the barcode, rather than the sequences
+    #[clap(
+        long = "from-header",
+        conflicts_with_all = [
+            "both_strands",
+            "rescue_anchor",
+            "min_barcode_quality",
+            "min_barcode_base_quality",
+            "barcode_quality_profile_path",
+            "quality_weighted_correction",
+            "quality_weighted"
+        ]
+    )]
+    from_header: bool,
+    /// Count the index sequences of the Illumina header comments (`1:N:0:ACGTACGT+TTGCAACC`)
+    /// instead of searching with REGEX, which is then not given
+    #[clap(
+        long = "illumina-index",
+        conflicts_with_all = [
+            "patterns_path",
+            "batch_path",
+            "from_header",
+            "both_strands",
+            "rescue_anchor",
+            "min_barcode_quality",
+            "min_barcode_base_quality",
+            "barcode_quality_profile_path",
+            "quality_weighted_correction",
+            "quality_weighted"
+        ]
+    )]
+    illumina_index: bool,
+    /// Also search the reverse complement of reads that do not match, and count the reads of
+    /// each barcode in each orientation
+    #[clap(long = "both-strands")]
+    both_strands: bool,
+    /// Evaluate every search expression on every read, and report reads matching several
+    #[clap(long = "detect-conflicts", requires = "patterns_path")]
+    detect_conflicts: bool,
     /// Replacement expression
     #[clap(
         short = 'r',
@@ -31,14 +233,134 @@ struct Args {
         default_value = "${1}"
     )]
     barcode_replacement: String,
-    /// Threshold count for merging
+    /// UMI expression, counting the distinct UMIs of each barcode
+    #[clap(long = "umi", value_name = "EXPR")]
+    umi_replacement: Option<String>,
+    /// Add a column of display names from a (barcode, name) file to the output
+    #[clap(long = "names", value_name = "FILE")]
+    names_path: Option<PathBuf>,
+    /// Replace the barcodes with their display names, rather than adding a column
+    #[clap(long = "names-replace", requires = "names_path")]
+    names_replace: bool,
+    /// Remove the final barcodes listed in this file (one per line) from the output
+    #[clap(long = "exclude-barcodes", value_name = "FILE")]
+    exclude_barcodes_path: Option<PathBuf>,
+    /// Also remove final barcodes within the threshold distance of an excluded barcode
+    #[clap(long = "exclude-fuzzy", requires = "exclude_barcodes_path")]
+    exclude_fuzzy: bool,
+    /// Write the UMI length, base composition and most frequent UMIs to file
+    #[clap(long = "umi-report", value_name = "FILE", requires = "umi_replacement")]
+    umi_report_path: Option<PathBuf>,
+    /// Warn if the most frequent UMI is more than this fraction of all UMIs
+    #[clap(long = "umi-max-fraction", value_name = "F", default_value = "0.01")]
+    umi_max_fraction: f64,
+    /// Collapse UMI sequencing errors within each barcode by directional adjacency
+    #[clap(long = "correct-umis", requires = "umi_replacement")]
+    correct_umis: bool,
+    /// Treat barcodes as pairs joined by this separator in the replacement expression,
+    /// merging each component separately and writing them as separate columns
+    #[clap(long = "pair-separator", value_name = "SEP")]
+    pair_separator: Option<String>,
+    /// Merge barcodes of each length separately, and add a length column to the output
+    #[clap(long = "split-by-length")]
+    split_by_length: bool,
+    /// Only merge barcodes into barcodes of the same length, without splitting the output by
+    /// length
+    #[clap(long = "merge-same-length")]
+    merge_same_length: bool,
+    /// Write the barcodes of each length to PREFIX<length>.tsv (or the extension of the
+    /// --output-format) instead of the standard output
+    #[clap(
+        long = "output-prefix",
+        value_name = "PREFIX",
+        requires = "split_by_length"
+    )]
+    output_prefix: Option<String>,
+    /// Count the reads of each input file separately, and write a barcode × sample matrix
+    /// instead of the table
+    #[clap(long = "matrix", conflicts_with_all = ["label", "output_prefix"])]
+    matrix: bool,
+    /// Write the matrix of --matrix, --batch or merge-tables as a sparse matrix.mtx with
+    /// barcodes.tsv and samples.tsv in DIR, in the 10x style, instead
+    #[clap(long = "mtx", value_name = "DIR")]
+    mtx_dir: Option<PathBuf>,
+    /// Write the table in the style of another tool: starcode writes the sequence and count of
+    /// each barcode, and mageck writes the guide counts of a --library-tsv screen (of each
+    /// sample, with --batch) as MAGeCK count does, both without the unassigned counts
+    #[clap(
+        long = "output-style",
+        value_name = "STYLE",
+        default_value = "fqbarcode",
+        value_parser = ["fqbarcode", "starcode", "mageck"],
+        requires_if("mageck", "library_path"),
+        conflicts_with_all = ["matrix", "output_format", "output_columns", "header", "label", "output_prefix", "names_path"]
+    )]
+    output_style: String,
+    /// With --output-style starcode, add a column of the barcodes merged into each, starting
+    /// with the barcode itself, as starcode --print-clusters does
+    #[clap(long = "print-clusters", requires = "output_style")]
+    print_clusters: bool,
+    /// Write only the N most frequent barcodes to the table
+    #[clap(long = "top", value_name = "N", conflicts_with = "matrix")]
+    top: Option<usize>,
+    /// Write only the barcodes with at least N reads to the table
+    #[clap(
+        long = "min-count",
+        value_name = "N",
+        default_value = "0",
+        conflicts_with = "matrix"
+    )]
+    min_count: u64,
+    /// Write the reads of the barcodes left out by --top and --min-count as an other count,
+    /// so that the table still adds up
+    #[clap(long = "other", conflicts_with = "matrix")]
+    other: bool,
+    /// Add fraction and cumulative_fraction columns, of each barcode's share of the barcoded
+    /// reads and that of the barcodes down to it in the table
+    #[clap(long = "fractions", conflicts_with = "matrix")]
+    fractions: bool,
+    /// Start the table with a header line of its column names
+    #[clap(long = "header", conflicts_with = "matrix")]
+    header: bool,
+    /// Write only these columns of the table, in this order (such as count,barcode,fraction)
+    #[clap(
+        long = "output-columns",
+        value_name = "NAMES",
+        value_delimiter = ',',
+        conflicts_with = "matrix"
+    )]
+    output_columns: Option<Vec<String>>,
+    /// Write the table as tab- or comma-separated values, as JSON with the run totals, as
+    /// Parquet, or into the SQLite database given by -o (with sqlite3), adding the run to any
+    /// already there
+    #[clap(
+        long = "output-format",
+        value_name = "FORMAT",
+        default_value = "tsv",
+        value_parser = ["tsv", "csv", "json", "parquet", "sqlite"],
+        conflicts_with_all = ["matrix", "grep", "batch_path"]
+    )]
+    output_format: String,
+    /// Threshold count for merging, or auto to use the inflection of the barcode rank curve
     #[clap(
         short = 'm',
         long = "merge-count",
         value_name = "N",
         default_value = "0"
     )]
-    threshold_count: u64,
+    threshold_count: String,
+    /// Threshold count for merging as a fraction of the barcoded reads: barcodes with at least
+    /// this fraction of the reads are endpoints, whatever the sequencing depth
+    #[clap(
+        long = "merge-frac",
+        value_name = "F",
+        conflicts_with = "threshold_count"
+    )]
+    merge_fraction: Option<f64>,
+    /// Only merge a barcode into an endpoint with at least R times its reads, so that
+    /// similarly abundant barcodes are kept apart
+    #[clap(long = "merge-ratio", value_name = "R")]
+    merge_ratio: Option<f64>,
     /// Threshold edit distance for merging
     #[clap(
         short = 't',
@@ -47,47 +369,1221 @@ struct Args {
         default_value = "1"
     )]
     threshold_distance: usize,
-    /// Input fastq.gz file
+    /// How the distance between barcodes is measured for merging: the edit distance, or the
+    /// Hamming distance (the positions that differ) for fixed-length barcodes, which never
+    /// merges barcodes of different lengths
+    #[clap(
+        long = "distance-metric",
+        value_name = "METRIC",
+        default_value = "levenshtein",
+        value_parser = ["levenshtein", "hamming"]
+    )]
+    distance_metric: String,
+    /// Let an N match any base at no cost when measuring the distance between barcodes for
+    /// merging, so that barcodes with an N can be merged into their parent
+    #[clap(long = "n-wildcard")]
+    n_wildcard: bool,
+    /// Weight the distances to the endpoints within the threshold distance by the mean
+    /// quality of each barcode's bases, so that barcodes are merged into the endpoint they
+    /// differ from at the least confident bases
+    #[clap(long = "quality-weighted")]
+    quality_weighted: bool,
+    /// How the barcodes near each other are found for merging: by the q-grams they share, or
+    /// by their deletion neighbourhoods (the strings left by deleting up to the threshold
+    /// distance of bases), which is faster but takes much more memory and only supports
+    /// threshold distances of 1 or 2
+    #[clap(
+        long = "merge-index",
+        value_name = "INDEX",
+        default_value = "qgram",
+        value_parser = ["qgram", "deletion"]
+    )]
+    merge_index: String,
+    /// How barcodes are merged: into the nearest endpoint (with more than the threshold count
+    /// of reads); with the directional adjacency method of UMI-tools, into any barcode within
+    /// the threshold distance with at least 2n - 1 reads; or by clustering, each connected
+    /// component of the barcodes within the threshold distance of each other into its most
+    /// frequent barcode (both of which ignore -m)
+    #[clap(
+        long = "merge-method",
+        value_name = "METHOD",
+        default_value = "endpoint",
+        value_parser = ["endpoint", "directional", "cluster"]
+    )]
+    merge_method: String,
+    /// Write the map of every barcode before merging to its final barcode, with their
+    /// distance and counts, to file
+    #[clap(long = "merge-map", value_name = "FILE", conflicts_with = "batch_path")]
+    merge_map_path: Option<PathBuf>,
+    /// Write the merges as a graph to file, as GraphML if it ends in .graphml and otherwise
+    /// as DOT
+    #[clap(
+        long = "merge-graph",
+        value_name = "FILE",
+        conflicts_with = "batch_path"
+    )]
+    merge_graph_path: Option<PathBuf>,
+    /// How a barcode chooses between endpoints at the same distance: the endpoint with the
+    /// highest count (and then the first in lexicographic order), or one at random
+    #[clap(
+        long = "tie-break",
+        value_name = "POLICY",
+        default_value = "highest-count",
+        value_parser = ["highest-count", "random"]
+    )]
+    tie_break: String,
+    /// Only process reads whose header matches this expression
+    #[clap(long = "header-filter", value_name = "REGEX")]
+    header_filter: Option<String>,
+    /// Discard reads marked as filtered in a Casava header comment
+    #[clap(long = "discard-filtered")]
+    discard_filtered: bool,
+    /// Trim poly-G tails before adapter trimming
+    #[clap(long = "trim-poly-g")]
+    trim_poly_g: bool,
+    /// Trim poly-A tails after adapter trimming
+    #[clap(long = "trim-poly-a")]
+    trim_poly_a: bool,
+    /// Minimum length of a trimmed poly-G or poly-A tail
+    #[clap(long = "poly-min-length", value_name = "N", default_value = "10")]
+    poly_min_length: usize,
+    /// Trim a 3' adapter sequence before matching (may be repeated)
+    #[clap(long = "trim-adapter", value_name = "SEQ")]
+    adapters: Vec<String>,
+    /// Maximum mismatch rate when searching for adapters
+    #[clap(long = "adapter-error-rate", value_name = "R", default_value = "0.1")]
+    adapter_error_rate: f64,
+    /// Minimum adapter overlap at the end of a read
+    #[clap(long = "adapter-min-overlap", value_name = "N", default_value = "3")]
+    adapter_min_overlap: usize,
+    /// Constant anchor to align (allowing one edit) when the search expression does not match
+    #[clap(
+        long = "rescue-anchor",
+        value_name = "SEQ",
+        requires = "rescue_barcode_length"
+    )]
+    rescue_anchor: Option<String>,
+    /// After merging, search unmatched reads for the final barcodes (allowing one
+    /// substitution), and count the rescued reads of each barcode separately
+    #[clap(long = "rescue")]
+    rescue: bool,
+    /// Length of the barcode following the rescue anchor
+    #[clap(
+        long = "rescue-barcode-length",
+        value_name = "N",
+        requires = "rescue_anchor"
+    )]
+    rescue_barcode_length: Option<usize>,
+    /// Write trimmed rather than original sequences to the unmatched file
+    #[clap(long = "unmatched-trimmed")]
+    unmatched_trimmed: bool,
+    /// Minimum read length
+    #[clap(long = "min-read-length", value_name = "N")]
+    min_read_length: Option<usize>,
+    /// Maximum read length
+    #[clap(long = "max-read-length", value_name = "N")]
+    max_read_length: Option<usize>,
+    /// With paired reads, whether both mates must be within the read length limits, or only
+    /// either of them
+    #[clap(
+        long = "read-length-mates",
+        value_name = "MATES",
+        default_value = "both",
+        value_parser = ["both", "either"]
+    )]
+    read_length_mates: String,
+    /// Write the read length histogram to file
+    #[clap(long = "read-length-histogram", value_name = "FILE")]
+    read_length_histogram_path: Option<PathBuf>,
+    /// Minimum mean quality of the whole read
+    #[clap(long = "min-read-quality", value_name = "Q")]
+    min_read_quality: Option<f64>,
+    /// Maximum fraction of N bases in the read
+    #[clap(long = "max-n-fraction", value_name = "F")]
+    max_n_fraction: Option<f64>,
+    /// Minimum mean quality of the barcode region
+    #[clap(long = "min-barcode-quality", value_name = "Q")]
+    min_barcode_quality: Option<f64>,
+    /// Minimum quality of every base in the barcode region
+    #[clap(long = "min-barcode-base-quality", value_name = "Q")]
+    min_barcode_base_quality: Option<u8>,
+    /// Write low-quality barcode sequences to file
+    #[clap(long = "low-quality", value_name = "FILE")]
+    low_quality_path: Option<PathBuf>,
+    /// Write the mean quality at each barcode position to file
+    #[clap(long = "barcode-quality-profile", value_name = "FILE")]
+    barcode_quality_profile_path: Option<PathBuf>,
+    /// Minimum length of the extracted barcode
+    #[clap(long = "min-barcode-len", value_name = "N")]
+    min_barcode_len: Option<usize>,
+    /// Maximum length of the extracted barcode
+    #[clap(long = "max-barcode-len", value_name = "N")]
+    max_barcode_len: Option<usize>,
+    /// Write the extracted barcode length histogram to file
+    #[clap(long = "barcode-length-histogram", value_name = "FILE")]
+    barcode_length_histogram_path: Option<PathBuf>,
+    /// Write the fraction of each base at each barcode position to file
+    #[clap(long = "base-composition", value_name = "FILE")]
+    base_composition_path: Option<PathBuf>,
+    /// Minimum GC fraction of the barcode
+    #[clap(long = "barcode-gc-min", value_name = "F")]
+    barcode_gc_min: Option<f64>,
+    /// Maximum GC fraction of the barcode
+    #[clap(long = "barcode-gc-max", value_name = "F")]
+    barcode_gc_max: Option<f64>,
+    /// Every N reads, write the composition of the last N reads to FILE
+    #[clap(long = "chunk-stats", num_args = 2, value_names = ["N", "FILE"])]
+    chunk_stats: Option<Vec<String>>,
+    /// Number of top barcodes tracked by --chunk-stats
+    #[clap(long = "chunk-top", value_name = "K", default_value = "5")]
+    chunk_top: usize,
+    /// Write matched and unmatched read counts for each flow cell tile to file
+    #[clap(long = "tile-stats", value_name = "FILE")]
+    tile_stats_path: Option<PathBuf>,
+    /// Warn about tiles with an unmatched rate this many MADs from the median
+    #[clap(long = "tile-max-mads", value_name = "N", default_value = "3")]
+    tile_max_mads: f64,
+    /// Count the distinct read sequences of each barcode
+    #[clap(long = "duplicate-stats")]
+    duplicate_stats: bool,
+    /// Store 64-bit hashes rather than full reads for --duplicate-stats
+    #[clap(long = "dup-hash")]
+    dup_hash: bool,
+    /// Correct barcodes against a 10x Genomics style whitelist file
+    #[clap(long = "whitelist-10x", value_name = "FILE")]
+    whitelist_10x_path: Option<PathBuf>,
+    /// Correct barcodes to the nearest entry of a list of known barcodes within the threshold
+    /// distance, instead of merging them
+    #[clap(
+        long = "whitelist",
+        value_name = "FILE",
+        conflicts_with = "whitelist_10x_path"
+    )]
+    whitelist_path: Option<PathBuf>,
+    /// Accept this many of the most frequent barcodes into the whitelist, instead of those
+    /// above the knee (with the whitelist subcommand)
+    #[clap(long = "cell-number", value_name = "N")]
+    cell_number: Option<usize>,
+    /// List the barcodes within the threshold distance of each accepted barcode as its error
+    /// variants (with the whitelist subcommand)
+    #[clap(long = "whitelist-variants")]
+    whitelist_variants: bool,
+    /// Only count exact matches to the whitelist or library, without correction or merging
+    #[clap(long = "exact-only", requires = "reference")]
+    exact_only: bool,
+    /// Use the barcode qualities to resolve ambiguous whitelist corrections
+    #[clap(long = "quality-weighted-correction")]
+    quality_weighted_correction: bool,
+    /// Assign barcodes to the guides of a tab-delimited CRISPR library file
+    #[clap(long = "library-tsv", value_name = "FILE")]
+    library_path: Option<PathBuf>,
+    /// Library columns holding the guide name, gene and sequence
+    #[clap(
+        long = "library-columns",
+        value_name = "NAME,GENE,SEQ",
+        value_delimiter = ',',
+        default_value = "guide_name,gene,sequence"
+    )]
+    library_columns: Vec<String>,
+    /// Write the total guide counts of each gene to file
+    #[clap(long = "gene-counts", value_name = "FILE", requires = "library_path")]
+    gene_counts_path: Option<PathBuf>,
+    /// Decode barcode pairs as the cells of a plate, the first barcode giving the row from a
+    /// (barcode, label, [plate]) file
+    #[clap(
+        long = "rows",
+        value_name = "FILE",
+        requires_all = ["columns_path", "pair_separator"]
+    )]
+    rows_path: Option<PathBuf>,
+    /// The (barcode, label, [plate]) file of the second, column barcodes of a plate
+    #[clap(long = "columns", value_name = "FILE", requires = "rows_path")]
+    columns_path: Option<PathBuf>,
+    /// Threshold edit distance for correcting row barcodes
+    #[clap(long = "row-distance", value_name = "D", default_value = "1")]
+    row_distance: usize,
+    /// Threshold edit distance for correcting column barcodes
+    #[clap(long = "column-distance", value_name = "D", default_value = "1")]
+    column_distance: usize,
+    /// Write a grid of the cell counts of each plate to PREFIX<plate>.tsv
+    #[clap(long = "plate-grid", value_name = "PREFIX", requires = "rows_path")]
+    plate_grid_prefix: Option<String>,
+    /// Assign barcodes to the samples of a (sample, barcode) sample sheet file
+    #[clap(long = "sample-sheet", value_name = "FILE")]
+    sample_sheet_path: Option<PathBuf>,
+    /// Write the matrix of reads ambiguous between pairs of samples to file
+    #[clap(
+        long = "crosstalk",
+        value_name = "FILE",
+        requires = "sample_sheet_path"
+    )]
+    crosstalk_path: Option<PathBuf>,
+    /// Write the nearest whitelist entries of the most frequent unassigned barcodes to file
+    #[clap(
+        long = "unexpected-report",
+        value_name = "FILE",
+        requires = "whitelists"
+    )]
+    unexpected_report_path: Option<PathBuf>,
+    /// Number of unassigned barcodes in the unexpected barcode report
+    #[clap(long = "unexpected-top", value_name = "N", default_value = "20")]
+    unexpected_top: usize,
+    /// Maximum substitutions searched for the nearest whitelist entry (of a --whitelist-10x
+    /// whitelist; --whitelist entries are searched up to the threshold distance)
+    #[clap(
+        long = "unexpected-max-distance",
+        value_name = "D",
+        default_value = "3"
+    )]
+    unexpected_max_distance: usize,
+    /// Also estimate complexity by extrapolating the saturation curve
+    #[clap(long = "extrapolate-complexity")]
+    extrapolate_complexity: bool,
+    /// Subsample the reads at several depths while counting, and write the distinct
+    /// barcodes seen at each depth to file
+    #[clap(
+        long = "saturation",
+        value_name = "FILE",
+        conflicts_with = "batch_path"
+    )]
+    saturation_path: Option<PathBuf>,
+    /// Depths subsampled by --saturation, as percentages of the reads
+    #[clap(
+        long = "saturation-depths",
+        value_name = "PERCENTS",
+        value_delimiter = ',',
+        default_value = "10,25,50,75,100"
+    )]
+    saturation_depths: Vec<f64>,
+    /// Compare the barcode counts with the expected fractions in file
+    #[clap(long = "expected", value_name = "FILE")]
+    expected_path: Option<PathBuf>,
+    /// Estimate 95% intervals for the top barcode fractions from N bootstrap replicates
+    #[clap(long = "bootstrap", value_name = "N")]
+    bootstrap: Option<usize>,
+    /// Number of top barcodes given bootstrap intervals
+    #[clap(long = "bootstrap-top", value_name = "K", default_value = "10")]
+    bootstrap_top: usize,
+    /// Seed for the random number generator (used for random merge ties, bootstrapping and
+    /// --saturation subsampling), which makes runs reproducible on any machine [default: a
+    /// random seed, which is logged]
+    #[clap(long = "seed", value_name = "N")]
+    seed: Option<u64>,
+    /// Number of worker threads searching reads for barcodes and finding the barcodes to merge
+    #[clap(long = "threads", value_name = "N", default_value = "1")]
+    threads: usize,
+    /// Decompress the input on background threads, using N threads for BGZF input
+    #[clap(long = "decompress-threads", value_name = "N")]
+    decompress_threads: Option<usize>,
+    /// Read the inputs as this format, rather than going by their names and contents
+    #[clap(long = "format", value_name = "FORMAT", value_parser = ["fastq", "fasta"])]
+    input_format: Option<String>,
+    /// Count the reads before the truncation of a truncated input (such as a partially
+    /// transferred fastq.gz file), with a warning, rather than failing
+    #[clap(long = "allow-truncated")]
+    allow_truncated: bool,
+    /// Skip malformed records (and those with bases other than ACGTN), reporting how many
+    /// were skipped and why, rather than failing
+    #[clap(long = "skip-invalid")]
+    skip_invalid: bool,
+    /// Reference FASTA for decoding CRAM input (with samtools)
+    #[clap(long = "reference", value_name = "FASTA")]
+    reference_path: Option<PathBuf>,
+    /// ASCII offset of the quality scores
+    #[clap(long = "phred-offset", value_name = "N", default_value = "33")]
+    phred_offset: u8,
+    /// Add a constant sample label as the first column of the output (by default, the
+    /// first input file name without its extensions)
+    #[clap(long = "label", value_name = "NAME", num_args = 0..=1)]
+    label: Option<Option<String>>,
+    /// Write a tab-delimited run report to file
+    #[clap(long = "report", value_name = "FILE")]
+    report_path: Option<PathBuf>,
+    /// Write a JSON summary of the run (read and barcode totals, merge parameters, runtime
+    /// and version) to file
+    #[clap(
+        long = "stats-json",
+        value_name = "FILE",
+        conflicts_with = "batch_path"
+    )]
+    stats_json_path: Option<PathBuf>,
+    /// Write MultiQC custom content (the match rate and barcodes for the general statistics,
+    /// and the reads of the top barcodes) to <sample>_fqbarcode_mqc.json and
+    /// <sample>_fqbarcode_top_mqc.json in DIR
+    #[clap(long = "multiqc", value_name = "DIR")]
+    multiqc_dir: Option<PathBuf>,
+    /// Write a self-contained HTML report of the run (match rate, merging, barcode rank curve
+    /// and top barcodes) to file
+    #[clap(
+        long = "html-report",
+        value_name = "FILE",
+        conflicts_with = "batch_path"
+    )]
+    html_report_path: Option<PathBuf>,
+    /// Write the barcode rank curve (the count of each barcode by rank) to file, with its
+    /// knee and inflection points marked, for knee plots
+    #[clap(long = "knee-plot", value_name = "FILE", conflicts_with = "batch_path")]
+    knee_plot_path: Option<PathBuf>,
+    /// Write the QC warnings raised during the run to file, as JSON lines
+    #[clap(long = "warnings", value_name = "FILE")]
+    warnings_path: Option<PathBuf>,
+    /// Audit the pairwise edit distances within a whitelist file
+    #[clap(long = "audit-whitelist", value_name = "FILE")]
+    audit_whitelist_path: Option<PathBuf>,
+    /// Maximum pairwise distance searched when auditing [default: 2D]
+    #[clap(long = "audit-max-distance", value_name = "D")]
+    audit_max_distance: Option<usize>,
+    /// Count each sample of a (sample, fastq path, [search expression]) sheet in turn, and
+    /// write a barcode × sample matrix
+    #[clap(
+        long = "batch",
+        value_name = "FILE",
+        conflicts_with_all = ["file_paths", "grep", "matrix", "label", "output_prefix", "report_path", "warnings_path", "audit_whitelist_path"]
+    )]
+    batch_path: Option<PathBuf>,
+    /// Write the table of each batch sample to PREFIX<sample>.tsv, and a summary of the
+    /// samples to PREFIXsummary.tsv
+    #[clap(long = "batch-prefix", value_name = "PREFIX", requires = "batch_path")]
+    batch_prefix: Option<String>,
+    /// Input FASTQ (gzipped or not), FASTA, BAM or CRAM files or URLs, counted together (the standard input if "-" or omitted)
     #[clap(value_name = "FILE")]
-    file_path: PathBuf,
+    file_paths: Vec<PathBuf>,
+    /// The steps of the run, set by its subcommand.
+    #[clap(skip)]
+    mode: Mode,
 }
 
-fn sort_barcodes(s: &mut [(String, u64)]) {
-    s.sort_by(|a, b| b.1.cmp(&a.1));
+fn count_barcodes(m: &HashMap<String, u64>) -> u64 {
+    m.values().sum()
 }
 
-fn count_barcodes(m: &HashMap<String, u64>) -> u64 {
-    m.iter().map(|(_, i)| *i).sum()
+/// Open the input reads, decompressing them on background threads if requested.
+fn open_input(args: &Args, file_paths: &[PathBuf]) -> Result<FastqFiles, Report> {
+    let mut inputs: Vec<Box<dyn fastq::RecordReader>> = Vec::new();
+    for file_path in file_paths {
+        match file_path == Path::new("-") {
+            true => info!("parsing reads from the standard input"),
+            false => info!("parsing reads from {}", file_path.to_string_lossy()),
+        }
+        let input = match args.decompress_threads {
+            Some(0) => return Err(eyre!("--decompress-threads must be at least 1")),
+            Some(threads) => {
+                decompress::open_reads_threaded(file_path, threads, args.reference_path.as_deref())?
+            }
+            None => fastq::open_reads(file_path, args.reference_path.as_deref())?,
+        };
+        let fasta = match args.input_format.as_deref() {
+            Some(format) => format == "fasta",
+            None => fasta::is_fasta_path(file_path),
+        };
+        inputs.push(fastq::record_reader(input, fasta)?);
+    }
+    let names: Vec<String> = file_paths
+        .iter()
+        .map(|file_path| match file_path == Path::new("-") {
+            true => "the standard input".to_string(),
+            false => file_path.to_string_lossy().into_owned(),
+        })
+        .collect();
+    Ok(FastqFiles::new(
+        inputs,
+        names,
+        args.allow_truncated,
+        args.skip_invalid,
+    ))
+}
+
+/// Create an output file, gzipped if its name ends in `.gz`.
+fn create_output(path: &Path) -> Result<Box<dyn Write>, Report> {
+    let buffer = BufWriter::new(File::create(path)?);
+    Ok(match path.extension().is_some_and(|e| e == "gz") {
+        true => Box::new(GzEncoder::new(buffer, Compression::default())),
+        false => Box::new(buffer),
+    })
+}
+
+/// Open the `--output` file, or the standard output.
+fn open_output(args: &Args) -> Result<Box<dyn Write>, Report> {
+    if args.output_format == "sqlite" {
+        let path = args
+            .output_path
+            .as_ref()
+            .ok_or_else(|| eyre!("--output-format sqlite needs the database as -o/--output"))?;
+        if args.output_prefix.is_some() {
+            return Err(eyre!(
+                "--output-format sqlite cannot be used with --output-prefix"
+            ));
+        }
+        info!("adding the run to the database {}", path.to_string_lossy());
+        return Ok(Box::new(sqlite::Database::open(path)?));
+    }
+    Ok(match &args.output_path {
+        Some(path) => {
+            info!("writing output to {}", path.to_string_lossy());
+            create_output(path)?
+        }
+        None => Box::new(BufWriter::new(std::io::stdout().lock())),
+    })
+}
+
+/// Build the search expression, followed by any from the `--patterns` file.
+fn search_expressions(args: &Args, barcode_expression: &str) -> Result<Vec<Regex>, Report> {
+    debug!("building barcode regular expression");
+    trace!("barcode regular expression is {}", barcode_expression);
+    let mut barcode_res: Vec<Regex> = vec![Regex::new(barcode_expression)?];
+    if let Some(path) = &args.patterns_path {
+        info!("reading search expressions from {}", path.to_string_lossy());
+        barcode_res.extend(pattern::read_patterns(path)?);
+        info!("searching with {} expressions", barcode_res.len());
+    }
+    Ok(barcode_res)
+}
+
+/// Write the reads matching the search expressions (or the rescue anchor) as FASTQ, without
+/// counting them, and print a one-line summary to the standard error.
+///
+/// Output stops quietly if the reader of the standard output goes away, so that the output
+/// can be piped into `head` and the like.
+fn grep(args: &Args, barcode_expression: &str, file_paths: &[PathBuf]) -> Result<(), Report> {
+    let barcode_res = search_expressions(args, barcode_expression)?;
+    let mut reader = open_input(args, file_paths)?;
+    let mut writer = open_output(args)?;
+    let mut record = Record::default();
+    let mut label = String::new();
+    let (mut total_reads, mut matched_reads) = (0_u64, 0_u64);
+    while reader.read_record(&mut record)? {
+        total_reads += 1_u64;
+        let read = match args.from_header || args.illumina_index {
+            true => record.header.strip_prefix('@').unwrap_or(&record.header),
+            false => &record.seq,
+        };
+        let rescue = |read: &str| match (&args.rescue_anchor, args.rescue_barcode_length) {
+            (Some(anchor), Some(length)) => {
+                anchor::align_anchor(read.as_bytes(), anchor.as_bytes(), 1)
+                    .filter(|m| m.distance > 0 && m.end + length <= read.len())
+                    .map(|m| (m.end, m.end + length))
+            }
+            _ => None,
+        };
+        let matched = match &args.barcode_equals {
+            Some(barcode) => {
+                label.clear();
+                match barcode_res.iter().find_map(|re| re.captures(read)) {
+                    Some(c) => c.expand(&args.barcode_replacement, &mut label),
+                    None => {
+                        if let Some((start, end)) = rescue(read) {
+                            label.push_str(&read[start..end]);
+                        }
+                    }
+                }
+                !label.is_empty()
+                    && levenshtein::levenshtein(&label, barcode) <= args.threshold_distance
+            }
+            None => barcode_res.iter().any(|re| re.is_match(read)) || rescue(read).is_some(),
+        };
+        if matched {
+            matched_reads += 1_u64;
+        }
+        if matched != args.invert {
+            let written = writeln!(
+                writer,
+                "{}\n{}\n+\n{}",
+                record.header, record.seq, record.qual
+            );
+            match written {
+                Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => return Ok(()),
+                written => written?,
+            }
+        }
+    }
+    match writer.flush() {
+        Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => return Ok(()),
+        flushed => flushed?,
+    }
+    eprintln!(
+        "{matched_reads}/{total_reads} ({:0.2}%) reads matched",
+        (matched_reads as f64 / total_reads.max(1) as f64) * 100_f64
+    );
+    Ok(())
+}
+
+/// The name of a sample from its input file name, without its compression and FASTQ
+/// extensions (or `stdin` for the standard input). The sample number, lane, read and chunk
+/// of Illumina file names (as in `A_S1_L001_R1_001.fastq.gz`) are also removed, so that the
+/// lanes of a sample have the same name.
+fn sample_name(path: &Path) -> String {
+    if path == Path::new("-") {
+        return "stdin".to_string();
+    }
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let name = fastq::strip_query(path, &name);
+    let name = table::strip_extension(fastq::strip_extensions(name));
+    let illumina = Regex::new(r"^(.+?)(_S\d+)?_L\d{3}(_[RI]\d)?(_\d{3})?$")
+        .expect("the Illumina file name expression is valid");
+    match illumina.captures(name) {
+        Some(c) => c[1].to_string(),
+        None => name.to_string(),
+    }
+}
+
+/// Audit the pairwise edit distances within a whitelist, logging whether correction at the
+/// threshold distance is safe. In standalone mode the closest pairs are printed as a table.
+fn audit_whitelist(
+    path: &Path,
+    args: &Args,
+    standalone: bool,
+    warnings: &mut Warnings,
+) -> Result<(), Report> {
+    info!("auditing whitelist {}", path.to_string_lossy());
+    let barcodes = whitelist::read_barcode_list(path)?;
+    let max_distance = args
+        .audit_max_distance
+        .unwrap_or(2 * args.threshold_distance);
+    let audit = audit::audit_barcodes(barcodes, max_distance);
+    info!(
+        "whitelist has {} entries; searched pairs up to distance {}",
+        audit.entries, max_distance
+    );
+    for (distance, pairs) in audit.histogram.iter() {
+        info!("{pairs} whitelist pairs are at distance {distance}");
+    }
+    info!(
+        "{} whitelist pairs are at distance > {}",
+        audit.distant_pairs(),
+        max_distance
+    );
+    match audit.min_distance {
+        Some(min) => info!("minimum pairwise whitelist distance is {min}"),
+        None => info!("minimum pairwise whitelist distance is > {max_distance}"),
+    }
+    match audit.is_safe(args.threshold_distance) {
+        true => info!(
+            "correction at threshold distance {} is unambiguous",
+            args.threshold_distance
+        ),
+        false => {
+            warnings.add(
+                Code::AmbiguousWhitelist,
+                format!(
+                    "correction at threshold distance {} is ambiguous for this whitelist",
+                    args.threshold_distance
+                ),
+                &[
+                    ("threshold_distance", args.threshold_distance as f64),
+                    ("close_pairs", audit.closest.len() as f64),
+                ],
+            );
+            for (a, b) in audit.closest.iter().take(10) {
+                warnings.add(
+                    Code::CloseWhitelistEntries,
+                    format!("whitelist entries {a} and {b} are too close"),
+                    &[("distance", audit.min_distance.unwrap_or(0) as f64)],
+                );
+            }
+            if audit.closest.len() > 10 {
+                warn!("... and {} more pairs", audit.closest.len() - 10);
+            }
+        }
+    }
+    if standalone {
+        println!("distance\tbarcode_a\tbarcode_b");
+        if let Some(min) = audit.min_distance {
+            for (a, b) in audit.closest.iter() {
+                println!("{min}\t{a}\t{b}");
+            }
+        }
+    }
+    Ok(())
 }
 
 fn main() -> Result<(), Report> {
     // Register the Eyre handler:
     simple_eyre::install()?;
     // Parse the CLI arguments:
-    let args = Args::parse();
+    let cli = Cli::parse();
+    let args = match cli.command {
+        None => cli.args,
+        Some(Command::Count(args)) => Args {
+            mode: Mode::Count,
+            ..args
+        },
+        Some(Command::Merge(args)) => Args {
+            mode: Mode::Merge,
+            ..args
+        },
+        Some(Command::Stats(args)) => Args {
+            mode: Mode::Stats,
+            ..args
+        },
+        Some(Command::MergeTables(args)) => Args {
+            mode: Mode::MergeTables,
+            ..args
+        },
+        Some(Command::Whitelist(args)) => Args {
+            mode: Mode::Whitelist,
+            ..args
+        },
+    };
     // Build the logger:
     stderrlog::new()
         .module(module_path!())
         .verbosity(args.verbose as usize)
         .timestamp(stderrlog::Timestamp::Millisecond)
         .init()?;
-    // Set up the RNG:
-    let mut rng = thread_rng();
-    // Load the input file:
-    info!("parsing reads from {}", args.file_path.to_string_lossy());
-    let input_file = BufReader::new(File::open(&args.file_path)?);
-    let input_buffer = BufReader::new(MultiGzDecoder::new(input_file));
-    // Build the regular expression:
-    debug!("building barcode regular expression");
-    trace!("barcode regular expression is {}", args.barcode_expression);
-    let barcode_re = Regex::new(&args.barcode_expression)?;
+    if args.mode.reads_tables() && (args.batch_path.is_some() || args.grep) {
+        return Err(eyre!("count tables cannot be read with --batch or --grep"));
+    }
+    match args.mode {
+        Mode::Whitelist if args.batch_path.is_some() || args.grep || args.matrix => {
+            return Err(eyre!(
+                "a whitelist cannot be derived with --batch, --grep or --matrix"
+            ))
+        }
+        Mode::Whitelist => (),
+        _ if args.cell_number.is_some() || args.whitelist_variants => {
+            return Err(eyre!(
+                "--cell-number and --whitelist-variants are only used by the whitelist subcommand"
+            ))
+        }
+        _ => (),
+    }
+    if args.mtx_dir.is_some()
+        && !(args.matrix || args.batch_path.is_some() || args.mode == Mode::MergeTables)
+    {
+        return Err(eyre!("--mtx needs --matrix, --batch or merge-tables"));
+    }
+    if let Some(path) = &args.batch_path {
+        return batch(&args, path);
+    }
+    let mut warnings = Warnings::default();
+    // If requested, audit the whitelist before (or instead of) counting:
+    if let Some(path) = &args.audit_whitelist_path {
+        let standalone = args.barcode_expression.is_none() && !args.illumina_index;
+        audit_whitelist(path, &args, standalone, &mut warnings)?;
+        if standalone {
+            if let Some(path) = &args.warnings_path {
+                warnings.write(path)?;
+            }
+            return Ok(());
+        }
+    }
+    // Illumina index counting has no REGEX, and count tables are not searched, so the first
+    // argument is an input file:
+    let no_expression = args.illumina_index || args.mode.reads_tables();
+    let (barcode_expression, file_paths) = match (no_expression, &args.barcode_expression) {
+        (true, first) => {
+            let file_paths: Vec<PathBuf> = first
+                .iter()
+                .map(PathBuf::from)
+                .chain(args.file_paths.iter().cloned())
+                .collect();
+            match args.illumina_index {
+                true => (header::ILLUMINA_INDEX_EXPRESSION, file_paths),
+                false => ("", file_paths),
+            }
+        }
+        (false, Some(barcode_expression)) => (barcode_expression.as_str(), args.file_paths.clone()),
+        (false, None) => return Err(eyre!("a search expression is required")),
+    };
+    let file_paths = match (file_paths.is_empty(), args.mode.reads_tables()) {
+        (true, true) => return Err(eyre!("the count tables to read are needed")),
+        (true, false) => vec![PathBuf::from("-")],
+        (false, true) => file_paths,
+        (false, false) => fastq::expand_inputs(&file_paths)?,
+    };
+    if args.grep {
+        return grep(&args, barcode_expression, &file_paths);
+    }
+    let mut output = open_output(&args)?;
+    count(
+        &args,
+        barcode_expression,
+        &file_paths,
+        warnings,
+        &mut output,
+    )?;
+    Ok(())
+}
+
+/// Count the samples of a batch sheet in turn, each with the options of the run (and its own
+/// search expression, if it has one), and write a barcode × sample matrix of them all.
+fn batch(args: &Args, path: &Path) -> Result<(), Report> {
+    info!("reading batch sheet from {}", path.to_string_lossy());
+    let samples = batch::read_batch(path)?;
+    let mut counted: Vec<batch::Counted> = Vec::new();
+    for sample in samples.iter() {
+        let barcode_expression = sample
+            .expression
+            .as_ref()
+            .or(args.barcode_expression.as_ref())
+            .ok_or_else(|| {
+                eyre!(
+                    "batch sample {} has no search expression, and none was given",
+                    sample.name
+                )
+            })?;
+        info!("counting batch sample {}", sample.name);
+        let mut output: Box<dyn Write> = match &args.batch_prefix {
+            Some(prefix) => Box::new(BufWriter::new(File::create(format!(
+                "{prefix}{}.tsv",
+                sample.name
+            ))?)),
+            None => Box::new(std::io::sink()),
+        };
+        let sample_counted = count(
+            args,
+            barcode_expression,
+            &fastq::expand_inputs(&sample.file_paths)?,
+            Warnings::default(),
+            &mut output,
+        )?;
+        info!(
+            "batch sample {}: {} reads, {} barcoded, {} unmatched, {} filtered",
+            sample.name,
+            sample_counted.total_reads,
+            sample_counted.barcoded_reads,
+            sample_counted.no_barcode,
+            sample_counted.filtered_reads
+        );
+        counted.push(sample_counted);
+    }
+    if let Some(prefix) = &args.batch_prefix {
+        let path = format!("{prefix}summary.tsv");
+        info!("writing batch summary to {path}");
+        batch::write_summary(Path::new(&path), &samples, &counted)?;
+    }
+    if let Some(dir) = &args.mtx_dir {
+        info!("writing sparse matrix to {}", dir.to_string_lossy());
+        return batch::write_mtx(dir, &samples, &counted);
+    }
+    let mut writer = open_output(args)?;
+    let written = match args.output_style.as_str() {
+        "mageck" => batch::write_mageck(&mut writer, &samples, &counted),
+        _ => batch::write_matrix(&mut writer, &samples, &counted),
+    };
+    match written {
+        Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => (),
+        written => written?,
+    }
+    Ok(())
+}
+
+/// The barcodes counted from the reads (or read from count tables) of one sample, with
+/// everything the later stages need from the counting.
+struct Counts<'a> {
+    // The sample label, and what was counted:
+    sample: Option<String>,
+    seed: u64,
+    barcode_expression: &'a str,
+    file_paths: &'a [PathBuf],
+    tables: table::CountTable,
+    // The barcode counts, and the reads not counted on a barcode:
+    barcodes: HashMap<String, u64>,
+    total_reads: u64,
+    barcoded_reads: u64,
+    no_barcode: u64,
+    filtered_reads: u64,
+    detected_barcodes: usize,
+    mate_barcoded: u64,
+    search_mates: bool,
+    unmatched_reads: Option<Vec<String>>,
+    read2_buffer: Option<Box<dyn Write>>,
+    invalid_records: BTreeMap<&'static str, u64>,
+    // The reads dropped by each filter:
+    header_filtered: u64,
+    chastity_filtered: u64,
+    casava_unparsed: u64,
+    length_filtered: u64,
+    filter_lengths: bool,
+    low_read_quality: u64,
+    too_many_n: u64,
+    low_quality: u64,
+    low_quality_mean: u64,
+    low_quality_base: u64,
+    filter_barcode_quality: bool,
+    implausible_barcode: u64,
+    filter_gc: bool,
+    barcode_length_filtered: u64,
+    filter_barcode_lengths: bool,
+    // The reads trimmed or rescued:
+    adapter_trimmed: u64,
+    adapter_trimmed_bases: u64,
+    poly_g_trimmed: u64,
+    poly_a_trimmed: u64,
+    anchor_rescued: u64,
+    anchor_indels: u64,
+    // The distributions collected while counting:
+    read_lengths: Option<Histogram>,
+    n_fractions: Option<Histogram>,
+    barcode_quality: Option<Histogram>,
+    barcode_quality_profile: Option<quality::QualityProfile>,
+    barcode_gc: Option<Histogram>,
+    barcode_lengths: Option<Histogram>,
+    base_composition: Option<sequence::BaseComposition>,
+    tile_stats: Option<tile::TileStats>,
+    conflicts: Option<pattern::Conflicts>,
+    conflict_matrix: Option<Vec<Vec<u64>>>,
+    // The assignment of barcodes to a whitelist, library or plate:
+    whitelisted: bool,
+    whitelist_exact: u64,
+    whitelist_corrected: u64,
+    not_whitelisted: u64,
+    exact_match_rate: Option<f64>,
+    library: Option<library::Library>,
+    no_guide: u64,
+    names: Option<HashMap<String, String>>,
+    layout: Option<plate::Layout>,
+    row_unassigned: u64,
+    column_unassigned: u64,
+    both_unassigned: u64,
+    impossible_cell: u64,
+    // The counts kept alongside each barcode, which follow it when it is merged:
+    sample_counts: Option<matrix::SampleCounts>,
+    strands: Option<strand::StrandCounts>,
+    duplicates: Option<duplicate::DuplicateStats>,
+    umis: Option<umi::UmiSets>,
+    umi_stats: Option<umi::UmiStats>,
+    merge_qualities: Option<quality::BarcodeQualities>,
+    unaligned_qualities: u64,
+    // How the table is written, and how the barcodes are merged:
+    columns: Columns,
+    fractions: bool,
+    merge_count: Option<u64>,
+    similarity: merge::Similarity,
+    pool: Option<rayon::ThreadPool>,
+}
+
+/// The merges of the counted barcodes, and what was excluded and rescued after merging.
+struct Merged {
+    threshold_count: u64,
+    endpoints: u64,
+    barcode_length: usize,
+    collisions: stats::Collisions,
+    merges: Vec<merge::Merge>,
+    error_profile: merge::ErrorProfile,
+    error_rates: Vec<(usize, f64)>,
+    substitutions: Vec<((char, char), u64)>,
+    pair_corrections: Option<[u64; 3]>,
+    length_classes: BTreeMap<usize, (u64, u64)>,
+    excluded: u64,
+    excluded_barcodes: HashSet<String>,
+    excluded_matches: Vec<(String, u64)>,
+    rescued: Option<HashMap<String, u64>>,
+}
+
+/// Count the barcodes of the reads of one sample, merge them (if the mode merges) and report
+/// them, writing the table to `output`. Every subcommand runs these three stages in turn, and
+/// what each stage does is chosen by the mode.
+fn count(
+    args: &Args,
+    barcode_expression: &str,
+    file_paths: &[PathBuf],
+    mut warnings: Warnings,
+    output: &mut dyn Write,
+) -> Result<batch::Counted, Report> {
+    let started = Instant::now();
+    // Set up the RNG, with a portable algorithm so that a seed gives the same results on any
+    // machine. Without a seed, one is drawn and logged so that the run can be repeated:
+    let seed = args.seed.unwrap_or_else(rand::random);
+    info!("random seed is {seed}");
+    let mut rng = ChaCha12Rng::seed_from_u64(seed);
+    let mut counts = count_reads(args, barcode_expression, file_paths, seed, &mut warnings)?;
+    let merged = merge_counts(args, &mut counts, &mut rng, &mut warnings)?;
+    report(args, counts, merged, started, &mut rng, warnings, output)
+}
+
+/// Count the barcodes of the reads (or read them from count tables), with their filters,
+/// trimming, distributions and per-barcode side counts, and match them to the whitelist,
+/// library or plate.
+fn count_reads<'a>(
+    args: &'a Args,
+    barcode_expression: &'a str,
+    file_paths: &'a [PathBuf],
+    seed: u64,
+    warnings: &mut Warnings,
+) -> Result<Counts<'a>, Report> {
+    let sample = args.label.as_ref().map(|label| match label {
+        Some(name) => name.to_owned(),
+        None => sample_name(&file_paths[0]),
+    });
+    // Load the input file (count tables have no reads to read):
+    let mut reader = match args.mode.reads_tables() {
+        true => open_input(args, &[])?,
+        false => open_input(args, file_paths)?,
+    };
+    let barcode_res = search_expressions(args, barcode_expression)?;
+    let mut conflicts = match args.detect_conflicts {
+        true => Some(pattern::Conflicts::default()),
+        false => None,
+    };
+    let header_re = match &args.header_filter {
+        Some(expression) => {
+            trace!("header regular expression is {expression}");
+            Some(Regex::new(expression)?)
+        }
+        None => None,
+    };
     // Define the barcode counts:
-    let mut barcode_label = String::new();
     let mut barcodes: HashMap<String, u64> = HashMap::new();
     let mut total_reads: u64 = 0_u64;
     let mut no_barcode: u64 = 0_u64;
+    let mut header_filtered: u64 = 0_u64;
+    let mut chastity_filtered: u64 = 0_u64;
+    let mut casava_unparsed: u64 = 0_u64;
+    let mut adapter_trimmed: u64 = 0_u64;
+    let mut adapter_trimmed_bases: u64 = 0_u64;
+    let mut untrimmed_read = String::new();
+    let mut poly_g_trimmed: u64 = 0_u64;
+    let mut poly_a_trimmed: u64 = 0_u64;
+    let trimming = args.trim_poly_g || !args.adapters.is_empty() || args.trim_poly_a;
+    let keep_untrimmed = trimming && !args.unmatched_trimmed;
+    let mut length_filtered: u64 = 0_u64;
+    let filter_lengths = args.min_read_length.is_some() || args.max_read_length.is_some();
+    let read_length_in_range = |length: usize| {
+        !(args.min_read_length.is_some_and(|n| length < n)
+            || args.max_read_length.is_some_and(|n| length > n))
+    };
+    // If either mate of a pair may be in range, the searcher can't drop reads on their own:
+    let either_mate_length =
+        (!args.read2_paths.is_empty() || args.interleaved) && args.read_length_mates == "either";
+    let mut read_lengths = match filter_lengths || args.read_length_histogram_path.is_some() {
+        true => Some(Histogram::default()),
+        false => None,
+    };
+    let mut low_read_quality: u64 = 0_u64;
+    let mut too_many_n: u64 = 0_u64;
+    let mut n_fractions = args.max_n_fraction.map(|_| Histogram::default());
+    let mut low_quality: u64 = 0_u64;
+    // The barcode region quality distribution is only collected when filtering on it:
+    let mut low_quality_mean: u64 = 0_u64;
+    let mut low_quality_base: u64 = 0_u64;
+    let filter_barcode_quality =
+        args.min_barcode_quality.is_some() || args.min_barcode_base_quality.is_some();
+    let mut barcode_quality = args.min_barcode_quality.map(|_| Histogram::default());
+    let mut barcode_quality_profile = args
+        .barcode_quality_profile_path
+        .as_ref()
+        .map(|_| quality::QualityProfile::default());
+    let mut merge_qualities = args
+        .quality_weighted
+        .then(quality::BarcodeQualities::default);
+    let mut base_composition = args
+        .base_composition_path
+        .as_ref()
+        .map(|_| sequence::BaseComposition::default());
+    let mut implausible_barcode: u64 = 0_u64;
+    let mut barcode_length_filtered: u64 = 0_u64;
+    let filter_barcode_lengths = args.min_barcode_len.is_some() || args.max_barcode_len.is_some();
+    let mut barcode_lengths = match filter_barcode_lengths
+        || args.barcode_length_histogram_path.is_some()
+        || args.report_path.is_some()
+    {
+        true => Some(Histogram::default()),
+        false => None,
+    };
+    let filter_gc = args.barcode_gc_min.is_some() || args.barcode_gc_max.is_some();
+    let mut barcode_gc = match filter_gc {
+        true => Some(Histogram::default()),
+        false => None,
+    };
+    let mut tile_stats = args
+        .tile_stats_path
+        .as_ref()
+        .map(|_| tile::TileStats::default());
+    let mut sample_counts = match args.matrix || args.mode == Mode::MergeTables {
+        true => {
+            // Files with the same sample name (such as the lanes of a sample) are counted as
+            // one sample:
+            let mut samples: Vec<String> = Vec::new();
+            for path in file_paths.iter() {
+                let sample = sample_name(path);
+                if !samples.contains(&sample) {
+                    samples.push(sample);
+                }
+            }
+            let file_samples: Vec<usize> = file_paths
+                .iter()
+                .map(|path| {
+                    let sample = sample_name(path);
+                    samples.iter().position(|s| *s == sample).unwrap_or(0)
+                })
+                .collect();
+            if samples.len() < file_paths.len() {
+                info!(
+                    "counting {} input files as {} samples",
+                    file_paths.len(),
+                    samples.len()
+                );
+            }
+            Some(matrix::SampleCounts::new(samples, file_samples))
+        }
+        false => None,
+    };
+    let mut strands = match args.both_strands {
+        true => Some(strand::StrandCounts::default()),
+        false => None,
+    };
+    let mut unmatched_reads: Option<Vec<String>> = match args.rescue {
+        true => Some(Vec::new()),
+        false => None,
+    };
+    let mut duplicates = match args.duplicate_stats {
+        true => Some(duplicate::DuplicateStats::new(args.dup_hash)),
+        false => None,
+    };
+    let whitelist = match &args.whitelist_10x_path {
+        Some(path) => {
+            info!("loading whitelist from {}", path.to_string_lossy());
+            let start = Instant::now();
+            let whitelist = whitelist::Whitelist::read(path)?;
+            info!(
+                "loaded {} whitelist barcodes in {:0.2}s using about {:0.1} MB",
+                whitelist.len(),
+                start.elapsed().as_secs_f64(),
+                whitelist.memory() as f64 / 1e6
+            );
+            Some(whitelist)
+        }
+        None => None,
+    };
+    let library = match &args.library_path {
+        Some(path) => {
+            info!("reading guide library from {}", path.to_string_lossy());
+            let [name, gene, sequence] = &args.library_columns[..] else {
+                return Err(eyre!("three library column names are required"));
+            };
+            let library = library::Library::read(path, [name, gene, sequence])?;
+            info!("library has {} guides", library.guides.len());
+            for (first, later) in library.duplicates.iter() {
+                let (first, later) = (&library.guides[*first], &library.guides[*later]);
+                warnings.add(
+                    Code::DuplicateGuide,
+                    format!(
+                        "guides {} and {} have the same sequence; counting it as {}",
+                        first.name, later.name, first.name
+                    ),
+                    &[],
+                );
+            }
+            Some(library)
+        }
+        None => None,
+    };
+    let names = match &args.names_path {
+        Some(path) => {
+            info!("reading barcode names from {}", path.to_string_lossy());
+            Some(names::read_names(path)?)
+        }
+        None => None,
+    };
+    let mut no_guide: u64 = 0_u64;
+    let mut layout = match (&args.rows_path, &args.columns_path) {
+        (Some(rows), Some(columns)) => {
+            info!(
+                "reading plate rows from {} and columns from {}",
+                rows.to_string_lossy(),
+                columns.to_string_lossy()
+            );
+            let layout = plate::Layout {
+                rows: plate::Component::read(rows, args.row_distance)?,
+                columns: plate::Component::read(columns, args.column_distance)?,
+            };
+            info!(
+                "plate layout has {} rows, {} columns and {} cells",
+                layout.rows.labels.len(),
+                layout.columns.labels.len(),
+                layout.cells().len()
+            );
+            Some(layout)
+        }
+        _ => None,
+    };
+    let mut row_unassigned: u64 = 0_u64;
+    let mut column_unassigned: u64 = 0_u64;
+    let mut both_unassigned: u64 = 0_u64;
+    let mut impossible_cell: u64 = 0_u64;
+    let mut whitelist_exact: u64 = 0_u64;
+    let mut whitelist_corrected: u64 = 0_u64;
+    let mut not_whitelisted: u64 = 0_u64;
+    let mut unexpected_barcodes: Option<HashMap<String, u64>> =
+        args.unexpected_report_path.as_ref().map(|_| HashMap::new());
+    let mut umis = args
+        .umi_replacement
+        .as_ref()
+        .map(|_| umi::UmiSets::default());
+    let mut umi_stats = args
+        .umi_report_path
+        .as_ref()
+        .map(|_| umi::UmiStats::default());
+    // The columns of the table are known before counting, so a selection is checked up front:
+    let selects = |name: &str| {
+        args.output_columns
+            .as_ref()
+            .is_some_and(|columns| columns.iter().any(|column| column == name))
+    };
+    let fractions = args.fractions || selects("fraction") || selects("cumulative_fraction");
+    let mut column_names: Vec<&str> = Vec::new();
+    if sample.is_some() {
+        column_names.push("sample");
+    }
+    column_names.push("count");
+    match (&library, &layout, &args.pair_separator) {
+        _ if names.is_some() && args.names_replace => column_names.push("name"),
+        (_, Some(layout), Some(_)) => {
+            if layout.plated() {
+                column_names.push("plate");
+            }
+            column_names.extend(["row", "column"]);
+        }
+        (Some(_), _, _) => column_names.push("guide_name"),
+        (None, _, Some(_)) => column_names.extend(["barcode_1", "barcode_2"]),
+        _ => column_names.push("barcode"),
+    }
+    if names.is_some() && !args.names_replace {
+        column_names.push("name");
+    }
+    if library.is_some() {
+        column_names.push("gene");
+    }
+    if duplicates.is_some() {
+        column_names.push("distinct_reads");
+    }
+    if umis.is_some() {
+        column_names.push("distinct_umis");
+    }
+    if strands.is_some() {
+        column_names.extend(["forward_reads", "reverse_reads"]);
+    }
+    if unmatched_reads.is_some() {
+        column_names.push("rescued");
+    }
+    if args.split_by_length {
+        column_names.push("length");
+    }
+    if fractions {
+        column_names.extend(["fraction", "cumulative_fraction"]);
+    }
+    let columns = Columns::new(
+        column_names
+            .into_iter()
+            .map(|name| name.to_owned())
+            .collect(),
+        args.output_columns.as_deref(),
+        args.header,
+    )?;
+    let mut anchor_rescued: u64 = 0_u64;
+    let mut anchor_indels: u64 = 0_u64;
+    // The qualities of the label's own bases weight the correction and merge distances:
+    let label_qualities_needed =
+        (whitelist.is_some() && args.quality_weighted_correction) || merge_qualities.is_some();
+    let barcode_qualities_needed =
+        filter_barcode_quality || barcode_quality_profile.is_some() || label_qualities_needed;
+    let mut unaligned_qualities: u64 = 0_u64;
     // If requested, open the non-barcode sequence file:
-    let mut unmatched_buffer = match args.unmatched_path {
+    let mut unmatched_buffer = match &args.unmatched_path {
         Some(unmatched_path) => {
             info!(
                 "writing non-barcoded sequences to {}",
@@ -97,126 +1593,2536 @@ fn main() -> Result<(), Report> {
         }
         None => None,
     };
-    // Loop over all reads:
-    debug!("processing reads");
-    for read in input_buffer.lines().skip(1).step_by(4) {
-        let read = read?;
-        match barcode_re.captures(&read) {
-            Some(c) => {
-                c.expand(&args.barcode_replacement, &mut barcode_label);
-                trace!("read {} barcode label is {}", read, barcode_label);
-                let i = barcodes.entry(barcode_label.to_owned()).or_insert(0_u64);
-                *i += 1_u64;
-                barcode_label.clear();
+    // If requested, open the low-quality barcode sequence file:
+    let mut low_quality_buffer = match &args.low_quality_path {
+        Some(low_quality_path) => {
+            info!(
+                "writing low-quality barcode sequences to {}",
+                low_quality_path.to_string_lossy()
+            );
+            Some(BufWriter::new(File::create(low_quality_path)?))
+        }
+        None => None,
+    };
+    // If requested, open the per-chunk composition file:
+    let mut chunk_tracker = match args.chunk_stats.as_deref() {
+        Some([size, path]) => {
+            let size: u64 = size
+                .parse::<NonZeroU64>()
+                .map_err(|_| eyre!("invalid --chunk-stats chunk size {size}"))?
+                .get();
+            info!("writing statistics for every {size} reads to {path}");
+            Some(chunk::ChunkTracker::new(
+                Path::new(path),
+                size,
+                args.chunk_top,
+            )?)
+        }
+        _ => None,
+    };
+    // If requested, subsample the reads for the saturation curve:
+    let mut saturation = match args.saturation_path {
+        Some(_) => Some(saturation::Saturation::new(&args.saturation_depths, seed)?),
+        None => None,
+    };
+    // Read in chunks, which are searched by the worker threads (if any) and then counted in
+    // input order:
+    let searcher = Searcher {
+        barcode_groups: barcode_res
+            .iter()
+            .map(|re| search::replacement_groups(re, &args.barcode_replacement))
+            .collect(),
+        expressions: barcode_res,
+        barcode_replacement: args.barcode_replacement.clone(),
+        umi_replacement: args.umi_replacement.clone(),
+        trim_poly_g: args.trim_poly_g,
+        trim_poly_a: args.trim_poly_a,
+        poly_min_length: args.poly_min_length,
+        adapters: args.adapters.clone(),
+        adapter_error_rate: args.adapter_error_rate,
+        adapter_min_overlap: args.adapter_min_overlap,
+        from_header: args.from_header || args.illumina_index,
+        both_strands: args.both_strands,
+        detect_conflicts: args.detect_conflicts,
+        rescue_anchor: args.rescue_anchor.clone().zip(args.rescue_barcode_length),
+        filter: ReadFilter {
+            header: header_re,
+            discard_filtered: args.discard_filtered,
+            min_length: args.min_read_length.filter(|_| !either_mate_length),
+            max_length: args.max_read_length.filter(|_| !either_mate_length),
+            min_quality: args.min_read_quality,
+            max_n_fraction: args.max_n_fraction,
+            phred_offset: args.phred_offset,
+        },
+    };
+    // A merge count of auto is found from the barcodes once they are counted:
+    let merge_count: Option<u64> = match args.threshold_count.as_str() {
+        "auto" => None,
+        count => Some(
+            count
+                .parse()
+                .map_err(|_| eyre!("--merge-count {count} is not a count or auto"))?,
+        ),
+    };
+    if let Some(fraction) = args
+        .merge_fraction
+        .filter(|fraction| fraction.is_nan() || *fraction <= 0_f64 || *fraction > 1_f64)
+    {
+        return Err(eyre!(
+            "--merge-frac {fraction} is not more than 0 and at most 1"
+        ));
+    }
+    if let Some(ratio) = args
+        .merge_ratio
+        .filter(|ratio| ratio.is_nan() || *ratio < 0_f64)
+    {
+        return Err(eyre!("--merge-ratio {ratio} is not at least 0"));
+    }
+    if args.quality_weighted && (args.merge_method != "endpoint" || args.pair_separator.is_some()) {
+        return Err(eyre!(
+            "--quality-weighted only supports endpoint merging of single barcodes"
+        ));
+    }
+    if args.merge_index == "deletion" && args.threshold_distance > 2 {
+        return Err(eyre!(
+            "--merge-index deletion only supports threshold distances of at most 2"
+        ));
+    }
+    let similarity = merge::Similarity {
+        threshold_distance: args.threshold_distance,
+        metric: merge::Metric::new(&args.distance_metric, args.n_wildcard),
+        index: merge::IndexKind::new(&args.merge_index),
+    };
+    let mut known = match &args.whitelist_path {
+        Some(path) => {
+            info!("loading known barcodes from {}", path.to_string_lossy());
+            let start = Instant::now();
+            let known =
+                whitelist::KnownBarcodes::read(path, args.pair_separator.as_deref(), similarity)?;
+            info!(
+                "loaded {} known barcodes in {:0.2}s",
+                known.len(),
+                start.elapsed().as_secs_f64()
+            );
+            Some(known)
+        }
+        None => None,
+    };
+    let whitelisted = whitelist.is_some() || known.is_some();
+    let pool = match args.threads {
+        0 => return Err(eyre!("--threads must be at least 1")),
+        1 => None,
+        threads => {
+            info!("searching reads with {threads} worker threads");
+            Some(
+                rayon::ThreadPoolBuilder::new()
+                    .num_threads(threads)
+                    .build()?,
+            )
+        }
+    };
+    let mut records: Vec<Record> = vec![Record::default(); CHUNK_READS];
+    let mut searches: Vec<(Search, bool)> = Vec::with_capacity(CHUNK_READS);
+    let mut sources: Vec<usize> = vec![0_usize; CHUNK_READS];
+    // If given, read 2 is read in step with read 1:
+    let mut read2_reader = match args.read2_paths.is_empty() {
+        true => None,
+        false => {
+            let read2_paths = fastq::expand_inputs(&args.read2_paths)?;
+            if read2_paths.len() != file_paths.len() {
+                return Err(eyre!(
+                    "{} read 2 files were given for {} input files",
+                    read2_paths.len(),
+                    file_paths.len()
+                ));
             }
-            None => {
-                trace!("no barcode detected in read {}", read);
-                if let Some(ref mut buffer) = unmatched_buffer {
-                    writeln!(buffer, "{read}")?;
+            Some(open_input(args, &read2_paths)?)
+        }
+    };
+    let paired = read2_reader.is_some() || args.interleaved;
+    let mut read2_records: Vec<Record> = match paired {
+        true => vec![Record::default(); CHUNK_READS],
+        false => Vec::new(),
+    };
+    let (swap_reads, search_mates) = (args.barcode_read == "2", args.barcode_read == "both");
+    let mut mate_barcoded = 0_u64;
+    let mut read2_buffer = match &args.read2_output_path {
+        Some(path) => {
+            info!(
+                "writing read 2 of barcoded reads to {}",
+                path.to_string_lossy()
+            );
+            Some(create_output(path)?)
+        }
+        None => None,
+    };
+    // Count tables have their barcodes counted already, and their reads are the total of the
+    // barcodes and the unassigned counts:
+    let mut tables = table::CountTable::default();
+    if args.mode.reads_tables() {
+        for (i, path) in file_paths.iter().enumerate() {
+            info!("reading counts from {}", path.to_string_lossy());
+            let mut table = table::CountTable::default();
+            table.read(path, args.pair_separator.as_deref())?;
+            if let Some(sample_counts) = sample_counts.as_mut() {
+                for (barcode, count) in table.barcodes.iter() {
+                    sample_counts.add_reads(barcode, i, *count);
                 }
-                no_barcode += 1_u64;
             }
+            tables.add(table);
         }
-        total_reads += 1_u64;
+        barcodes = mem::take(&mut tables.barcodes);
+        no_barcode = tables.take_unassigned("no_barcode");
+        if let Some((_, other)) = tables.unassigned.iter().find(|(name, _)| name == "other") {
+            warnings.add(
+                Code::UnmergedOther,
+                format!(
+                    "{other} reads of the tables are on other barcodes, which cannot be merged"
+                ),
+                &[("reads", *other as f64)],
+            );
+        }
+        total_reads = count_barcodes(&barcodes)
+            + no_barcode
+            + tables
+                .unassigned
+                .iter()
+                .map(|(_, count)| count)
+                .sum::<u64>();
+    }
+    // Loop over all reads:
+    debug!("processing reads");
+    loop {
+        let mut chunk_length = 0_usize;
+        while chunk_length < records.len()
+            && match args.interleaved {
+                true => reader
+                    .read_pair(&mut records[chunk_length], &mut read2_records[chunk_length])?,
+                false => reader.read_record(&mut records[chunk_length])?,
+            }
+        {
+            sources[chunk_length] = reader.current();
+            if paired {
+                let (read1, read2) = (&records[chunk_length], &mut read2_records[chunk_length]);
+                if let Some(read2_reader) = read2_reader.as_mut() {
+                    if !read2_reader.read_record(read2)? {
+                        return Err(eyre!("the read 2 files end before read {}", read1.header));
+                    }
+                }
+                if !fastq::same_read(&read1.header, &read2.header) {
+                    return Err(eyre!(
+                        "read 2 {} does not match read 1 {}",
+                        read2.header,
+                        read1.header
+                    ));
+                }
+            }
+            chunk_length += 1;
+        }
+        if chunk_length < records.len() {
+            if let Some(read2_reader) = read2_reader.as_mut() {
+                let mut extra = Record::default();
+                if read2_reader.read_record(&mut extra)? {
+                    return Err(eyre!(
+                        "the read 2 files have more reads than read 1, from {}",
+                        extra.header
+                    ));
+                }
+            }
+        }
+        if swap_reads {
+            for (read1, read2) in records[..chunk_length]
+                .iter_mut()
+                .zip(read2_records.iter_mut())
+            {
+                mem::swap(read1, read2);
+            }
+        }
+        // If asked, pairs with no barcode in read 1 are searched again in read 2, and the
+        // reads of a pair are swapped if only read 2 has one:
+        let search_pair = |i: usize| {
+            let search = searcher.search(&records[i]);
+            if search_mates && search.barcode.is_none() {
+                let mate_search = searcher.search(&read2_records[i]);
+                if mate_search.barcode.is_some() {
+                    return (mate_search, true);
+                }
+            }
+            (search, false)
+        };
+        searches.clear();
+        match &pool {
+            Some(pool) => pool.install(|| {
+                (0..chunk_length)
+                    .into_par_iter()
+                    .map(search_pair)
+                    .collect_into_vec(&mut searches)
+            }),
+            None => searches.extend((0..chunk_length).map(search_pair)),
+        }
+        for (i, (_, swapped)) in searches.iter().enumerate() {
+            if *swapped {
+                mem::swap(&mut records[i], &mut read2_records[i]);
+                mate_barcoded += 1_u64;
+            }
+        }
+        let chunk = &mut records[..chunk_length];
+        for (i, ((record, search), source)) in chunk
+            .iter_mut()
+            .zip(searches.drain(..).map(|(search, _)| search))
+            .zip(sources.iter())
+            .enumerate()
+        {
+            if let Some(tracker) = chunk_tracker.as_mut() {
+                tracker.observe(total_reads, no_barcode, &barcodes)?;
+            }
+            total_reads += 1_u64;
+            if let Some(saturation) = saturation.as_mut() {
+                saturation.read();
+            }
+            // Skip reads whose header does not match the header filter (the filters were
+            // applied by the searcher, so that filtered reads are not searched):
+            if search.filtered == Some(Filtered::Header) {
+                trace!("read header {} does not match header filter", record.header);
+                header_filtered += 1_u64;
+                continue;
+            }
+            // Skip reads that failed the chastity filter, treating other headers as unfiltered:
+            if args.discard_filtered {
+                if search.filtered == Some(Filtered::Chastity) {
+                    trace!("read {} failed the chastity filter", record.header);
+                    chastity_filtered += 1_u64;
+                    continue;
+                }
+                if header::parse_casava_comment(&record.header).is_none() {
+                    casava_unparsed += 1_u64;
+                }
+            }
+            // Trim poly-G tails, 3' adapters and then poly-A tails, keeping the original sequence
+            // for the unmatched file if needed:
+            if keep_untrimmed && unmatched_buffer.is_some() {
+                untrimmed_read.clear();
+                untrimmed_read.push_str(&record.seq);
+            }
+            if args.trim_poly_g {
+                if let Some(start) = search.poly_g {
+                    trace!("trimming poly-G tail from read {} at {}", record.seq, start);
+                    poly_g_trimmed += 1_u64;
+                    record.seq.truncate(start);
+                    record.qual.truncate(start);
+                }
+            }
+            if let Some(start) = search.adapter {
+                trace!("trimming adapter from read {} at {}", record.seq, start);
+                adapter_trimmed += 1_u64;
+                adapter_trimmed_bases += (record.seq.len() - start) as u64;
+                record.seq.truncate(start);
+                record.qual.truncate(start);
+            }
+            if args.trim_poly_a {
+                if let Some(start) = search.poly_a {
+                    trace!("trimming poly-A tail from read {} at {}", record.seq, start);
+                    poly_a_trimmed += 1_u64;
+                    record.seq.truncate(start);
+                    record.qual.truncate(start);
+                }
+            }
+            let read = &record.seq;
+            // Discard reads of unexpected length before looking for a barcode:
+            if let Some(histogram) = read_lengths.as_mut() {
+                histogram.add(read.len() as u64);
+            }
+            // The mate of a paired read is checked as it is, without trimming:
+            let out_of_range = match paired && filter_lengths {
+                true => {
+                    let mate_in_range = read_length_in_range(read2_records[i].seq.len());
+                    match either_mate_length {
+                        true => !read_length_in_range(read.len()) && !mate_in_range,
+                        false => search.filtered == Some(Filtered::Length) || !mate_in_range,
+                    }
+                }
+                false => search.filtered == Some(Filtered::Length),
+            };
+            if out_of_range {
+                trace!("read {} length {} is out of range", read, read.len());
+                length_filtered += 1_u64;
+                continue;
+            }
+            // Discard low-quality and N-rich reads:
+            if search.filtered == Some(Filtered::ReadQuality) {
+                trace!("read {} mean quality is too low", read);
+                low_read_quality += 1_u64;
+                continue;
+            }
+            if let (Some(n_fraction), Some(histogram)) = (search.n_fraction, n_fractions.as_mut()) {
+                histogram.add((n_fraction * 100_f64) as u64);
+                if search.filtered == Some(Filtered::TooManyN) {
+                    trace!("read {} N fraction {n_fraction:0.2} is too high", read);
+                    too_many_n += 1_u64;
+                    continue;
+                }
+            }
+            // Reads that only match in reverse complement are turned around (with their
+            // qualities) so that everything downstream sees the barcode strand:
+            let reverse = search.reverse.is_some();
+            if let Some(reverse_read) = search.reverse {
+                trace!("read {} matched in reverse complement", read);
+                record.seq = reverse_read;
+                record.qual = record.qual.chars().rev().collect();
+            }
+            let read = &record.seq;
+            if let Some(conflicts) = conflicts.as_mut() {
+                conflicts.add(&search.matched);
+            }
+            if let Some(indel) = search.anchor_indel {
+                trace!("rescued read {} by aligning the anchor", read);
+                anchor_rescued += 1_u64;
+                if indel {
+                    anchor_indels += 1_u64;
+                }
+            }
+            let extracted = search.barcode;
+            if let Some(tiles) = tile_stats.as_mut() {
+                tiles.add(&record.header, extracted.is_some());
+            }
+            match extracted {
+                Some(extracted) => {
+                    let (start, end) = (extracted.start, extracted.end);
+                    // Get the barcode region qualities, if they are needed:
+                    let barcode_qual = match barcode_qualities_needed {
+                        true => Some(record.qual.as_bytes().get(start..end).ok_or_else(|| {
+                            eyre!(
+                                "quality string is shorter than sequence in read {}",
+                                record.header
+                            )
+                        })?),
+                        false => None,
+                    };
+                    if let (Some(qual), Some(profile)) =
+                        (barcode_qual, barcode_quality_profile.as_mut())
+                    {
+                        profile.add(qual, args.phred_offset);
+                    }
+                    // The label is made of the groups it refers to, in order, so their qualities
+                    // line up with it unless the replacement adds text of its own:
+                    let label_qual: Option<Vec<u8>> = match label_qualities_needed {
+                        true => Some(
+                            extracted
+                                .groups
+                                .iter()
+                                .filter_map(|(start, end)| record.qual.as_bytes().get(*start..*end))
+                                .flatten()
+                                .copied()
+                                .collect(),
+                        ),
+                        false => None,
+                    };
+                    // Check the mean and per-base qualities of the barcode region, if requested.
+                    // Both checks must pass, and each failure is counted in its own sub-category:
+                    if let (Some(qual), true) = (barcode_qual, filter_barcode_quality) {
+                        let check = quality::BarcodeQuality::check(
+                            qual,
+                            args.phred_offset,
+                            args.min_barcode_quality,
+                            args.min_barcode_base_quality,
+                        );
+                        if let (Some(mean), Some(histogram)) =
+                            (check.mean, barcode_quality.as_mut())
+                        {
+                            histogram.add(mean as u64);
+                        }
+                        if check.low_mean {
+                            trace!("read {} barcode mean quality is too low", read);
+                            low_quality_mean += 1_u64;
+                        }
+                        if check.low_base {
+                            trace!("read {} barcode has a base below the minimum quality", read);
+                            low_quality_base += 1_u64;
+                        }
+                        if check.failed() {
+                            if let Some(ref mut buffer) = low_quality_buffer {
+                                writeln!(buffer, "{read}")?;
+                            }
+                            low_quality += 1_u64;
+                            continue;
+                        }
+                    }
+                    let mut barcode_label = extracted.label;
+                    trace!("read {} barcode label is {}", read, barcode_label);
+                    if let Some(composition) = base_composition.as_mut() {
+                        composition.add(barcode_label.as_bytes());
+                    }
+                    // Discard barcodes of unexpected length (such as from a greedy REGEX):
+                    if let Some(histogram) = barcode_lengths.as_mut() {
+                        histogram.add(barcode_label.len() as u64);
+                    }
+                    if filter_barcode_lengths
+                        && (args
+                            .min_barcode_len
+                            .is_some_and(|n| barcode_label.len() < n)
+                            || args
+                                .max_barcode_len
+                                .is_some_and(|n| barcode_label.len() > n))
+                    {
+                        trace!("barcode {} length is out of range", barcode_label);
+                        barcode_length_filtered += 1_u64;
+                        continue;
+                    }
+                    // Check the barcode GC content is plausible, if requested:
+                    if let Some(histogram) = barcode_gc.as_mut() {
+                        match sequence::gc_fraction(barcode_label.as_bytes()) {
+                            Some(gc)
+                                if !args.barcode_gc_min.is_some_and(|min| gc < min)
+                                    && !args.barcode_gc_max.is_some_and(|max| gc > max) =>
+                            {
+                                histogram.add((gc * 100_f64) as u64);
+                            }
+                            _ => {
+                                trace!("barcode {} has implausible GC content", barcode_label);
+                                implausible_barcode += 1_u64;
+                                continue;
+                            }
+                        }
+                    }
+                    // Correct the barcode against the whitelist or known barcodes, if given:
+                    let correction = match (&whitelist, known.as_mut()) {
+                        (Some(whitelist), _) => Some(whitelist.correct(
+                            barcode_label.as_bytes(),
+                            label_qual.as_deref(),
+                            args.phred_offset,
+                            !args.exact_only,
+                        )),
+                        (None, Some(known)) => {
+                            Some(known.correct(&barcode_label, !args.exact_only))
+                        }
+                        (None, None) => None,
+                    };
+                    if let Some(correction) = correction {
+                        match correction {
+                            whitelist::Correction::Exact => whitelist_exact += 1_u64,
+                            whitelist::Correction::Corrected(corrected) => {
+                                trace!("barcode {} corrected to {}", barcode_label, corrected);
+                                whitelist_corrected += 1_u64;
+                                barcode_label = corrected;
+                            }
+                            whitelist::Correction::Unassigned => {
+                                trace!("barcode {} is not in the whitelist", barcode_label);
+                                not_whitelisted += 1_u64;
+                                if let Some(unexpected) = unexpected_barcodes.as_mut() {
+                                    *unexpected.entry(barcode_label.to_owned()).or_insert(0_u64) +=
+                                        1_u64;
+                                }
+                                continue;
+                            }
+                        }
+                    }
+                    // Assign the barcode to a guide, if a library is given:
+                    if let Some(library) = &library {
+                        match library.assign(barcode_label.as_bytes(), !args.exact_only) {
+                            Some(guide) => {
+                                trace!(
+                                    "barcode {} assigned to guide {}",
+                                    barcode_label,
+                                    guide.name
+                                );
+                                barcode_label.clone_from(&guide.sequence);
+                            }
+                            None => {
+                                trace!("barcode {} matches no guide", barcode_label);
+                                no_guide += 1_u64;
+                                continue;
+                            }
+                        }
+                    }
+                    // Decode the barcode pair as a plate cell, correcting each component on its own:
+                    if let (Some(layout), Some(separator)) = (layout.as_mut(), &args.pair_separator)
+                    {
+                        let (row, column) = barcode_label
+                            .split_once(separator.as_str())
+                            .unwrap_or((&barcode_label, ""));
+                        let cell = match (layout.rows.assign(row), layout.columns.assign(column)) {
+                            (Some(r), Some(c)) if layout.compatible(r, c) => {
+                                Some(layout.key(r, c, separator))
+                            }
+                            (Some(_), Some(_)) => {
+                                trace!("barcode {} is an impossible plate cell", barcode_label);
+                                impossible_cell += 1_u64;
+                                None
+                            }
+                            (None, column) => {
+                                trace!("barcode {} has no plate row", barcode_label);
+                                if column.is_none() {
+                                    both_unassigned += 1_u64;
+                                }
+                                row_unassigned += 1_u64;
+                                None
+                            }
+                            (Some(_), None) => {
+                                trace!("barcode {} has no plate column", barcode_label);
+                                column_unassigned += 1_u64;
+                                None
+                            }
+                        };
+                        match cell {
+                            Some(cell) => barcode_label = cell,
+                            None => continue,
+                        }
+                    }
+                    let count = barcodes.entry(barcode_label.to_owned()).or_insert(0_u64);
+                    *count += 1_u64;
+                    if let (Some(qualities), Some(qual)) =
+                        (merge_qualities.as_mut(), label_qual.as_deref())
+                    {
+                        if !qualities.add(&barcode_label, qual, args.phred_offset) {
+                            unaligned_qualities += 1_u64;
+                        }
+                    }
+                    if let Some(saturation) = saturation.as_mut() {
+                        saturation.barcode(&barcode_label);
+                    }
+                    if let Some(buffer) = read2_buffer.as_mut() {
+                        let read2 = &read2_records[i];
+                        writeln!(
+                            buffer,
+                            "{} BC:Z:{barcode_label}\n{}\n+\n{}",
+                            read2.header, read2.seq, read2.qual
+                        )?;
+                    }
+                    if let Some(sample_counts) = sample_counts.as_mut() {
+                        sample_counts.add(&barcode_label, *source);
+                    }
+                    if let Some(strands) = strands.as_mut() {
+                        strands.add(&barcode_label, reverse);
+                    }
+                    if let Some(duplicates) = duplicates.as_mut() {
+                        duplicates.add(&barcode_label, read);
+                    }
+                    if let (Some(umis), Some(umi_label)) = (umis.as_mut(), &extracted.umi) {
+                        umis.add(&barcode_label, umi_label);
+                        if let Some(umi_stats) = umi_stats.as_mut() {
+                            umi_stats.add(umi_label);
+                        }
+                    }
+                }
+                None => {
+                    trace!("no barcode detected in read {}", read);
+                    if let Some(ref mut buffer) = unmatched_buffer {
+                        match keep_untrimmed {
+                            true => writeln!(buffer, "{untrimmed_read}")?,
+                            false => writeln!(buffer, "{read}")?,
+                        }
+                    }
+                    if let Some(unmatched_reads) = unmatched_reads.as_mut() {
+                        unmatched_reads.push(read.to_owned());
+                    }
+                    no_barcode += 1_u64;
+                }
+            }
+        }
+        if chunk_length < records.len() {
+            break;
+        }
+    }
+    if let Some(tracker) = chunk_tracker.as_mut() {
+        tracker.finish(total_reads, no_barcode, &barcodes)?;
     }
     info!("processed {total_reads} reads");
+    if let (Some(path), Some(saturation)) = (&args.saturation_path, &saturation) {
+        for (percent, reads, _, barcodes) in saturation.points() {
+            info!("{barcodes} distinct barcodes seen in {reads} reads ({percent}%)");
+        }
+        info!("writing saturation curve to {}", path.to_string_lossy());
+        saturation.write(path)?;
+    }
+    let mut invalid_records = reader.invalid.clone();
+    if let Some(read2_reader) = &read2_reader {
+        for (reason, records) in read2_reader.invalid.iter() {
+            *invalid_records.entry(reason).or_insert(0_u64) += records;
+        }
+    }
+    if !invalid_records.is_empty() {
+        let skipped: u64 = invalid_records.values().sum();
+        let reasons: Vec<String> = invalid_records
+            .iter()
+            .map(|(reason, records)| format!("{records} {reason}"))
+            .collect();
+        warnings.add(
+            Code::InvalidRecords,
+            format!(
+                "{skipped} malformed records were skipped: {}",
+                reasons.join(", ")
+            ),
+            &[("records", skipped as f64)],
+        );
+    }
+    if !reader.truncated.is_empty() {
+        let truncated: Vec<String> = reader
+            .truncated
+            .iter()
+            .map(|i| {
+                format!(
+                    "{} after {} reads",
+                    file_paths[*i].to_string_lossy(),
+                    reader.counts[*i]
+                )
+            })
+            .collect();
+        warnings.add(
+            Code::TruncatedInput,
+            format!(
+                "{} inputs were truncated, and only their reads before the truncation were counted: {}",
+                truncated.len(),
+                truncated.join(", ")
+            ),
+            &[("inputs", truncated.len() as f64)],
+        );
+    }
+    if file_paths.len() > 1 {
+        for (file_path, reads) in file_paths.iter().zip(reader.counts.iter()) {
+            info!("{reads} reads from {}", file_path.to_string_lossy());
+        }
+    }
     info!(
         "{}/{} ({:0.2}%) reads did not match barcode",
         no_barcode,
         total_reads,
         (no_barcode as f32 / total_reads as f32) * 100_f32
     );
-    info!("{} barcodes detected", barcodes.len());
+    if search_mates {
+        info!(
+            "{}/{} ({:0.2}%) read pairs had a barcode only in read 2",
+            mate_barcoded,
+            total_reads,
+            (mate_barcoded as f32 / total_reads as f32) * 100_f32
+        );
+    }
+    if args.header_filter.is_some() {
+        info!(
+            "{}/{} ({:0.2}%) reads did not match the header filter",
+            header_filtered,
+            total_reads,
+            (header_filtered as f32 / total_reads as f32) * 100_f32
+        );
+    }
+    if args.discard_filtered {
+        info!(
+            "{}/{} ({:0.2}%) reads failed the chastity filter",
+            chastity_filtered,
+            total_reads,
+            (chastity_filtered as f32 / total_reads as f32) * 100_f32
+        );
+        if casava_unparsed > 0 {
+            warnings.add(
+                Code::NoCasavaComment,
+                format!("{casava_unparsed} read headers had no Casava comment and were treated as unfiltered"),
+                &[("reads", casava_unparsed as f64)],
+            );
+        }
+    }
+    if args.trim_poly_g {
+        info!(
+            "{}/{} ({:0.2}%) reads had a poly-G tail trimmed",
+            poly_g_trimmed,
+            total_reads,
+            (poly_g_trimmed as f32 / total_reads as f32) * 100_f32
+        );
+    }
+    if !args.adapters.is_empty() {
+        info!(
+            "{}/{} ({:0.2}%) reads were adapter-trimmed, removing {:0.2} bases on average",
+            adapter_trimmed,
+            total_reads,
+            (adapter_trimmed as f32 / total_reads as f32) * 100_f32,
+            adapter_trimmed_bases as f32 / adapter_trimmed.max(1) as f32
+        );
+    }
+    if args.trim_poly_a {
+        info!(
+            "{}/{} ({:0.2}%) reads had a poly-A tail trimmed",
+            poly_a_trimmed,
+            total_reads,
+            (poly_a_trimmed as f32 / total_reads as f32) * 100_f32
+        );
+    }
+    if let Some(histogram) = &read_lengths {
+        info!("read length: {}", histogram.describe());
+    }
+    if filter_lengths {
+        info!(
+            "{}/{} ({:0.2}%) reads had a length outside the accepted range",
+            length_filtered,
+            total_reads,
+            (length_filtered as f32 / total_reads as f32) * 100_f32
+        );
+    }
+    if let Some(min_quality) = args.min_read_quality {
+        info!(
+            "{}/{} ({:0.2}%) reads had a mean quality below {}",
+            low_read_quality,
+            total_reads,
+            (low_read_quality as f32 / total_reads as f32) * 100_f32,
+            min_quality
+        );
+    }
+    if let (Some(max_n_fraction), Some(histogram)) = (args.max_n_fraction, &n_fractions) {
+        info!("read N percentage: {}", histogram.describe());
+        info!(
+            "{}/{} ({:0.2}%) reads had an N fraction above {}",
+            too_many_n,
+            histogram.total(),
+            (too_many_n as f32 / histogram.total() as f32) * 100_f32,
+            max_n_fraction
+        );
+    }
+    if let (Some(min_quality), Some(histogram)) = (args.min_barcode_quality, &barcode_quality) {
+        info!("barcode region mean quality: {}", histogram.describe());
+        info!(
+            "{}/{} ({:0.2}%) barcodes had a mean quality below {}",
+            low_quality_mean,
+            histogram.total(),
+            (low_quality_mean as f32 / histogram.total() as f32) * 100_f32,
+            min_quality
+        );
+    }
+    if let Some(min_base_quality) = args.min_barcode_base_quality {
+        info!("{low_quality_base} barcodes had a base with quality below {min_base_quality}");
+    }
+    if filter_barcode_quality {
+        info!("{low_quality} barcodes failed the barcode quality filters");
+    }
+    if let Some(histogram) = &barcode_gc {
+        info!("accepted barcode GC percentage: {}", histogram.describe());
+        info!(
+            "{}/{} ({:0.2}%) barcodes had implausible GC content",
+            implausible_barcode,
+            histogram.total() + implausible_barcode,
+            (implausible_barcode as f32 / (histogram.total() + implausible_barcode) as f32)
+                * 100_f32
+        );
+    }
+    if let Some(histogram) = &barcode_lengths {
+        info!("barcode length: {}", histogram.describe());
+    }
+    if let (true, Some(histogram)) = (filter_barcode_lengths, &barcode_lengths) {
+        info!(
+            "{}/{} ({:0.2}%) barcodes had a length outside the accepted range",
+            barcode_length_filtered,
+            histogram.total(),
+            (barcode_length_filtered as f32 / histogram.total() as f32) * 100_f32
+        );
+    }
+    if let Some(tiles) = &tile_stats {
+        info!("{} tiles seen in read headers", tiles.len());
+        if tiles.unparsed > 0 {
+            warnings.add(
+                Code::UnparsedTileLocation,
+                format!(
+                    "{} read headers had no lane and tile and were left out of the tile statistics",
+                    tiles.unparsed
+                ),
+                &[("reads", tiles.unparsed as f64)],
+            );
+        }
+        if let Some((median, mad)) = tiles.median_rate() {
+            info!("median tile unmatched rate is {median:0.4} (MAD {mad:0.4})");
+        }
+        for (location, rate) in tiles.outliers(args.tile_max_mads) {
+            warnings.add(
+                Code::TileOutlier,
+                format!(
+                    "lane {} tile {} has an unusual unmatched rate of {rate:0.4}",
+                    location.lane, location.tile
+                ),
+                &[
+                    ("lane", location.lane as f64),
+                    ("tile", location.tile as f64),
+                    ("unmatched_rate", rate),
+                ],
+            );
+        }
+    }
+    if whitelisted {
+        let looked_up = whitelist_exact + whitelist_corrected + not_whitelisted;
+        let percent = |n: u64| (n as f32 / looked_up as f32) * 100_f32;
+        info!(
+            "{}/{} ({:0.2}%) barcodes were in the whitelist",
+            whitelist_exact,
+            looked_up,
+            percent(whitelist_exact)
+        );
+        info!(
+            "{}/{} ({:0.2}%) barcodes were corrected to the whitelist",
+            whitelist_corrected,
+            looked_up,
+            percent(whitelist_corrected)
+        );
+        info!(
+            "{}/{} ({:0.2}%) barcodes could not be assigned to the whitelist",
+            not_whitelisted,
+            looked_up,
+            percent(not_whitelisted)
+        );
+    }
+    if let Some(library) = &library {
+        info!(
+            "{}/{} ({:0.2}%) library guides were seen",
+            barcodes.len(),
+            library.guides.len(),
+            (barcodes.len() as f32 / library.guides.len() as f32) * 100_f32
+        );
+        info!("{no_guide} barcodes matched no guide");
+    }
+    if let Some(layout) = &layout {
+        info!(
+            "{}/{} plate cells were seen",
+            barcodes.len(),
+            layout.cells().len()
+        );
+        info!("{row_unassigned} barcodes had no plate row ({both_unassigned} of them also had no column)");
+        info!("{column_unassigned} barcodes had a plate row but no column");
+        info!("{impossible_cell} barcodes had a row and column on different plates");
+    }
+    let mut exact_match_rate: Option<f64> = None;
+    if args.exact_only {
+        let exact: u64 = barcodes.values().sum();
+        let looked_up = exact + not_whitelisted + no_guide;
+        let rate = exact as f64 / looked_up.max(1) as f64;
+        info!(
+            "exact-only mode: {exact}/{looked_up} ({:0.2}%) barcodes matched exactly; no correction or merging was performed",
+            rate * 100_f64
+        );
+        exact_match_rate = Some(rate);
+    }
+    let conflict_matrix = conflicts
+        .as_ref()
+        .map(|conflicts| conflicts.matrix(searcher.expressions.len()));
+    if let (Some(conflicts), Some(matrix)) = (&conflicts, &conflict_matrix) {
+        for (i, row) in matrix.iter().enumerate() {
+            info!("{} reads matched search expression {}", row[i], i + 1);
+        }
+        let conflicted = conflicts.conflicted();
+        match conflicted {
+            0 => info!("no reads matched more than one search expression"),
+            _ => warnings.add(
+                Code::PatternConflict,
+                format!("{conflicted} reads matched more than one search expression: the designs may overlap or an expression may be too permissive"),
+                &[("reads", conflicted as f64)],
+            ),
+        }
+        for (i, row) in matrix.iter().enumerate() {
+            for (j, count) in row.iter().enumerate().skip(i + 1) {
+                if *count > 0 {
+                    warnings.add(
+                        Code::PatternConflict,
+                        format!(
+                            "{count} reads matched both search expressions {} and {}",
+                            i + 1,
+                            j + 1
+                        ),
+                        &[
+                            ("reads", *count as f64),
+                            ("expression_a", (i + 1) as f64),
+                            ("expression_b", (j + 1) as f64),
+                        ],
+                    );
+                }
+            }
+        }
+    }
+    if let Some(strands) = &strands {
+        let [forward, reverse] = strands.total();
+        info!(
+            "{forward} counted reads matched in the forward orientation and {reverse} ({:0.2}%) in reverse complement",
+            (reverse as f64 / (forward + reverse).max(1) as f64) * 100_f64
+        );
+    }
+    if args.rescue_anchor.is_some() {
+        info!(
+            "{}/{} ({:0.2}%) reads were rescued by aligning the anchor, {} of them with an indel",
+            anchor_rescued,
+            total_reads,
+            (anchor_rescued as f32 / total_reads as f32) * 100_f32,
+            anchor_indels
+        );
+    }
+    if let (Some(path), Some(unexpected)) = (&args.unexpected_report_path, &unexpected_barcodes) {
+        info!(
+            "writing nearest whitelist entries of unassigned barcodes to {}",
+            path.to_string_lossy()
+        );
+        let mut top: Vec<(&String, &u64)> = unexpected.iter().collect();
+        top.sort_by(|a, b| b.1.cmp(a.1).then_with(|| a.0.cmp(b.0)));
+        let mut buffer = BufWriter::new(File::create(path)?);
+        writeln!(buffer, "barcode\tcount\tnearest\tdistance")?;
+        // 10x whitelists are searched by substitutions, and known barcodes through their
+        // index up to the threshold distance:
+        let max_distance = match &whitelist {
+            Some(_) => args.unexpected_max_distance,
+            None => args.threshold_distance,
+        };
+        for (barcode, count) in top.into_iter().take(args.unexpected_top) {
+            let nearest = match (&whitelist, &known) {
+                (Some(whitelist), _) => whitelist.nearest(barcode.as_bytes(), max_distance),
+                (None, Some(known)) => known.nearest(barcode),
+                (None, None) => None,
+            };
+            match nearest {
+                Some((nearest, distance)) => {
+                    writeln!(buffer, "{barcode}\t{count}\t{nearest}\t{distance}")?
+                }
+                None => writeln!(buffer, "{barcode}\t{count}\tNA\t>{max_distance}")?,
+            }
+        }
+        buffer.flush()?;
+    }
+    let detected_barcodes = barcodes.len();
+    let barcoded_reads = count_barcodes(&barcodes);
+    let filtered_reads = header_filtered
+        + chastity_filtered
+        + length_filtered
+        + low_read_quality
+        + too_many_n
+        + low_quality
+        + implausible_barcode
+        + barcode_length_filtered
+        + not_whitelisted
+        + no_guide
+        + row_unassigned
+        + column_unassigned
+        + impossible_cell;
+    info!(
+        "{barcoded_reads} barcoded + {no_barcode} unmatched + {filtered_reads} filtered = {} reads",
+        barcoded_reads + no_barcode + filtered_reads
+    );
+    if barcoded_reads + no_barcode + filtered_reads != total_reads {
+        warnings.add(
+            Code::ReconciliationMismatch,
+            format!("read categories do not sum to the {total_reads} reads processed"),
+            &[
+                ("total_reads", total_reads as f64),
+                (
+                    "categorised_reads",
+                    (barcoded_reads + no_barcode + filtered_reads) as f64,
+                ),
+            ],
+        );
+    }
+    info!("{detected_barcodes} barcodes detected");
+
+    // If given a sample sheet, assign the barcodes to samples and estimate the crosstalk
+    // between them (before merging, so that each read's own barcode is used):
+    if let Some(path) = &args.sample_sheet_path {
+        info!("reading sample sheet from {}", path.to_string_lossy());
+        let distance = args.threshold_distance.max(1);
+        let sheet = demux::read_sample_sheet(path, 2 * distance)?;
+        for (a, b, d) in sheet.close_pairs(2 * distance) {
+            warnings.add(
+                Code::CloseSamples,
+                format!(
+                    "samples {} ({}) and {} ({}) have barcodes only {d} edits apart",
+                    sheet.samples[a],
+                    sheet.barcode(a),
+                    sheet.samples[b],
+                    sheet.barcode(b)
+                ),
+                &[("distance", d as f64)],
+            );
+        }
+        let crosstalk = sheet.crosstalk(&barcodes, args.threshold_distance);
+        for (sample, reads) in sheet.samples.iter().zip(crosstalk.assigned.iter()) {
+            info!("{reads} reads assigned to sample {sample}");
+        }
+        info!(
+            "{} barcoded reads could not be assigned to a sample",
+            crosstalk.unassigned
+        );
+        for (a, row) in crosstalk.matrix.iter().enumerate() {
+            let ambiguous: u64 = row.iter().sum();
+            if ambiguous > 0 {
+                info!(
+                    "{ambiguous}/{} ({:0.2}%) reads assigned to sample {} are within one error of another sample",
+                    crosstalk.assigned[a],
+                    (ambiguous as f32 / crosstalk.assigned[a] as f32) * 100_f32,
+                    sheet.samples[a]
+                );
+            }
+        }
+        if let Some(path) = &args.crosstalk_path {
+            info!("writing crosstalk matrix to {}", path.to_string_lossy());
+            crosstalk.write(path, &sheet)?;
+        }
+    }
+    Ok(Counts {
+        sample,
+        seed,
+        barcode_expression,
+        file_paths,
+        tables,
+        barcodes,
+        total_reads,
+        barcoded_reads,
+        no_barcode,
+        filtered_reads,
+        detected_barcodes,
+        mate_barcoded,
+        search_mates,
+        unmatched_reads,
+        read2_buffer,
+        invalid_records,
+        header_filtered,
+        chastity_filtered,
+        casava_unparsed,
+        length_filtered,
+        filter_lengths,
+        low_read_quality,
+        too_many_n,
+        low_quality,
+        low_quality_mean,
+        low_quality_base,
+        filter_barcode_quality,
+        implausible_barcode,
+        filter_gc,
+        barcode_length_filtered,
+        filter_barcode_lengths,
+        adapter_trimmed,
+        adapter_trimmed_bases,
+        poly_g_trimmed,
+        poly_a_trimmed,
+        anchor_rescued,
+        anchor_indels,
+        read_lengths,
+        n_fractions,
+        barcode_quality,
+        barcode_quality_profile,
+        barcode_gc,
+        barcode_lengths,
+        base_composition,
+        tile_stats,
+        conflicts,
+        conflict_matrix,
+        whitelisted,
+        whitelist_exact,
+        whitelist_corrected,
+        not_whitelisted,
+        exact_match_rate,
+        library,
+        no_guide,
+        names,
+        layout,
+        row_unassigned,
+        column_unassigned,
+        both_unassigned,
+        impossible_cell,
+        sample_counts,
+        strands,
+        duplicates,
+        umis,
+        umi_stats,
+        merge_qualities,
+        unaligned_qualities,
+        columns,
+        fractions,
+        merge_count,
+        similarity,
+        pool,
+    })
+}
 
-    // Now we have all the barcodes we can extract a list of the "endpoint" barcodes, i.e.
-    // those that can accept merged barcodes. A barcode is an endpoint if it currently has
-    // at least args.threshold_count reads associated to it.
-    let endpoint_barcodes: HashSet<String> = barcodes
+/// Decide which barcodes are endpoints, merge the others into them (if the mode merges), then
+/// remove the excluded barcodes and rescue the unmatched reads. The side counts are merged
+/// along with the barcodes.
+fn merge_counts(
+    args: &Args,
+    counts: &mut Counts,
+    rng: &mut ChaCha12Rng,
+    warnings: &mut Warnings,
+) -> Result<Merged, Report> {
+    let Counts {
+        ref mut barcodes,
+        ref mut duplicates,
+        merge_count,
+        ref mut merge_qualities,
+        ref pool,
+        ref mut sample_counts,
+        similarity,
+        ref mut strands,
+        ref mut umis,
+        unaligned_qualities,
+        ref unmatched_reads,
+        ..
+    } = *counts;
+    let threshold_count = match args.merge_fraction {
+        Some(fraction) => {
+            let barcoded: u64 = barcodes.values().sum();
+            let threshold_count = ((fraction * barcoded as f64).ceil() as u64).saturating_sub(1);
+            info!(
+                "barcodes with {fraction} of the {barcoded} barcoded reads (more than {threshold_count}) are endpoints"
+            );
+            threshold_count
+        }
+        None => match merge_count {
+            Some(count) => count,
+            None => {
+                let mut counts: Vec<u64> = barcodes.values().copied().collect();
+                counts.sort_unstable_by(|a, b| b.cmp(a));
+                match stats::inflection(&counts) {
+                    Some(i) => {
+                        info!(
+                            "barcode rank curve inflection is at rank {} ({} reads); barcodes with more reads are endpoints",
+                            i + 1,
+                            counts[i]
+                        );
+                        counts[i]
+                    }
+                    None => {
+                        warnings.add(
+                            Code::NoInflection,
+                            "the barcode rank curve has no inflection to use as the merge count; not merging".to_string(),
+                            &[("barcodes", counts.len() as f64)],
+                        );
+                        0
+                    }
+                }
+            }
+        },
+    };
+    // Note the reads and bases on the endpoint barcodes for the error rate estimate:
+    let (endpoints, endpoint_reads, endpoint_bases) = barcodes
         .iter()
-        .filter_map(|(barcode, count)| match count > &args.threshold_count {
-            false => None,
-            true => Some(barcode.to_owned()),
-        })
-        .collect();
-    debug!("{} barcodes pass threshold count", endpoint_barcodes.len());
+        .filter(|(_, count)| **count > threshold_count)
+        .fold(
+            (0_u64, 0_u64, 0_u64),
+            |(endpoints, reads, bases), (barcode, count)| {
+                (
+                    endpoints + 1,
+                    reads + count,
+                    bases + count * barcode.len() as u64,
+                )
+            },
+        );
+    // Estimate how many endpoints would be within the merge distance purely by chance:
+    let mean_length = endpoint_bases as f64 / endpoint_reads.max(1) as f64;
+    let barcode_length = mean_length.round() as usize;
+    let collisions = stats::collisions(endpoints, barcode_length, args.threshold_distance);
+    info!(
+        "random {barcode_length}bp barcodes are within distance {} with probability {:0.3e}",
+        args.threshold_distance, collisions.pair_probability
+    );
+    match collisions.expected < 0.05 {
+        true => info!(
+            "{:0.3e} spurious collisions expected among {endpoints} endpoint barcodes",
+            collisions.expected
+        ),
+        false => warnings.add(
+            Code::SpuriousCollisions,
+            format!(
+                "{:0.3} spurious collisions expected among {endpoints} endpoint barcodes (probability of any {:0.3}): the threshold distance may be too high for this library",
+                collisions.expected, collisions.probability_any
+            ),
+            &[
+                ("expected", collisions.expected),
+                ("probability_any", collisions.probability_any),
+                ("endpoints", endpoints as f64),
+            ],
+        ),
+    }
+    let tie_break = merge::TieBreak::new(&args.tie_break);
+    let rule = merge::EndpointRule {
+        threshold_count,
+        ratio: args.merge_ratio.unwrap_or(0_f64),
+    };
+    if unaligned_qualities > 0 {
+        warnings.add(
+            Code::UnalignedQualities,
+            format!(
+                "{unaligned_qualities} reads had qualities that could not be lined up with their barcode, so they were not used to weight the merge distances"
+            ),
+            &[("reads", unaligned_qualities as f64)],
+        );
+    }
+    let merge_qualities = merge_qualities.take().map(|qualities| qualities.means());
+    let mut merge_class = |barcodes: &mut HashMap<String, u64>| match &args.pair_separator {
+        _ if args.exact_only || args.whitelist_path.is_some() || !args.mode.merges() => Vec::new(),
+        _ if args.merge_method == "directional" => merge::merge_directional(
+            barcodes,
+            args.pair_separator.as_deref(),
+            similarity,
+            pool.as_ref(),
+        ),
+        _ if args.merge_method == "cluster" => merge::merge_components(
+            barcodes,
+            args.pair_separator.as_deref(),
+            similarity,
+            pool.as_ref(),
+        ),
+        Some(separator) => merge::merge_pairs(
+            barcodes,
+            separator,
+            rule,
+            similarity,
+            tie_break,
+            rng,
+            pool.as_ref(),
+        ),
+        None => merge::merge_barcodes(
+            barcodes,
+            rule,
+            similarity,
+            tie_break,
+            rng,
+            pool.as_ref(),
+            merge_qualities.as_ref(),
+        ),
+    };
+    let merges = match args.split_by_length || args.merge_same_length {
+        true => {
+            // Merging across lengths would only ever be by insertions and deletions, so each
+            // length class is merged on its own:
+            let mut classes: BTreeMap<usize, HashMap<String, u64>> = BTreeMap::new();
+            for (barcode, count) in barcodes.drain() {
+                classes
+                    .entry(barcode.len())
+                    .or_default()
+                    .insert(barcode, count);
+            }
+            let mut merges: Vec<merge::Merge> = Vec::new();
+            for class in classes.values_mut() {
+                merges.extend(merge_class(class));
+                barcodes.extend(class.drain());
+            }
+            merges
+        }
+        false => merge_class(barcodes),
+    };
+    if let Some(path) = &args.merge_map_path {
+        info!("writing merge map to {}", path.to_string_lossy());
+        merge::write_merge_map(path, barcodes, &merges)?;
+    }
+    if let Some(path) = &args.merge_graph_path {
+        info!("writing merge graph to {}", path.to_string_lossy());
+        merge::write_merge_graph(path, barcodes, &merges)?;
+    }
+    let mut pair_corrections: Option<[u64; 3]> = None;
+    if let (Some(separator), false) = (&args.pair_separator, args.exact_only) {
+        // Count how many merged pairs were corrected in each component:
+        let mut corrected = [0_u64; 3];
+        for merge in merges.iter() {
+            let barcode = merge.barcode.split_once(separator.as_str());
+            let endpoint = merge.endpoint.split_once(separator.as_str());
+            if let (Some((a1, a2)), Some((b1, b2))) = (barcode, endpoint) {
+                match (a1 != b1, a2 != b2) {
+                    (true, false) => corrected[0] += 1_u64,
+                    (false, true) => corrected[1] += 1_u64,
+                    _ => corrected[2] += 1_u64,
+                }
+            }
+        }
+        info!(
+            "{} barcode pairs were corrected in the first component only, {} in the second only and {} in both",
+            corrected[0], corrected[1], corrected[2]
+        );
+        pair_corrections = Some(corrected);
+    }
+    let mut length_classes: BTreeMap<usize, (u64, u64)> = BTreeMap::new();
+    if args.split_by_length {
+        for (barcode, count) in barcodes.iter() {
+            let class = length_classes.entry(barcode.len()).or_default();
+            class.0 += 1_u64;
+            class.1 += count;
+        }
+        for (length, (distinct, reads)) in length_classes.iter() {
+            info!(
+                "{distinct} barcodes of length {length} remain after merging, with {reads} reads"
+            );
+        }
+    }
+    if let Some(duplicates) = duplicates.as_mut() {
+        duplicates.merge(&merges);
+    }
+    if let Some(strands) = strands.as_mut() {
+        strands.merge(&merges);
+    }
+    if let Some(sample_counts) = sample_counts.as_mut() {
+        sample_counts.merge(&merges);
+    }
+    // Remove excluded barcodes after merging, so that their absorbed variants go with them:
+    let mut excluded: u64 = 0_u64;
+    let mut excluded_matches: Vec<(String, u64)> = Vec::new();
+    let mut excluded_barcodes: HashSet<String> = HashSet::new();
+    if let Some(path) = &args.exclude_barcodes_path {
+        info!("reading excluded barcodes from {}", path.to_string_lossy());
+        let entries = whitelist::read_barcode_list(path)?;
+        let radius = match args.exclude_fuzzy {
+            true => args.threshold_distance,
+            false => 0,
+        };
+        let mut matched = vec![0_u64; entries.len()];
+        let index = index::CandidateIndex::new(entries, radius);
+        for (barcode, count) in barcodes.iter() {
+            let near = index.within(barcode);
+            if near.is_empty() {
+                continue;
+            }
+            for (entry, _) in near {
+                matched[entry] += count;
+            }
+            trace!("excluding barcode {barcode} (count={count})");
+            excluded += count;
+            excluded_barcodes.insert(barcode.to_owned());
+        }
+        barcodes.retain(|barcode, _| !excluded_barcodes.contains(barcode));
+        for (entry, reads) in matched.iter().enumerate() {
+            if *reads > 0 {
+                info!(
+                    "excluded barcode {} matched {reads} reads",
+                    index.get(entry)
+                );
+                excluded_matches.push((index.get(entry).to_string(), *reads));
+            }
+        }
+        info!(
+            "excluded {excluded} reads on {} barcodes, matching {}/{} excluded barcodes",
+            excluded_barcodes.len(),
+            excluded_matches.len(),
+            index.len()
+        );
+    }
+    // Search the unmatched reads for the endpoint barcodes, keeping the rescued reads apart
+    // from the strict counts:
+    let rescued = match &unmatched_reads {
+        Some(reads) => {
+            let index = rescue::BarcodeIndex::new(
+                barcodes
+                    .iter()
+                    .filter(|(_, count)| **count > threshold_count)
+                    .map(|(barcode, _)| barcode),
+            );
+            info!(
+                "searching {} unmatched reads for {} barcodes",
+                reads.len(),
+                index.len()
+            );
+            let mut rescued: HashMap<String, u64> = HashMap::new();
+            for read in reads.iter() {
+                if let Some(barcode) = index.find(read) {
+                    *rescued.entry(barcode.to_owned()).or_insert(0_u64) += 1_u64;
+                }
+            }
+            let total: u64 = rescued.values().sum();
+            info!(
+                "{total}/{} ({:0.2}%) unmatched reads were rescued by searching for the barcodes",
+                reads.len(),
+                (total as f64 / reads.len().max(1) as f64) * 100_f64
+            );
+            Some(rescued)
+        }
+        None => None,
+    };
+    if let Some(umis) = umis.as_mut() {
+        umis.merge(&merges);
+    }
+    let error_profile = merge::ErrorProfile::new(&merges);
+    let error_rates = error_profile.error_rates(endpoint_reads, mean_length);
+    for (distance, reads) in error_profile.absorbed.iter() {
+        info!("{reads} reads were merged into endpoints at distance {distance}");
+    }
+    for (distance, rate) in error_rates.iter() {
+        info!("estimated per-base error rate from distance {distance} merges is {rate:0.6}");
+    }
+    let substitutions = error_profile.ranked_substitutions();
+    if !substitutions.is_empty() {
+        let listed: Vec<String> = substitutions
+            .iter()
+            .map(|((from, to), reads)| format!("{from}>{to} {reads}"))
+            .collect();
+        info!(
+            "reads merged by each substitution (endpoint>read base): {}",
+            listed.join(", ")
+        );
+    }
+    Ok(Merged {
+        threshold_count,
+        endpoints,
+        barcode_length,
+        collisions,
+        merges,
+        error_profile,
+        error_rates,
+        substitutions,
+        pair_corrections,
+        length_classes,
+        excluded,
+        excluded_barcodes,
+        excluded_matches,
+        rescued,
+    })
+}
 
-    if !endpoint_barcodes.is_empty() {
-        // Get a list of the non-endpoint barcodes sorted by their count (lowest first):
-        let mut non_endpoint_barcodes: Vec<(String, u64)> = barcodes
+/// Write the count table and every requested report and file, log the summary of the run and
+/// return the counts for a batch matrix.
+fn report(
+    args: &Args,
+    counts: Counts,
+    merged: Merged,
+    started: Instant,
+    rng: &mut ChaCha12Rng,
+    mut warnings: Warnings,
+    output: &mut dyn Write,
+) -> Result<batch::Counted, Report> {
+    let Counts {
+        sample,
+        seed,
+        barcode_expression,
+        file_paths,
+        tables,
+        barcodes,
+        total_reads,
+        barcoded_reads,
+        no_barcode,
+        filtered_reads,
+        detected_barcodes,
+        mate_barcoded,
+        search_mates,
+        unmatched_reads,
+        mut read2_buffer,
+        invalid_records,
+        header_filtered,
+        chastity_filtered,
+        casava_unparsed,
+        length_filtered,
+        filter_lengths,
+        low_read_quality,
+        too_many_n,
+        low_quality,
+        low_quality_mean,
+        low_quality_base,
+        filter_barcode_quality,
+        implausible_barcode,
+        filter_gc,
+        barcode_length_filtered,
+        filter_barcode_lengths,
+        adapter_trimmed,
+        adapter_trimmed_bases,
+        poly_g_trimmed,
+        poly_a_trimmed,
+        anchor_rescued,
+        anchor_indels,
+        read_lengths,
+        n_fractions,
+        barcode_quality,
+        barcode_quality_profile,
+        barcode_gc,
+        barcode_lengths,
+        base_composition,
+        tile_stats,
+        conflicts,
+        conflict_matrix,
+        whitelisted,
+        whitelist_exact,
+        whitelist_corrected,
+        not_whitelisted,
+        exact_match_rate,
+        library,
+        no_guide,
+        names,
+        layout,
+        row_unassigned,
+        column_unassigned,
+        both_unassigned,
+        impossible_cell,
+        sample_counts,
+        strands,
+        duplicates,
+        umis,
+        umi_stats,
+        columns,
+        fractions,
+        similarity,
+        ..
+    } = counts;
+    let Merged {
+        threshold_count,
+        endpoints,
+        barcode_length,
+        collisions,
+        merges,
+        error_profile,
+        error_rates,
+        substitutions,
+        pair_corrections,
+        length_classes,
+        excluded,
+        excluded_barcodes,
+        excluded_matches,
+        rescued,
+    } = merged;
+    // The run report is the output of stats, and the accepted barcodes are the output of
+    // whitelist, in place of the barcode table:
+    let mut sink = std::io::sink();
+    let (report_output, list_output, output): (
+        Option<&mut dyn Write>,
+        Option<&mut dyn Write>,
+        &mut dyn Write,
+    ) = match args.mode {
+        Mode::Stats => (Some(output), None, &mut sink),
+        Mode::Whitelist => (None, Some(output), &mut sink),
+        _ => (None, None, output),
+    };
+    if let (Some(path), Some(profile)) =
+        (&args.barcode_quality_profile_path, &barcode_quality_profile)
+    {
+        info!(
+            "writing barcode quality profile to {}",
+            path.to_string_lossy()
+        );
+        profile.write(path)?;
+    }
+    if let (Some(path), Some(composition)) = (&args.base_composition_path, &base_composition) {
+        info!(
+            "writing barcode base composition to {}",
+            path.to_string_lossy()
+        );
+        composition.write(path)?;
+    }
+    if let (Some(prefix), Some(layout), Some(separator)) =
+        (&args.plate_grid_prefix, &layout, &args.pair_separator)
+    {
+        for path in layout.write_grids(prefix, &barcodes, separator)? {
+            info!("wrote plate grid to {path}");
+        }
+    }
+    if let (Some(path), Some(library)) = (&args.gene_counts_path, &library) {
+        info!("writing gene counts to {}", path.to_string_lossy());
+        library.write_gene_counts(path, &barcodes)?;
+    }
+    if let (Some(path), Some(tiles)) = (&args.tile_stats_path, &tile_stats) {
+        info!("writing tile statistics to {}", path.to_string_lossy());
+        tiles.write(path)?;
+    }
+    if let (Some(path), Some(histogram)) = (&args.read_length_histogram_path, &read_lengths) {
+        info!(
+            "writing read length histogram to {}",
+            path.to_string_lossy()
+        );
+        histogram.write(path, "length")?;
+    }
+    if let (Some(path), Some(histogram)) = (&args.barcode_length_histogram_path, &barcode_lengths) {
+        info!(
+            "writing barcode length histogram to {}",
+            path.to_string_lossy()
+        );
+        histogram.write(path, "length")?;
+    }
+
+    // Print out the results:
+    info!("{} reads assigned a barcode", count_barcodes(&barcodes));
+    info!("{} barcodes remain after merging", barcodes.len());
+    // Estimate the library complexity from the final barcode counts:
+    let frequencies = stats::counts_of_counts(barcodes.values().copied());
+    let chao1 = stats::chao1(&frequencies);
+    info!(
+        "estimated library complexity (Chao1) is {:0.1} ± {:0.1} barcodes ({:0.2}% observed)",
+        chao1.estimate,
+        chao1.variance.sqrt(),
+        (barcodes.len() as f64 / chao1.estimate.max(1_f64)) * 100_f64
+    );
+    // Measure the skew of the final counts, over every guide of a library:
+    let diversity = stats::diversity(&match &library {
+        Some(library) => library.guide_counts(&barcodes),
+        None => barcodes.values().copied().collect(),
+    });
+    info!(
+        "barcode diversity has Shannon entropy {:0.3} bits, Gini coefficient {:0.3} and \
+        90/10 ratio {}",
+        diversity.shannon_entropy,
+        diversity.gini,
+        match diversity.ratio_90_10 {
+            Some(ratio) => format!("{ratio:0.2}"),
+            None => "undefined".to_string(),
+        }
+    );
+    let saturation_asymptote = match args.extrapolate_complexity {
+        true => {
+            let barcoded_reads = count_barcodes(&barcodes);
+            let points: Vec<(f64, f64)> = (1..=10)
+                .map(|i| {
+                    let depth = barcoded_reads * i / 10;
+                    (depth as f64, stats::rarefaction(&frequencies, depth))
+                })
+                .collect();
+            let asymptote = stats::saturation_asymptote(&points);
+            match asymptote {
+                Some(asymptote) => info!(
+                    "extrapolated library complexity is {:0.1} barcodes ({:0.2}% observed)",
+                    asymptote,
+                    (barcodes.len() as f64 / asymptote.max(1_f64)) * 100_f64
+                ),
+                None => warnings.add(
+                    Code::NoSaturation,
+                    "saturation curve could not be extrapolated".to_string(),
+                    &[],
+                ),
+            }
+            asymptote
+        }
+        false => None,
+    };
+    // Find the knee and inflection of the barcode rank curve, where the real barcodes give
+    // way to the background:
+    let rank_points = match (&args.knee_plot_path, &args.report_path) {
+        (None, None) => None,
+        _ => {
+            let mut ranked: Vec<(&str, u64)> = barcodes
+                .iter()
+                .filter(|(_, count)| **count > 0)
+                .map(|(barcode, count)| (barcode.as_str(), *count))
+                .collect();
+            ranked.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+            let counts: Vec<u64> = ranked.iter().map(|(_, count)| *count).collect();
+            let (knee, inflection) = (stats::knee(&counts), stats::inflection(&counts));
+            for (name, point) in [("knee", knee), ("inflection", inflection)] {
+                if let Some(i) = point {
+                    info!(
+                        "barcode rank curve {name} is at rank {} ({} reads)",
+                        i + 1,
+                        counts[i]
+                    );
+                }
+            }
+            if let Some(path) = &args.knee_plot_path {
+                info!("writing barcode rank curve to {}", path.to_string_lossy());
+                let mut writer = create_output(path)?;
+                output::write_knee_plot(&mut writer, &ranked, knee, inflection)?;
+            }
+            let point = |point: Option<usize>| point.map(|i| (i + 1, counts[i]));
+            Some((point(knee), point(inflection)))
+        }
+    };
+    // Derive the whitelist from the barcodes, if that is the output:
+    if let Some(list_output) = list_output {
+        let variants = args
+            .whitelist_variants
+            .then_some((args.pair_separator.as_deref(), similarity));
+        let accepted = whitelist::derive(&barcodes, args.cell_number, variants)?;
+        let accepted_reads: u64 = accepted
             .iter()
-            .filter_map(|(barcode, count)| {
-                let barcode = barcode.to_owned();
-                match endpoint_barcodes.contains(&barcode) {
-                    false => Some((barcode, *count)),
-                    true => None,
+            .map(|entry| entry.count + entry.variants.iter().map(|(_, n)| n).sum::<u64>())
+            .sum();
+        info!(
+            "accepted {} barcodes with {}/{} ({:0.2}%) barcoded reads into the whitelist",
+            accepted.len(),
+            accepted_reads,
+            count_barcodes(&barcodes),
+            100_f64 * accepted_reads as f64 / count_barcodes(&barcodes).max(1) as f64
+        );
+        if args.whitelist_variants {
+            info!(
+                "listed {} error variants of the accepted barcodes",
+                accepted
+                    .iter()
+                    .map(|entry| entry.variants.len())
+                    .sum::<usize>()
+            );
+        }
+        match output::write_whitelist(list_output, &accepted) {
+            Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => (),
+            written => written?,
+        }
+    }
+    // If given, compare the final barcode counts with their expected fractions:
+    let fit = match &args.expected_path {
+        Some(path) => {
+            info!(
+                "reading expected barcode fractions from {}",
+                path.to_string_lossy()
+            );
+            let expected = gof::read_expected(path)?;
+            let fit = gof::goodness_of_fit(&expected, &barcodes);
+            info!(
+                "{} reads on {} expected barcodes: chi-square {:0.2}, G {:0.2} ({} df)",
+                fit.observed_reads,
+                fit.representation.len(),
+                fit.chi_square,
+                fit.g,
+                fit.degrees_of_freedom
+            );
+            if fit.unexpected_reads > 0 {
+                info!("{} reads were on unexpected barcodes", fit.unexpected_reads);
+            }
+            if fit.zero_expected_reads > 0 {
+                warnings.add(
+                    Code::UnexpectedBarcodes,
+                    format!(
+                        "{} reads were on barcodes with an expected fraction of zero",
+                        fit.zero_expected_reads
+                    ),
+                    &[("reads", fit.zero_expected_reads as f64)],
+                );
+            }
+            for r in fit.over_represented().take(5) {
+                info!(
+                    "over-represented barcode {}: {} reads, {:0.1} expected ({:0.2}-fold)",
+                    r.barcode, r.observed, r.expected, r.fold_change
+                );
+            }
+            for r in fit.under_represented().take(5) {
+                info!(
+                    "under-represented barcode {}: {} reads, {:0.1} expected ({:0.2}-fold)",
+                    r.barcode, r.observed, r.expected, r.fold_change
+                );
+            }
+            if let Some(ratio) = fit.max_min_ratio() {
+                info!("max/min barcode representation ratio is {ratio:0.2}");
+            }
+            Some(fit)
+        }
+        None => None,
+    };
+    if let Some(duplicates) = &duplicates {
+        let barcoded_reads = count_barcodes(&barcodes);
+        let distinct_reads = duplicates.total_distinct_reads();
+        info!(
+            "{distinct_reads}/{barcoded_reads} barcoded reads were distinct (duplication rate {:0.2}%)",
+            (1_f64 - distinct_reads as f64 / barcoded_reads.max(1) as f64) * 100_f64
+        );
+    }
+    if let (Some(path), Some(umi_stats)) = (&args.umi_report_path, &umi_stats) {
+        info!("UMI length: {}", umi_stats.lengths.describe());
+        if let Some(fraction) = umi_stats.top_fraction() {
+            let top = &umi_stats.top(1)[0].0;
+            match fraction > args.umi_max_fraction {
+                true => warnings.add(
+                    Code::DominantUmi,
+                    format!(
+                        "the most frequent UMI {top} is {:0.2}% of all UMIs, above the {:0.2}% expected at most of random UMIs",
+                        fraction * 100_f64,
+                        args.umi_max_fraction * 100_f64
+                    ),
+                    &[
+                        ("fraction", fraction),
+                        ("max_fraction", args.umi_max_fraction),
+                    ],
+                ),
+                false => info!(
+                    "the most frequent UMI {top} is {:0.2}% of all UMIs",
+                    fraction * 100_f64
+                ),
+            }
+        }
+        info!("writing UMI report to {}", path.to_string_lossy());
+        let mut report = RunReport::default();
+        umi_stats.add_to_report(&mut report, 100);
+        report.write(path)?;
+    }
+    let distinct_umis = match &umis {
+        Some(umis) => {
+            if umis.invalid > 0 {
+                warnings.add(
+                    Code::InvalidUmis,
+                    format!(
+                        "{} UMIs had ambiguous bases or were too long and were not counted",
+                        umis.invalid
+                    ),
+                    &[("umis", umis.invalid as f64)],
+                );
+            }
+            let distinct = umis.distinct_umis(args.correct_umis);
+            let total: usize = distinct.values().sum();
+            info!("{total} distinct UMIs across all barcodes");
+            if args.correct_umis {
+                let raw: usize = umis.distinct_umis(false).values().sum();
+                info!(
+                    "{raw} raw UMIs collapsed to {total} (collapse rate {:0.2}%)",
+                    (1_f64 - total as f64 / raw.max(1) as f64) * 100_f64
+                );
+            }
+            Some(distinct)
+        }
+        None => None,
+    };
+    // If requested, bootstrap the fractions of the top barcodes, capping the replicates and
+    // barcodes so the cost stays small:
+    let bootstrap = match args.bootstrap {
+        Some(replicates) => {
+            let replicates = replicates.clamp(1, 10000);
+            let mut top: Vec<(String, u64)> =
+                barcodes.iter().map(|(b, c)| (b.to_owned(), *c)).collect();
+            top.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+            top.truncate(args.bootstrap_top.clamp(1, 100));
+            let counts: Vec<u64> = top.iter().map(|(_, count)| *count).collect();
+            let total = count_barcodes(&barcodes);
+            let intervals = stats::bootstrap_intervals(&counts, total, replicates, 0.05, rng);
+            for ((barcode, count), (lower, upper)) in top.iter().zip(intervals.iter()) {
+                info!(
+                    "barcode {barcode} is {:0.4}% of barcoded reads (95% interval {:0.4}-{:0.4}%)",
+                    *count as f64 / total as f64 * 100_f64,
+                    lower * 100_f64,
+                    upper * 100_f64
+                );
+            }
+            Some((top, intervals, total))
+        }
+        None => None,
+    };
+    if args.report_path.is_some() || report_output.is_some() {
+        let mut report = RunReport::default();
+        if let Some(sample) = &sample {
+            report.add("run", "sample", sample);
+        }
+        report.add("run", "seed", seed);
+        report.add("reads", "total", total_reads);
+        report.add("reads", "barcoded", count_barcodes(&barcodes));
+        report.add("reads", "no_barcode", no_barcode);
+        if args.header_filter.is_some() {
+            report.add("reads", "header_filtered", header_filtered);
+        }
+        if args.discard_filtered {
+            report.add("reads", "chastity_filtered", chastity_filtered);
+        }
+        if filter_lengths {
+            report.add("reads", "length_filtered", length_filtered);
+        }
+        if args.min_read_quality.is_some() {
+            report.add("reads", "low_read_quality", low_read_quality);
+        }
+        if args.max_n_fraction.is_some() {
+            report.add("reads", "too_many_n", too_many_n);
+        }
+        if filter_barcode_quality {
+            report.add("reads", "low_quality", low_quality);
+        }
+        if args.min_barcode_quality.is_some() {
+            report.add("reads", "low_quality_mean", low_quality_mean);
+        }
+        if args.min_barcode_base_quality.is_some() {
+            report.add("reads", "low_quality_base", low_quality_base);
+        }
+        if filter_gc {
+            report.add("reads", "implausible_barcode", implausible_barcode);
+        }
+        if filter_barcode_lengths {
+            report.add("reads", "barcode_length_filtered", barcode_length_filtered);
+        }
+        if whitelisted {
+            report.add("reads", "whitelist_exact", whitelist_exact);
+            report.add("reads", "whitelist_corrected", whitelist_corrected);
+            report.add("reads", "not_whitelisted", not_whitelisted);
+        }
+        if library.is_some() {
+            report.add("reads", "no_guide", no_guide);
+        }
+        if layout.is_some() {
+            report.add("reads", "row_unassigned", row_unassigned);
+            report.add("reads", "column_unassigned", column_unassigned);
+            report.add("reads", "row_and_column_unassigned", both_unassigned);
+            report.add("reads", "impossible_cell", impossible_cell);
+        }
+        if args.exclude_barcodes_path.is_some() {
+            report.add("reads", "excluded", excluded);
+            for (entry, reads) in excluded_matches.iter() {
+                report.add("excluded", entry, reads);
+            }
+        }
+        if let (Some(conflicts), Some(matrix)) = (&conflicts, &conflict_matrix) {
+            for (set, count) in conflicts.sets.iter() {
+                let set: Vec<String> = set.iter().map(|i| (i + 1).to_string()).collect();
+                report.add("pattern_sets", set.join("+"), count);
+            }
+            for (i, row) in matrix.iter().enumerate() {
+                for (j, count) in row.iter().enumerate().skip(i) {
+                    report.add("pattern_conflicts", format!("{}_{}", i + 1, j + 1), count);
+                }
+            }
+        }
+        if let (Some(rescued), Some(reads)) = (&rescued, &unmatched_reads) {
+            report.add("reads", "searched_unmatched", reads.len());
+            report.add("reads", "rescued", rescued.values().sum::<u64>());
+        }
+        if search_mates {
+            report.add("reads", "read2_barcoded", mate_barcoded);
+        }
+        for (reason, records) in invalid_records.iter() {
+            report.add("invalid_records", reason, records);
+        }
+        if let Some(strands) = &strands {
+            let [forward, reverse] = strands.total();
+            report.add("strands", "forward_reads", forward);
+            report.add("strands", "reverse_reads", reverse);
+        }
+        if let Some(rate) = exact_match_rate {
+            report.add("reads", "exact_match_rate", format!("{rate:0.4}"));
+        }
+        if args.rescue_anchor.is_some() {
+            report.add("reads", "anchor_rescued", anchor_rescued);
+            report.add("reads", "anchor_indel_rescued", anchor_indels);
+        }
+        if args.trim_poly_g {
+            report.add("trimming", "poly_g_trimmed_reads", poly_g_trimmed);
+        }
+        if !args.adapters.is_empty() {
+            report.add("trimming", "adapter_trimmed_reads", adapter_trimmed);
+            report.add(
+                "trimming",
+                "adapter_mean_trimmed_length",
+                format!(
+                    "{:0.2}",
+                    adapter_trimmed_bases as f64 / adapter_trimmed.max(1) as f64
+                ),
+            );
+        }
+        if args.trim_poly_a {
+            report.add("trimming", "poly_a_trimmed_reads", poly_a_trimmed);
+        }
+        if args.discard_filtered {
+            report.add("headers", "casava_unparsed", casava_unparsed);
+        }
+        if let Some(tiles) = &tile_stats {
+            report.add("headers", "tiles", tiles.len());
+            report.add("headers", "location_unparsed", tiles.unparsed);
+            report.add(
+                "headers",
+                "outlier_tiles",
+                tiles.outliers(args.tile_max_mads).len(),
+            );
+        }
+        if let Some(duplicates) = &duplicates {
+            report.add("reads", "distinct", duplicates.total_distinct_reads());
+        }
+        if let (Some(umis), Some(distinct_umis)) = (&umis, &distinct_umis) {
+            report.add("umis", "invalid", umis.invalid);
+            report.add("umis", "distinct", distinct_umis.values().sum::<usize>());
+            if args.correct_umis {
+                let raw: usize = umis.distinct_umis(false).values().sum();
+                report.add("umis", "distinct_uncorrected", raw);
+            }
+        }
+        report.add("barcodes", "detected", detected_barcodes);
+        report.add("barcodes", "after_merging", barcodes.len());
+        report.add("collisions", "endpoints", endpoints);
+        report.add("collisions", "barcode_length", barcode_length);
+        report.add(
+            "collisions",
+            "pair_probability",
+            format!("{:0.6e}", collisions.pair_probability),
+        );
+        report.add(
+            "collisions",
+            "expected",
+            format!("{:0.6e}", collisions.expected),
+        );
+        report.add(
+            "collisions",
+            "probability_any",
+            format!("{:0.6}", collisions.probability_any),
+        );
+        for (distance, reads) in error_profile.absorbed.iter() {
+            report.add("merging", format!("absorbed_reads_d{distance}"), reads);
+        }
+        for (length, (distinct, reads)) in length_classes.iter() {
+            report.add("lengths", format!("distinct_{length}"), distinct);
+            report.add("lengths", format!("reads_{length}"), reads);
+        }
+        if let Some([first, second, both]) = pair_corrections {
+            report.add("merging", "pairs_corrected_first", first);
+            report.add("merging", "pairs_corrected_second", second);
+            report.add("merging", "pairs_corrected_both", both);
+        }
+        for (distance, rate) in error_rates.iter() {
+            report.add("error_rate", format!("d{distance}"), format!("{rate:0.6}"));
+        }
+        for ((from, to), reads) in substitutions.iter() {
+            report.add("substitutions", format!("{from}>{to}"), reads);
+        }
+        report.add("complexity", "chao1", format!("{:0.2}", chao1.estimate));
+        report.add(
+            "complexity",
+            "chao1_variance",
+            format!("{:0.2}", chao1.variance),
+        );
+        report.add(
+            "complexity",
+            "chao1_fraction_observed",
+            format!("{:0.4}", barcodes.len() as f64 / chao1.estimate.max(1_f64)),
+        );
+        if let Some(asymptote) = saturation_asymptote {
+            report.add("complexity", "extrapolated", format!("{asymptote:0.2}"));
+            report.add(
+                "complexity",
+                "extrapolated_fraction_observed",
+                format!("{:0.4}", barcodes.len() as f64 / asymptote.max(1_f64)),
+            );
+        }
+        if let Some(fit) = &fit {
+            report.add("distribution", "observed_reads", fit.observed_reads);
+            report.add("distribution", "unexpected_reads", fit.unexpected_reads);
+            report.add(
+                "distribution",
+                "zero_expected_reads",
+                fit.zero_expected_reads,
+            );
+            report.add(
+                "distribution",
+                "chi_square",
+                format!("{:0.4}", fit.chi_square),
+            );
+            report.add("distribution", "g", format!("{:0.4}", fit.g));
+            report.add("distribution", "degrees_of_freedom", fit.degrees_of_freedom);
+            if let Some(ratio) = fit.max_min_ratio() {
+                report.add("distribution", "max_min_ratio", format!("{ratio:0.4}"));
+            }
+            for r in fit.over_represented().take(5) {
+                report.add(
+                    "over_represented",
+                    &r.barcode,
+                    format!("{:0.4}", r.fold_change),
+                );
+            }
+            for r in fit.under_represented().take(5) {
+                report.add(
+                    "under_represented",
+                    &r.barcode,
+                    format!("{:0.4}", r.fold_change),
+                );
+            }
+        }
+        if let Some((top, intervals, total)) = &bootstrap {
+            for ((barcode, count), (lower, upper)) in top.iter().zip(intervals.iter()) {
+                let fraction = *count as f64 / *total as f64;
+                report.add(
+                    "bootstrap",
+                    format!("{barcode}_fraction"),
+                    format!("{fraction:0.6}"),
+                );
+                report.add(
+                    "bootstrap",
+                    format!("{barcode}_lower"),
+                    format!("{lower:0.6}"),
+                );
+                report.add(
+                    "bootstrap",
+                    format!("{barcode}_upper"),
+                    format!("{upper:0.6}"),
+                );
+            }
+        }
+        if let Some((knee, inflection)) = rank_points {
+            for (name, point) in [("knee", knee), ("inflection", inflection)] {
+                if let Some((rank, count)) = point {
+                    report.add("rank_curve", format!("{name}_rank"), rank);
+                    report.add("rank_curve", format!("{name}_count"), count);
                 }
+            }
+        }
+        report.add("diversity", "shannon_entropy", diversity.shannon_entropy);
+        report.add("diversity", "gini", diversity.gini);
+        if let Some(ratio) = diversity.ratio_90_10 {
+            report.add("diversity", "ratio_90_10", ratio);
+        }
+        for (count, n) in frequencies.iter() {
+            report.add("counts_of_counts", count, n);
+        }
+        if let Some(histogram) = &read_lengths {
+            report.add_histogram("read_length", histogram);
+        }
+        if let Some(histogram) = &barcode_lengths {
+            report.add_histogram("barcode_length", histogram);
+        }
+        if let Some(histogram) = &n_fractions {
+            report.add_histogram("read_n_percent", histogram);
+        }
+        if let Some(histogram) = &barcode_quality {
+            report.add_histogram("barcode_mean_quality", histogram);
+        }
+        if let Some(histogram) = &barcode_gc {
+            report.add_histogram("barcode_gc_percent", histogram);
+        }
+        match (&args.report_path, report_output) {
+            (Some(report_path), _) => {
+                info!("writing run report to {}", report_path.to_string_lossy());
+                report.write(report_path)?;
+            }
+            (None, Some(report_output)) => report.write_to(report_output)?,
+            (None, None) => (),
+        }
+    }
+    // With a library, every guide is listed (including those with no reads) with its gene:
+    let mut rows: Vec<Row> = match (&library, &layout, &args.pair_separator) {
+        (_, Some(layout), Some(separator)) => layout
+            .cells()
+            .into_iter()
+            .map(|(r, c)| {
+                let key = layout.key(r, c, separator);
+                let mut row = Row::new(&key, barcodes.get(&key).copied().unwrap_or(0));
+                row.label = format!("{}\t{}", layout.rows.labels[r], layout.columns.labels[c]);
+                if layout.plated() {
+                    row.label = format!("{}\t{}", layout.plate(r, c), row.label);
+                }
+                row
             })
+            .collect(),
+        (Some(library), _, _) => library
+            .guides
+            .iter()
+            .zip(library.guide_counts(&barcodes))
+            .map(|(guide, count)| {
+                let mut row = Row::new(&guide.sequence, count);
+                row.label.clone_from(&guide.name);
+                row.columns.push(guide.gene.to_owned());
+                row
+            })
+            .collect(),
+        // Otherwise the barcodes are listed in lexicographic order, so that barcodes with the
+        // same count are always written in the same order:
+        _ => {
+            let mut rows: Vec<Row> = barcodes
+                .iter()
+                .map(|(barcode, count)| Row::new(barcode, *count))
+                .collect();
+            rows.sort_by(|a, b| a.key.cmp(&b.key));
+            rows
+        }
+    };
+    // Guides and plate cells are listed even without reads, unless they were excluded:
+    rows.retain(|row| !excluded_barcodes.contains(&row.key));
+    if let (Some(separator), None) = (&args.pair_separator, &layout) {
+        for row in rows.iter_mut() {
+            row.label = row.label.replacen(separator.as_str(), "\t", 1);
+        }
+    }
+    // Name the final (merged and corrected) barcodes, keeping the sequences of unnamed ones:
+    if let Some(names) = &names {
+        let mut unnamed = 0_usize;
+        for row in rows.iter_mut() {
+            let name = match names.get(&row.key) {
+                Some(name) => name.to_owned(),
+                None => {
+                    unnamed += 1_usize;
+                    row.key.to_owned()
+                }
+            };
+            match args.names_replace {
+                true => row.label = name,
+                false => row.columns.insert(0, name),
+            }
+        }
+        info!(
+            "{}/{} barcodes were named; {unnamed} were unnamed",
+            rows.len() - unnamed,
+            rows.len()
+        );
+    }
+    if let Some(duplicates) = &duplicates {
+        for row in rows.iter_mut() {
+            let distinct = duplicates.distinct_reads(&row.key);
+            row.columns.push(distinct.to_string());
+        }
+    }
+    if let Some(distinct_umis) = &distinct_umis {
+        for row in rows.iter_mut() {
+            let distinct = distinct_umis.get(&row.key).copied().unwrap_or(0);
+            row.columns.push(distinct.to_string());
+        }
+    }
+    if let Some(strands) = &strands {
+        for row in rows.iter_mut() {
+            let [forward, reverse] = strands.get(&row.key);
+            row.columns.push(forward.to_string());
+            row.columns.push(reverse.to_string());
+        }
+    }
+    if let Some(rescued) = &rescued {
+        for row in rows.iter_mut() {
+            let count = rescued.get(&row.key).copied().unwrap_or(0);
+            row.columns.push(count.to_string());
+        }
+    }
+    if args.split_by_length {
+        for row in rows.iter_mut() {
+            row.columns.push(row.key.len().to_string());
+        }
+    }
+    rows.sort_by_key(|row| std::cmp::Reverse(row.count));
+    // The cumulative fractions are of the barcodes down to each one, in the order written:
+    if fractions {
+        let mut cumulative = 0_u64;
+        for row in rows.iter_mut() {
+            cumulative += row.count;
+            let share = row.count as f64 / barcoded_reads.max(1) as f64;
+            let cumulative_share = cumulative as f64 / barcoded_reads.max(1) as f64;
+            row.columns.push(format!("{share:0.6}"));
+            row.columns.push(format!("{cumulative_share:0.6}"));
+        }
+    }
+    // Only the top barcodes with enough reads are written, with the rest as `other`:
+    let shown = rows
+        .iter()
+        .take(args.top.unwrap_or(usize::MAX))
+        .take_while(|row| row.count >= args.min_count)
+        .count();
+    let other: u64 = rows[shown..].iter().map(|row| row.count).sum();
+    if shown < rows.len() {
+        info!(
+            "leaving {} barcodes with {other} reads out of the table",
+            rows.len() - shown
+        );
+    }
+    let shown = &rows[..shown];
+    // The unassigned counts are not split by sample, so the matrix has the barcodes only:
+    if let Some(sample_counts) = &sample_counts {
+        let named = names.is_some() && !args.names_replace;
+        match &args.mtx_dir {
+            Some(dir) => {
+                info!("writing sparse matrix to {}", dir.to_string_lossy());
+                sample_counts.write_mtx(dir, &rows, named)?;
+            }
+            None => match sample_counts.write(output, &rows, named) {
+                Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => (),
+                written => written?,
+            },
+        }
+    } else if args.output_style == "mageck" {
+        let sample = match &sample {
+            Some(sample) => sample.to_owned(),
+            None => sample_name(&file_paths[0]),
+        };
+        let counts: Vec<[u64; 1]> = shown.iter().map(|row| [row.count]).collect();
+        let guides: Vec<(&str, &str, &[u64])> = shown
+            .iter()
+            .zip(counts.iter())
+            .map(|(row, count)| (row.label.as_str(), row.columns[0].as_str(), &count[..]))
             .collect();
-        non_endpoint_barcodes.sort_by(|a, b| a.1.cmp(&b.1));
-
-        // Iterate through each of the non-endpoint barcodes, and attempt to mege it into a single on of the endpoints.
-        for (barcode, count) in non_endpoint_barcodes.iter() {
-            let barcode = barcode.to_owned();
-            debug!(
-                "barcode {barcode} count {count} <= {}; attempting to merge",
-                &args.threshold_count
-            );
-            // Get the edit distances between this barcode and all endpoints:
-            let mut end_point_distances: HashMap<String, usize> =
-                HashMap::with_capacity(endpoint_barcodes.len());
-            for endpoint_barcode in endpoint_barcodes.iter() {
-                end_point_distances.insert(
-                    endpoint_barcode.to_owned(),
-                    levenshtein(&barcode, endpoint_barcode),
-                );
-            }
-            // Find the minimum edit distance:
-            let min_endpoint_distance: usize = end_point_distances
-                .values()
-                .copied()
-                .min()
-                .unwrap_or(0_usize);
-            // Only continue if the minimum distance is suitably low:
-            if min_endpoint_distance <= args.threshold_distance {
-                // Get a set of all the endpoints with the minimum distance:
-                let min_distance_endpoint_barcodes: Vec<String> = end_point_distances
+        match output::write_mageck(output, &[&sample], &guides) {
+            Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => (),
+            written => written?,
+        }
+    } else if args.output_style == "starcode" {
+        // Starcode lists the members of each cluster, starting with the centroid itself:
+        let clusters = match args.print_clusters {
+            true => {
+                let mut clusters: HashMap<&str, Vec<&merge::Merge>> = HashMap::new();
+                for merge in merges.iter() {
+                    clusters.entry(&merge.endpoint).or_default().push(merge);
+                }
+                let clusters: HashMap<&str, Vec<&str>> = shown
                     .iter()
-                    .filter_map(
-                        |(barcode, distance)| match distance == &min_endpoint_distance {
-                            false => None,
-                            true => Some(barcode.to_owned()),
-                        },
-                    )
+                    .map(|row| {
+                        let mut members = clusters.remove(row.key.as_str()).unwrap_or_default();
+                        members
+                            .sort_by(|a, b| b.count.cmp(&a.count).then(a.barcode.cmp(&b.barcode)));
+                        let members = std::iter::once(row.key.as_str())
+                            .chain(members.iter().map(|merge| merge.barcode.as_str()))
+                            .collect();
+                        (row.key.as_str(), members)
+                    })
                     .collect();
-                // Select a single endpoint from the available options:
-                if let Some(selected_endpoint) = min_distance_endpoint_barcodes.choose(&mut rng) {
-                    let selected_endpoint = selected_endpoint.to_owned();
-                    debug!("merging barcode {barcode} (count={count}) into {selected_endpoint} (distance is {min_endpoint_distance})");
-                    // Move across the merged counts to the endpoint:
-                    *barcodes.entry(selected_endpoint).or_insert(0) += count;
-                    // Delete this now-merged barcode:
-                    barcodes.remove(&barcode);
-                }
-            } else {
-                debug!("barcode {barcode} minumum edit distance ({min_endpoint_distance}) is too great; not merging");
+                Some(clusters)
             }
+            false => None,
+        };
+        match output::write_starcode(output, shown, clusters.as_ref()) {
+            Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => (),
+            written => written?,
         }
     } else {
+        let format = match args.output_format.as_str() {
+            "csv" => TableFormat::Csv,
+            "json" => TableFormat::Json,
+            "parquet" => TableFormat::Parquet,
+            "sqlite" => TableFormat::Sqlite,
+            _ => TableFormat::Tsv,
+        };
+        let metadata = vec![
+            ("total_reads", total_reads),
+            ("barcoded_reads", barcoded_reads),
+            ("unmatched_reads", no_barcode),
+            ("barcodes_before_merging", detected_barcodes as u64),
+            ("barcodes_after_merging", barcodes.len() as u64),
+        ];
+        let mut table = TableWriter::new(&mut *output, sample.clone(), columns.clone(), format)
+            .with_metadata(metadata.clone());
+        match &args.output_prefix {
+            Some(prefix) => {
+                let lengths: BTreeSet<usize> = shown.iter().map(|row| row.key.len()).collect();
+                for length in lengths {
+                    let path = format!("{prefix}{length}.{}", format.extension());
+                    info!("writing barcodes of length {length} to {path}");
+                    let file = BufWriter::new(File::create(&path)?);
+                    let mut writer =
+                        TableWriter::new(file, sample.clone(), columns.clone(), format)
+                            .with_metadata(metadata.clone());
+                    for row in shown.iter().filter(|row| row.key.len() == length) {
+                        writer.write(row)?;
+                    }
+                    writer.flush()?;
+                }
+            }
+            None => {
+                for row in shown.iter() {
+                    table.write(row)?;
+                }
+            }
+        }
+        if args.other {
+            table.write_count("other", other)?;
+        }
+        table.write_count("no_barcode", no_barcode)?;
+        for (name, count) in tables.unassigned.iter() {
+            table.write_count(name, *count)?;
+        }
+        if args.header_filter.is_some() {
+            table.write_count("header_filtered", header_filtered)?;
+        }
+        if args.discard_filtered {
+            table.write_count("chastity_filtered", chastity_filtered)?;
+        }
+        if filter_lengths {
+            table.write_count("length_filtered", length_filtered)?;
+        }
+        if args.min_read_quality.is_some() {
+            table.write_count("low_read_quality", low_read_quality)?;
+        }
+        if args.max_n_fraction.is_some() {
+            table.write_count("too_many_n", too_many_n)?;
+        }
+        if filter_barcode_quality {
+            table.write_count("low_quality", low_quality)?;
+        }
+        if filter_gc {
+            table.write_count("implausible_barcode", implausible_barcode)?;
+        }
+        if filter_barcode_lengths {
+            table.write_count("barcode_length_filtered", barcode_length_filtered)?;
+        }
+        if whitelisted {
+            table.write_count("not_whitelisted", not_whitelisted)?;
+        }
+        if library.is_some() {
+            table.write_count("no_guide", no_guide)?;
+        }
+        if layout.is_some() {
+            table.write_count("row_unassigned", row_unassigned)?;
+            table.write_count("column_unassigned", column_unassigned)?;
+            table.write_count("impossible_cell", impossible_cell)?;
+        }
+        if args.exclude_barcodes_path.is_some() {
+            table.write_count("excluded", excluded)?;
+        }
+        table.flush()?;
+    }
+    if let Some(buffer) = read2_buffer.as_mut() {
+        buffer.flush()?;
+    }
+    if let Some(path) = &args.warnings_path {
         info!(
-            "no barcodes have counts > {}; merging not performed",
-            args.threshold_count
+            "writing {} warnings to {}",
+            warnings.len(),
+            path.to_string_lossy()
         );
+        warnings.write(path)?;
     }
-
-    // Print out the results:
-    info!("{} reads assigned a barcode", count_barcodes(&barcodes));
-    info!("{} barcodes remain after merging", barcodes.len());
-    let mut barcodes: Vec<(String, u64)> =
-        barcodes.iter().map(|(s, i)| (s.to_owned(), *i)).collect();
-    sort_barcodes(&mut barcodes);
-    for (barcode, count) in barcodes {
-        println!("{}\t{}", count, barcode);
+    if let Some(path) = &args.stats_json_path {
+        info!("writing run summary to {}", path.to_string_lossy());
+        let mut summary = json::JsonObject::default();
+        summary.string("tool", "fqbarcode");
+        summary.string("version", env!("CARGO_PKG_VERSION"));
+        if let Some(sample) = &sample {
+            summary.string("sample", sample);
+        }
+        let inputs: Vec<String> = file_paths
+            .iter()
+            .map(|path| path.to_string_lossy().into_owned())
+            .collect();
+        summary.strings("inputs", &inputs);
+        summary.string("expression", barcode_expression);
+        summary.number("runtime_seconds", started.elapsed().as_secs_f64());
+        summary.integer("threads", args.threads as u64);
+        summary.integer("seed", seed);
+        let mut reads = json::JsonObject::default();
+        reads.integer("total", total_reads);
+        reads.integer("barcoded", barcoded_reads);
+        reads.integer("unmatched", no_barcode);
+        reads.integer("filtered", filtered_reads);
+        reads.number("match_rate", barcoded_reads as f64 / total_reads as f64);
+        summary.object("reads", reads);
+        let mut barcode_totals = json::JsonObject::default();
+        barcode_totals.integer("before_merging", detected_barcodes as u64);
+        barcode_totals.integer("after_merging", barcodes.len() as u64);
+        summary.object("barcodes", barcode_totals);
+        let mut skew = json::JsonObject::default();
+        skew.number("shannon_entropy", diversity.shannon_entropy);
+        skew.number("gini", diversity.gini);
+        skew.number("ratio_90_10", diversity.ratio_90_10.unwrap_or(f64::NAN));
+        summary.object("diversity", skew);
+        let mut merging = json::JsonObject::default();
+        merging.integer("threshold_count", threshold_count);
+        if let Some(fraction) = args.merge_fraction {
+            merging.number("merge_fraction", fraction);
+        }
+        if let Some(ratio) = args.merge_ratio {
+            merging.number("merge_ratio", ratio);
+        }
+        merging.integer("threshold_distance", args.threshold_distance as u64);
+        merging.string("distance_metric", &args.distance_metric);
+        merging.string("merge_index", &args.merge_index);
+        merging.integer("merges", merges.len() as u64);
+        summary.object("merging", merging);
+        summary.write(path)?;
     }
-    println!("{no_barcode}\tno_barcode");
-    Ok(())
+    if let Some(path) = &args.html_report_path {
+        info!("writing HTML report to {}", path.to_string_lossy());
+        let sample = match &sample {
+            Some(sample) => sample.to_owned(),
+            None => sample_name(&file_paths[0]),
+        };
+        let summary = html::RunSummary {
+            sample: &sample,
+            inputs: file_paths
+                .iter()
+                .map(|path| path.to_string_lossy().into_owned())
+                .collect(),
+            expression: barcode_expression,
+            total_reads,
+            barcoded_reads,
+            no_barcode,
+            filtered_reads,
+            barcodes_before_merging: detected_barcodes,
+            barcodes_after_merging: barcodes.len(),
+            threshold_count,
+            threshold_distance: args.threshold_distance,
+            merges: merges.len(),
+            merged_reads: merges.iter().map(|merge| merge.count).sum(),
+        };
+        html::write_report(path, &summary, &rows)?;
+    }
+    if let Some(dir) = &args.multiqc_dir {
+        let sample = match &sample {
+            Some(sample) => sample.to_owned(),
+            None => sample_name(&file_paths[0]),
+        };
+        info!(
+            "writing MultiQC content for {sample} to {}",
+            dir.to_string_lossy()
+        );
+        std::fs::create_dir_all(dir)?;
+        multiqc::write_general_stats(
+            &dir.join(format!("{sample}_fqbarcode_mqc.json")),
+            &sample,
+            total_reads,
+            barcoded_reads,
+            barcodes.len(),
+        )?;
+        multiqc::write_top_barcodes(
+            &dir.join(format!("{sample}_fqbarcode_top_mqc.json")),
+            &sample,
+            &rows,
+            no_barcode,
+        )?;
+    }
+    Ok(batch::Counted {
+        rows,
+        total_reads,
+        barcoded_reads,
+        no_barcode,
+        filtered_reads,
+    })
 }
@@ -0,0 +1,144 @@
+use crate::merge::Merge;
+use crate::output::Row;
+use simple_eyre::eyre::Report;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+/// The reads of each barcode from each sample, for a barcode × sample count matrix.
+pub struct SampleCounts {
+    pub samples: Vec<String>,
+    /// The sample of each input file.
+    file_samples: Vec<usize>,
+    barcodes: HashMap<String, Vec<u64>>,
+}
+
+impl SampleCounts {
+    pub fn new(samples: Vec<String>, file_samples: Vec<usize>) -> Self {
+        SampleCounts {
+            samples,
+            file_samples,
+            barcodes: HashMap::new(),
+        }
+    }
+
+    /// Count a read of a barcode from an input file.
+    pub fn add(&mut self, barcode: &str, file: usize) {
+        self.add_reads(barcode, file, 1_u64);
+    }
+
+    /// Count several reads of a barcode from an input file.
+    pub fn add_reads(&mut self, barcode: &str, file: usize, reads: u64) {
+        let sample = self.file_samples[file];
+        match self.barcodes.get_mut(barcode) {
+            Some(counts) => counts[sample] += reads,
+            None => {
+                let mut counts = vec![0_u64; self.samples.len()];
+                counts[sample] = reads;
+                self.barcodes.insert(barcode.to_owned(), counts);
+            }
+        }
+    }
+
+    /// Add the sample counts of merged barcodes to those of their endpoints.
+    pub fn merge(&mut self, merges: &[Merge]) {
+        let n = self.samples.len();
+        for merge in merges.iter() {
+            if let Some(counts) = self.barcodes.remove(&merge.barcode) {
+                let endpoint = self
+                    .barcodes
+                    .entry(merge.endpoint.to_owned())
+                    .or_insert_with(|| vec![0_u64; n]);
+                for (total, count) in endpoint.iter_mut().zip(counts) {
+                    *total += count;
+                }
+            }
+        }
+    }
+
+    /// Write the matrix of the rows' barcodes, with a header of the sample names. If the rows
+    /// are `named`, their first extra column is the name, which follows the barcode.
+    pub fn write(&self, writer: &mut dyn Write, rows: &[Row], named: bool) -> io::Result<()> {
+        write!(writer, "barcode")?;
+        if named {
+            write!(writer, "\tname")?;
+        }
+        for sample in self.samples.iter() {
+            write!(writer, "\t{sample}")?;
+        }
+        writeln!(writer)?;
+        let zeros = vec![0_u64; self.samples.len()];
+        for row in rows.iter() {
+            write!(writer, "{}", row.label)?;
+            if named {
+                write!(writer, "\t{}", row.columns[0])?;
+            }
+            for count in self.barcodes.get(&row.key).unwrap_or(&zeros) {
+                write!(writer, "\t{count}")?;
+            }
+            writeln!(writer)?;
+        }
+        writer.flush()
+    }
+
+    /// Write the matrix of the rows' barcodes to a Matrix Market directory, as `write_mtx`.
+    pub fn write_mtx(&self, dir: &Path, rows: &[Row], named: bool) -> Result<(), Report> {
+        let zeros = vec![0_u64; self.samples.len()];
+        let barcodes: Vec<(String, &[u64])> = rows
+            .iter()
+            .map(|row| {
+                let label = match named {
+                    true => format!("{}\t{}", row.label, row.columns[0]),
+                    false => row.label.to_owned(),
+                };
+                let counts = self.barcodes.get(&row.key).unwrap_or(&zeros);
+                (label, counts.as_slice())
+            })
+            .collect();
+        write_mtx(dir, &barcodes, &self.samples)
+    }
+}
+
+/// Write a barcode × sample matrix as a directory in the 10x Matrix Market style, so that
+/// single-cell tools can load it directly: the sparse `matrix.mtx` of the non-zero counts
+/// (with the barcodes as rows and the samples as columns, numbered from 1 and ordered by
+/// column), and the `barcodes.tsv` and `samples.tsv` naming the rows and columns.
+pub fn write_mtx(
+    dir: &Path,
+    barcodes: &[(String, &[u64])],
+    samples: &[String],
+) -> Result<(), Report> {
+    fs::create_dir_all(dir)?;
+    let mut buffer = BufWriter::new(File::create(dir.join("barcodes.tsv"))?);
+    for (label, _) in barcodes.iter() {
+        writeln!(buffer, "{label}")?;
+    }
+    buffer.flush()?;
+    let mut buffer = BufWriter::new(File::create(dir.join("samples.tsv"))?);
+    for sample in samples.iter() {
+        writeln!(buffer, "{sample}")?;
+    }
+    buffer.flush()?;
+    let entries = barcodes
+        .iter()
+        .map(|(_, counts)| counts.iter().filter(|count| **count > 0).count())
+        .sum::<usize>();
+    let mut buffer = BufWriter::new(File::create(dir.join("matrix.mtx"))?);
+    writeln!(buffer, "%%MatrixMarket matrix coordinate integer general")?;
+    writeln!(
+        buffer,
+        "%metadata_json: {{\"software_version\": \"fqbarcode-{}\"}}",
+        env!("CARGO_PKG_VERSION")
+    )?;
+    writeln!(buffer, "{} {} {entries}", barcodes.len(), samples.len())?;
+    for sample in 0..samples.len() {
+        for (i, (_, counts)) in barcodes.iter().enumerate() {
+            if counts[sample] > 0 {
+                writeln!(buffer, "{} {} {}", i + 1, sample + 1, counts[sample])?;
+            }
+        }
+    }
+    buffer.flush()?;
+    Ok(())
+}
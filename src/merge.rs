@@ -0,0 +1,1200 @@
+use crate::index::{CandidateIndex, DeletionIndex};
+use levenshtein::levenshtein;
+use log::*;
+use rand::seq::SliceRandom;
+use rand::Rng;
+use rayon::prelude::*;
+use rayon::ThreadPool;
+use simple_eyre::eyre::Report;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+/// How a barcode chooses between the endpoints at its minimum distance.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum TieBreak {
+    /// The endpoint with the highest count, and then the first in lexicographic order, so
+    /// that repeated runs merge identically.
+    HighestCount,
+    /// An endpoint chosen at random.
+    Random,
+}
+
+impl TieBreak {
+    pub fn new(name: &str) -> Self {
+        match name {
+            "random" => TieBreak::Random,
+            _ => TieBreak::HighestCount,
+        }
+    }
+
+    /// Choose one of the endpoints, given their counts before merging.
+    fn choose<'a>(
+        &self,
+        endpoints: &[&'a String],
+        counts: &HashMap<String, u64>,
+        rng: &mut impl Rng,
+    ) -> Option<&'a String> {
+        match self {
+            TieBreak::HighestCount => endpoints
+                .iter()
+                .max_by(|a, b| counts[**a].cmp(&counts[**b]).then(b.cmp(a)))
+                .copied(),
+            // The endpoints are sorted first, as their order (from hashing) differs between
+            // runs, and then the choice only depends on the seed:
+            TieBreak::Random => {
+                let mut endpoints = endpoints.to_vec();
+                endpoints.sort();
+                endpoints.choose(rng).copied()
+            }
+        }
+    }
+}
+
+/// How the distance between two barcodes is measured. With `wildcard`, an N matches any base
+/// at no cost.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Metric {
+    /// The edit distance, counting substitutions, insertions and deletions.
+    Levenshtein { wildcard: bool },
+    /// The number of positions that differ, for fixed-length barcodes. Barcodes of different
+    /// lengths are never within any distance of each other.
+    Hamming { wildcard: bool },
+}
+
+/// Whether two bases match, where an N matches any base if `wildcard` is set.
+fn matches(x: u8, y: u8, wildcard: bool) -> bool {
+    x == y || (wildcard && (x.eq_ignore_ascii_case(&b'N') || y.eq_ignore_ascii_case(&b'N')))
+}
+
+impl Metric {
+    pub fn new(name: &str, wildcard: bool) -> Self {
+        match name {
+            "hamming" => Metric::Hamming { wildcard },
+            _ => Metric::Levenshtein { wildcard },
+        }
+    }
+
+    /// Whether an N matches any base.
+    pub fn wildcard(&self) -> bool {
+        match self {
+            Metric::Levenshtein { wildcard } | Metric::Hamming { wildcard } => *wildcard,
+        }
+    }
+
+    pub fn distance(&self, a: &str, b: &str) -> usize {
+        match *self {
+            Metric::Levenshtein { wildcard: false } => levenshtein(a, b),
+            Metric::Levenshtein { wildcard: true } => {
+                bounded_levenshtein(a, b, usize::MAX, true).unwrap_or(usize::MAX)
+            }
+            Metric::Hamming { wildcard } => match a.len() == b.len() {
+                true => a
+                    .bytes()
+                    .zip(b.bytes())
+                    .filter(|(x, y)| !matches(*x, *y, wildcard))
+                    .count(),
+                false => usize::MAX,
+            },
+        }
+    }
+
+    /// The distance between two barcodes if it is no more than `max_distance`. This stops as
+    /// soon as the distance is known to be greater, so it is much faster than `distance` for
+    /// the small thresholds used in merging.
+    pub fn within(&self, a: &str, b: &str, max_distance: usize) -> Option<usize> {
+        match *self {
+            Metric::Levenshtein { wildcard } => bounded_levenshtein(a, b, max_distance, wildcard),
+            Metric::Hamming { wildcard } => {
+                if a.len() != b.len() {
+                    return None;
+                }
+                let mut distance = 0_usize;
+                for (x, y) in a.bytes().zip(b.bytes()) {
+                    if !matches(x, y, wildcard) {
+                        distance += 1;
+                        if distance > max_distance {
+                            return None;
+                        }
+                    }
+                }
+                Some(distance)
+            }
+        }
+    }
+
+    /// The distance from barcode `a` to `b`, where substituting a base of `a` costs its
+    /// Phred quality in `qualities` over `MISMATCH_QUALITY` (up to 1), so differences at
+    /// low-confidence bases cost less. Insertions and deletions cost 1.
+    pub fn weighted_distance(&self, a: &str, b: &str, qualities: &[f64]) -> f64 {
+        let (a, b) = (a.as_bytes(), b.as_bytes());
+        let wildcard = self.wildcard();
+        let cost = |i: usize| match qualities.get(i) {
+            Some(q) => q.clamp(0_f64, MISMATCH_QUALITY) / MISMATCH_QUALITY,
+            None => 1_f64,
+        };
+        match self {
+            Metric::Hamming { .. } => match a.len() == b.len() {
+                true => (0..a.len())
+                    .filter(|i| !matches(a[*i], b[*i], wildcard))
+                    .map(cost)
+                    .sum(),
+                false => f64::INFINITY,
+            },
+            Metric::Levenshtein { .. } => {
+                let mut previous: Vec<f64> = (0..=b.len()).map(|j| j as f64).collect();
+                let mut current: Vec<f64> = vec![0_f64; b.len() + 1];
+                for (i, x) in a.iter().enumerate() {
+                    current[0] = (i + 1) as f64;
+                    for (j, y) in b.iter().enumerate() {
+                        let substitution = match matches(*x, *y, wildcard) {
+                            true => 0_f64,
+                            false => cost(i),
+                        };
+                        current[j + 1] = (previous[j] + substitution)
+                            .min(previous[j + 1] + 1_f64)
+                            .min(current[j] + 1_f64);
+                    }
+                    std::mem::swap(&mut previous, &mut current);
+                }
+                previous[b.len()]
+            }
+        }
+    }
+}
+
+/// The Phred quality at which a substitution costs a whole edit in quality-weighted merging.
+const MISMATCH_QUALITY: f64 = 30_f64;
+
+/// How the barcodes near each other are found for merging.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum IndexKind {
+    /// Barcodes sharing a q-gram with each other, which suits any threshold distance.
+    QGrams,
+    /// Barcodes sharing a deletion neighbour with each other, which is found in a few lookups
+    /// but takes memory growing steeply with the threshold distance.
+    Deletions,
+}
+
+impl IndexKind {
+    pub fn new(name: &str) -> Self {
+        match name {
+            "deletion" => IndexKind::Deletions,
+            _ => IndexKind::QGrams,
+        }
+    }
+}
+
+/// How near two barcodes have to be for one to be merged into the other, and how the
+/// barcodes near each other are found.
+#[derive(Clone, Copy)]
+pub struct Similarity {
+    pub threshold_distance: usize,
+    pub metric: Metric,
+    pub index: IndexKind,
+}
+
+/// The edit distance between two barcodes if it is no more than `max_distance`.
+///
+/// Only the band of the dynamic programming matrix within `max_distance` of its diagonal can
+/// hold distances that small, so only that band is filled in, and the comparison stops at the
+/// first row whose band is entirely over `max_distance`. With `wildcard`, an N matches any
+/// base. Barcodes that are not ASCII are compared by character rather than by byte.
+fn bounded_levenshtein(a: &str, b: &str, max_distance: usize, wildcard: bool) -> Option<usize> {
+    if a.is_ascii() && b.is_ascii() {
+        return banded_levenshtein(a.as_bytes(), b.as_bytes(), max_distance, |x, y| {
+            matches(x, y, wildcard)
+        });
+    }
+    let (a, b): (Vec<char>, Vec<char>) = (a.chars().collect(), b.chars().collect());
+    banded_levenshtein(&a, &b, max_distance, |x, y| {
+        x == y || (wildcard && (x.eq_ignore_ascii_case(&'N') || y.eq_ignore_ascii_case(&'N')))
+    })
+}
+
+/// The banded edit distance of `bounded_levenshtein`, between sequences of bytes or characters
+/// compared by `same`.
+fn banded_levenshtein<T: Copy>(
+    a: &[T],
+    b: &[T],
+    max_distance: usize,
+    same: impl Fn(T, T) -> bool,
+) -> Option<usize> {
+    let (a, b) = match a.len() <= b.len() {
+        true => (a, b),
+        false => (b, a),
+    };
+    if b.len() - a.len() > max_distance {
+        return None;
+    }
+    // The distance is never more than the longer length, which keeps the sums in range:
+    let max_distance = max_distance.min(b.len());
+    let over = max_distance + 1;
+    let mut previous: Vec<usize> = (0..=b.len()).map(|j| j.min(over)).collect();
+    let mut current: Vec<usize> = vec![over; b.len() + 1];
+    for i in 1..=a.len() {
+        let (low, high) = (
+            i.saturating_sub(max_distance).max(1),
+            (i + max_distance).min(b.len()),
+        );
+        current[low - 1] = match low {
+            1 => i.min(over),
+            _ => over,
+        };
+        let mut row_min = current[low - 1];
+        for j in low..=high {
+            let substitution = previous[j - 1] + usize::from(!same(a[i - 1], b[j - 1]));
+            let distance = substitution
+                .min(previous[j] + 1)
+                .min(current[j - 1] + 1)
+                .min(over);
+            current[j] = distance;
+            row_min = row_min.min(distance);
+        }
+        if high < b.len() {
+            current[high + 1] = over;
+        }
+        if row_min > max_distance {
+            return None;
+        }
+        std::mem::swap(&mut previous, &mut current);
+    }
+    let distance = previous[b.len()];
+    (distance <= max_distance).then_some(distance)
+}
+
+/// A low-count barcode that was merged into an endpoint.
+pub struct Merge {
+    pub barcode: String,
+    pub endpoint: String,
+    pub distance: usize,
+    pub count: u64,
+}
+
+/// Which barcodes are endpoints, and which barcodes they can absorb.
+#[derive(Clone, Copy)]
+pub struct EndpointRule {
+    /// Barcodes with more than this many reads are endpoints.
+    pub threshold_count: u64,
+    /// A barcode is only merged into an endpoint with at least this many times its reads.
+    pub ratio: f64,
+}
+
+impl EndpointRule {
+    /// Whether an endpoint, with its count before merging, can absorb a barcode.
+    fn absorbs(&self, endpoint_count: u64, count: u64) -> bool {
+        endpoint_count as f64 >= self.ratio * count as f64
+    }
+}
+
+/// Merge low-count barcodes into their nearest endpoint, returning the merges performed.
+///
+/// A barcode is an endpoint if it has more than the threshold count of `rule` reads. All
+/// other barcodes are processed in ascending order of count, and merged into the endpoint
+/// with the minimum distance if it is within the threshold distance of `similarity` (with
+/// ties broken by `tie_break`), and has at least the ratio of `rule` times their reads.
+/// Barcodes with the same count are processed in lexicographic order.
+///
+/// If the mean per-position `qualities` of a barcode are given, its distances to the
+/// endpoints within the threshold distance are weighted by the qualities of the bases that
+/// differ (see `Metric::weighted_distance`), and it is merged into the endpoint with the
+/// minimum weighted distance.
+pub fn merge_barcodes(
+    barcodes: &mut HashMap<String, u64>,
+    rule: EndpointRule,
+    similarity: Similarity,
+    tie_break: TieBreak,
+    rng: &mut impl Rng,
+    pool: Option<&ThreadPool>,
+    qualities: Option<&HashMap<String, Vec<f64>>>,
+) -> Vec<Merge> {
+    let threshold_count = rule.threshold_count;
+    let mut merges: Vec<Merge> = Vec::new();
+    // Now we have all the barcodes we can extract a list of the "endpoint" barcodes, i.e.
+    // those that can accept merged barcodes. A barcode is an endpoint if it currently has
+    // at least threshold_count reads associated to it.
+    let endpoint_barcodes: HashSet<String> = barcodes
+        .iter()
+        .filter_map(|(barcode, count)| match count > &threshold_count {
+            false => None,
+            true => Some(barcode.to_owned()),
+        })
+        .collect();
+    debug!("{} barcodes pass threshold count", endpoint_barcodes.len());
+    let endpoint_index = Neighbours::new(
+        endpoint_barcodes.iter().cloned().collect(),
+        None,
+        similarity,
+    );
+    let endpoint_counts: HashMap<String, u64> = endpoint_barcodes
+        .iter()
+        .map(|barcode| (barcode.to_owned(), barcodes[barcode]))
+        .collect();
+
+    if !endpoint_barcodes.is_empty() {
+        // Get a list of the non-endpoint barcodes sorted by their count (lowest first):
+        let mut non_endpoint_barcodes: Vec<(String, u64)> = barcodes
+            .iter()
+            .filter_map(|(barcode, count)| {
+                let barcode = barcode.to_owned();
+                match endpoint_barcodes.contains(&barcode) {
+                    false => Some((barcode, *count)),
+                    true => None,
+                }
+            })
+            .collect();
+        non_endpoint_barcodes.sort_by(|a, b| a.1.cmp(&b.1).then(a.0.cmp(&b.0)));
+        // The endpoints do not change as barcodes are merged, so those near each barcode can
+        // be found first (and in parallel):
+        let near = endpoint_index.near_each(&non_endpoint_barcodes, pool);
+
+        // Iterate through each of the non-endpoint barcodes, and attempt to mege it into a single on of the endpoints.
+        for ((barcode, count), near) in non_endpoint_barcodes.iter().zip(near) {
+            let barcode = barcode.to_owned();
+            debug!(
+                "barcode {barcode} count {count} <= {}; attempting to merge",
+                &threshold_count
+            );
+            // Get the distances between this barcode and the endpoints within the threshold:
+            let end_point_distances: HashMap<&String, usize> = near
+                .into_iter()
+                .map(|(i, distance)| (endpoint_index.get(i), distance))
+                .collect();
+            // Weight the distances by the qualities of the barcode's bases, if it has them:
+            let weighted_distances: HashMap<&String, f64> = end_point_distances
+                .iter()
+                .map(|(endpoint, distance)| {
+                    let weighted = match qualities.and_then(|qualities| qualities.get(&barcode)) {
+                        Some(qualities) => similarity
+                            .metric
+                            .weighted_distance(&barcode, endpoint, qualities),
+                        None => *distance as f64,
+                    };
+                    (*endpoint, weighted)
+                })
+                .collect();
+            let min_weighted_distance = weighted_distances.values().copied().reduce(f64::min);
+            // Only continue if there is an endpoint within the threshold, at the minimum distance:
+            if let Some(min_weighted_distance) = min_weighted_distance {
+                // Get a set of all the endpoints with the minimum distance and enough reads:
+                let min_distance_endpoint_barcodes: Vec<&String> = weighted_distances
+                    .iter()
+                    .filter_map(|(barcode, weighted)| {
+                        match *weighted == min_weighted_distance
+                            && rule.absorbs(endpoint_counts[*barcode], *count)
+                        {
+                            false => None,
+                            true => Some(*barcode),
+                        }
+                    })
+                    .collect();
+                // Select a single endpoint from the available options:
+                if let Some(selected_endpoint) =
+                    tie_break.choose(&min_distance_endpoint_barcodes, &endpoint_counts, rng)
+                {
+                    let min_endpoint_distance = end_point_distances[selected_endpoint];
+                    let selected_endpoint = selected_endpoint.to_owned();
+                    debug!("merging barcode {barcode} (count={count}) into {selected_endpoint} (distance is {min_endpoint_distance})");
+                    // Move across the merged counts to the endpoint:
+                    *barcodes.entry(selected_endpoint.to_owned()).or_insert(0) += count;
+                    merges.push(Merge {
+                        barcode: barcode.to_owned(),
+                        endpoint: selected_endpoint,
+                        distance: min_endpoint_distance,
+                        count: *count,
+                    });
+                    // Delete this now-merged barcode:
+                    barcodes.remove(&barcode);
+                } else {
+                    debug!(
+                        "barcode {barcode} has no endpoint at distance {min_weighted_distance} with {} times its count; not merging",
+                        rule.ratio
+                    );
+                }
+            } else {
+                debug!(
+                    "barcode {barcode} has no endpoint within distance {}; not merging",
+                    similarity.threshold_distance
+                );
+            }
+        }
+    } else {
+        info!(
+            "no barcodes have counts > {}; merging not performed",
+            threshold_count
+        );
+    }
+    merges
+}
+
+/// Merge low-count barcode pairs (joined by `separator`) into their nearest endpoint pair,
+/// returning the merges performed.
+///
+/// This works as `merge_barcodes`, except that a pair is only merged into an endpoint pair if
+/// each component is within the threshold distance of the endpoint's, so that a pair is never
+/// merged across an unrelated component. The distance of each merge is the sum of the
+/// component distances.
+pub fn merge_pairs(
+    barcodes: &mut HashMap<String, u64>,
+    separator: &str,
+    rule: EndpointRule,
+    similarity: Similarity,
+    tie_break: TieBreak,
+    rng: &mut impl Rng,
+    pool: Option<&ThreadPool>,
+) -> Vec<Merge> {
+    let threshold_count = rule.threshold_count;
+    let mut merges: Vec<Merge> = Vec::new();
+    let endpoints: Vec<String> = barcodes
+        .iter()
+        .filter(|(_, count)| **count > threshold_count)
+        .map(|(barcode, _)| barcode.to_owned())
+        .collect();
+    debug!("{} barcode pairs pass threshold count", endpoints.len());
+    let endpoint_counts: HashMap<String, u64> = endpoints
+        .iter()
+        .map(|barcode| (barcode.to_owned(), barcodes[barcode]))
+        .collect();
+    if endpoints.is_empty() {
+        info!("no barcode pairs have counts > {threshold_count}; merging not performed");
+        return merges;
+    }
+    let endpoint_index = Neighbours::new(endpoints, Some(separator), similarity);
+    let mut non_endpoints: Vec<(String, u64)> = barcodes
+        .iter()
+        .filter(|(_, count)| **count <= threshold_count)
+        .map(|(barcode, count)| (barcode.to_owned(), *count))
+        .collect();
+    non_endpoints.sort_by(|a, b| a.1.cmp(&b.1).then(a.0.cmp(&b.0)));
+    let near = endpoint_index.near_each(&non_endpoints, pool);
+    for ((barcode, count), near) in non_endpoints.iter().zip(near) {
+        // Get the distances to the endpoints within the threshold in both components:
+        let near: Vec<(&String, usize)> = near
+            .into_iter()
+            .map(|(i, distance)| (endpoint_index.get(i), distance))
+            .collect();
+        let Some(min_distance) = near.iter().map(|(_, distance)| *distance).min() else {
+            debug!(
+                "barcode pair {barcode} has no endpoint within distance {} in both components; not merging",
+                similarity.threshold_distance
+            );
+            continue;
+        };
+        let nearest: Vec<&String> = near
+            .iter()
+            .filter(|(endpoint, distance)| {
+                *distance == min_distance && rule.absorbs(endpoint_counts[*endpoint], *count)
+            })
+            .map(|(endpoint, _)| *endpoint)
+            .collect();
+        if let Some(selected_endpoint) = tie_break.choose(&nearest, &endpoint_counts, rng) {
+            debug!("merging barcode pair {barcode} (count={count}) into {selected_endpoint} (distance is {min_distance})");
+            *barcodes.entry(selected_endpoint.to_string()).or_insert(0) += count;
+            merges.push(Merge {
+                barcode: barcode.to_owned(),
+                endpoint: selected_endpoint.to_string(),
+                distance: min_distance,
+                count: *count,
+            });
+            barcodes.remove(barcode);
+        } else {
+            debug!(
+                "barcode pair {barcode} has no endpoint at distance {min_distance} with {} times its count; not merging",
+                rule.ratio
+            );
+        }
+    }
+    merges
+}
+
+/// The components of a barcode pair joined by `separator` (or a barcode and nothing).
+fn split<'a>(barcode: &'a str, separator: Option<&str>) -> (&'a str, &'a str) {
+    match separator {
+        Some(separator) => barcode.split_once(separator).unwrap_or((barcode, "")),
+        None => (barcode, ""),
+    }
+}
+
+/// The distance between two barcodes (by `metric`) if it is no more than
+/// `threshold_distance`. Barcode pairs (joined by `separator`) are only within the threshold if
+/// each component is, and their distance is the sum of the component distances.
+fn within(
+    a: &str,
+    b: &str,
+    separator: Option<&str>,
+    threshold_distance: usize,
+    metric: Metric,
+) -> Option<usize> {
+    let ((a1, a2), (b1, b2)) = (split(a, separator), split(b, separator));
+    let d1 = metric.within(a1, b1, threshold_distance)?;
+    let d2 = metric.within(a2, b2, threshold_distance)?;
+    Some(d1 + d2)
+}
+
+/// The distance between two barcodes (or pairs joined by `separator`), however far apart.
+fn distance(a: &str, b: &str, separator: Option<&str>, metric: Metric) -> usize {
+    let ((a1, a2), (b1, b2)) = (split(a, separator), split(b, separator));
+    metric
+        .distance(a1, b1)
+        .saturating_add(metric.distance(a2, b2))
+}
+
+/// The index of the first components of the barcodes of `Neighbours`.
+enum Index {
+    QGrams(CandidateIndex),
+    Deletions(DeletionIndex),
+}
+
+/// An index of barcodes (or pairs joined by `separator`) for finding those within the
+/// threshold distance of a barcode, without comparing it against all of them. Pairs are
+/// indexed by their first components, which must be within the threshold for the pairs to be.
+pub struct Neighbours<'a> {
+    barcodes: Vec<String>,
+    index: Index,
+    separator: Option<&'a str>,
+    similarity: Similarity,
+}
+
+impl<'a> Neighbours<'a> {
+    pub fn new(barcodes: Vec<String>, separator: Option<&'a str>, similarity: Similarity) -> Self {
+        let keys: Vec<String> = barcodes
+            .iter()
+            .map(|barcode| split(barcode, separator).0.to_owned())
+            .collect();
+        let Similarity {
+            threshold_distance,
+            metric,
+            index,
+        } = similarity;
+        let index = match index {
+            IndexKind::QGrams => Index::QGrams(CandidateIndex::with_metric(
+                keys,
+                threshold_distance,
+                metric,
+            )),
+            IndexKind::Deletions => {
+                Index::Deletions(DeletionIndex::new(keys, threshold_distance, metric))
+            }
+        };
+        Neighbours {
+            barcodes,
+            index,
+            separator,
+            similarity,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.barcodes.len()
+    }
+
+    pub fn get(&self, i: usize) -> &String {
+        &self.barcodes[i]
+    }
+
+    /// The barcodes, in the order they were given.
+    pub fn barcodes(&self) -> &[String] {
+        &self.barcodes
+    }
+
+    /// The (index, distance) pairs of the barcodes within the threshold of `barcode`, in the
+    /// order they were given.
+    pub fn near(&self, barcode: &str) -> Vec<(usize, usize)> {
+        let key = split(barcode, self.separator).0;
+        let candidates = match &self.index {
+            Index::QGrams(index) => index.within(key),
+            Index::Deletions(index) => index.within(key),
+        };
+        let mut near: Vec<(usize, usize)> = candidates
+            .into_iter()
+            .filter_map(|(i, _)| {
+                let distance = within(
+                    barcode,
+                    &self.barcodes[i],
+                    self.separator,
+                    self.similarity.threshold_distance,
+                    self.similarity.metric,
+                )?;
+                Some((i, distance))
+            })
+            .collect();
+        near.sort_unstable();
+        near
+    }
+
+    /// The barcodes near each of `barcodes` (given with their counts), found on the threads of
+    /// `pool` if given.
+    fn near_each(
+        &self,
+        barcodes: &[(String, u64)],
+        pool: Option<&ThreadPool>,
+    ) -> Vec<Vec<(usize, usize)>> {
+        match pool {
+            Some(pool) => pool.install(|| {
+                barcodes
+                    .par_iter()
+                    .map(|(barcode, _)| self.near(barcode))
+                    .collect()
+            }),
+            None => barcodes
+                .iter()
+                .map(|(barcode, _)| self.near(barcode))
+                .collect(),
+        }
+    }
+}
+
+/// The barcodes in descending order of count, and then in lexicographic order.
+fn by_count(barcodes: &HashMap<String, u64>) -> Vec<(String, u64)> {
+    let mut order: Vec<(String, u64)> = barcodes
+        .iter()
+        .map(|(barcode, count)| (barcode.to_owned(), *count))
+        .collect();
+    order.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    order
+}
+
+/// Move the counts of the merged barcodes to their endpoints.
+fn apply(barcodes: &mut HashMap<String, u64>, merges: &[Merge]) {
+    for merge in merges.iter() {
+        barcodes.remove(&merge.barcode);
+        *barcodes.entry(merge.endpoint.to_owned()).or_insert(0) += merge.count;
+    }
+}
+
+/// Merge barcodes (or pairs joined by `separator`) with the directional adjacency method of
+/// UMI-tools, returning the merges performed.
+///
+/// A barcode `a` absorbs a barcode `b` if `count(a) >= 2·count(b) - 1` and they are within
+/// the threshold distance of `similarity`. Starting from each unabsorbed barcode, in
+/// descending order of count (and then lexicographic order), the barcodes it absorbs, those
+/// they absorb in turn, and so on are all merged into it. So unlike endpoint merging, a
+/// barcode is only merged into one with enough reads to explain it as an error, and distinct
+/// low-count barcodes are kept.
+pub fn merge_directional(
+    barcodes: &mut HashMap<String, u64>,
+    separator: Option<&str>,
+    similarity: Similarity,
+    pool: Option<&ThreadPool>,
+) -> Vec<Merge> {
+    let order = by_count(barcodes);
+    let index = Neighbours::new(
+        order
+            .iter()
+            .map(|(barcode, _)| barcode.to_owned())
+            .collect(),
+        separator,
+        similarity,
+    );
+    let near = index.near_each(&order, pool);
+    let mut absorbed = vec![false; order.len()];
+    let mut merges: Vec<Merge> = Vec::new();
+    let mut queue: VecDeque<usize> = VecDeque::new();
+    for root in 0..order.len() {
+        if absorbed[root] {
+            continue;
+        }
+        absorbed[root] = true;
+        queue.push_back(root);
+        while let Some(i) = queue.pop_front() {
+            let (barcode, count) = &order[i];
+            // Only the barcodes with few enough reads can be absorbed:
+            let first = order.partition_point(|(_, other)| 2 * other > count + 1);
+            for (j, _) in near[i].iter().copied() {
+                if j < first || absorbed[j] {
+                    continue;
+                }
+                absorbed[j] = true;
+                queue.push_back(j);
+                let (endpoint, (merged, merged_count)) = (&order[root].0, &order[j]);
+                debug!(
+                    "merging barcode {merged} (count={merged_count}) into {endpoint} via {barcode}"
+                );
+                merges.push(Merge {
+                    barcode: merged.to_owned(),
+                    endpoint: endpoint.to_owned(),
+                    distance: distance(merged, endpoint, separator, similarity.metric),
+                    count: *merged_count,
+                });
+            }
+        }
+    }
+    apply(barcodes, &merges);
+    merges
+}
+
+/// Merge barcodes (or pairs joined by `separator`) by clustering, returning the merges
+/// performed.
+///
+/// The graph of all the barcodes, with an edge between every two within the threshold
+/// distance of `similarity` of each other, is built, and each of its connected components is
+/// merged into its barcode with the most reads (and then the first in lexicographic order). So a chain of variants
+/// one edit apart is always grouped together, whichever of them has the most reads.
+pub fn merge_components(
+    barcodes: &mut HashMap<String, u64>,
+    separator: Option<&str>,
+    similarity: Similarity,
+    pool: Option<&ThreadPool>,
+) -> Vec<Merge> {
+    let order = by_count(barcodes);
+    // Join the components in a union-find forest, where the root of each component is its
+    // barcode that comes first in order:
+    fn root(parents: &mut [usize], mut i: usize) -> usize {
+        while parents[i] != i {
+            parents[i] = parents[parents[i]];
+            i = parents[i];
+        }
+        i
+    }
+    let index = Neighbours::new(
+        order
+            .iter()
+            .map(|(barcode, _)| barcode.to_owned())
+            .collect(),
+        separator,
+        similarity,
+    );
+    let near = index.near_each(&order, pool);
+    let mut parents: Vec<usize> = (0..order.len()).collect();
+    for (i, near) in near.into_iter().enumerate() {
+        for (j, _) in near {
+            if j > i {
+                let (a, b) = (root(&mut parents, i), root(&mut parents, j));
+                parents[a.max(b)] = a.min(b);
+            }
+        }
+    }
+    let mut merges: Vec<Merge> = Vec::new();
+    for (i, (barcode, count)) in order.iter().enumerate() {
+        let endpoint = &order[root(&mut parents, i)].0;
+        if endpoint != barcode {
+            debug!("merging barcode {barcode} (count={count}) into {endpoint} by clustering");
+            merges.push(Merge {
+                barcode: barcode.to_owned(),
+                endpoint: endpoint.to_owned(),
+                distance: distance(barcode, endpoint, separator, similarity.metric),
+                count: *count,
+            });
+        }
+    }
+    apply(barcodes, &merges);
+    merges
+}
+
+/// A final barcode, with its count before and after merging and the barcodes merged into it.
+struct Cluster<'a> {
+    barcode: &'a str,
+    count: u64,
+    final_count: u64,
+    members: Vec<&'a Merge>,
+}
+
+/// The final barcodes (given their counts after merging) in descending order of count, each
+/// with the barcodes merged into it in descending order of count.
+fn clusters<'a>(barcodes: &'a HashMap<String, u64>, merges: &'a [Merge]) -> Vec<Cluster<'a>> {
+    let mut merged: HashMap<&str, Vec<&Merge>> = HashMap::new();
+    for merge in merges.iter() {
+        merged
+            .entry(merge.endpoint.as_str())
+            .or_default()
+            .push(merge);
+    }
+    let mut clusters: Vec<Cluster> = barcodes
+        .iter()
+        .map(|(barcode, final_count)| {
+            let mut members = merged.remove(barcode.as_str()).unwrap_or_default();
+            members.sort_by(|a, b| b.count.cmp(&a.count).then(a.barcode.cmp(&b.barcode)));
+            Cluster {
+                barcode,
+                count: final_count - members.iter().map(|merge| merge.count).sum::<u64>(),
+                final_count: *final_count,
+                members,
+            }
+        })
+        .collect();
+    clusters.sort_by(|a, b| {
+        b.final_count
+            .cmp(&a.final_count)
+            .then(a.barcode.cmp(b.barcode))
+    });
+    clusters
+}
+
+/// Write the map of every barcode before merging to its final barcode, with their distance
+/// and counts, given the counts after merging. The final barcodes are in descending order of
+/// count, each mapped to itself first and then followed by the barcodes merged into it.
+pub fn write_merge_map(
+    path: &Path,
+    barcodes: &HashMap<String, u64>,
+    merges: &[Merge],
+) -> Result<(), Report> {
+    let mut buffer = BufWriter::new(File::create(path)?);
+    writeln!(
+        buffer,
+        "barcode\tfinal_barcode\tdistance\tcount\tfinal_count"
+    )?;
+    for cluster in clusters(barcodes, merges) {
+        let (endpoint, final_count) = (cluster.barcode, cluster.final_count);
+        writeln!(
+            buffer,
+            "{endpoint}\t{endpoint}\t0\t{}\t{final_count}",
+            cluster.count
+        )?;
+        for merge in cluster.members {
+            writeln!(
+                buffer,
+                "{}\t{endpoint}\t{}\t{}\t{final_count}",
+                merge.barcode, merge.distance, merge.count
+            )?;
+        }
+    }
+    buffer.flush()?;
+    Ok(())
+}
+
+/// Escape text for a quoted DOT identifier, or for XML if `xml` is set.
+fn escape(text: &str, xml: bool) -> String {
+    match xml {
+        true => text
+            .replace('&', "&amp;")
+            .replace('<', "&lt;")
+            .replace('>', "&gt;")
+            .replace('"', "&quot;"),
+        false => text.replace('\\', "\\\\").replace('"', "\\\""),
+    }
+}
+
+/// Write the merges as a graph, given the counts after merging: a node for every barcode
+/// before merging (with its count, and whether it is a final barcode) and an edge from each
+/// merged barcode to its final barcode (with their distance). The graph is written as
+/// GraphML if the file name ends in `.graphml`, and otherwise as DOT.
+pub fn write_merge_graph(
+    path: &Path,
+    barcodes: &HashMap<String, u64>,
+    merges: &[Merge],
+) -> Result<(), Report> {
+    let graphml = path.extension().is_some_and(|e| e == "graphml");
+    let mut buffer = BufWriter::new(File::create(path)?);
+    let clusters = clusters(barcodes, merges);
+    match graphml {
+        true => {
+            writeln!(
+                buffer,
+                "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+                <graphml xmlns=\"http://graphml.graphdrawing.org/xmlns\">\n\
+                <key id=\"count\" for=\"node\" attr.name=\"count\" attr.type=\"long\"/>\n\
+                <key id=\"final\" for=\"node\" attr.name=\"final\" attr.type=\"boolean\"/>\n\
+                <key id=\"distance\" for=\"edge\" attr.name=\"distance\" attr.type=\"int\"/>\n\
+                <graph id=\"merges\" edgedefault=\"directed\">"
+            )?;
+            for cluster in clusters.iter() {
+                let nodes = [(cluster.barcode, cluster.count, true)].into_iter().chain(
+                    cluster
+                        .members
+                        .iter()
+                        .map(|merge| (merge.barcode.as_str(), merge.count, false)),
+                );
+                for (barcode, count, is_final) in nodes {
+                    writeln!(
+                        buffer,
+                        "<node id=\"{}\"><data key=\"count\">{count}</data>\
+                        <data key=\"final\">{is_final}</data></node>",
+                        escape(barcode, true)
+                    )?;
+                }
+            }
+            for cluster in clusters.iter() {
+                for merge in cluster.members.iter() {
+                    writeln!(
+                        buffer,
+                        "<edge source=\"{}\" target=\"{}\"><data key=\"distance\">{}</data></edge>",
+                        escape(&merge.barcode, true),
+                        escape(cluster.barcode, true),
+                        merge.distance
+                    )?;
+                }
+            }
+            writeln!(buffer, "</graph>\n</graphml>")?;
+        }
+        false => {
+            writeln!(buffer, "digraph merges {{")?;
+            for cluster in clusters.iter() {
+                writeln!(
+                    buffer,
+                    "  \"{}\" [count={}, final=true];",
+                    escape(cluster.barcode, false),
+                    cluster.count
+                )?;
+                for merge in cluster.members.iter() {
+                    writeln!(
+                        buffer,
+                        "  \"{}\" [count={}, final=false];",
+                        escape(&merge.barcode, false),
+                        merge.count
+                    )?;
+                }
+            }
+            for cluster in clusters.iter() {
+                for merge in cluster.members.iter() {
+                    writeln!(
+                        buffer,
+                        "  \"{}\" -> \"{}\" [distance={}];",
+                        escape(&merge.barcode, false),
+                        escape(cluster.barcode, false),
+                        merge.distance
+                    )?;
+                }
+            }
+            writeln!(buffer, "}}")?;
+        }
+    }
+    buffer.flush()?;
+    Ok(())
+}
+
+/// The reads absorbed by merging at each edit distance, and by each substitution of an
+/// endpoint base by the base observed in its place.
+#[derive(Default)]
+pub struct ErrorProfile {
+    pub absorbed: BTreeMap<usize, u64>,
+    pub substitutions: BTreeMap<(char, char), u64>,
+}
+
+impl ErrorProfile {
+    pub fn new(merges: &[Merge]) -> Self {
+        let mut profile = ErrorProfile::default();
+        for merge in merges.iter() {
+            *profile.absorbed.entry(merge.distance).or_insert(0_u64) += merge.count;
+            // The bases of a merge line up if its edit distance is all substitutions:
+            if merge.barcode.len() == merge.endpoint.len() {
+                let mismatches: Vec<(char, char)> = merge
+                    .endpoint
+                    .chars()
+                    .zip(merge.barcode.chars())
+                    .filter(|(from, to)| from != to)
+                    .collect();
+                if mismatches.len() == merge.distance {
+                    for substitution in mismatches.into_iter() {
+                        *profile.substitutions.entry(substitution).or_insert(0_u64) += merge.count;
+                    }
+                }
+            }
+        }
+        profile
+    }
+
+    /// The substitutions, most common first.
+    pub fn ranked_substitutions(&self) -> Vec<((char, char), u64)> {
+        let mut ranked: Vec<((char, char), u64)> = self
+            .substitutions
+            .iter()
+            .map(|(substitution, reads)| (*substitution, *reads))
+            .collect();
+        ranked.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        ranked
+    }
+
+    /// Crude per-base sequencing error rates implied by the reads absorbed at each distance.
+    ///
+    /// Assuming every absorbed read is an erroneous copy of its endpoint, and that errors occur
+    /// independently at each of the `barcode_length` bases with rate `p`, the fraction `f` of
+    /// reads absorbed at distance `d` relative to the endpoint reads is about `C(L, d)·p^d`.
+    pub fn error_rates(&self, endpoint_reads: u64, barcode_length: f64) -> Vec<(usize, f64)> {
+        if endpoint_reads == 0 || barcode_length < 1_f64 {
+            return Vec::new();
+        }
+        self.absorbed
+            .iter()
+            .filter(|(distance, _)| **distance > 0)
+            .map(|(distance, reads)| {
+                let fraction = *reads as f64 / endpoint_reads as f64;
+                let sites: f64 = (0..*distance)
+                    .map(|i| (barcode_length - i as f64) / (i + 1) as f64)
+                    .product();
+                (*distance, (fraction / sites).powf(1_f64 / *distance as f64))
+            })
+            .collect()
+    }
+}
+
+/// Collapse packed sequences (e.g. UMIs) with the directional adjacency method, returning the
+/// number of groups.
+///
+/// A sequence `a` absorbs a sequence `b` one substitution away if `count(a) >= 2·count(b) - 1`,
+/// and the groups are the sets of sequences reachable from each unabsorbed sequence, taken in
+/// descending order of count. Neighbours are found by substituting each base of the packed
+/// key in turn, so the cost is linear in the number of sequences.
+pub fn directional_groups(counts: &HashMap<u64, u64>) -> usize {
+    let mut order: Vec<(u64, u64)> = counts.iter().map(|(k, c)| (*k, *c)).collect();
+    order.sort_by_key(|(key, count)| (std::cmp::Reverse(*count), *key));
+    let mut grouped: HashSet<u64> = HashSet::with_capacity(order.len());
+    let mut groups = 0_usize;
+    let mut stack: Vec<(u64, u64)> = Vec::new();
+    for (key, count) in order {
+        if !grouped.insert(key) {
+            continue;
+        }
+        groups += 1;
+        stack.push((key, count));
+        while let Some((key, count)) = stack.pop() {
+            // The number of bases, from the position of the sentinel bit:
+            let length = (63 - key.leading_zeros()) / 2;
+            for i in 0..length {
+                for substitution in 1..=3_u64 {
+                    let neighbour = key ^ (substitution << (2 * i));
+                    if let Some(neighbour_count) = counts.get(&neighbour) {
+                        if count + 1 >= 2 * neighbour_count && grouped.insert(neighbour) {
+                            stack.push((neighbour, *neighbour_count));
+                        }
+                    }
+                }
+            }
+        }
+    }
+    groups
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    /// Merge pairs joined by `_` into the endpoint pairs of more than 5 reads, within 1 edit
+    /// in each component.
+    fn merge_planted(pairs: &[(&str, u64)]) -> (HashMap<String, u64>, Vec<Merge>) {
+        let mut barcodes: HashMap<String, u64> = pairs
+            .iter()
+            .map(|(pair, count)| (pair.to_string(), *count))
+            .collect();
+        let merges = merge_pairs(
+            &mut barcodes,
+            "_",
+            EndpointRule {
+                threshold_count: 5,
+                ratio: 1_f64,
+            },
+            Similarity {
+                threshold_distance: 1,
+                metric: Metric::Levenshtein { wildcard: false },
+                index: IndexKind::QGrams,
+            },
+            TieBreak::HighestCount,
+            &mut StdRng::seed_from_u64(1),
+            None,
+        );
+        (barcodes, merges)
+    }
+
+    const ENDPOINT: &str = "ACGTACGT_TTGCATGC";
+
+    #[test]
+    fn substitution_profile() {
+        let merge = |barcode: &str, endpoint: &str, distance: usize, count: u64| Merge {
+            barcode: barcode.to_string(),
+            endpoint: endpoint.to_string(),
+            distance,
+            count,
+        };
+        let merges = [
+            merge("ACGA", "ACGT", 1, 3),
+            merge("CCGT", "ACGT", 1, 2),
+            merge("TTTA", "TTTT", 1, 1),
+            // A deletion and an insertion, which don't line up base by base:
+            merge("CGTA", "ACGT", 2, 5),
+        ];
+        let profile = ErrorProfile::new(&merges);
+        assert_eq!(profile.absorbed[&1], 6);
+        assert_eq!(profile.absorbed[&2], 5);
+        assert_eq!(
+            profile.ranked_substitutions(),
+            vec![(('T', 'A'), 4), (('A', 'C'), 2)]
+        );
+    }
+
+    #[test]
+    fn error_in_first_component() {
+        let (barcodes, merges) = merge_planted(&[(ENDPOINT, 100), ("ACGTACGA_TTGCATGC", 3)]);
+        assert_eq!(barcodes.len(), 1);
+        assert_eq!(barcodes[ENDPOINT], 103);
+        assert_eq!(merges[0].distance, 1);
+    }
+
+    #[test]
+    fn error_in_second_component() {
+        let (barcodes, merges) = merge_planted(&[(ENDPOINT, 100), ("ACGTACGT_TTGCATGG", 3)]);
+        assert_eq!(barcodes.len(), 1);
+        assert_eq!(barcodes[ENDPOINT], 103);
+        assert_eq!(merges[0].distance, 1);
+    }
+
+    #[test]
+    fn errors_in_both_components() {
+        // Each component is within the threshold, so the pair merges at a total distance of 2:
+        let (barcodes, merges) = merge_planted(&[(ENDPOINT, 100), ("ACGTACGA_TTGCATGG", 3)]);
+        assert_eq!(barcodes.len(), 1);
+        assert_eq!(barcodes[ENDPOINT], 103);
+        assert_eq!(merges[0].distance, 2);
+    }
+
+    #[test]
+    fn pairs_over_threshold_in_one_component_are_kept() {
+        let (barcodes, merges) = merge_planted(&[
+            (ENDPOINT, 100),
+            ("ACGTAGGA_TTGCATGC", 3),
+            ("ACGTACGA_GGGGGGGG", 3),
+            ("ACGTACGT_TTGCAAAA", 3),
+        ]);
+        assert!(merges.is_empty());
+        assert_eq!(barcodes.len(), 4);
+        assert_eq!(barcodes[ENDPOINT], 100);
+    }
+
+    /// The edit distance by the full dynamic programming matrix, where an N matches anything
+    /// with `wildcard`.
+    fn full_levenshtein(a: &str, b: &str, wildcard: bool) -> usize {
+        let (a, b): (Vec<char>, Vec<char>) = (a.chars().collect(), b.chars().collect());
+        let mut previous: Vec<usize> = (0..=b.len()).collect();
+        for (i, x) in a.iter().enumerate() {
+            let mut current = vec![i + 1; b.len() + 1];
+            for (j, y) in b.iter().enumerate() {
+                let cost = usize::from(!(x == y || (wildcard && (*x == 'N' || *y == 'N'))));
+                current[j + 1] = (previous[j] + cost)
+                    .min(previous[j + 1] + 1)
+                    .min(current[j] + 1);
+            }
+            previous = current;
+        }
+        previous[b.len()]
+    }
+
+    #[test]
+    fn bounded_levenshtein_matches_the_full_matrix() {
+        let mut rng = StdRng::seed_from_u64(3);
+        for alphabet in [
+            &['A', 'C', 'G', 'T', 'N'][..],
+            &['A', 'C', 'N', '\u{e9}'][..],
+        ] {
+            let mut random = || -> String {
+                (0..rng.gen_range(0..=8))
+                    .map(|_| alphabet[rng.gen_range(0..alphabet.len())])
+                    .collect()
+            };
+            for _ in 0..3000 {
+                let (a, b) = (random(), random());
+                for wildcard in [false, true] {
+                    let distance = full_levenshtein(&a, &b, wildcard);
+                    for max_distance in 0..=4 {
+                        assert_eq!(
+                            bounded_levenshtein(&a, &b, max_distance, wildcard),
+                            (distance <= max_distance).then_some(distance),
+                            "{a} {b} within {max_distance} (wildcard {wildcard})"
+                        );
+                    }
+                    assert_eq!(Metric::Levenshtein { wildcard }.distance(&a, &b), distance);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,101 @@
+use crate::json::JsonObject;
+use crate::output::Row;
+use simple_eyre::eyre::Report;
+use std::path::Path;
+
+/// The number of top barcodes shown by rank in the MultiQC bar graph.
+const TOP_BARCODES: usize = 10;
+
+/// Write MultiQC custom content adding the match rate and barcode totals of a sample to the
+/// general statistics table.
+pub fn write_general_stats(
+    path: &Path,
+    sample: &str,
+    total_reads: u64,
+    barcoded_reads: u64,
+    barcodes: usize,
+) -> Result<(), Report> {
+    let mut content = JsonObject::default();
+    content.string("id", "fqbarcode_general_stats");
+    content.string("plot_type", "generalstats");
+    let mut match_rate = JsonObject::default();
+    match_rate.string("title", "% Barcoded");
+    match_rate.string(
+        "description",
+        "Percentage of reads with a barcode (fqbarcode)",
+    );
+    match_rate.number("min", 0_f64);
+    match_rate.number("max", 100_f64);
+    match_rate.string("suffix", "%");
+    match_rate.string("scale", "RdYlGn");
+    let mut barcoded = JsonObject::default();
+    barcoded.string("title", "Barcoded reads");
+    barcoded.string("description", "Reads with a barcode (fqbarcode)");
+    barcoded.string("format", "{:,.0f}");
+    let mut barcode_count = JsonObject::default();
+    barcode_count.string("title", "Barcodes");
+    barcode_count.string("description", "Barcodes after merging (fqbarcode)");
+    barcode_count.string("format", "{:,.0f}");
+    let columns: Vec<JsonObject> = [
+        ("fqbarcode_match_rate", match_rate),
+        ("fqbarcode_barcoded_reads", barcoded),
+        ("fqbarcode_barcodes", barcode_count),
+    ]
+    .into_iter()
+    .map(|(name, config)| {
+        let mut column = JsonObject::default();
+        column.object(name, config);
+        column
+    })
+    .collect();
+    content.objects("pconfig", columns);
+    let mut values = JsonObject::default();
+    values.number(
+        "fqbarcode_match_rate",
+        100_f64 * barcoded_reads as f64 / total_reads as f64,
+    );
+    values.integer("fqbarcode_barcoded_reads", barcoded_reads);
+    values.integer("fqbarcode_barcodes", barcodes as u64);
+    let mut data = JsonObject::default();
+    data.object(sample, values);
+    content.object("data", data);
+    content.write(path)
+}
+
+/// Write MultiQC custom content of a bar graph of a sample's reads on each of its top
+/// barcodes by rank (so that samples can be compared whatever their barcodes), on the other
+/// barcodes, and without a barcode.
+pub fn write_top_barcodes(
+    path: &Path,
+    sample: &str,
+    rows: &[Row],
+    no_barcode: u64,
+) -> Result<(), Report> {
+    let mut content = JsonObject::default();
+    content.string("id", "fqbarcode_top_barcodes");
+    content.string("section_name", "fqbarcode top barcodes");
+    content.string(
+        "description",
+        &format!(
+            "Reads on each of the {TOP_BARCODES} most frequent barcodes of each sample, \
+            on the other barcodes, and without a barcode."
+        ),
+    );
+    content.string("plot_type", "bargraph");
+    let mut config = JsonObject::default();
+    config.string("id", "fqbarcode_top_barcodes_plot");
+    config.string("title", "fqbarcode: Top barcodes");
+    config.string("ylab", "Reads");
+    content.object("pconfig", config);
+    let mut values = JsonObject::default();
+    for (rank, row) in rows.iter().take(TOP_BARCODES).enumerate() {
+        values.integer(&format!("Barcode {}", rank + 1), row.count);
+    }
+    let others: u64 = rows.iter().skip(TOP_BARCODES).map(|row| row.count).sum();
+    values.integer("Other barcodes", others);
+    values.integer("No barcode", no_barcode);
+    let mut data = JsonObject::default();
+    data.object(sample, values);
+    content.object("data", data);
+    content.write(path)
+}
@@ -0,0 +1,41 @@
+use crate::whitelist::open_text;
+use simple_eyre::eyre::{eyre, Report};
+use std::collections::HashMap;
+use std::io::BufRead;
+use std::path::Path;
+
+/// Read a two-column (barcode, name) file of display names, skipping blank lines and `#`
+/// comments. A barcode or name given twice is an error.
+pub fn read_names(path: &Path) -> Result<HashMap<String, String>, Report> {
+    let mut names: HashMap<String, String> = HashMap::new();
+    let mut lines_by_name: HashMap<String, usize> = HashMap::new();
+    let mut lines_by_barcode: HashMap<String, usize> = HashMap::new();
+    for (i, line) in open_text(path)?.lines().enumerate() {
+        let line = line?;
+        let mut fields = line.split('\t').map(|f| f.trim());
+        let Some(barcode) = fields
+            .next()
+            .filter(|f| !f.is_empty() && !f.starts_with('#'))
+        else {
+            continue;
+        };
+        let name = fields
+            .next()
+            .filter(|f| !f.is_empty())
+            .ok_or_else(|| eyre!("line {} of the names file has no name", i + 1))?;
+        if let Some(first) = lines_by_barcode.insert(barcode.to_string(), i + 1) {
+            return Err(eyre!(
+                "barcode {barcode} is named on both lines {first} and {} of the names file",
+                i + 1
+            ));
+        }
+        if let Some(first) = lines_by_name.insert(name.to_string(), i + 1) {
+            return Err(eyre!(
+                "name {name} is given on both lines {first} and {} of the names file",
+                i + 1
+            ));
+        }
+        names.insert(barcode.to_string(), name.to_string());
+    }
+    Ok(names)
+}
@@ -0,0 +1,543 @@
+use crate::json::json_string;
+use crate::parquet::{ColumnType, ParquetTable};
+use crate::sqlite;
+use crate::whitelist::Accepted;
+use simple_eyre::eyre::{eyre, Report};
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, Write};
+
+/// A row of the barcode table: the count and label of a barcode, followed by any extra
+/// columns. The key is the sequence the barcode was counted under, which is used to look up
+/// the per-barcode statistics for the extra columns.
+pub struct Row {
+    pub key: String,
+    pub label: String,
+    pub count: u64,
+    pub columns: Vec<String>,
+}
+
+impl Row {
+    pub fn new(key: &str, count: u64) -> Self {
+        Row {
+            key: key.to_owned(),
+            label: key.to_owned(),
+            count,
+            columns: Vec::new(),
+        }
+    }
+}
+
+impl fmt::Display for Row {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}\t{}", self.count, self.label)?;
+        for column in self.columns.iter() {
+            write!(f, "\t{column}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Write the rows as starcode does: the sequence and count of each barcode, followed by the
+/// comma-separated members of its cluster if they are given.
+pub fn write_starcode(
+    writer: &mut dyn Write,
+    rows: &[Row],
+    clusters: Option<&HashMap<&str, Vec<&str>>>,
+) -> io::Result<()> {
+    for row in rows.iter() {
+        write!(writer, "{}\t{}", row.key, row.count)?;
+        if let Some(members) = clusters.and_then(|clusters| clusters.get(row.key.as_str())) {
+            write!(writer, "\t{}", members.join(","))?;
+        }
+        writeln!(writer)?;
+    }
+    writer.flush()
+}
+
+/// Write the guide counts of a library screen as a MAGeCK count table: a header of `sgRNA`,
+/// `Gene` and the sample names, and then the name, gene and per-sample counts of each guide.
+pub fn write_mageck(
+    writer: &mut dyn Write,
+    samples: &[&str],
+    guides: &[(&str, &str, &[u64])],
+) -> io::Result<()> {
+    writeln!(writer, "sgRNA\tGene\t{}", samples.join("\t"))?;
+    for (name, gene, counts) in guides.iter() {
+        write!(writer, "{name}\t{gene}")?;
+        for count in counts.iter() {
+            write!(writer, "\t{count}")?;
+        }
+        writeln!(writer)?;
+    }
+    writer.flush()
+}
+
+/// Write the barcode rank curve: the rank, barcode and count of each barcode with reads,
+/// most frequent first, with the knee and inflection points marked.
+pub fn write_knee_plot(
+    writer: &mut dyn Write,
+    ranked: &[(&str, u64)],
+    knee: Option<usize>,
+    inflection: Option<usize>,
+) -> io::Result<()> {
+    writeln!(writer, "rank\tbarcode\tcount\tpoint")?;
+    for (i, (barcode, count)) in ranked.iter().enumerate() {
+        let point = match (Some(i) == knee, Some(i) == inflection) {
+            (true, true) => "knee,inflection",
+            (true, false) => "knee",
+            (false, true) => "inflection",
+            (false, false) => "",
+        };
+        writeln!(writer, "{}\t{barcode}\t{count}\t{point}", i + 1)?;
+    }
+    writer.flush()
+}
+
+/// Write a derived whitelist in the layout of `umi_tools whitelist`: each accepted barcode,
+/// its error variants (separated by commas), its count, and the counts of its variants.
+pub fn write_whitelist(writer: &mut dyn Write, accepted: &[Accepted]) -> io::Result<()> {
+    for entry in accepted {
+        let (variants, counts): (Vec<&str>, Vec<String>) = entry
+            .variants
+            .iter()
+            .map(|(variant, count)| (variant.as_str(), count.to_string()))
+            .unzip();
+        writeln!(
+            writer,
+            "{}\t{}\t{}\t{}",
+            entry.barcode,
+            variants.join(","),
+            entry.count,
+            counts.join(",")
+        )?;
+    }
+    writer.flush()
+}
+
+/// The names of the columns of a barcode table, which of them are written (and in what
+/// order), and whether they are written as a header line before the rows.
+#[derive(Clone, Default)]
+pub struct Columns {
+    names: Vec<String>,
+    selected: Option<Vec<usize>>,
+    header: bool,
+}
+
+impl Columns {
+    /// Select columns by name, or all of them if there is no selection.
+    pub fn new(
+        names: Vec<String>,
+        selection: Option<&[String]>,
+        header: bool,
+    ) -> Result<Self, Report> {
+        let selected = match selection {
+            Some(selection) => Some(
+                selection
+                    .iter()
+                    .map(|name| {
+                        names.iter().position(|n| n == name).ok_or_else(|| {
+                            eyre!(
+                                "there is no {name} column; the columns are {}",
+                                names.join(",")
+                            )
+                        })
+                    })
+                    .collect::<Result<Vec<usize>, Report>>()?,
+            ),
+            None => None,
+        };
+        Ok(Columns {
+            names,
+            selected,
+            header,
+        })
+    }
+}
+
+/// The columns whose values are numbers, written unquoted in JSON.
+const NUMERIC_COLUMNS: [&str; 9] = [
+    "count",
+    "distinct_reads",
+    "distinct_umis",
+    "forward_reads",
+    "reverse_reads",
+    "rescued",
+    "length",
+    "fraction",
+    "cumulative_fraction",
+];
+
+/// The format of the barcode table.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum TableFormat {
+    Tsv,
+    Csv,
+    Json,
+    Parquet,
+    Sqlite,
+}
+
+impl TableFormat {
+    /// The extension of files of the format.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            TableFormat::Tsv => "tsv",
+            TableFormat::Csv => "csv",
+            TableFormat::Json => "json",
+            TableFormat::Parquet => "parquet",
+            TableFormat::Sqlite => "sqlite",
+        }
+    }
+}
+
+/// How far through a JSON table the writer is.
+#[derive(PartialEq, Eq)]
+enum Section {
+    Start,
+    Rows,
+    Unassigned,
+    End,
+}
+
+/// Quote a CSV field if it holds a comma, quote or line break.
+fn csv_field(field: &str) -> String {
+    match field.contains([',', '"', '\n', '\r']) {
+        true => format!("\"{}\"", field.replace('"', "\"\"")),
+        false => field.to_owned(),
+    }
+}
+
+/// Writes the rows of a barcode table, including the unassigned counts, with a constant
+/// sample label as the first column of every row if one is given.
+///
+/// With a selection of columns, each row is split into its fields and only the selected
+/// ones are written; an unassigned count leaves the fields it does not have empty.
+///
+/// As JSON, the table is a single object of the run's `metadata`, the `barcodes` (an array
+/// of objects keyed by column name) and the `unassigned` counts, which is closed by `flush`.
+/// As Parquet, the rows are the barcodes only, typed by column, and the run totals and the
+/// unassigned counts are written as the key-value metadata of the file by `flush`.
+/// As SQLite, the table is written as the SQL statements adding a run, with its totals, its
+/// barcodes and its unassigned counts, to a database, in a transaction ended by `flush`.
+///
+/// If the reader goes away (e.g. the table is piped into `head`), the remaining rows are
+/// quietly dropped rather than failing the run.
+pub struct TableWriter<W: Write> {
+    writer: W,
+    sample: Option<String>,
+    columns: Columns,
+    format: TableFormat,
+    metadata: Vec<(&'static str, u64)>,
+    section: Section,
+    /// The number of items written in the current section of a JSON table.
+    items: usize,
+    parquet: Option<ParquetTable>,
+    unassigned: Vec<(String, u64)>,
+    closed: bool,
+}
+
+impl<W: Write> TableWriter<W> {
+    pub fn new(writer: W, sample: Option<String>, columns: Columns, format: TableFormat) -> Self {
+        let mut table = TableWriter {
+            writer,
+            sample,
+            columns,
+            format,
+            metadata: Vec::new(),
+            section: Section::Start,
+            items: 0,
+            parquet: None,
+            unassigned: Vec::new(),
+            closed: false,
+        };
+        if format == TableFormat::Parquet {
+            let names: Vec<String> = table
+                .selected()
+                .into_iter()
+                .map(|i| table.columns.names[i].to_owned())
+                .collect();
+            let types: Vec<ColumnType> = names
+                .iter()
+                .map(|name| match name.as_str() {
+                    "fraction" | "cumulative_fraction" => ColumnType::Double,
+                    name if NUMERIC_COLUMNS.contains(&name) => ColumnType::Int64,
+                    _ => ColumnType::Utf8,
+                })
+                .collect();
+            table.parquet = Some(ParquetTable::new(names, types));
+        }
+        table
+    }
+
+    /// Give the run totals to write as the metadata of a JSON table.
+    pub fn with_metadata(mut self, metadata: Vec<(&'static str, u64)>) -> Self {
+        self.metadata = metadata;
+        self
+    }
+
+    /// The indices of the columns that are written.
+    fn selected(&self) -> Vec<usize> {
+        match &self.columns.selected {
+            Some(selected) => selected.clone(),
+            None => (0..self.columns.names.len()).collect(),
+        }
+    }
+
+    /// Write the header line, if there is one and it has not been written yet, or start the
+    /// object of a JSON table or the run of a SQLite table.
+    fn write_header(&mut self) -> io::Result<()> {
+        if self.format == TableFormat::Parquet {
+            return Ok(());
+        }
+        if self.format == TableFormat::Sqlite {
+            if self.section != Section::Start {
+                return Ok(());
+            }
+            self.section = Section::Rows;
+            let sample = match &self.sample {
+                Some(sample) => sqlite::quote(sample),
+                None => "NULL".to_string(),
+            };
+            let names: Vec<&str> = self.metadata.iter().map(|(name, _)| *name).collect();
+            let values: Vec<String> = self
+                .metadata
+                .iter()
+                .map(|(_, value)| value.to_string())
+                .collect();
+            let written = write!(
+                self.writer,
+                "BEGIN;\n{}INSERT INTO runs (sample, {}) VALUES ({sample}, {});\n",
+                sqlite::schema(),
+                names.join(", "),
+                values.join(", ")
+            );
+            return self.check(written);
+        }
+        if self.format == TableFormat::Json {
+            if self.section != Section::Start {
+                return Ok(());
+            }
+            self.section = Section::Rows;
+            let mut metadata: Vec<String> = Vec::new();
+            if let Some(sample) = &self.sample {
+                metadata.push(format!("\"sample\":{}", json_string(sample)));
+            }
+            for (name, value) in self.metadata.iter() {
+                metadata.push(format!("{}:{value}", json_string(name)));
+            }
+            let written = writeln!(
+                self.writer,
+                "{{\"metadata\":{{{}}},\n\"barcodes\":[",
+                metadata.join(",")
+            );
+            return self.check(written);
+        }
+        if !self.columns.header {
+            return Ok(());
+        }
+        self.columns.header = false;
+        let names: Vec<String> = self
+            .selected()
+            .iter()
+            .map(|i| match self.format {
+                TableFormat::Csv => csv_field(&self.columns.names[*i]),
+                _ => self.columns.names[*i].to_owned(),
+            })
+            .collect();
+        let delimiter = match self.format {
+            TableFormat::Csv => ",",
+            _ => "\t",
+        };
+        let written = writeln!(self.writer, "{}", names.join(delimiter));
+        self.check(written)
+    }
+
+    pub fn write(&mut self, row: &Row) -> io::Result<()> {
+        self.write_header()?;
+        if self.closed {
+            return Ok(());
+        }
+        let count = row.count.to_string();
+        let fields: Vec<&str> = self
+            .sample
+            .iter()
+            .map(|sample| sample.as_str())
+            .chain([count.as_str()])
+            .chain(row.label.split('\t'))
+            .chain(row.columns.iter().map(|column| column.as_str()))
+            .collect();
+        let written = match (self.format, &self.columns.selected) {
+            (TableFormat::Parquet, _) => {
+                let fields: Vec<&str> = self
+                    .selected()
+                    .into_iter()
+                    .map(|i| fields.get(i).copied().unwrap_or(""))
+                    .collect();
+                match self.parquet.as_mut() {
+                    Some(parquet) => parquet.add(&mut self.writer, &fields),
+                    None => Ok(()),
+                }
+            }
+            (TableFormat::Sqlite, _) => {
+                let (mut names, mut values) = (Vec::new(), Vec::new());
+                for i in self.selected() {
+                    let name = self.columns.names[i].as_str();
+                    if name == "sample" {
+                        continue;
+                    }
+                    names.push(format!("\"{name}\""));
+                    values.push(match fields.get(i) {
+                        Some(field) if NUMERIC_COLUMNS.contains(&name) => sqlite::number(field),
+                        Some(field) => sqlite::quote(field),
+                        None => "NULL".to_string(),
+                    });
+                }
+                writeln!(
+                    self.writer,
+                    "INSERT INTO barcodes (run, {}) VALUES ((SELECT max(run) FROM runs), {});",
+                    names.join(", "),
+                    values.join(", ")
+                )
+            }
+            (TableFormat::Json, _) => {
+                let object: Vec<String> = self
+                    .selected()
+                    .into_iter()
+                    .map(|i| {
+                        let name = &self.columns.names[i];
+                        let value = match (fields.get(i), NUMERIC_COLUMNS.contains(&name.as_str()))
+                        {
+                            (Some(field), true) => field.to_string(),
+                            (Some(field), false) => json_string(field),
+                            (None, _) => "null".to_string(),
+                        };
+                        format!("{}:{value}", json_string(name))
+                    })
+                    .collect();
+                let separator = match self.items {
+                    0 => "",
+                    _ => ",\n",
+                };
+                self.items += 1;
+                write!(self.writer, "{separator}{{{}}}", object.join(","))
+            }
+            (format, selected) => {
+                let fields: Vec<&str> = match selected {
+                    Some(selected) => selected
+                        .iter()
+                        .map(|i| fields.get(*i).copied().unwrap_or(""))
+                        .collect(),
+                    None => fields,
+                };
+                match format {
+                    TableFormat::Csv => {
+                        let fields: Vec<String> =
+                            fields.iter().map(|field| csv_field(field)).collect();
+                        writeln!(self.writer, "{}", fields.join(","))
+                    }
+                    _ => writeln!(self.writer, "{}", fields.join("\t")),
+                }
+            }
+        };
+        self.check(written)
+    }
+
+    /// Write an unassigned count, such as `no_barcode`.
+    pub fn write_count(&mut self, name: &str, count: u64) -> io::Result<()> {
+        if self.format == TableFormat::Parquet {
+            self.unassigned.push((name.to_owned(), count));
+            return Ok(());
+        }
+        if self.format == TableFormat::Sqlite {
+            self.write_header()?;
+            if self.closed {
+                return Ok(());
+            }
+            let written = writeln!(
+                self.writer,
+                "INSERT INTO unassigned (run, name, count) \
+                VALUES ((SELECT max(run) FROM runs), {}, {count});",
+                sqlite::quote(name)
+            );
+            return self.check(written);
+        }
+        if self.format != TableFormat::Json {
+            return self.write(&Row::new(name, count));
+        }
+        self.write_header()?;
+        if self.closed {
+            return Ok(());
+        }
+        if self.section == Section::Rows {
+            let end = match self.items {
+                0 => "",
+                _ => "\n",
+            };
+            let written = write!(self.writer, "{end}],\n\"unassigned\":{{");
+            self.check(written)?;
+            self.section = Section::Unassigned;
+            self.items = 0;
+        }
+        let separator = match self.items {
+            0 => "",
+            _ => ",",
+        };
+        self.items += 1;
+        let written = write!(self.writer, "{separator}{}:{count}", json_string(name));
+        self.check(written)
+    }
+
+    /// Flush the table, closing the object of a JSON table or writing the metadata of a
+    /// Parquet table.
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.write_header()?;
+        if self.closed {
+            return Ok(());
+        }
+        if let Some(mut parquet) = self.parquet.take() {
+            let mut metadata: Vec<(String, String)> = Vec::new();
+            if let Some(sample) = &self.sample {
+                metadata.push(("sample".to_string(), sample.to_owned()));
+            }
+            for (name, value) in self.metadata.iter() {
+                metadata.push((name.to_string(), value.to_string()));
+            }
+            for (name, count) in self.unassigned.iter() {
+                metadata.push((name.to_owned(), count.to_string()));
+            }
+            let written = parquet.finish(&mut self.writer, &metadata);
+            self.check(written)?;
+        }
+        let closing = match (&self.section, self.items) {
+            (Section::Rows, 0) => "]}\n",
+            (Section::Rows, _) => "\n]}\n",
+            (Section::Unassigned, _) => "}}\n",
+            _ => "",
+        };
+        let closing = match self.format {
+            TableFormat::Sqlite if self.section == Section::Rows => "COMMIT;\n",
+            _ => closing,
+        };
+        if matches!(self.format, TableFormat::Json | TableFormat::Sqlite) {
+            self.section = Section::End;
+        }
+        let written = write!(self.writer, "{closing}");
+        self.check(written)?;
+        let flushed = self.writer.flush();
+        self.check(flushed)
+    }
+
+    fn check(&mut self, result: io::Result<()>) -> io::Result<()> {
+        match result {
+            // A failure of sqlite3 is reported when the database is flushed:
+            Err(e)
+                if e.kind() == io::ErrorKind::BrokenPipe && self.format != TableFormat::Sqlite =>
+            {
+                self.closed = true;
+                Ok(())
+            }
+            result => result,
+        }
+    }
+}
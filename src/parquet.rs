@@ -0,0 +1,576 @@
+use std::io::{self, Write};
+
+/// The magic bytes that Parquet files start and end with.
+const MAGIC: &[u8] = b"PAR1";
+
+/// The rows of each row group, which bounds the memory used for the column values of very
+/// large tables.
+const ROW_GROUP_ROWS: usize = 1 << 20;
+
+/// The Thrift compact protocol types of the fields used in Parquet metadata.
+const I32: u8 = 5;
+const I64: u8 = 6;
+const BINARY: u8 = 8;
+const LIST: u8 = 9;
+const STRUCT: u8 = 12;
+
+/// The Parquet physical types, encodings and other enums used.
+const TYPE_INT64: i32 = 2;
+const TYPE_DOUBLE: i32 = 5;
+const TYPE_BYTE_ARRAY: i32 = 6;
+const REQUIRED: i32 = 0;
+const CONVERTED_UTF8: i32 = 0;
+const ENCODING_PLAIN: i32 = 0;
+const ENCODING_RLE: i32 = 3;
+const UNCOMPRESSED: i32 = 0;
+const DATA_PAGE: i32 = 0;
+
+/// The type of a column of the table.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ColumnType {
+    Int64,
+    Double,
+    Utf8,
+}
+
+impl ColumnType {
+    fn physical(&self) -> i32 {
+        match self {
+            ColumnType::Int64 => TYPE_INT64,
+            ColumnType::Double => TYPE_DOUBLE,
+            ColumnType::Utf8 => TYPE_BYTE_ARRAY,
+        }
+    }
+}
+
+/// Encodes Parquet metadata in the Thrift compact protocol, keeping the last field id of each
+/// struct being written (as the ids are written as deltas).
+struct Thrift {
+    bytes: Vec<u8>,
+    last: Vec<i16>,
+}
+
+impl Thrift {
+    fn new() -> Self {
+        Thrift {
+            bytes: Vec::new(),
+            last: vec![0],
+        }
+    }
+
+    fn varint(&mut self, mut value: u64) {
+        while value >= 0x80 {
+            self.bytes.push((value as u8) | 0x80);
+            value >>= 7;
+        }
+        self.bytes.push(value as u8);
+    }
+
+    fn zigzag(&mut self, value: i64) {
+        self.varint(((value << 1) ^ (value >> 63)) as u64);
+    }
+
+    fn field(&mut self, id: i16, kind: u8) {
+        let last = self.last.last_mut().expect("a struct is open");
+        match id - *last {
+            delta @ 1..=15 => self.bytes.push(((delta as u8) << 4) | kind),
+            _ => {
+                self.bytes.push(kind);
+                let id = id as i64;
+                self.zigzag(id);
+            }
+        }
+        *self.last.last_mut().expect("a struct is open") = id;
+    }
+
+    fn i32(&mut self, id: i16, value: i32) {
+        self.field(id, I32);
+        self.zigzag(value as i64);
+    }
+
+    fn i64(&mut self, id: i16, value: i64) {
+        self.field(id, I64);
+        self.zigzag(value);
+    }
+
+    fn binary(&mut self, id: i16, value: &[u8]) {
+        self.field(id, BINARY);
+        self.varint(value.len() as u64);
+        self.bytes.extend_from_slice(value);
+    }
+
+    fn list(&mut self, id: i16, kind: u8, size: usize) {
+        self.field(id, LIST);
+        match size {
+            0..=14 => self.bytes.push(((size as u8) << 4) | kind),
+            _ => {
+                self.bytes.push(0xf0 | kind);
+                self.varint(size as u64);
+            }
+        }
+    }
+
+    /// Start a struct, either as a field or (with no id) as an element of a list.
+    fn begin(&mut self, id: Option<i16>) {
+        if let Some(id) = id {
+            self.field(id, STRUCT);
+        }
+        self.last.push(0);
+    }
+
+    fn end(&mut self) {
+        self.bytes.push(0);
+        self.last.pop();
+    }
+}
+
+/// Where the page of one column of a row group was written.
+struct ColumnChunk {
+    offset: u64,
+    size: u64,
+    values: usize,
+}
+
+/// Writes a table as a Parquet file of required, plain-encoded and uncompressed columns, in
+/// row groups of up to `ROW_GROUP_ROWS` rows with a single page per column. Integer and
+/// floating point columns are written as INT64 and DOUBLE, and the others as UTF-8 strings.
+/// Only the bytes written so far are tracked, so the file can be written to a pipe.
+pub struct ParquetTable {
+    names: Vec<String>,
+    types: Vec<ColumnType>,
+    /// The plain-encoded values of each column of the current row group.
+    values: Vec<Vec<u8>>,
+    rows: usize,
+    offset: u64,
+    row_groups: Vec<(usize, Vec<ColumnChunk>)>,
+}
+
+impl ParquetTable {
+    pub fn new(names: Vec<String>, types: Vec<ColumnType>) -> Self {
+        ParquetTable {
+            values: vec![Vec::new(); names.len()],
+            names,
+            types,
+            rows: 0,
+            offset: 0,
+            row_groups: Vec::new(),
+        }
+    }
+
+    fn write_bytes(&mut self, writer: &mut dyn Write, bytes: &[u8]) -> io::Result<()> {
+        if self.offset == 0 {
+            writer.write_all(MAGIC)?;
+            self.offset = MAGIC.len() as u64;
+        }
+        writer.write_all(bytes)?;
+        self.offset += bytes.len() as u64;
+        Ok(())
+    }
+
+    /// Add a row of the table, writing a row group if it is full.
+    pub fn add(&mut self, writer: &mut dyn Write, fields: &[&str]) -> io::Result<()> {
+        for ((values, kind), field) in self.values.iter_mut().zip(&self.types).zip(fields) {
+            let invalid = || {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{field} is not a number"),
+                )
+            };
+            match kind {
+                ColumnType::Int64 => {
+                    let value: i64 = field.parse().map_err(|_| invalid())?;
+                    values.extend_from_slice(&value.to_le_bytes());
+                }
+                ColumnType::Double => {
+                    let value: f64 = field.parse().map_err(|_| invalid())?;
+                    values.extend_from_slice(&value.to_le_bytes());
+                }
+                ColumnType::Utf8 => {
+                    values.extend_from_slice(&(field.len() as u32).to_le_bytes());
+                    values.extend_from_slice(field.as_bytes());
+                }
+            }
+        }
+        self.rows += 1;
+        match self.rows == ROW_GROUP_ROWS {
+            true => self.write_row_group(writer),
+            false => Ok(()),
+        }
+    }
+
+    /// Write the rows added so far as a row group, each column as one data page.
+    fn write_row_group(&mut self, writer: &mut dyn Write) -> io::Result<()> {
+        let mut chunks: Vec<ColumnChunk> = Vec::new();
+        for i in 0..self.names.len() {
+            let values = std::mem::take(&mut self.values[i]);
+            let mut header = Thrift::new();
+            header.i32(1, DATA_PAGE);
+            header.i32(2, values.len() as i32);
+            header.i32(3, values.len() as i32);
+            header.begin(Some(5));
+            header.i32(1, self.rows as i32);
+            header.i32(2, ENCODING_PLAIN);
+            header.i32(3, ENCODING_RLE);
+            header.i32(4, ENCODING_RLE);
+            header.end();
+            header.end();
+            let offset = self.offset.max(MAGIC.len() as u64);
+            self.write_bytes(writer, &header.bytes)?;
+            self.write_bytes(writer, &values)?;
+            chunks.push(ColumnChunk {
+                offset,
+                size: (header.bytes.len() + values.len()) as u64,
+                values: self.rows,
+            });
+        }
+        self.row_groups.push((self.rows, chunks));
+        self.rows = 0;
+        Ok(())
+    }
+
+    /// Write any remaining rows and the file metadata, including the key-value pairs (such as
+    /// the run totals).
+    pub fn finish(
+        &mut self,
+        writer: &mut dyn Write,
+        metadata: &[(String, String)],
+    ) -> io::Result<()> {
+        if self.rows > 0 || self.row_groups.is_empty() {
+            self.write_row_group(writer)?;
+        }
+        let mut footer = Thrift::new();
+        footer.i32(1, 1);
+        footer.list(2, STRUCT, self.names.len() + 1);
+        footer.begin(None);
+        footer.binary(4, b"schema");
+        footer.i32(5, self.names.len() as i32);
+        footer.end();
+        for (name, kind) in self.names.iter().zip(&self.types) {
+            footer.begin(None);
+            footer.i32(1, kind.physical());
+            footer.i32(3, REQUIRED);
+            footer.binary(4, name.as_bytes());
+            if *kind == ColumnType::Utf8 {
+                footer.i32(6, CONVERTED_UTF8);
+            }
+            footer.end();
+        }
+        let rows: usize = self.row_groups.iter().map(|(rows, _)| rows).sum();
+        footer.i64(3, rows as i64);
+        footer.list(4, STRUCT, self.row_groups.len());
+        for (rows, chunks) in self.row_groups.iter() {
+            footer.begin(None);
+            footer.list(1, STRUCT, chunks.len());
+            for ((chunk, name), kind) in chunks.iter().zip(&self.names).zip(&self.types) {
+                footer.begin(None);
+                footer.i64(2, chunk.offset as i64);
+                footer.begin(Some(3));
+                footer.i32(1, kind.physical());
+                footer.list(2, I32, 1);
+                footer.zigzag(ENCODING_PLAIN as i64);
+                footer.list(3, BINARY, 1);
+                footer.varint(name.len() as u64);
+                footer.bytes.extend_from_slice(name.as_bytes());
+                footer.i32(4, UNCOMPRESSED);
+                footer.i64(5, chunk.values as i64);
+                footer.i64(6, chunk.size as i64);
+                footer.i64(7, chunk.size as i64);
+                footer.i64(9, chunk.offset as i64);
+                footer.end();
+                footer.end();
+            }
+            footer.i64(2, chunks.iter().map(|chunk| chunk.size).sum::<u64>() as i64);
+            footer.i64(3, *rows as i64);
+            footer.end();
+        }
+        footer.list(5, STRUCT, metadata.len());
+        for (key, value) in metadata.iter() {
+            footer.begin(None);
+            footer.binary(1, key.as_bytes());
+            footer.binary(2, value.as_bytes());
+            footer.end();
+        }
+        footer.binary(
+            6,
+            concat!("fqbarcode version ", env!("CARGO_PKG_VERSION")).as_bytes(),
+        );
+        footer.end();
+        self.write_bytes(writer, &footer.bytes)?;
+        self.write_bytes(writer, &(footer.bytes.len() as u32).to_le_bytes())?;
+        self.write_bytes(writer, MAGIC)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    /// A value decoded from the Thrift compact protocol.
+    #[derive(Debug, PartialEq)]
+    enum Value {
+        Int(i64),
+        Binary(Vec<u8>),
+        List(Vec<Value>),
+        Struct(BTreeMap<i16, Value>),
+    }
+
+    impl Value {
+        fn int(&self) -> i64 {
+            match self {
+                Value::Int(value) => *value,
+                _ => panic!("{self:?} is not an integer"),
+            }
+        }
+
+        fn binary(&self) -> &[u8] {
+            match self {
+                Value::Binary(value) => value,
+                _ => panic!("{self:?} is not binary"),
+            }
+        }
+
+        fn list(&self) -> &[Value] {
+            match self {
+                Value::List(values) => values,
+                _ => panic!("{self:?} is not a list"),
+            }
+        }
+
+        fn field(&self, id: i16) -> &Value {
+            match self {
+                Value::Struct(fields) => &fields[&id],
+                _ => panic!("{self:?} is not a struct"),
+            }
+        }
+
+        fn has(&self, id: i16) -> bool {
+            matches!(self, Value::Struct(fields) if fields.contains_key(&id))
+        }
+    }
+
+    /// Decodes the Thrift compact protocol, independently of the encoder above.
+    struct Decoder<'a> {
+        bytes: &'a [u8],
+        position: usize,
+    }
+
+    impl Decoder<'_> {
+        fn byte(&mut self) -> u8 {
+            self.position += 1;
+            self.bytes[self.position - 1]
+        }
+
+        fn varint(&mut self) -> u64 {
+            let (mut value, mut shift) = (0_u64, 0);
+            loop {
+                let byte = self.byte();
+                value |= ((byte & 0x7f) as u64) << shift;
+                if byte & 0x80 == 0 {
+                    return value;
+                }
+                shift += 7;
+            }
+        }
+
+        fn zigzag(&mut self) -> i64 {
+            let value = self.varint();
+            (value >> 1) as i64 ^ -((value & 1) as i64)
+        }
+
+        fn value(&mut self, kind: u8) -> Value {
+            match kind {
+                5 | 6 => Value::Int(self.zigzag()),
+                8 => {
+                    let length = self.varint() as usize;
+                    self.position += length;
+                    Value::Binary(self.bytes[self.position - length..self.position].to_vec())
+                }
+                9 => {
+                    let header = self.byte();
+                    let size = match header >> 4 {
+                        15 => self.varint() as usize,
+                        size => size as usize,
+                    };
+                    Value::List((0..size).map(|_| self.value(header & 0x0f)).collect())
+                }
+                12 => {
+                    let mut fields = BTreeMap::new();
+                    let mut id = 0_i16;
+                    loop {
+                        let header = self.byte();
+                        if header == 0 {
+                            return Value::Struct(fields);
+                        }
+                        id = match header >> 4 {
+                            0 => self.zigzag() as i16,
+                            delta => id + delta as i16,
+                        };
+                        let value = self.value(header & 0x0f);
+                        assert!(fields.insert(id, value).is_none(), "field {id} repeated");
+                    }
+                }
+                _ => panic!("unexpected compact type {kind}"),
+            }
+        }
+    }
+
+    fn decode_struct(bytes: &[u8]) -> (Value, usize) {
+        let mut decoder = Decoder { bytes, position: 0 };
+        let value = decoder.value(STRUCT);
+        (value, decoder.position)
+    }
+
+    /// Write a table, returning the file and its decoded footer.
+    fn write(names: &[&str], types: &[ColumnType], rows: &[Vec<String>]) -> (Vec<u8>, Value) {
+        let mut file: Vec<u8> = Vec::new();
+        let mut table = ParquetTable::new(
+            names.iter().map(|name| name.to_string()).collect(),
+            types.to_vec(),
+        );
+        for row in rows.iter() {
+            let fields: Vec<&str> = row.iter().map(|field| field.as_str()).collect();
+            table.add(&mut file, &fields).unwrap();
+        }
+        let metadata = [("reads".to_string(), "42".to_string())];
+        table.finish(&mut file, &metadata).unwrap();
+        assert_eq!(&file[..4], MAGIC);
+        assert_eq!(&file[file.len() - 4..], MAGIC);
+        let length = u32::from_le_bytes(file[file.len() - 8..file.len() - 4].try_into().unwrap());
+        let start = file.len() - 8 - length as usize;
+        let (footer, used) = decode_struct(&file[start..file.len() - 8]);
+        assert_eq!(used, length as usize);
+        (file, footer)
+    }
+
+    /// The plain-encoded values of the page at a column chunk's data page offset, after
+    /// checking its header and sizes against the column metadata.
+    fn page<'a>(file: &'a [u8], chunk: &Value) -> (&'a [u8], i64) {
+        let meta = chunk.field(3);
+        let offset = meta.field(9).int() as usize;
+        assert_eq!(chunk.field(2).int() as usize, offset);
+        let (header, header_length) = decode_struct(&file[offset..]);
+        assert_eq!(header.field(1).int(), DATA_PAGE as i64);
+        let size = header.field(2).int();
+        assert_eq!(header.field(3).int(), size);
+        assert_eq!(
+            meta.field(6).int(),
+            header_length as i64 + size,
+            "the chunk size includes the page header"
+        );
+        assert_eq!(meta.field(7).int(), meta.field(6).int());
+        let data = header.field(5);
+        assert_eq!(data.field(1).int(), meta.field(5).int());
+        assert_eq!(data.field(2).int(), ENCODING_PLAIN as i64);
+        let start = offset + header_length;
+        (&file[start..start + size as usize], data.field(1).int())
+    }
+
+    #[test]
+    fn footer_schema_and_pages() {
+        let rows = vec![
+            vec!["ACGT".to_string(), "10".to_string(), "0.25".to_string()],
+            vec!["TTGCA".to_string(), "-3".to_string(), "1.5".to_string()],
+        ];
+        let types = [ColumnType::Utf8, ColumnType::Int64, ColumnType::Double];
+        let (file, footer) = write(&["barcode", "count", "fraction"], &types, &rows);
+        assert_eq!(footer.field(1).int(), 1);
+        assert_eq!(footer.field(3).int(), 2);
+        let schema = footer.field(2).list();
+        assert_eq!(schema.len(), 4);
+        assert_eq!(schema[0].field(4).binary(), b"schema");
+        assert_eq!(schema[0].field(5).int(), 3);
+        let physical = [TYPE_BYTE_ARRAY, TYPE_INT64, TYPE_DOUBLE];
+        for (element, (name, kind)) in schema[1..]
+            .iter()
+            .zip(["barcode", "count", "fraction"].iter().zip(physical))
+        {
+            assert_eq!(element.field(1).int(), kind as i64);
+            assert_eq!(element.field(3).int(), REQUIRED as i64);
+            assert_eq!(element.field(4).binary(), name.as_bytes());
+            assert_eq!(element.has(6), kind == TYPE_BYTE_ARRAY);
+        }
+        let row_groups = footer.field(4).list();
+        assert_eq!(row_groups.len(), 1);
+        assert_eq!(row_groups[0].field(3).int(), 2);
+        let chunks = row_groups[0].field(1).list();
+        assert_eq!(chunks.len(), 3);
+        for (chunk, (name, kind)) in chunks
+            .iter()
+            .zip(["barcode", "count", "fraction"].iter().zip(physical))
+        {
+            let meta = chunk.field(3);
+            assert_eq!(meta.field(1).int(), kind as i64);
+            assert_eq!(meta.field(2).list(), &[Value::Int(ENCODING_PLAIN as i64)]);
+            assert_eq!(
+                meta.field(3).list(),
+                &[Value::Binary(name.as_bytes().to_vec())]
+            );
+            assert_eq!(meta.field(4).int(), UNCOMPRESSED as i64);
+            assert_eq!(meta.field(5).int(), 2);
+        }
+        // The chunks follow each other from just after the leading magic:
+        assert_eq!(chunks[0].field(2).int(), 4);
+        for pair in chunks.windows(2) {
+            assert_eq!(
+                pair[0].field(2).int() + pair[0].field(3).field(6).int(),
+                pair[1].field(2).int()
+            );
+        }
+        let sizes: i64 = chunks
+            .iter()
+            .map(|chunk| chunk.field(3).field(6).int())
+            .sum();
+        assert_eq!(row_groups[0].field(2).int(), sizes);
+        let (barcodes, _) = page(&file, &chunks[0]);
+        assert_eq!(barcodes, b"\x04\0\0\0ACGT\x05\0\0\0TTGCA");
+        let (counts, _) = page(&file, &chunks[1]);
+        assert_eq!(
+            counts,
+            [10_i64.to_le_bytes(), (-3_i64).to_le_bytes()].concat()
+        );
+        let (fractions, _) = page(&file, &chunks[2]);
+        assert_eq!(
+            fractions,
+            [0.25_f64.to_le_bytes(), 1.5_f64.to_le_bytes()].concat()
+        );
+        let metadata = footer.field(5).list();
+        assert_eq!(metadata[0].field(1).binary(), b"reads");
+        assert_eq!(metadata[0].field(2).binary(), b"42");
+        assert!(footer.field(6).binary().starts_with(b"fqbarcode version "));
+    }
+
+    #[test]
+    fn long_lists_and_an_empty_table() {
+        // More than 14 columns need the long form of the list header:
+        let names: Vec<String> = (0..20).map(|i| format!("c{i}")).collect();
+        let names: Vec<&str> = names.iter().map(|name| name.as_str()).collect();
+        let (file, footer) = write(&names, &[ColumnType::Int64; 20], &[]);
+        assert_eq!(footer.field(2).list().len(), 21);
+        assert_eq!(footer.field(3).int(), 0);
+        let chunks = footer.field(4).list()[0].field(1).list();
+        assert_eq!(chunks.len(), 20);
+        for chunk in chunks.iter() {
+            let (values, count) = page(&file, chunk);
+            assert!(values.is_empty());
+            assert_eq!(count, 0);
+        }
+    }
+
+    #[test]
+    fn row_groups_are_split() {
+        let rows: Vec<Vec<String>> = (0..ROW_GROUP_ROWS + 3)
+            .map(|i| vec![i.to_string()])
+            .collect();
+        let (file, footer) = write(&["count"], &[ColumnType::Int64], &rows);
+        assert_eq!(footer.field(3).int(), rows.len() as i64);
+        let row_groups = footer.field(4).list();
+        assert_eq!(row_groups.len(), 2);
+        assert_eq!(row_groups[0].field(3).int(), ROW_GROUP_ROWS as i64);
+        assert_eq!(row_groups[1].field(3).int(), 3);
+        let (values, count) = page(&file, &row_groups[1].field(1).list()[0]);
+        assert_eq!(count, 3);
+        let first = i64::from_le_bytes(values[..8].try_into().unwrap());
+        assert_eq!(first, ROW_GROUP_ROWS as i64);
+    }
+}
@@ -0,0 +1,62 @@
+use crate::whitelist::open_text;
+use regex::Regex;
+use simple_eyre::eyre::{eyre, Report};
+use std::collections::BTreeMap;
+use std::io::BufRead;
+use std::path::Path;
+
+/// Read one regular expression per line from a pattern file, skipping blank lines and `#`
+/// comments.
+pub fn read_patterns(path: &Path) -> Result<Vec<Regex>, Report> {
+    let mut patterns: Vec<Regex> = Vec::new();
+    for line in open_text(path)?.lines() {
+        let line = line?;
+        let expression = line.trim();
+        if expression.is_empty() || expression.starts_with('#') {
+            continue;
+        }
+        patterns.push(
+            Regex::new(expression)
+                .map_err(|e| eyre!("invalid pattern {expression} in pattern file: {e}"))?,
+        );
+    }
+    Ok(patterns)
+}
+
+/// The reads matched by each set of patterns, when every pattern is evaluated on every read.
+#[derive(Default)]
+pub struct Conflicts {
+    pub sets: BTreeMap<Vec<usize>, u64>,
+}
+
+impl Conflicts {
+    /// Record the (ascending) indexes of the patterns that matched a read.
+    pub fn add(&mut self, matched: &[usize]) {
+        if !matched.is_empty() {
+            *self.sets.entry(matched.to_vec()).or_insert(0_u64) += 1_u64;
+        }
+    }
+
+    /// The number of reads matching more than one pattern.
+    pub fn conflicted(&self) -> u64 {
+        self.sets
+            .iter()
+            .filter(|(set, _)| set.len() > 1)
+            .map(|(_, count)| count)
+            .sum()
+    }
+
+    /// The `n` × `n` matrix of reads matching both patterns `i` and `j`, with the reads
+    /// matching each pattern on the diagonal.
+    pub fn matrix(&self, n: usize) -> Vec<Vec<u64>> {
+        let mut matrix = vec![vec![0_u64; n]; n];
+        for (set, count) in self.sets.iter() {
+            for i in set.iter() {
+                for j in set.iter() {
+                    matrix[*i][*j] += count;
+                }
+            }
+        }
+        matrix
+    }
+}
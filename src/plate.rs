@@ -0,0 +1,161 @@
+use crate::index::CandidateIndex;
+use crate::whitelist::open_text;
+use simple_eyre::eyre::{eyre, Report};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufWriter, Write};
+use std::path::Path;
+
+/// The row or column barcodes of a combinatorial plate layout, with their labels and
+/// (optional) plates.
+pub struct Component {
+    pub labels: Vec<String>,
+    pub plates: Vec<String>,
+    index: CandidateIndex,
+    distance: usize,
+    assigned: HashMap<String, Option<usize>>,
+}
+
+impl Component {
+    /// Read a (barcode, label, [plate]) file, correcting barcodes within `distance` edits.
+    pub fn read(path: &Path, distance: usize) -> Result<Component, Report> {
+        let mut barcodes: Vec<String> = Vec::new();
+        let mut labels: Vec<String> = Vec::new();
+        let mut plates: Vec<String> = Vec::new();
+        for (i, line) in open_text(path)?.lines().enumerate() {
+            let line = line?;
+            let mut fields = line.split_ascii_whitespace();
+            let Some(barcode) = fields.next().filter(|s| !s.starts_with('#')) else {
+                continue;
+            };
+            let label = fields.next().ok_or_else(|| {
+                eyre!("line {} of {} has no label", i + 1, path.to_string_lossy())
+            })?;
+            barcodes.push(barcode.to_string());
+            labels.push(label.to_string());
+            plates.push(fields.next().unwrap_or_default().to_string());
+        }
+        Ok(Component {
+            labels,
+            plates,
+            index: CandidateIndex::new(barcodes, distance),
+            distance,
+            assigned: HashMap::new(),
+        })
+    }
+
+    pub fn barcode(&self, i: usize) -> &str {
+        self.index.get(i)
+    }
+
+    /// Assign a barcode to the unique nearest entry within the distance threshold. The
+    /// assignments are cached, as each barcode is usually seen many times.
+    pub fn assign(&mut self, barcode: &str) -> Option<usize> {
+        if let Some(assigned) = self.assigned.get(barcode) {
+            return *assigned;
+        }
+        let assigned = self.index.nearest(barcode, self.distance);
+        self.assigned.insert(barcode.to_owned(), assigned);
+        assigned
+    }
+}
+
+/// A combinatorial plate layout, in which each cell is identified by a (row, column) pair
+/// of barcodes. Rows and columns must be on the same plate, unless either has no plate.
+pub struct Layout {
+    pub rows: Component,
+    pub columns: Component,
+}
+
+impl Layout {
+    pub fn compatible(&self, row: usize, column: usize) -> bool {
+        let (a, b) = (&self.rows.plates[row], &self.columns.plates[column]);
+        a.is_empty() || b.is_empty() || a == b
+    }
+
+    /// The plate of a cell (empty for a single, unnamed plate).
+    pub fn plate(&self, row: usize, column: usize) -> &str {
+        match self.rows.plates[row].is_empty() {
+            true => &self.columns.plates[column],
+            false => &self.rows.plates[row],
+        }
+    }
+
+    /// Whether any row or column is given a plate.
+    pub fn plated(&self) -> bool {
+        self.rows
+            .plates
+            .iter()
+            .chain(self.columns.plates.iter())
+            .any(|plate| !plate.is_empty())
+    }
+
+    /// The key that the reads of a cell are counted under: the row and column barcodes
+    /// joined by `separator`.
+    pub fn key(&self, row: usize, column: usize, separator: &str) -> String {
+        format!(
+            "{}{separator}{}",
+            self.rows.barcode(row),
+            self.columns.barcode(column)
+        )
+    }
+
+    /// All the possible cells, in row then column order.
+    pub fn cells(&self) -> Vec<(usize, usize)> {
+        (0..self.rows.labels.len())
+            .flat_map(|row| (0..self.columns.labels.len()).map(move |column| (row, column)))
+            .filter(|(row, column)| self.compatible(*row, *column))
+            .collect()
+    }
+
+    /// Write a grid of the cell counts of each plate to `<prefix><plate>.tsv`, with the
+    /// rows and columns in the order of their files.
+    pub fn write_grids(
+        &self,
+        prefix: &str,
+        counts: &HashMap<String, u64>,
+        separator: &str,
+    ) -> Result<Vec<String>, Report> {
+        let mut plates: Vec<&str> = Vec::new();
+        for (row, column) in self.cells() {
+            let plate = self.plate(row, column);
+            if !plates.contains(&plate) {
+                plates.push(plate);
+            }
+        }
+        let mut paths: Vec<String> = Vec::new();
+        for plate in plates {
+            let on_plate = |p: &String| p.is_empty() || p == plate;
+            let rows: Vec<usize> = (0..self.rows.labels.len())
+                .filter(|i| on_plate(&self.rows.plates[*i]))
+                .collect();
+            let columns: Vec<usize> = (0..self.columns.labels.len())
+                .filter(|i| on_plate(&self.columns.plates[*i]))
+                .collect();
+            let path = match plate.is_empty() {
+                true => format!("{prefix}plate.tsv"),
+                false => format!("{prefix}{plate}.tsv"),
+            };
+            let mut buffer = BufWriter::new(File::create(&path)?);
+            write!(buffer, "row")?;
+            for column in columns.iter() {
+                write!(buffer, "\t{}", self.columns.labels[*column])?;
+            }
+            writeln!(buffer)?;
+            for row in rows.iter() {
+                write!(buffer, "{}", self.rows.labels[*row])?;
+                for column in columns.iter() {
+                    let count = counts
+                        .get(&self.key(*row, *column, separator))
+                        .copied()
+                        .unwrap_or(0);
+                    write!(buffer, "\t{count}")?;
+                }
+                writeln!(buffer)?;
+            }
+            buffer.flush()?;
+            paths.push(path);
+        }
+        Ok(paths)
+    }
+}
@@ -0,0 +1,162 @@
+use simple_eyre::eyre::Report;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+/// Decode a single quality character into a Phred score.
+pub fn phred(q: u8, offset: u8) -> u8 {
+    q.saturating_sub(offset)
+}
+
+/// The mean Phred score of a quality string, or zero if it is empty.
+pub fn mean_quality(qual: &[u8], offset: u8) -> f64 {
+    if qual.is_empty() {
+        return 0_f64;
+    }
+    let sum: u64 = qual.iter().map(|q| phred(*q, offset) as u64).sum();
+    sum as f64 / qual.len() as f64
+}
+
+/// The outcome of the quality checks of a barcode region: its mean quality, if that is
+/// checked, and which of the mean and per-base minimums it falls below. A barcode has to pass
+/// both checks to be counted.
+pub struct BarcodeQuality {
+    pub mean: Option<f64>,
+    pub low_mean: bool,
+    pub low_base: bool,
+}
+
+impl BarcodeQuality {
+    /// Check the qualities of a barcode region against a minimum mean and a minimum for
+    /// every base, each only if given.
+    pub fn check(qual: &[u8], offset: u8, min_mean: Option<f64>, min_base: Option<u8>) -> Self {
+        let mean = min_mean.map(|_| mean_quality(qual, offset));
+        BarcodeQuality {
+            mean,
+            low_mean: mean.zip(min_mean).is_some_and(|(mean, min)| mean < min),
+            low_base: min_base.is_some_and(|min| qual.iter().any(|q| phred(*q, offset) < min)),
+        }
+    }
+
+    pub fn failed(&self) -> bool {
+        self.low_mean || self.low_base
+    }
+}
+
+/// Per-position quality score totals across many reads, grouped by read length.
+#[derive(Default)]
+pub struct QualityProfile {
+    lengths: BTreeMap<usize, (u64, Vec<u64>)>,
+}
+
+impl QualityProfile {
+    pub fn add(&mut self, qual: &[u8], offset: u8) {
+        let (reads, sums) = self
+            .lengths
+            .entry(qual.len())
+            .or_insert_with(|| (0_u64, vec![0_u64; qual.len()]));
+        *reads += 1_u64;
+        for (sum, q) in sums.iter_mut().zip(qual.iter()) {
+            *sum += phred(*q, offset) as u64;
+        }
+    }
+
+    /// Write the mean quality at each (1-based) position of each length class to file.
+    pub fn write(&self, path: &Path) -> Result<(), Report> {
+        let mut buffer = BufWriter::new(File::create(path)?);
+        writeln!(buffer, "length\tposition\treads\tmean_quality")?;
+        for (length, (reads, sums)) in self.lengths.iter() {
+            for (position, sum) in sums.iter().enumerate() {
+                let mean = *sum as f64 / *reads as f64;
+                writeln!(buffer, "{length}\t{}\t{reads}\t{mean:0.2}", position + 1)?;
+            }
+        }
+        buffer.flush()?;
+        Ok(())
+    }
+}
+
+/// Per-position quality score totals of the reads of each barcode, from the qualities of the
+/// bases the barcode was read from.
+#[derive(Default)]
+pub struct BarcodeQualities {
+    barcodes: HashMap<String, (u64, Vec<u64>)>,
+}
+
+impl BarcodeQualities {
+    /// Add the qualities of a read of a barcode, returning `false` (and leaving them out) if
+    /// they can't be lined up with the barcode because their lengths differ.
+    pub fn add(&mut self, barcode: &str, qual: &[u8], offset: u8) -> bool {
+        if qual.len() != barcode.len() {
+            return false;
+        }
+        let (reads, sums) = match self.barcodes.get_mut(barcode) {
+            Some(totals) => totals,
+            None => self
+                .barcodes
+                .entry(barcode.to_owned())
+                .or_insert_with(|| (0_u64, vec![0_u64; qual.len()])),
+        };
+        *reads += 1_u64;
+        for (sum, q) in sums.iter_mut().zip(qual.iter()) {
+            *sum += phred(*q, offset) as u64;
+        }
+        true
+    }
+
+    /// The mean quality at each position of each barcode.
+    pub fn means(self) -> HashMap<String, Vec<f64>> {
+        self.barcodes
+            .into_iter()
+            .map(|(barcode, (reads, sums))| {
+                let means = sums.iter().map(|sum| *sum as f64 / reads as f64).collect();
+                (barcode, means)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn passes_mean_but_fails_per_base_minimum() {
+        // Four Q40 bases and one Q2 base have a mean of 32.4:
+        let check = BarcodeQuality::check(b"IIII#", 33, Some(30_f64), Some(20));
+        assert!(!check.low_mean);
+        assert!(check.low_base);
+        assert!(check.failed());
+    }
+
+    #[test]
+    fn passes_per_base_minimum_but_fails_mean() {
+        // Every base is Q20, above the per-base minimum but below the mean minimum:
+        let check = BarcodeQuality::check(b"55555", 33, Some(30_f64), Some(15));
+        assert_eq!(check.mean, Some(20_f64));
+        assert!(check.low_mean);
+        assert!(!check.low_base);
+        assert!(check.failed());
+    }
+
+    #[test]
+    fn barcode_qualities_must_line_up() {
+        let mut qualities = BarcodeQualities::default();
+        assert!(qualities.add("ACGT", b"II55", 33));
+        assert!(qualities.add("ACGT", b"5555", 33));
+        assert!(!qualities.add("ACGT", b"IIIIII", 33));
+        assert!(!qualities.add("AC+GT", b"IIII", 33));
+        let means = qualities.means();
+        assert_eq!(means["ACGT"], vec![30_f64, 30_f64, 20_f64, 20_f64]);
+        assert!(!means.contains_key("AC+GT"));
+    }
+
+    #[test]
+    fn checks_only_apply_if_given() {
+        let check = BarcodeQuality::check(b"IIII#", 33, None, None);
+        assert_eq!(check.mean, None);
+        assert!(!check.failed());
+        assert!(!BarcodeQuality::check(b"IIIII", 33, Some(30_f64), Some(20)).failed());
+    }
+}
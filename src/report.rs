@@ -0,0 +1,39 @@
+use crate::histogram::Histogram;
+use simple_eyre::eyre::Report;
+use std::fmt::Display;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+/// A run report made up of (section, metric, value) records, written as a tab-delimited table.
+#[derive(Default)]
+pub struct RunReport {
+    records: Vec<(String, String, String)>,
+}
+
+impl RunReport {
+    pub fn add(&mut self, section: &str, metric: impl Display, value: impl Display) {
+        self.records
+            .push((section.to_string(), metric.to_string(), value.to_string()));
+    }
+
+    /// Add every bin of a histogram as a metric of the given section.
+    pub fn add_histogram(&mut self, section: &str, histogram: &Histogram) {
+        for (bin, count) in histogram.iter() {
+            self.add(section, bin, count);
+        }
+    }
+
+    pub fn write(&self, path: &Path) -> Result<(), Report> {
+        self.write_to(&mut BufWriter::new(File::create(path)?))
+    }
+
+    pub fn write_to(&self, writer: &mut dyn Write) -> Result<(), Report> {
+        writeln!(writer, "section\tmetric\tvalue")?;
+        for (section, metric, value) in self.records.iter() {
+            writeln!(writer, "{section}\t{metric}\t{value}")?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,82 @@
+use std::collections::{BTreeSet, HashMap};
+
+/// An index of barcodes for finding them, with up to one substitution, anywhere in a read.
+///
+/// Each barcode is split into two halves, one of which must match exactly if the barcode is
+/// within one substitution, so the halves are used as seeds: every seed-length window of the
+/// read is looked up, and the barcodes it seeds are verified at the implied position.
+pub struct BarcodeIndex {
+    barcodes: Vec<String>,
+    seeds: HashMap<Vec<u8>, Vec<(usize, usize)>>,
+    seed_lengths: BTreeSet<usize>,
+}
+
+impl BarcodeIndex {
+    pub fn new<'a>(barcodes: impl Iterator<Item = &'a String>) -> Self {
+        let mut index = BarcodeIndex {
+            barcodes: Vec::new(),
+            seeds: HashMap::new(),
+            seed_lengths: BTreeSet::new(),
+        };
+        for barcode in barcodes.filter(|barcode| barcode.len() >= 2) {
+            let i = index.barcodes.len();
+            let bytes = barcode.as_bytes();
+            let half = bytes.len() / 2;
+            for (offset, seed) in [(0, &bytes[..half]), (half, &bytes[half..])] {
+                index.seed_lengths.insert(seed.len());
+                index
+                    .seeds
+                    .entry(seed.to_vec())
+                    .or_default()
+                    .push((i, offset));
+            }
+            index.barcodes.push(barcode.to_owned());
+        }
+        index
+    }
+
+    pub fn len(&self) -> usize {
+        self.barcodes.len()
+    }
+
+    /// The barcode found in a read with the fewest substitutions (at most one), or `None` if
+    /// there is none or the nearest is not unique.
+    pub fn find(&self, read: &str) -> Option<&str> {
+        let read = read.as_bytes();
+        // The best distance and the barcodes found at it:
+        let mut best: Option<(usize, BTreeSet<usize>)> = None;
+        for k in self.seed_lengths.iter().copied() {
+            for p in 0..read.len().saturating_sub(k - 1) {
+                let Some(candidates) = self.seeds.get(&read[p..p + k]) else {
+                    continue;
+                };
+                for (i, offset) in candidates.iter().copied() {
+                    let barcode = self.barcodes[i].as_bytes();
+                    let Some(start) = p.checked_sub(offset) else {
+                        continue;
+                    };
+                    let Some(window) = read.get(start..start + barcode.len()) else {
+                        continue;
+                    };
+                    let distance = window.iter().zip(barcode).filter(|(a, b)| a != b).count();
+                    if distance > 1 {
+                        continue;
+                    }
+                    match best.as_mut() {
+                        Some((d, found)) if *d == distance => {
+                            found.insert(i);
+                        }
+                        Some((d, _)) if *d < distance => (),
+                        _ => best = Some((distance, BTreeSet::from([i]))),
+                    }
+                }
+            }
+        }
+        match best {
+            Some((_, found)) if found.len() == 1 => {
+                found.first().map(|i| self.barcodes[*i].as_str())
+            }
+            _ => None,
+        }
+    }
+}
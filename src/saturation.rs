@@ -0,0 +1,112 @@
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha12Rng;
+use simple_eyre::eyre::{eyre, Report};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+/// The distinct barcodes seen in subsamples of the reads at several depths, from a single
+/// pass over the reads.
+///
+/// Each read is given a uniform random draw, and is in the subsample of every depth above
+/// its draw, so the subsamples are nested. A barcode is seen at a depth if the lowest draw
+/// of its reads is below it, so only that draw has to be kept for each barcode. The draws
+/// have their own stream of the seeded random number generator so that the rest of the run
+/// is unchanged.
+pub struct Saturation {
+    /// The depths, as fractions of the reads in increasing order.
+    depths: Vec<f64>,
+    rng: ChaCha12Rng,
+    draw: f64,
+    reads: Vec<u64>,
+    barcoded_reads: Vec<u64>,
+    lowest_draws: HashMap<String, f64>,
+}
+
+impl Saturation {
+    /// Subsample the reads at each of the depths (given as percentages of the reads).
+    pub fn new(percentages: &[f64], seed: u64) -> Result<Self, Report> {
+        let mut depths: Vec<f64> = Vec::new();
+        for percentage in percentages.iter() {
+            if !(*percentage > 0_f64 && *percentage <= 100_f64) {
+                return Err(eyre!(
+                    "saturation depth {percentage}% is not between 0% and 100%"
+                ));
+            }
+            depths.push(percentage / 100_f64);
+        }
+        depths.sort_by(|a, b| a.total_cmp(b));
+        depths.dedup();
+        let mut rng = ChaCha12Rng::seed_from_u64(seed);
+        rng.set_stream(1);
+        Ok(Saturation {
+            reads: vec![0_u64; depths.len()],
+            barcoded_reads: vec![0_u64; depths.len()],
+            depths,
+            rng,
+            draw: 0_f64,
+            lowest_draws: HashMap::new(),
+        })
+    }
+
+    /// The first of the depths whose subsample includes the last read drawn.
+    fn first_depth(&self) -> usize {
+        self.depths.partition_point(|depth| *depth < self.draw)
+    }
+
+    /// Draw a read, counting it in the subsamples that include it.
+    pub fn read(&mut self) {
+        // A draw in (0, 1] so that the full depth includes every read:
+        self.draw = 1_f64 - self.rng.gen::<f64>();
+        let first = self.first_depth();
+        for reads in self.reads[first..].iter_mut() {
+            *reads += 1_u64;
+        }
+    }
+
+    /// Count the barcode of the last read drawn.
+    pub fn barcode(&mut self, barcode: &str) {
+        let first = self.first_depth();
+        for reads in self.barcoded_reads[first..].iter_mut() {
+            *reads += 1_u64;
+        }
+        match self.lowest_draws.get_mut(barcode) {
+            Some(lowest) => *lowest = lowest.min(self.draw),
+            None => {
+                self.lowest_draws.insert(barcode.to_owned(), self.draw);
+            }
+        }
+    }
+
+    /// The percentage, reads, barcoded reads and distinct barcodes of each subsample.
+    pub fn points(&self) -> Vec<(f64, u64, u64, u64)> {
+        let mut barcodes = vec![0_u64; self.depths.len()];
+        for lowest in self.lowest_draws.values() {
+            let first = self.depths.partition_point(|depth| depth < lowest);
+            for n in barcodes[first..].iter_mut() {
+                *n += 1_u64;
+            }
+        }
+        self.depths
+            .iter()
+            .zip(self.reads.iter().zip(self.barcoded_reads.iter()))
+            .zip(barcodes)
+            .map(|((depth, (reads, barcoded)), barcodes)| {
+                (100_f64 * depth, *reads, *barcoded, barcodes)
+            })
+            .collect()
+    }
+
+    /// Write the subsamples as a table of their depth (as a percentage of the reads), reads,
+    /// barcoded reads and distinct barcodes.
+    pub fn write(&self, path: &Path) -> Result<(), Report> {
+        let mut buffer = BufWriter::new(File::create(path)?);
+        writeln!(buffer, "percent\treads\tbarcoded_reads\tbarcodes")?;
+        for (percent, reads, barcoded, barcodes) in self.points() {
+            writeln!(buffer, "{percent}\t{reads}\t{barcoded}\t{barcodes}")?;
+        }
+        buffer.flush()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,41 @@
+use crate::quality::phred;
+
+/// Per-read statistics gathered in a single pass over the sequence and quality bytes.
+pub struct ReadScan {
+    pub length: usize,
+    pub n_count: usize,
+    pub quality_sum: u64,
+}
+
+impl ReadScan {
+    pub fn new(seq: &[u8], qual: &[u8], phred_offset: u8) -> Self {
+        let mut scan = ReadScan {
+            length: seq.len(),
+            n_count: 0_usize,
+            quality_sum: 0_u64,
+        };
+        for (base, q) in seq.iter().copied().zip(qual.iter().copied()) {
+            if base == b'N' || base == b'n' {
+                scan.n_count += 1_usize;
+            }
+            scan.quality_sum += phred(q, phred_offset) as u64;
+        }
+        scan
+    }
+
+    /// The mean Phred score of the read, or zero if it is empty.
+    pub fn mean_quality(&self) -> f64 {
+        match self.length {
+            0 => 0_f64,
+            n => self.quality_sum as f64 / n as f64,
+        }
+    }
+
+    /// The fraction of the read's bases that are N, or zero if it is empty.
+    pub fn n_fraction(&self) -> f64 {
+        match self.length {
+            0 => 0_f64,
+            n => self.n_count as f64 / n as f64,
+        }
+    }
+}
@@ -0,0 +1,383 @@
+use crate::fastq::Record;
+use crate::scan::ReadScan;
+use crate::{anchor, header, sequence, trim};
+use regex::{Captures, Regex};
+use std::collections::HashMap;
+
+/// Why a read was filtered out before being searched for its barcode.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Filtered {
+    Header,
+    Chastity,
+    Length,
+    ReadQuality,
+    TooManyN,
+}
+
+/// The filters a read must pass to be searched for its barcode. The header filters apply to
+/// the read as it is, and the others to the read once it has been trimmed.
+pub struct ReadFilter {
+    pub header: Option<Regex>,
+    pub discard_filtered: bool,
+    pub min_length: Option<usize>,
+    pub max_length: Option<usize>,
+    pub min_quality: Option<f64>,
+    pub max_n_fraction: Option<f64>,
+    pub phred_offset: u8,
+}
+
+/// How reads are filtered, trimmed and searched for their barcode.
+///
+/// Searching depends on nothing but the read, so chunks of reads can be searched by worker
+/// threads, leaving the counting to be done in input order. Reads that are filtered out are
+/// not searched at all.
+pub struct Searcher {
+    pub expressions: Vec<Regex>,
+    /// The capture groups of each expression that the barcode replacement refers to.
+    pub barcode_groups: Vec<Vec<usize>>,
+    pub barcode_replacement: String,
+    pub umi_replacement: Option<String>,
+    pub trim_poly_g: bool,
+    pub trim_poly_a: bool,
+    pub poly_min_length: usize,
+    pub adapters: Vec<String>,
+    pub adapter_error_rate: f64,
+    pub adapter_min_overlap: usize,
+    /// Search the read header rather than the sequence.
+    pub from_header: bool,
+    pub both_strands: bool,
+    pub detect_conflicts: bool,
+    /// The rescue anchor and the length of the barcode following it.
+    pub rescue_anchor: Option<(String, usize)>,
+    pub filter: ReadFilter,
+}
+
+/// A barcode found in a (trimmed) read, or in its header.
+pub struct Barcode {
+    pub start: usize,
+    pub end: usize,
+    /// The spans of the capture groups making up the label, in the order they are used.
+    pub groups: Vec<(usize, usize)>,
+    pub label: String,
+    pub umi: Option<String>,
+}
+
+/// The outcome of searching a read.
+pub struct Search {
+    /// Where each trimmed tail or adapter starts, in the order they are trimmed.
+    pub poly_g: Option<usize>,
+    pub adapter: Option<usize>,
+    pub poly_a: Option<usize>,
+    /// The reverse complement of the trimmed read, if only it matched.
+    pub reverse: Option<String>,
+    /// The expressions matching the read, if conflicts are being detected.
+    pub matched: Vec<usize>,
+    /// Whether the anchor alignment needed an indel, if the read was rescued by it.
+    pub anchor_indel: Option<bool>,
+    pub barcode: Option<Barcode>,
+    /// Why the read was filtered out, if it was (when it has no barcode).
+    pub filtered: Option<Filtered>,
+    /// The fraction of the trimmed read that is N, if the N filter reached it.
+    pub n_fraction: Option<f64>,
+}
+
+impl Search {
+    /// The outcome for a read filtered out after trimming (if it got that far).
+    fn filtered(
+        filtered: Filtered,
+        trims: (Option<usize>, Option<usize>, Option<usize>),
+        n_fraction: Option<f64>,
+    ) -> Self {
+        Search {
+            poly_g: trims.0,
+            adapter: trims.1,
+            poly_a: trims.2,
+            reverse: None,
+            matched: Vec::new(),
+            anchor_indel: None,
+            barcode: None,
+            filtered: Some(filtered),
+            n_fraction,
+        }
+    }
+}
+
+/// The capture groups of `re` that a replacement expression refers to, as `$1`, `${1}`,
+/// `$name` or `${name}`, following the syntax of `Captures::expand`.
+pub fn replacement_groups(re: &Regex, replacement: &str) -> Vec<usize> {
+    let names: HashMap<&str, usize> = re
+        .capture_names()
+        .enumerate()
+        .filter_map(|(i, name)| name.map(|name| (name, i)))
+        .collect();
+    let mut groups: Vec<usize> = Vec::new();
+    let mut rest = replacement;
+    while let Some(i) = rest.find('$') {
+        rest = &rest[i + 1..];
+        // A `$$` is a literal `$`, and a `$` with an unclosed brace is taken literally:
+        let name = if let Some(escaped) = rest.strip_prefix('$') {
+            rest = escaped;
+            continue;
+        } else if let Some(braced) = rest.strip_prefix('{') {
+            let Some(end) = braced.find('}') else {
+                continue;
+            };
+            rest = &braced[end + 1..];
+            &braced[..end]
+        } else {
+            let end = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(rest.len());
+            let name = &rest[..end];
+            rest = &rest[end..];
+            name
+        };
+        let group = match name.parse::<usize>() {
+            Ok(group) => Some(group),
+            Err(_) => names.get(name).copied(),
+        };
+        if let Some(group) = group.filter(|group| *group < re.captures_len()) {
+            groups.push(group);
+        }
+    }
+    groups
+}
+
+/// The span of the read covered by the given capture groups, or the whole match if none of
+/// them took part in it.
+fn capture_span(c: &Captures, groups: &[usize]) -> (usize, usize) {
+    let span = groups.iter().filter_map(|group| c.get(*group)).fold(
+        None,
+        |span: Option<(usize, usize)>, m| match span {
+            Some((start, end)) => Some((start.min(m.start()), end.max(m.end()))),
+            None => Some((m.start(), m.end())),
+        },
+    );
+    span.unwrap_or_else(|| {
+        let m = c.get(0).expect("capture group 0 always participates");
+        (m.start(), m.end())
+    })
+}
+
+impl Searcher {
+    /// Filter the read on its header, trim poly-G tails, 3' adapters and then poly-A tails,
+    /// filter the read on what is left, and then look for the barcode in it.
+    pub fn search(&self, record: &Record) -> Search {
+        let filter = &self.filter;
+        let name = record.header.strip_prefix('@').unwrap_or(&record.header);
+        if filter.header.as_ref().is_some_and(|re| !re.is_match(name)) {
+            return Search::filtered(Filtered::Header, (None, None, None), None);
+        }
+        if filter.discard_filtered
+            && header::parse_casava_comment(&record.header).is_some_and(|c| c.filtered)
+        {
+            return Search::filtered(Filtered::Chastity, (None, None, None), None);
+        }
+        let mut seq = record.seq.as_bytes();
+        let poly_g = match self.trim_poly_g {
+            true => trim::find_poly_tail(seq, b'G', self.poly_min_length),
+            false => None,
+        };
+        seq = &seq[..poly_g.unwrap_or(seq.len())];
+        let adapter = self
+            .adapters
+            .iter()
+            .filter_map(|adapter| {
+                trim::find_adapter(
+                    seq,
+                    adapter.as_bytes(),
+                    self.adapter_error_rate,
+                    self.adapter_min_overlap,
+                )
+            })
+            .min();
+        seq = &seq[..adapter.unwrap_or(seq.len())];
+        let poly_a = match self.trim_poly_a {
+            true => trim::find_poly_tail(seq, b'A', self.poly_min_length),
+            false => None,
+        };
+        let end = poly_a.unwrap_or(seq.len());
+        let trims = (poly_g, adapter, poly_a);
+        if filter.min_length.is_some_and(|n| end < n) || filter.max_length.is_some_and(|n| end > n)
+        {
+            return Search::filtered(Filtered::Length, trims, None);
+        }
+        let mut n_fraction = None;
+        if filter.min_quality.is_some() || filter.max_n_fraction.is_some() {
+            let qual = record.qual.as_bytes();
+            let scan = ReadScan::new(
+                &seq[..end],
+                &qual[..end.min(qual.len())],
+                filter.phred_offset,
+            );
+            if filter.min_quality.is_some_and(|q| scan.mean_quality() < q) {
+                return Search::filtered(Filtered::ReadQuality, trims, None);
+            }
+            if let Some(max_n_fraction) = filter.max_n_fraction {
+                n_fraction = Some(scan.n_fraction());
+                if scan.n_fraction() > max_n_fraction {
+                    return Search::filtered(Filtered::TooManyN, trims, n_fraction);
+                }
+            }
+        }
+        let mut read = &record.seq[..end];
+        // Barcodes placed in the read name by the basecaller are searched for there instead:
+        if self.from_header {
+            read = record.header.strip_prefix('@').unwrap_or(&record.header);
+        }
+        // Reads that only match in reverse complement are searched in that orientation:
+        let mut reverse = None;
+        if self.both_strands && !self.expressions.iter().any(|re| re.is_match(read)) {
+            let reverse_read = sequence::reverse_complement(read);
+            if self.expressions.iter().any(|re| re.is_match(&reverse_read)) {
+                reverse = Some(reverse_read);
+            }
+        }
+        if let Some(reverse_read) = &reverse {
+            read = reverse_read;
+        }
+        // The expressions are tried in priority order, but if conflicts are being detected
+        // every one of them is evaluated:
+        let mut matched: Vec<usize> = Vec::new();
+        let captures = match self.detect_conflicts {
+            true => {
+                matched = (0..self.expressions.len())
+                    .filter(|i| self.expressions[*i].is_match(read))
+                    .collect();
+                matched
+                    .first()
+                    .and_then(|i| self.expressions[*i].captures(read).map(|c| (*i, c)))
+            }
+            false => self
+                .expressions
+                .iter()
+                .enumerate()
+                .find_map(|(i, re)| re.captures(read).map(|c| (i, c))),
+        };
+        // If no expression matches, fall back to slicing the barcode after the aligned
+        // anchor, if requested:
+        let mut anchor_indel = None;
+        let barcode = match (captures, &self.rescue_anchor) {
+            (Some((i, c)), _) => {
+                // Only the barcode's own groups count, not (for example) those of the UMI:
+                let (start, end) = capture_span(&c, &self.barcode_groups[i]);
+                let groups = self.barcode_groups[i]
+                    .iter()
+                    .filter_map(|group| c.get(*group))
+                    .map(|m| (m.start(), m.end()))
+                    .collect();
+                let mut label = String::new();
+                c.expand(&self.barcode_replacement, &mut label);
+                let umi = self.umi_replacement.as_ref().map(|replacement| {
+                    let mut umi = String::new();
+                    c.expand(replacement, &mut umi);
+                    umi
+                });
+                Some(Barcode {
+                    start,
+                    end,
+                    groups,
+                    label,
+                    umi,
+                })
+            }
+            (None, Some((anchor, length))) => {
+                anchor::align_anchor(read.as_bytes(), anchor.as_bytes(), 1)
+                    .filter(|m| m.distance > 0 && m.end + length <= read.len())
+                    .map(|m| {
+                        anchor_indel = Some(m.indel);
+                        Barcode {
+                            start: m.end,
+                            end: m.end + length,
+                            groups: vec![(m.end, m.end + length)],
+                            label: read[m.end..m.end + length].to_string(),
+                            umi: None,
+                        }
+                    })
+            }
+            (None, None) => None,
+        };
+        Search {
+            poly_g,
+            adapter,
+            poly_a,
+            reverse,
+            matched,
+            anchor_indel,
+            barcode,
+            filtered: None,
+            n_fraction,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A searcher that only looks for the barcode of one expression.
+    fn searcher(expression: &str, barcode: &str, umi: Option<&str>) -> Searcher {
+        let re = Regex::new(expression).unwrap();
+        Searcher {
+            barcode_groups: vec![replacement_groups(&re, barcode)],
+            expressions: vec![re],
+            barcode_replacement: barcode.to_string(),
+            umi_replacement: umi.map(|umi| umi.to_string()),
+            trim_poly_g: false,
+            trim_poly_a: false,
+            poly_min_length: 10,
+            adapters: Vec::new(),
+            adapter_error_rate: 0.1_f64,
+            adapter_min_overlap: 3,
+            from_header: false,
+            both_strands: false,
+            detect_conflicts: false,
+            rescue_anchor: None,
+            filter: ReadFilter {
+                header: None,
+                discard_filtered: false,
+                min_length: None,
+                max_length: None,
+                min_quality: None,
+                max_n_fraction: None,
+                phred_offset: 33,
+            },
+        }
+    }
+
+    fn record(seq: &str) -> Record {
+        Record {
+            header: "@r".to_string(),
+            seq: seq.to_string(),
+            qual: "I".repeat(seq.len()),
+        }
+    }
+
+    #[test]
+    fn groups_referred_to() {
+        let re = Regex::new("(?P<bc>.{4})(.{2})(?P<umi>.{3})").unwrap();
+        assert_eq!(replacement_groups(&re, "$1"), vec![1]);
+        assert_eq!(replacement_groups(&re, "${2}_$bc"), vec![2, 1]);
+        assert_eq!(replacement_groups(&re, "${umi}x$0"), vec![3, 0]);
+        // Escaped, unclosed and unknown references refer to nothing:
+        assert!(replacement_groups(&re, "$$1 ${1 $9 $bcx").is_empty());
+    }
+
+    #[test]
+    fn span_of_the_barcode_groups_only() {
+        let search =
+            searcher("(.{4})GGGG(.{6})", "${2}", Some("${1}")).search(&record("AAAAGGGGCCCCTTTT"));
+        let barcode = search.barcode.unwrap();
+        assert_eq!((barcode.start, barcode.end), (8, 14));
+        assert_eq!(barcode.groups, vec![(8, 14)]);
+        assert_eq!(barcode.label, "CCCCTT");
+        assert_eq!(barcode.umi.as_deref(), Some("AAAA"));
+    }
+
+    #[test]
+    fn span_of_the_match_without_barcode_groups() {
+        let search = searcher("AC(GT)", "fixed", None).search(&record("TTACGTTT"));
+        let barcode = search.barcode.unwrap();
+        assert_eq!((barcode.start, barcode.end), (2, 6));
+    }
+}
@@ -0,0 +1,94 @@
+use simple_eyre::eyre::Report;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+/// The fraction of G and C among the unambiguous (A, C, G, T) bases of `seq`.
+///
+/// N and other ambiguity codes are excluded from the denominator, so `None` is returned if
+/// there are no unambiguous bases at all.
+pub fn gc_fraction(seq: &[u8]) -> Option<f64> {
+    let mut gc = 0_usize;
+    let mut at = 0_usize;
+    for base in seq {
+        match base.to_ascii_uppercase() {
+            b'G' | b'C' => gc += 1_usize,
+            b'A' | b'T' => at += 1_usize,
+            _ => (),
+        }
+    }
+    match gc + at {
+        0 => None,
+        n => Some(gc as f64 / n as f64),
+    }
+}
+
+/// The reverse complement of a sequence, leaving N and other ambiguity codes as they are.
+pub fn reverse_complement(seq: &str) -> String {
+    seq.bytes()
+        .rev()
+        .map(|base| match base {
+            b'A' => 'T',
+            b'C' => 'G',
+            b'G' => 'C',
+            b'T' => 'A',
+            b'a' => 't',
+            b'c' => 'g',
+            b'g' => 'c',
+            b't' => 'a',
+            other => other as char,
+        })
+        .collect()
+}
+
+/// The bases (with N for any other letter) counted at each barcode position.
+const BASES: [u8; 5] = [b'A', b'C', b'G', b'T', b'N'];
+
+/// Per-position base counts across many barcodes, grouped by barcode length.
+#[derive(Default)]
+pub struct BaseComposition {
+    lengths: BTreeMap<usize, (u64, Vec<[u64; 5]>)>,
+}
+
+impl BaseComposition {
+    pub fn add(&mut self, seq: &[u8]) {
+        let (barcodes, counts) = self
+            .lengths
+            .entry(seq.len())
+            .or_insert_with(|| (0_u64, vec![[0_u64; 5]; seq.len()]));
+        *barcodes += 1_u64;
+        for (counts, base) in counts.iter_mut().zip(seq.iter()) {
+            let i = match base.to_ascii_uppercase() {
+                b'A' => 0,
+                b'C' => 1,
+                b'G' => 2,
+                b'T' => 3,
+                _ => 4,
+            };
+            counts[i] += 1_u64;
+        }
+    }
+
+    /// Write the fraction of each base at each (1-based) position of each length class to
+    /// file.
+    pub fn write(&self, path: &Path) -> Result<(), Report> {
+        let mut buffer = BufWriter::new(File::create(path)?);
+        write!(buffer, "length\tposition\tbarcodes")?;
+        for base in BASES.iter() {
+            write!(buffer, "\t{}", *base as char)?;
+        }
+        writeln!(buffer)?;
+        for (length, (barcodes, counts)) in self.lengths.iter() {
+            for (position, counts) in counts.iter().enumerate() {
+                write!(buffer, "{length}\t{}\t{barcodes}", position + 1)?;
+                for count in counts.iter() {
+                    write!(buffer, "\t{:0.4}", *count as f64 / *barcodes as f64)?;
+                }
+                writeln!(buffer)?;
+            }
+        }
+        buffer.flush()?;
+        Ok(())
+    }
+}
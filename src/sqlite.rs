@@ -0,0 +1,220 @@
+use simple_eyre::eyre::{eyre, Report};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::process::{Child, ChildStdin, Command, Stdio};
+
+/// The columns of the `barcodes` table and their types. These are the columns of every kind
+/// of run (those a run does not have are left null), so that runs with different options can
+/// be accumulated in one database.
+const BARCODE_COLUMNS: [(&str, &str); 18] = [
+    ("count", "INTEGER"),
+    ("barcode", "TEXT"),
+    ("barcode_1", "TEXT"),
+    ("barcode_2", "TEXT"),
+    ("guide_name", "TEXT"),
+    ("plate", "TEXT"),
+    ("row", "TEXT"),
+    ("column", "TEXT"),
+    ("name", "TEXT"),
+    ("gene", "TEXT"),
+    ("distinct_reads", "INTEGER"),
+    ("distinct_umis", "INTEGER"),
+    ("forward_reads", "INTEGER"),
+    ("reverse_reads", "INTEGER"),
+    ("rescued", "INTEGER"),
+    ("length", "INTEGER"),
+    ("fraction", "REAL"),
+    ("cumulative_fraction", "REAL"),
+];
+
+/// The run totals of the `runs` table, after its `sample`.
+const RUN_COLUMNS: [&str; 5] = [
+    "total_reads",
+    "barcoded_reads",
+    "unmatched_reads",
+    "barcodes_before_merging",
+    "barcodes_after_merging",
+];
+
+/// Quote a string as an SQL literal.
+pub fn quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "''"))
+}
+
+/// A numeric field as an SQL literal, or `NULL` if it is empty or not a finite number (which
+/// would not be valid SQL unquoted).
+pub fn number(value: &str) -> String {
+    match value.parse::<f64>() {
+        Ok(number) if number.is_finite() => value.to_owned(),
+        _ => "NULL".to_string(),
+    }
+}
+
+/// The statements creating the tables, if the database does not have them yet: a row of
+/// `runs` for each run, with the `barcodes` and `unassigned` counts of each run keyed by its
+/// `run` number.
+pub fn schema() -> String {
+    let runs: Vec<String> = RUN_COLUMNS
+        .iter()
+        .map(|name| format!("{name} INTEGER"))
+        .collect();
+    let barcodes: Vec<String> = BARCODE_COLUMNS
+        .iter()
+        .map(|(name, kind)| format!("\"{name}\" {kind}"))
+        .collect();
+    format!(
+        "CREATE TABLE IF NOT EXISTS runs (run INTEGER PRIMARY KEY, sample TEXT, {}, \
+        created TEXT DEFAULT CURRENT_TIMESTAMP);\n\
+        CREATE TABLE IF NOT EXISTS barcodes (run INTEGER NOT NULL REFERENCES runs (run), {});\n\
+        CREATE TABLE IF NOT EXISTS unassigned (run INTEGER NOT NULL REFERENCES runs (run), \
+        name TEXT, count INTEGER);\n",
+        runs.join(", "),
+        barcodes.join(", ")
+    )
+}
+
+/// A SQLite database, written by piping SQL statements into the `sqlite3` shell. Flushing
+/// it ends the statements and waits for `sqlite3` to finish writing the database, so that a
+/// failure is reported; a run that fails before then leaves the database as it was.
+pub struct Database {
+    child: Child,
+    stdin: Option<BufWriter<ChildStdin>>,
+}
+
+impl Database {
+    pub fn open(path: &Path) -> Result<Self, Report> {
+        let mut child = Command::new("sqlite3")
+            .arg("-bail")
+            .arg(path)
+            .stdin(Stdio::piped())
+            .spawn()
+            .map_err(|e| {
+                eyre!("cannot run sqlite3 to write the database (is it installed?): {e}")
+            })?;
+        let stdin = child.stdin.take().expect("the child's input is piped");
+        Ok(Database {
+            child,
+            stdin: Some(BufWriter::new(stdin)),
+        })
+    }
+}
+
+impl Write for Database {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.stdin.as_mut() {
+            Some(stdin) => stdin.write(buf),
+            None => Err(io::Error::other("the database has already been written")),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if let Some(mut stdin) = self.stdin.take() {
+            stdin.flush()?;
+            drop(stdin);
+            let status = self.child.wait()?;
+            if !status.success() {
+                return Err(io::Error::other(format!(
+                    "sqlite3 failed to write the database ({status})"
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::{Columns, Row, TableFormat, TableWriter};
+
+    #[test]
+    fn literals() {
+        assert_eq!(quote("it's"), "'it''s'");
+        assert_eq!(number("12"), "12");
+        assert_eq!(number("0.25"), "0.25");
+        assert_eq!(number(""), "NULL");
+        assert_eq!(number("NaN"), "NULL");
+        assert_eq!(number("1); DROP TABLE runs; --"), "NULL");
+    }
+
+    /// Query a database with the `sqlite3` shell, one line per row with `|` between fields.
+    fn query(path: &Path, sql: &str) -> Vec<String> {
+        let output = Command::new("sqlite3").arg(path).arg(sql).output().unwrap();
+        assert!(output.status.success(), "{sql} failed");
+        String::from_utf8(output.stdout)
+            .unwrap()
+            .lines()
+            .map(|line| line.to_owned())
+            .collect()
+    }
+
+    /// Write a run of two barcodes and an unassigned count to the database.
+    fn write_run(path: &Path, sample: &str, count: u64) {
+        let names = ["sample", "count", "barcode", "guide_name", "fraction"];
+        let columns = Columns::new(names.map(|name| name.to_owned()).to_vec(), None, true);
+        let mut table = TableWriter::new(
+            Database::open(path).unwrap(),
+            Some(sample.to_owned()),
+            columns.unwrap(),
+            TableFormat::Sqlite,
+        )
+        .with_metadata(vec![("total_reads", count + 5), ("barcoded_reads", count)]);
+        for (label, fraction) in [("ACGT\tguide 'a'", "0.75"), ("TTTT\tb", "NaN")] {
+            let row = Row {
+                label: label.to_owned(),
+                columns: vec![fraction.to_owned()],
+                ..Row::new("", count)
+            };
+            table.write(&row).unwrap();
+        }
+        table.write_count("no_barcode", 5).unwrap();
+        table.flush().unwrap();
+    }
+
+    #[test]
+    fn runs_are_written_and_accumulated() {
+        if Command::new("sqlite3").arg("-version").output().is_err() {
+            eprintln!("sqlite3 is not installed; skipping");
+            return;
+        }
+        let path = std::env::temp_dir().join(format!("fqbarcode-{}.sqlite", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        write_run(&path, "it's", 10);
+        write_run(&path, "second", 20);
+        assert_eq!(
+            query(
+                &path,
+                "SELECT run, sample, total_reads, barcoded_reads, unmatched_reads FROM runs"
+            ),
+            ["1|it's|15|10|", "2|second|25|20|"]
+        );
+        assert_eq!(
+            query(
+                &path,
+                "SELECT run, count, barcode, guide_name, fraction, typeof(fraction) FROM barcodes"
+            ),
+            [
+                "1|10|ACGT|guide 'a'|0.75|real",
+                "1|10|TTTT|b||null",
+                "2|20|ACGT|guide 'a'|0.75|real",
+                "2|20|TTTT|b||null"
+            ]
+        );
+        assert_eq!(
+            query(&path, "SELECT run, name, count FROM unassigned"),
+            ["1|no_barcode|5", "2|no_barcode|5"]
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn failures_are_reported_by_flush() {
+        if Command::new("sqlite3").arg("-version").output().is_err() {
+            return;
+        }
+        let mut database = Database::open(Path::new("/nonexistent/fqbarcode.sqlite")).unwrap();
+        writeln!(database, "{}", schema()).unwrap();
+        assert!(database.flush().is_err());
+        assert!(database.write(b"SELECT 1;").is_err());
+    }
+}
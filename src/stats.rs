@@ -0,0 +1,369 @@
+use rand::Rng;
+use rand_distr::{Binomial, Distribution};
+use std::collections::BTreeMap;
+
+/// The number of barcodes observed with each count (the "counts of counts").
+pub fn counts_of_counts(counts: impl Iterator<Item = u64>) -> BTreeMap<u64, u64> {
+    let mut frequencies: BTreeMap<u64, u64> = BTreeMap::new();
+    for count in counts.filter(|count| *count > 0) {
+        *frequencies.entry(count).or_insert(0_u64) += 1_u64;
+    }
+    frequencies
+}
+
+/// A Chao1 richness estimate and its variance.
+pub struct Chao1 {
+    pub estimate: f64,
+    pub variance: f64,
+}
+
+/// Estimate the total number of distinct barcodes from the counts of counts using Chao1.
+///
+/// The classic estimator `S + F1²/2F2` is used when there are doubletons, and the
+/// bias-corrected form `S + F1(F1 - 1)/2(F2 + 1)` when there are none. Without singletons
+/// the estimate is simply the observed number of barcodes.
+pub fn chao1(frequencies: &BTreeMap<u64, u64>) -> Chao1 {
+    let observed: f64 = frequencies.values().sum::<u64>() as f64;
+    let f1 = frequencies.get(&1).copied().unwrap_or(0) as f64;
+    let f2 = frequencies.get(&2).copied().unwrap_or(0) as f64;
+    if f1 == 0_f64 {
+        return Chao1 {
+            estimate: observed,
+            variance: 0_f64,
+        };
+    }
+    if f2 > 0_f64 {
+        let r = f1 / f2;
+        Chao1 {
+            estimate: observed + f1 * f1 / (2_f64 * f2),
+            variance: f2 * (r.powi(4) / 4_f64 + r.powi(3) + r.powi(2) / 2_f64),
+        }
+    } else {
+        let estimate = observed + f1 * (f1 - 1_f64) / 2_f64;
+        let variance = f1 * (f1 - 1_f64) / 2_f64 + f1 * (2_f64 * f1 - 1_f64).powi(2) / 4_f64
+            - f1.powi(4) / (4_f64 * estimate);
+        Chao1 {
+            estimate,
+            variance: variance.max(0_f64),
+        }
+    }
+}
+
+/// The expected number of distinct barcodes seen when subsampling `depth` of the reads.
+pub fn rarefaction(frequencies: &BTreeMap<u64, u64>, depth: u64) -> f64 {
+    let total: u64 = frequencies.iter().map(|(count, n)| count * n).sum();
+    if depth >= total {
+        return frequencies.values().sum::<u64>() as f64;
+    }
+    let (total, depth) = (total as f64, depth as f64);
+    frequencies
+        .iter()
+        .map(|(count, n)| {
+            // The probability that none of this barcode's reads are sampled:
+            let mut missed = 1_f64;
+            for j in 0..*count {
+                missed *= (total - depth - j as f64) / (total - j as f64);
+                if missed < 1e-12 {
+                    missed = 0_f64;
+                    break;
+                }
+            }
+            *n as f64 * (1_f64 - missed)
+        })
+        .sum()
+}
+
+/// Fit a saturation curve `S(n) = a·n / (b + n)` to (depth, distinct) points, returning the
+/// asymptote `a` (the estimated complexity at infinite depth).
+///
+/// The fit is made by least squares on the linearised (Lineweaver-Burk) form
+/// `1/S = 1/a + (b/a)·(1/n)`, and `None` is returned if the points do not saturate.
+pub fn saturation_asymptote(points: &[(f64, f64)]) -> Option<f64> {
+    let points: Vec<(f64, f64)> = points
+        .iter()
+        .filter(|(n, s)| *n > 0_f64 && *s > 0_f64)
+        .map(|(n, s)| (1_f64 / n, 1_f64 / s))
+        .collect();
+    if points.len() < 2 {
+        return None;
+    }
+    let k = points.len() as f64;
+    let mean_x = points.iter().map(|(x, _)| x).sum::<f64>() / k;
+    let mean_y = points.iter().map(|(_, y)| y).sum::<f64>() / k;
+    let sxx: f64 = points.iter().map(|(x, _)| (x - mean_x).powi(2)).sum();
+    let sxy: f64 = points
+        .iter()
+        .map(|(x, y)| (x - mean_x) * (y - mean_y))
+        .sum();
+    if sxx == 0_f64 {
+        return None;
+    }
+    let intercept = mean_y - (sxy / sxx) * mean_x;
+    match intercept > 0_f64 {
+        true => Some(1_f64 / intercept),
+        false => None,
+    }
+}
+
+/// The number of sequences of `length` over an alphabet of `alphabet` letters within Hamming
+/// distance `distance` of a given sequence.
+pub fn hamming_ball_size(length: usize, distance: usize, alphabet: u32) -> f64 {
+    let mut binomial = 1_f64;
+    let mut size = 0_f64;
+    for i in 0..=distance.min(length) {
+        if i > 0 {
+            binomial *= (length - i + 1) as f64 / i as f64;
+        }
+        size += binomial * (alphabet as f64 - 1_f64).powi(i as i32);
+    }
+    size
+}
+
+/// The chance of spurious merging between unrelated barcodes.
+pub struct Collisions {
+    /// The probability that two random barcodes are within the threshold distance.
+    pub pair_probability: f64,
+    /// The expected number of such pairs among the barcodes.
+    pub expected: f64,
+    /// The probability of at least one such pair among the barcodes.
+    pub probability_any: f64,
+}
+
+/// Estimate how many of `barcodes` random DNA barcodes of `length` would be within
+/// `distance` of another purely by chance, birthday-problem style.
+///
+/// Substitutions only are considered, so this slightly underestimates the collisions under an
+/// edit distance that also allows insertions and deletions.
+pub fn collisions(barcodes: u64, length: usize, distance: usize) -> Collisions {
+    let pair_probability =
+        (hamming_ball_size(length, distance, 4) / 4_f64.powi(length as i32)).min(1_f64);
+    let pairs = barcodes as f64 * barcodes.saturating_sub(1) as f64 / 2_f64;
+    let expected = pairs * pair_probability;
+    Collisions {
+        pair_probability,
+        expected,
+        probability_any: 1_f64 - (-expected).exp(),
+    }
+}
+
+/// Bootstrap percentile intervals for the fractions of the first `counts.len()` categories
+/// of a count vector with `total` observations, resampling `replicates` times.
+///
+/// Each replicate draws a multinomial sample of `total` observations, by sequential binomial
+/// draws, and the interval is between the `alpha / 2` and `1 - alpha / 2` quantiles.
+pub fn bootstrap_intervals(
+    counts: &[u64],
+    total: u64,
+    replicates: usize,
+    alpha: f64,
+    rng: &mut impl Rng,
+) -> Vec<(f64, f64)> {
+    let mut samples: Vec<Vec<f64>> = vec![Vec::with_capacity(replicates); counts.len()];
+    for _ in 0..replicates {
+        let mut remaining_n = total;
+        let mut remaining_p = 1_f64;
+        for (i, count) in counts.iter().enumerate() {
+            let p = *count as f64 / total.max(1) as f64;
+            let x = match remaining_n > 0 && remaining_p > 0_f64 {
+                true => Binomial::new(remaining_n, (p / remaining_p).clamp(0_f64, 1_f64))
+                    .map_or(0, |binomial| binomial.sample(rng)),
+                false => 0,
+            };
+            samples[i].push(x as f64 / total.max(1) as f64);
+            remaining_n -= x;
+            remaining_p -= p;
+        }
+    }
+    samples
+        .into_iter()
+        .map(|mut fractions| {
+            fractions.sort_by(f64::total_cmp);
+            let quantile = |q: f64| {
+                let i = ((fractions.len() - 1) as f64 * q).round() as usize;
+                fractions.get(i).copied().unwrap_or(0_f64)
+            };
+            (quantile(alpha / 2_f64), quantile(1_f64 - alpha / 2_f64))
+        })
+        .collect()
+}
+
+/// The knee of a barcode rank curve (the counts in decreasing order): the index of the
+/// barcode furthest below the straight line from the first barcode to the last on log-log
+/// scales, where the curve turns from the real barcodes to the background.
+pub fn knee(counts: &[u64]) -> Option<usize> {
+    let counts: Vec<u64> = counts.iter().copied().filter(|count| *count > 0).collect();
+    if counts.len() < 3 {
+        return None;
+    }
+    let point = |i: usize| (((i + 1) as f64).log10(), (counts[i] as f64).log10());
+    let (first, last) = (point(0), point(counts.len() - 1));
+    let (dx, dy) = (last.0 - first.0, last.1 - first.1);
+    let length = (dx * dx + dy * dy).sqrt();
+    if length == 0_f64 {
+        return None;
+    }
+    (1..counts.len() - 1)
+        .map(|i| {
+            let (x, y) = point(i);
+            // The distance below the line (which is positive for a concave curve):
+            (i, (dy * (x - first.0) - dx * (y - first.1)) / length)
+        })
+        .filter(|(_, distance)| *distance > 0_f64)
+        .max_by(|(_, a), (_, b)| a.total_cmp(b))
+        .map(|(i, _)| i)
+}
+
+/// The inflection of a barcode rank curve (the counts in decreasing order), as in
+/// DropletUtils' `barcodeRanks`: the index of the barcode where the log count falls fastest
+/// with the log rank. Each run of equal counts is taken at its middle rank.
+pub fn inflection(counts: &[u64]) -> Option<usize> {
+    let mut points: Vec<(usize, f64, f64)> = Vec::new();
+    let mut start = 0_usize;
+    for i in 1..=counts.len() {
+        if i == counts.len() || counts[i] != counts[start] {
+            if counts[start] > 0 {
+                let rank = (start + i + 1) as f64 / 2_f64;
+                points.push((start, rank.log10(), (counts[start] as f64).log10()));
+            }
+            start = i;
+        }
+    }
+    points
+        .windows(2)
+        .map(|pair| {
+            let ((_, x0, y0), (i, x1, y1)) = (pair[0], pair[1]);
+            (i, (y1 - y0) / (x1 - x0))
+        })
+        .min_by(|(_, a), (_, b)| a.total_cmp(b))
+        .map(|(i, _)| i)
+}
+
+/// The skew of the distribution of reads over the barcodes.
+pub struct Diversity {
+    /// The Shannon entropy of the barcode fractions, in bits.
+    pub shannon_entropy: f64,
+    /// The Gini coefficient of the counts, from 0 for even counts towards 1 when a few
+    /// barcodes have all the reads.
+    pub gini: f64,
+    /// The ratio of the 90th to the 10th percentile count, which is undefined if the 10th
+    /// percentile is zero.
+    pub ratio_90_10: Option<f64>,
+}
+
+/// Measure the skew of the counts of the barcodes (including any with no reads, such as the
+/// guides of a library).
+pub fn diversity(counts: &[u64]) -> Diversity {
+    let mut sorted = counts.to_vec();
+    sorted.sort_unstable();
+    let total: u64 = sorted.iter().sum();
+    let n = sorted.len() as f64;
+    let shannon_entropy = sorted
+        .iter()
+        .filter(|count| **count > 0)
+        .map(|count| {
+            let p = *count as f64 / total as f64;
+            -p * p.log2()
+        })
+        .sum::<f64>();
+    // A single barcode sums to -0, which would be written with its sign:
+    let shannon_entropy = match shannon_entropy <= 0_f64 {
+        true => 0_f64,
+        false => shannon_entropy,
+    };
+    let gini = match total {
+        0 => 0_f64,
+        total => {
+            let weighted: f64 = sorted
+                .iter()
+                .enumerate()
+                .map(|(i, count)| (i + 1) as f64 * *count as f64)
+                .sum();
+            2_f64 * weighted / (n * total as f64) - (n + 1_f64) / n
+        }
+    };
+    // The nearest-rank percentiles:
+    let percentile = |p: f64| sorted[((p * n).ceil() as usize).clamp(1, sorted.len()) - 1];
+    let ratio_90_10 = match sorted.is_empty() {
+        true => None,
+        false => match percentile(0.1) {
+            0 => None,
+            low => Some(percentile(0.9) as f64 / low as f64),
+        },
+    };
+    Diversity {
+        shannon_entropy,
+        gini,
+        ratio_90_10,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frequencies(pairs: &[(u64, u64)]) -> BTreeMap<u64, u64> {
+        pairs.iter().copied().collect()
+    }
+
+    fn assert_close(a: f64, b: f64) {
+        assert!((a - b).abs() < 1e-9, "{a} is not {b}");
+    }
+
+    #[test]
+    fn chao1_with_doubletons() {
+        // 6 + 4² / (2 × 2), and 2 × (2⁴ / 4 + 2³ + 2² / 2):
+        let chao1 = chao1(&frequencies(&[(1, 4), (2, 2)]));
+        assert_close(chao1.estimate, 10_f64);
+        assert_close(chao1.variance, 28_f64);
+    }
+
+    #[test]
+    fn chao1_bias_corrected_without_doubletons() {
+        // 6 + 4 × 3 / 2, and 4 × 3 / 2 + 4 × 7² / 4 - 4⁴ / (4 × 12):
+        let chao1 = chao1(&frequencies(&[(1, 4), (3, 2)]));
+        assert_close(chao1.estimate, 12_f64);
+        assert_close(chao1.variance, 6_f64 + 49_f64 - 16_f64 / 3_f64);
+    }
+
+    #[test]
+    fn chao1_without_singletons() {
+        let chao1 = chao1(&frequencies(&[(2, 3), (5, 1)]));
+        assert_close(chao1.estimate, 4_f64);
+        assert_close(chao1.variance, 0_f64);
+    }
+
+    #[test]
+    fn chao1_all_singletons() {
+        // 5 + 5 × 4 / 2, and 5 × 4 / 2 + 5 × 9² / 4 - 5⁴ / (4 × 15):
+        let chao1 = chao1(&frequencies(&[(1, 5)]));
+        assert_close(chao1.estimate, 15_f64);
+        assert_close(chao1.variance, 10_f64 + 101.25_f64 - 625_f64 / 60_f64);
+    }
+
+    #[test]
+    fn hamming_ball_sizes() {
+        // 1 + 4 × 3, then + 6 × 3²:
+        assert_close(hamming_ball_size(4, 0, 4), 1_f64);
+        assert_close(hamming_ball_size(4, 1, 4), 13_f64);
+        assert_close(hamming_ball_size(4, 2, 4), 67_f64);
+        // A radius beyond the length covers every sequence:
+        assert_close(hamming_ball_size(2, 5, 4), 16_f64);
+    }
+
+    #[test]
+    fn expected_collisions() {
+        // 10 barcodes of 4 bases make 45 pairs, each within 1 with probability 13 / 4⁴:
+        let ten = collisions(10, 4, 1);
+        assert_close(ten.pair_probability, 13_f64 / 256_f64);
+        assert_close(ten.expected, 585_f64 / 256_f64);
+        assert_close(ten.probability_any, 1_f64 - (-585_f64 / 256_f64).exp());
+        assert_close(collisions(1, 4, 1).expected, 0_f64);
+    }
+
+    #[test]
+    fn diversity_of_a_single_barcode() {
+        let diversity = diversity(&[42]);
+        assert_eq!(diversity.shannon_entropy, 0_f64);
+        assert!(diversity.shannon_entropy.is_sign_positive());
+    }
+}
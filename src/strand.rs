@@ -0,0 +1,44 @@
+use crate::merge::Merge;
+use std::collections::HashMap;
+
+/// The reads of each barcode that matched in the forward and reverse-complement orientations.
+#[derive(Default)]
+pub struct StrandCounts {
+    barcodes: HashMap<String, [u64; 2]>,
+}
+
+impl StrandCounts {
+    pub fn add(&mut self, barcode: &str, reverse: bool) {
+        match self.barcodes.get_mut(barcode) {
+            Some(counts) => counts[reverse as usize] += 1_u64,
+            None => {
+                let mut counts = [0_u64; 2];
+                counts[reverse as usize] = 1_u64;
+                self.barcodes.insert(barcode.to_owned(), counts);
+            }
+        }
+    }
+
+    /// Add the orientation counts of merged barcodes to those of their endpoints.
+    pub fn merge(&mut self, merges: &[Merge]) {
+        for merge in merges.iter() {
+            if let Some([forward, reverse]) = self.barcodes.remove(&merge.barcode) {
+                let endpoint = self.barcodes.entry(merge.endpoint.to_owned()).or_default();
+                endpoint[0] += forward;
+                endpoint[1] += reverse;
+            }
+        }
+    }
+
+    /// The forward and reverse read counts of a barcode.
+    pub fn get(&self, barcode: &str) -> [u64; 2] {
+        self.barcodes.get(barcode).copied().unwrap_or_default()
+    }
+
+    /// The forward and reverse read counts over all barcodes.
+    pub fn total(&self) -> [u64; 2] {
+        self.barcodes.values().fold([0_u64; 2], |total, counts| {
+            [total[0] + counts[0], total[1] + counts[1]]
+        })
+    }
+}
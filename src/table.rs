@@ -0,0 +1,192 @@
+use crate::fastq::strip_compression;
+use crate::whitelist::open_text;
+use simple_eyre::eyre::{eyre, Report};
+use std::collections::HashMap;
+use std::io::BufRead;
+use std::path::Path;
+
+/// The names of the unassigned counts written after the barcodes of a table, which are kept
+/// apart from the barcodes when a table is read back.
+pub const UNASSIGNED: [&str; 16] = [
+    "other",
+    "no_barcode",
+    "header_filtered",
+    "chastity_filtered",
+    "length_filtered",
+    "low_read_quality",
+    "too_many_n",
+    "low_quality",
+    "implausible_barcode",
+    "barcode_length_filtered",
+    "not_whitelisted",
+    "no_guide",
+    "row_unassigned",
+    "column_unassigned",
+    "impossible_cell",
+    "excluded",
+];
+
+/// The extensions of barcode tables, which are left out of their sample names.
+const EXTENSIONS: [&str; 3] = [".tsv", ".csv", ".txt"];
+
+/// A file name without any table extension.
+pub fn strip_extension(name: &str) -> &str {
+    EXTENSIONS
+        .iter()
+        .find_map(|extension| name.strip_suffix(extension))
+        .unwrap_or(name)
+}
+
+/// Where the count and barcode of each row are in a table.
+struct Layout {
+    count: usize,
+    /// The barcode, or the two barcodes of a pair.
+    barcode: Vec<usize>,
+}
+
+impl Layout {
+    /// Find the count and barcode columns by name, from the header line of a table.
+    fn from_header(names: &[&str]) -> Option<Self> {
+        let position = |name: &str| names.iter().position(|n| *n == name);
+        let count = position("count")?;
+        let barcode = match (
+            position("barcode"),
+            position("barcode_1"),
+            position("barcode_2"),
+        ) {
+            (Some(barcode), _, _) => vec![barcode],
+            (None, Some(first), Some(second)) => vec![first, second],
+            _ => return None,
+        };
+        Some(Layout { count, barcode })
+    }
+
+    /// Guess the columns of a table without a header from its first row: the count comes
+    /// first, unless it follows a sample label, and is followed by the barcode (or both
+    /// barcodes, if pairs are being read).
+    fn guess(fields: &[&str], pairs: bool) -> Self {
+        let count = match fields.first().is_some_and(|f| f.parse::<u64>().is_err())
+            && fields.get(1).is_some_and(|f| f.parse::<u64>().is_ok())
+        {
+            true => 1,
+            false => 0,
+        };
+        let barcode = match pairs {
+            true => vec![count + 1, count + 2],
+            false => vec![count + 1],
+        };
+        Layout { count, barcode }
+    }
+}
+
+/// The counts of barcode tables read back, such as those written by `fqbarcode count`.
+#[derive(Default)]
+pub struct CountTable {
+    pub barcodes: HashMap<String, u64>,
+    /// The unassigned counts, in the order they were first seen.
+    pub unassigned: Vec<(String, u64)>,
+}
+
+impl CountTable {
+    /// Add the counts of a (possibly gzipped) TSV or CSV barcode table to those read so far,
+    /// skipping blank lines. The count and barcode columns are found by name if the table has
+    /// a header line, and otherwise are taken to be the first columns after any sample label,
+    /// so tables with any of the other columns can be read. The two barcodes of a pair are
+    /// joined with the pair `separator`, if one is given.
+    pub fn read(&mut self, path: &Path, separator: Option<&str>) -> Result<(), Report> {
+        let name = path.to_string_lossy();
+        let file_name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let delimiter = match strip_compression(&file_name) {
+            name if name.ends_with(".csv") => ',',
+            name if name.ends_with(".tsv") || name.ends_with(".txt") || !name.contains('.') => '\t',
+            _ => {
+                return Err(eyre!(
+                    "{name} is not a TSV or CSV table; only those can be read back"
+                ))
+            }
+        };
+        let mut layout: Option<Layout> = None;
+        for (i, line) in open_text(path)?.lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let fields: Vec<&str> = line
+                .split(delimiter)
+                .map(|field| field.trim_matches('"'))
+                .collect();
+            let layout = match &layout {
+                // The header lines of tables joined together are skipped:
+                Some(layout) if fields.get(layout.count) == Some(&"count") => continue,
+                Some(layout) => layout,
+                None if fields.contains(&"count") => {
+                    let header = Layout::from_header(&fields).ok_or_else(|| {
+                        eyre!("{name} has no barcode column, so its barcodes cannot be read")
+                    })?;
+                    if header.barcode.len() > 1 && separator.is_none() {
+                        return Err(eyre!(
+                            "{name} is a table of barcode pairs, which needs --pair-separator"
+                        ));
+                    }
+                    layout = Some(header);
+                    continue;
+                }
+                None => layout.insert(Layout::guess(&fields, separator.is_some())),
+            };
+            let count = fields.get(layout.count).copied().unwrap_or_default();
+            let count: u64 = count.parse().map_err(|_| {
+                eyre!(
+                    "the count {count} on line {} of {name} is not a number",
+                    i + 1
+                )
+            })?;
+            let first = fields
+                .get(layout.barcode[0])
+                .filter(|f| !f.is_empty())
+                .ok_or_else(|| eyre!("line {} of {name} has no barcode", i + 1))?;
+            if UNASSIGNED.contains(first) {
+                match self.unassigned.iter_mut().find(|(n, _)| n == first) {
+                    Some((_, total)) => *total += count,
+                    None => self.unassigned.push((first.to_string(), count)),
+                }
+                continue;
+            }
+            let barcode = match (layout.barcode.get(1), separator) {
+                (Some(second), Some(separator)) => {
+                    let second = fields
+                        .get(*second)
+                        .filter(|f| !f.is_empty())
+                        .ok_or_else(|| eyre!("line {} of {name} has no second barcode", i + 1))?;
+                    format!("{first}{separator}{second}")
+                }
+                _ => first.to_string(),
+            };
+            *self.barcodes.entry(barcode).or_insert(0_u64) += count;
+        }
+        Ok(())
+    }
+
+    /// Add the counts of another table to these.
+    pub fn add(&mut self, other: CountTable) {
+        for (barcode, count) in other.barcodes {
+            *self.barcodes.entry(barcode).or_insert(0_u64) += count;
+        }
+        for (name, count) in other.unassigned {
+            match self.unassigned.iter_mut().find(|(n, _)| *n == name) {
+                Some((_, total)) => *total += count,
+                None => self.unassigned.push((name, count)),
+            }
+        }
+    }
+
+    /// Take one of the unassigned counts out of the table, or zero if it has none.
+    pub fn take_unassigned(&mut self, name: &str) -> u64 {
+        match self.unassigned.iter().position(|(n, _)| n == name) {
+            Some(i) => self.unassigned.remove(i).1,
+            None => 0_u64,
+        }
+    }
+}
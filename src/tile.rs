@@ -0,0 +1,84 @@
+use crate::header::{parse_read_location, ReadLocation};
+use simple_eyre::eyre::Report;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+/// Matched and unmatched read counts for each tile of each lane.
+#[derive(Default)]
+pub struct TileStats {
+    tiles: BTreeMap<ReadLocation, (u64, u64)>,
+    pub unparsed: u64,
+}
+
+impl TileStats {
+    /// Count a read against the tile given in its header, if it can be parsed.
+    pub fn add(&mut self, header: &str, matched: bool) {
+        match parse_read_location(header) {
+            Some(location) => {
+                let (m, u) = self.tiles.entry(location).or_insert((0_u64, 0_u64));
+                match matched {
+                    true => *m += 1_u64,
+                    false => *u += 1_u64,
+                }
+            }
+            None => self.unparsed += 1_u64,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.tiles.len()
+    }
+
+    fn unmatched_rates(&self) -> impl Iterator<Item = (ReadLocation, f64)> + '_ {
+        self.tiles
+            .iter()
+            .map(|(location, (m, u))| (*location, *u as f64 / (m + u) as f64))
+    }
+
+    /// The median tile unmatched rate and its median absolute deviation.
+    pub fn median_rate(&self) -> Option<(f64, f64)> {
+        let median = |mut values: Vec<f64>| -> Option<f64> {
+            values.sort_by(f64::total_cmp);
+            let n = values.len();
+            match n {
+                0 => None,
+                _ if n % 2 == 1 => Some(values[n / 2]),
+                _ => Some((values[n / 2 - 1] + values[n / 2]) / 2_f64),
+            }
+        };
+        let rates: Vec<f64> = self.unmatched_rates().map(|(_, rate)| rate).collect();
+        let m = median(rates.clone())?;
+        let mad = median(rates.iter().map(|rate| (rate - m).abs()).collect())?;
+        Some((m, mad))
+    }
+
+    /// The tiles whose unmatched rate is more than `mads` median absolute deviations from the
+    /// median tile.
+    pub fn outliers(&self, mads: f64) -> Vec<(ReadLocation, f64)> {
+        match self.median_rate() {
+            Some((median, mad)) => self
+                .unmatched_rates()
+                .filter(|(_, rate)| (rate - median).abs() > mads * mad)
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    pub fn write(&self, path: &Path) -> Result<(), Report> {
+        let mut buffer = BufWriter::new(File::create(path)?);
+        writeln!(buffer, "lane\ttile\tmatched\tunmatched\tunmatched_rate")?;
+        for (location, (m, u)) in self.tiles.iter() {
+            writeln!(
+                buffer,
+                "{}\t{}\t{m}\t{u}\t{:0.6}",
+                location.lane,
+                location.tile,
+                *u as f64 / (m + u) as f64
+            )?;
+        }
+        buffer.flush()?;
+        Ok(())
+    }
+}
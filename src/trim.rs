@@ -0,0 +1,55 @@
+/// Find the start of the leftmost 3' adapter occurrence in `seq`.
+///
+/// The adapter may lie entirely within the read, or overlap the end of the read by at least
+/// `min_overlap` bases. An occurrence is accepted if the number of mismatches is at most
+/// `max_error_rate` of the aligned length.
+pub fn find_adapter(
+    seq: &[u8],
+    adapter: &[u8],
+    max_error_rate: f64,
+    min_overlap: usize,
+) -> Option<usize> {
+    let min_overlap = min_overlap.clamp(1, adapter.len().max(1));
+    if seq.len() < min_overlap {
+        return None;
+    }
+    (0..=seq.len() - min_overlap).find(|&start| {
+        let overlap = adapter.len().min(seq.len() - start);
+        let max_mismatches = (overlap as f64 * max_error_rate) as usize;
+        let mut mismatches = 0_usize;
+        for (a, b) in seq[start..start + overlap].iter().zip(adapter.iter()) {
+            if !a.eq_ignore_ascii_case(b) {
+                mismatches += 1_usize;
+                if mismatches > max_mismatches {
+                    return false;
+                }
+            }
+        }
+        true
+    })
+}
+
+/// Find the start of a homopolymer tail at the 3' end of `seq` made of `base`.
+///
+/// The tail is extended towards the 5' end until it holds more than five mismatches, or more
+/// than one mismatch for every eight bases once it is `min_length` long. A tail is only
+/// reported if it starts with `base` and is at least `min_length` long.
+pub fn find_poly_tail(seq: &[u8], base: u8, min_length: usize) -> Option<usize> {
+    let mut start = seq.len();
+    let mut length = 0_usize;
+    let mut mismatches = 0_usize;
+    for (i, b) in seq.iter().enumerate().rev() {
+        match b.eq_ignore_ascii_case(&base) {
+            true => start = i,
+            false => mismatches += 1_usize,
+        }
+        if mismatches > 5 || (mismatches > (length + 1) / 8 && length + 1 >= min_length) {
+            break;
+        }
+        length += 1_usize;
+    }
+    match length >= min_length.max(1) {
+        true => Some(start),
+        false => None,
+    }
+}
@@ -0,0 +1,126 @@
+use crate::histogram::Histogram;
+use crate::merge::{directional_groups, Merge};
+use crate::report::RunReport;
+use crate::whitelist::{pack, unpack};
+use std::collections::HashMap;
+
+/// The UMIs seen with each barcode and their read counts, stored as packed keys.
+#[derive(Default)]
+pub struct UmiSets {
+    barcodes: HashMap<String, HashMap<u64, u64>>,
+    pub invalid: u64,
+}
+
+impl UmiSets {
+    /// Add a read's UMI to a barcode; UMIs that cannot be packed (with ambiguous bases, or
+    /// longer than 31 bases) are only counted as invalid.
+    pub fn add(&mut self, barcode: &str, umi: &str) {
+        let Some(key) = pack(umi.as_bytes()) else {
+            self.invalid += 1_u64;
+            return;
+        };
+        if !self.barcodes.contains_key(barcode) {
+            self.barcodes.insert(barcode.to_owned(), HashMap::new());
+        }
+        if let Some(umis) = self.barcodes.get_mut(barcode) {
+            *umis.entry(key).or_insert(0_u64) += 1_u64;
+        }
+    }
+
+    /// Combine the UMIs of merged barcodes with those of their endpoints.
+    pub fn merge(&mut self, merges: &[Merge]) {
+        for merge in merges.iter() {
+            if let Some(umis) = self.barcodes.remove(&merge.barcode) {
+                let endpoint = self.barcodes.entry(merge.endpoint.to_owned()).or_default();
+                for (key, count) in umis {
+                    *endpoint.entry(key).or_insert(0_u64) += count;
+                }
+            }
+        }
+    }
+
+    /// The number of distinct UMIs of each barcode, optionally after directional correction.
+    pub fn distinct_umis(&self, correct: bool) -> HashMap<String, usize> {
+        self.barcodes
+            .iter()
+            .map(|(barcode, umis)| {
+                let distinct = match correct {
+                    true => directional_groups(umis),
+                    false => umis.len(),
+                };
+                (barcode.to_owned(), distinct)
+            })
+            .collect()
+    }
+}
+
+/// The length, per-position composition and frequency of all extracted UMIs, to check that
+/// they look random.
+#[derive(Default)]
+pub struct UmiStats {
+    pub lengths: Histogram,
+    /// The counts of A, C, G, T and other bases at each position.
+    composition: Vec<[u64; 5]>,
+    counts: HashMap<u64, u64>,
+}
+
+impl UmiStats {
+    pub fn add(&mut self, umi: &str) {
+        self.lengths.add(umi.len() as u64);
+        if self.composition.len() < umi.len() {
+            self.composition.resize(umi.len(), [0_u64; 5]);
+        }
+        for (position, base) in self.composition.iter_mut().zip(umi.bytes()) {
+            let i = match base.to_ascii_uppercase() {
+                b'A' => 0,
+                b'C' => 1,
+                b'G' => 2,
+                b'T' => 3,
+                _ => 4,
+            };
+            position[i] += 1_u64;
+        }
+        if let Some(key) = pack(umi.as_bytes()) {
+            *self.counts.entry(key).or_insert(0_u64) += 1_u64;
+        }
+    }
+
+    /// The most frequent (unambiguous) UMIs and their counts.
+    pub fn top(&self, n: usize) -> Vec<(String, u64)> {
+        let mut top: Vec<(u64, u64)> = self.counts.iter().map(|(k, c)| (*k, *c)).collect();
+        top.sort_by_key(|(key, count)| (std::cmp::Reverse(*count), *key));
+        top.into_iter()
+            .take(n)
+            .map(|(key, count)| (unpack(key), count))
+            .collect()
+    }
+
+    /// The fraction of all UMIs that are the most frequent one.
+    pub fn top_fraction(&self) -> Option<f64> {
+        let max = self.counts.values().max()?;
+        Some(*max as f64 / self.lengths.total() as f64)
+    }
+
+    /// Add the length distribution, base composition and most frequent UMIs to a report.
+    pub fn add_to_report(&self, report: &mut RunReport, top: usize) {
+        report.add_histogram("umi_length", &self.lengths);
+        for (i, counts) in self.composition.iter().enumerate() {
+            let total: u64 = counts.iter().sum();
+            for (base, count) in ['A', 'C', 'G', 'T', 'N'].iter().zip(counts.iter()) {
+                report.add(
+                    "umi_composition",
+                    format!("{}{base}", i + 1),
+                    format!("{:0.4}", *count as f64 / total as f64),
+                );
+            }
+        }
+        report.add("umi_summary", "total", self.lengths.total());
+        report.add("umi_summary", "distinct", self.counts.len());
+        if let Some(fraction) = self.top_fraction() {
+            report.add("umi_summary", "top_fraction", format!("{fraction:0.6}"));
+        }
+        for (umi, count) in self.top(top) {
+            report.add("umi_top", umi, count);
+        }
+    }
+}
@@ -0,0 +1,161 @@
+use crate::json::json_string;
+use log::*;
+use simple_eyre::eyre::Report;
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+/// The stable identifiers of the QC warnings, so that pipelines can gate on them.
+///
+/// Codes are never renamed or reused; new checks get new codes.
+#[derive(Clone, Copy)]
+pub enum Code {
+    /// Correction at the threshold distance is ambiguous for the audited whitelist.
+    AmbiguousWhitelist,
+    /// Two audited whitelist entries are too close for correction at the threshold distance.
+    CloseWhitelistEntries,
+    /// Read headers had no Casava comment, so chastity filtering could not be applied.
+    NoCasavaComment,
+    /// Read names had no parseable lane and tile.
+    UnparsedTileLocation,
+    /// A tile's unmatched rate is an outlier among the tiles.
+    TileOutlier,
+    /// Reads matched more than one search expression.
+    PatternConflict,
+    /// The read categories do not sum to the reads processed.
+    ReconciliationMismatch,
+    /// Two samples of the sample sheet have close barcodes.
+    CloseSamples,
+    /// Spurious collisions are expected among the endpoint barcodes.
+    SpuriousCollisions,
+    /// The saturation curve could not be extrapolated.
+    NoSaturation,
+    /// Reads were on barcodes with an expected fraction of zero.
+    UnexpectedBarcodes,
+    /// The most frequent UMI is over-represented.
+    DominantUmi,
+    /// UMIs had ambiguous bases or were too long to count.
+    InvalidUmis,
+    /// Two library guides have the same sequence.
+    DuplicateGuide,
+    /// Inputs were truncated, and only their reads before the truncation were counted.
+    TruncatedInput,
+    /// Malformed records were skipped.
+    InvalidRecords,
+    /// Read qualities could not be lined up with their barcode to weight merging.
+    UnalignedQualities,
+    /// Count tables had reads on other barcodes, which cannot be merged.
+    UnmergedOther,
+    /// The barcode rank curve had no inflection to use as the merge count.
+    NoInflection,
+}
+
+impl Code {
+    pub fn id(&self) -> &'static str {
+        match self {
+            Code::AmbiguousWhitelist => "ambiguous_whitelist",
+            Code::CloseWhitelistEntries => "close_whitelist_entries",
+            Code::NoCasavaComment => "no_casava_comment",
+            Code::UnparsedTileLocation => "unparsed_tile_location",
+            Code::TileOutlier => "tile_outlier",
+            Code::PatternConflict => "pattern_conflict",
+            Code::ReconciliationMismatch => "reconciliation_mismatch",
+            Code::CloseSamples => "close_samples",
+            Code::SpuriousCollisions => "spurious_collisions",
+            Code::NoSaturation => "no_saturation",
+            Code::UnexpectedBarcodes => "unexpected_barcodes",
+            Code::DominantUmi => "dominant_umi",
+            Code::InvalidUmis => "invalid_umis",
+            Code::DuplicateGuide => "duplicate_guide",
+            Code::TruncatedInput => "truncated_input",
+            Code::InvalidRecords => "invalid_records",
+            Code::UnalignedQualities => "unaligned_qualities",
+            Code::UnmergedOther => "unmerged_other",
+            Code::NoInflection => "no_inflection",
+        }
+    }
+
+    /// How much the finding is likely to matter to the counts: `high` findings usually mean
+    /// the counts should not be trusted as they are.
+    pub fn severity(&self) -> &'static str {
+        match self {
+            Code::AmbiguousWhitelist
+            | Code::PatternConflict
+            | Code::ReconciliationMismatch
+            | Code::CloseSamples
+            | Code::SpuriousCollisions
+            | Code::TruncatedInput => "high",
+            Code::TileOutlier
+            | Code::UnexpectedBarcodes
+            | Code::DominantUmi
+            | Code::DuplicateGuide
+            | Code::InvalidRecords
+            | Code::UnmergedOther
+            | Code::NoInflection => "medium",
+            Code::CloseWhitelistEntries
+            | Code::NoCasavaComment
+            | Code::UnparsedTileLocation
+            | Code::NoSaturation
+            | Code::InvalidUmis
+            | Code::UnalignedQualities => "low",
+        }
+    }
+}
+
+/// A warning raised during the run.
+struct Warning {
+    code: Code,
+    message: String,
+    values: Vec<(&'static str, f64)>,
+}
+
+/// The warnings raised during the run, logged as they are raised and kept for writing as
+/// JSON lines at the end.
+#[derive(Default)]
+pub struct Warnings {
+    warnings: Vec<Warning>,
+}
+
+impl Warnings {
+    /// Log a warning and keep it, with any numbers associated with it.
+    pub fn add(&mut self, code: Code, message: String, values: &[(&'static str, f64)]) {
+        warn!("{message}");
+        self.warnings.push(Warning {
+            code,
+            message,
+            values: values.to_vec(),
+        });
+    }
+
+    pub fn len(&self) -> usize {
+        self.warnings.len()
+    }
+
+    /// Write one JSON object per warning, with its `code`, `severity`, `message` and `values`.
+    pub fn write(&self, path: &Path) -> Result<(), Report> {
+        let mut buffer = BufWriter::new(File::create(path)?);
+        for warning in self.warnings.iter() {
+            let mut values = String::new();
+            for (i, (name, value)) in warning.values.iter().enumerate() {
+                if i > 0 {
+                    values.push(',');
+                }
+                // JSON has no NaN or infinity:
+                match value.is_finite() {
+                    true => write!(values, "{}:{value}", json_string(name))?,
+                    false => write!(values, "{}:null", json_string(name))?,
+                }
+            }
+            writeln!(
+                buffer,
+                "{{\"code\":{},\"severity\":{},\"message\":{},\"values\":{{{values}}}}}",
+                json_string(warning.code.id()),
+                json_string(warning.code.severity()),
+                json_string(&warning.message)
+            )?;
+        }
+        buffer.flush()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,402 @@
+use crate::merge::{Neighbours, Similarity};
+use crate::quality::phred;
+use crate::stats;
+use flate2::read::MultiGzDecoder;
+use simple_eyre::eyre::{eyre, Report};
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::hash::{BuildHasherDefault, Hasher};
+use std::io::{BufRead, BufReader, Read};
+use std::path::Path;
+
+/// Open a (possibly gzipped) text file for reading line by line.
+pub fn open_text(path: &Path) -> Result<Box<dyn BufRead>, Report> {
+    let file = File::open(path)?;
+    let reader: Box<dyn Read> = match path.extension().is_some_and(|e| e == "gz") {
+        true => Box::new(MultiGzDecoder::new(file)),
+        false => Box::new(file),
+    };
+    Ok(Box::new(BufReader::new(reader)))
+}
+
+/// Read a list of barcodes from the first column of a (possibly gzipped) text file, skipping
+/// blank lines and `#` comments.
+pub fn read_barcode_list(path: &Path) -> Result<Vec<String>, Report> {
+    let mut barcodes: Vec<String> = Vec::new();
+    for line in open_text(path)?.lines() {
+        let line = line?;
+        if let Some(barcode) = line.split_ascii_whitespace().next() {
+            if !barcode.starts_with('#') {
+                barcodes.push(barcode.to_string());
+            }
+        }
+    }
+    Ok(barcodes)
+}
+
+/// Pack a barcode of up to 31 unambiguous bases into a 2-bit-per-base key, with a leading
+/// sentinel bit so that barcodes of different lengths have different keys.
+pub fn pack(barcode: &[u8]) -> Option<u64> {
+    if barcode.len() > 31 {
+        return None;
+    }
+    let mut key = 1_u64;
+    for base in barcode {
+        key = (key << 2) | base_code(*base)?;
+    }
+    Some(key)
+}
+
+fn base_code(base: u8) -> Option<u64> {
+    match base.to_ascii_uppercase() {
+        b'A' => Some(0),
+        b'C' => Some(1),
+        b'G' => Some(2),
+        b'T' => Some(3),
+        _ => None,
+    }
+}
+
+/// The single substitutions (position, base) of `barcode` whose packed keys are members of
+/// a set. A barcode with one ambiguous base can only be substituted at that base, and one
+/// with more than one ambiguous base has no candidates.
+///
+/// The substitutions are made on the packed key of the barcode, replacing two bits at a time,
+/// so that the whole neighbourhood of a barcode is probed without building any sequences.
+pub fn substitutions(barcode: &[u8], is_member: impl Fn(u64) -> bool) -> Vec<(usize, u8)> {
+    if barcode.len() > 31 {
+        return Vec::new();
+    }
+    // Pack the barcode with any ambiguous base as an A, to be substituted:
+    let mut key = 1_u64;
+    let mut ambiguous: Option<usize> = None;
+    for (i, base) in barcode.iter().enumerate() {
+        let code = match (base_code(*base), ambiguous) {
+            (Some(code), _) => code,
+            (None, None) => {
+                ambiguous = Some(i);
+                0_u64
+            }
+            (None, Some(_)) => return Vec::new(),
+        };
+        key = (key << 2) | code;
+    }
+    let positions = match ambiguous {
+        Some(i) => i..i + 1,
+        None => 0..barcode.len(),
+    };
+    let mut candidates: Vec<(usize, u8)> = Vec::new();
+    for i in positions {
+        let shift = 2 * (barcode.len() - 1 - i);
+        let original = base_code(barcode[i]);
+        for (code, base) in [b'A', b'C', b'G', b'T'].into_iter().enumerate() {
+            let code = code as u64;
+            if original == Some(code) {
+                continue;
+            }
+            if is_member((key & !(3_u64 << shift)) | (code << shift)) {
+                candidates.push((i, base));
+            }
+        }
+    }
+    candidates
+}
+
+/// A hasher for packed keys, which are already spread over their bits, so a single
+/// multiplication mixes them well enough and is much faster than the default hasher.
+#[derive(Default)]
+pub struct KeyHasher {
+    hash: u64,
+}
+
+impl Hasher for KeyHasher {
+    fn finish(&self) -> u64 {
+        self.hash
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.hash =
+                (self.hash.rotate_left(8) ^ *byte as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+        }
+    }
+
+    fn write_u64(&mut self, key: u64) {
+        self.hash = (self.hash ^ key).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+    }
+}
+
+/// A set of packed keys.
+pub type KeySet = HashSet<u64, BuildHasherDefault<KeyHasher>>;
+
+/// The outcome of looking up a barcode in a whitelist.
+pub enum Correction {
+    Exact,
+    Corrected(String),
+    Unassigned,
+}
+
+/// A set of permitted barcodes (e.g. a 10x Genomics list), stored as packed keys.
+pub struct Whitelist {
+    keys: KeySet,
+}
+
+impl Whitelist {
+    pub fn read(path: &Path) -> Result<Whitelist, Report> {
+        let mut keys = KeySet::default();
+        for line in open_text(path)?.lines() {
+            let line = line?;
+            if let Some(barcode) = line.split_ascii_whitespace().next() {
+                if barcode.starts_with('#') {
+                    continue;
+                }
+                // 10x lists append a "-1" gem group suffix in some contexts:
+                let barcode = barcode.split('-').next().unwrap_or(barcode);
+                keys.insert(pack(barcode.as_bytes()).ok_or_else(|| {
+                    eyre!("whitelist barcode {barcode} is not made up of at most 31 A, C, G and T bases")
+                })?);
+            }
+        }
+        Ok(Whitelist { keys })
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    /// The nearest whitelisted barcode by substitutions, searching outwards up to
+    /// `max_distance` substitutions (ties are broken by the first found).
+    pub fn nearest(&self, barcode: &[u8], max_distance: usize) -> Option<(String, usize)> {
+        fn search(
+            whitelist: &Whitelist,
+            candidate: &mut Vec<u8>,
+            from: usize,
+            remaining: usize,
+        ) -> Option<String> {
+            if remaining == 0 {
+                return pack(candidate)
+                    .filter(|key| whitelist.keys.contains(key))
+                    .map(|_| String::from_utf8_lossy(candidate).into_owned());
+            }
+            for i in from..candidate.len() {
+                let original = candidate[i];
+                for base in [b'A', b'C', b'G', b'T'] {
+                    if base == original.to_ascii_uppercase() {
+                        continue;
+                    }
+                    candidate[i] = base;
+                    let found = search(whitelist, candidate, i + 1, remaining - 1);
+                    candidate[i] = original;
+                    if found.is_some() {
+                        return found;
+                    }
+                }
+            }
+            None
+        }
+        let mut candidate = barcode.to_vec();
+        (0..=max_distance.min(barcode.len())).find_map(|distance| {
+            search(self, &mut candidate, 0, distance).map(|nearest| (nearest, distance))
+        })
+    }
+
+    /// The approximate memory used by the packed keys, in bytes.
+    pub fn memory(&self) -> usize {
+        self.keys.capacity() * (std::mem::size_of::<u64>() + 1)
+    }
+
+    /// Look up a barcode, rescuing it by a single substitution if exactly one substitution
+    /// gives a whitelisted barcode (unless `rescue` is false).
+    ///
+    /// If `qual` is given, ambiguous rescues are also resolved when one candidate has a
+    /// posterior probability of at least 0.975, weighting each candidate by the error
+    /// probability of the base it substitutes.
+    pub fn correct(
+        &self,
+        barcode: &[u8],
+        qual: Option<&[u8]>,
+        offset: u8,
+        rescue: bool,
+    ) -> Correction {
+        let is_member = |key: u64| self.keys.contains(&key);
+        if pack(barcode).is_some_and(is_member) {
+            return Correction::Exact;
+        }
+        if !rescue {
+            return Correction::Unassigned;
+        }
+        let candidates = substitutions(barcode, is_member);
+        let mut candidate = barcode.to_vec();
+        let selected = match (candidates.len(), qual) {
+            (0, _) => None,
+            (1, _) => Some(candidates[0]),
+            (_, Some(qual)) if qual.len() == barcode.len() => {
+                let weight = |i: usize| 10_f64.powf(-(phred(qual[i], offset) as f64) / 10_f64);
+                let total: f64 = candidates.iter().map(|(i, _)| weight(*i)).sum();
+                candidates
+                    .iter()
+                    .copied()
+                    .max_by(|a, b| weight(a.0).total_cmp(&weight(b.0)))
+                    .filter(|(i, _)| weight(*i) / total >= 0.975)
+            }
+            _ => None,
+        };
+        match selected {
+            Some((i, base)) => {
+                candidate[i] = base;
+                Correction::Corrected(String::from_utf8_lossy(&candidate).into_owned())
+            }
+            None => Correction::Unassigned,
+        }
+    }
+}
+
+/// The index of the nearest of some (index, distance) pairs, if only one is the nearest.
+fn unique_nearest(near: &[(usize, usize)]) -> Option<usize> {
+    let min_distance = near.iter().map(|(_, distance)| *distance).min();
+    let nearest: Vec<usize> = near
+        .iter()
+        .filter(|(_, distance)| Some(*distance) == min_distance)
+        .map(|(i, _)| *i)
+        .collect();
+    match nearest[..] {
+        [i] => Some(i),
+        _ => None,
+    }
+}
+
+/// A list of known barcodes of any length (or pairs joined by a separator), to which barcodes
+/// are corrected by the nearest entry within the threshold distance of a `Similarity`. A
+/// barcode with several nearest entries is unassigned. The correction of each distinct
+/// barcode is kept, so that each is only looked up once however many reads have it.
+pub struct KnownBarcodes<'a> {
+    entries: Neighbours<'a>,
+    /// The nearest entry of each barcode looked up, if it has a single one.
+    corrections: HashMap<String, Option<usize>>,
+}
+
+impl<'a> KnownBarcodes<'a> {
+    /// Read the known barcodes from the first column of a (possibly gzipped) text file.
+    pub fn read(
+        path: &Path,
+        separator: Option<&'a str>,
+        similarity: Similarity,
+    ) -> Result<Self, Report> {
+        let mut entries = read_barcode_list(path)?;
+        entries.sort_unstable();
+        entries.dedup();
+        Ok(KnownBarcodes {
+            entries: Neighbours::new(entries, separator, similarity),
+            corrections: HashMap::new(),
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// The nearest entry within the threshold distance and its distance (ties are broken by
+    /// the first entry in sorted order).
+    pub fn nearest(&self, barcode: &str) -> Option<(String, usize)> {
+        self.entries
+            .near(barcode)
+            .into_iter()
+            .min_by_key(|(i, distance)| (*distance, *i))
+            .map(|(i, distance)| (self.entries.get(i).to_owned(), distance))
+    }
+
+    /// Look up a barcode, correcting it to its nearest entry (unless `correct` is false).
+    pub fn correct(&mut self, barcode: &str, correct: bool) -> Correction {
+        // Without correction only an exact match is needed, which the sorted entries give:
+        if !correct {
+            return match self
+                .entries
+                .barcodes()
+                .binary_search_by(|entry| entry.as_str().cmp(barcode))
+            {
+                Ok(_) => Correction::Exact,
+                Err(_) => Correction::Unassigned,
+            };
+        }
+        let nearest = match self.corrections.get(barcode) {
+            Some(nearest) => *nearest,
+            None => {
+                let nearest = unique_nearest(&self.entries.near(barcode));
+                self.corrections.insert(barcode.to_owned(), nearest);
+                nearest
+            }
+        };
+        match nearest.map(|i| self.entries.get(i)) {
+            Some(entry) if entry == barcode => Correction::Exact,
+            Some(entry) if correct => Correction::Corrected(entry.to_owned()),
+            _ => Correction::Unassigned,
+        }
+    }
+}
+
+/// A barcode accepted into a derived whitelist, with the barcodes taken to be its errors.
+pub struct Accepted {
+    pub barcode: String,
+    pub count: u64,
+    /// The error variants and their counts, most frequent first.
+    pub variants: Vec<(String, u64)>,
+}
+
+/// Derive a whitelist from the counts of barcodes, as `umi_tools whitelist` does: the barcodes
+/// above the knee of the rank curve are accepted, or the `cells` most frequent if given. With
+/// a pair separator and `Similarity`, each other barcode whose nearest accepted barcode within
+/// the threshold distance is unique is listed as an error variant of it.
+pub fn derive(
+    barcodes: &HashMap<String, u64>,
+    cells: Option<usize>,
+    variants: Option<(Option<&str>, Similarity)>,
+) -> Result<Vec<Accepted>, Report> {
+    let mut ranked: Vec<(&String, u64)> = barcodes
+        .iter()
+        .filter(|(_, count)| **count > 0)
+        .map(|(barcode, count)| (barcode, *count))
+        .collect();
+    ranked.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+    let counts: Vec<u64> = ranked.iter().map(|(_, count)| *count).collect();
+    let accepted = match cells {
+        Some(cells) => cells.min(ranked.len()),
+        None => stats::knee(&counts).ok_or_else(|| {
+            eyre!("the barcode rank curve has no knee, so give the number of barcodes to accept with --cell-number")
+        })?,
+    };
+    let mut entries: Vec<Accepted> = ranked[..accepted]
+        .iter()
+        .map(|(barcode, count)| Accepted {
+            barcode: barcode.to_string(),
+            count: *count,
+            variants: Vec::new(),
+        })
+        .collect();
+    if let Some((separator, similarity)) = variants {
+        let index = Neighbours::new(
+            entries.iter().map(|entry| entry.barcode.clone()).collect(),
+            separator,
+            similarity,
+        );
+        for (barcode, count) in ranked[accepted..].iter() {
+            if let Some(i) = unique_nearest(&index.near(barcode)) {
+                entries[i].variants.push((barcode.to_string(), *count));
+            }
+        }
+    }
+    Ok(entries)
+}
+
+/// Unpack a key made by `pack` back into its sequence.
+pub fn unpack(key: u64) -> String {
+    let length = (63 - key.leading_zeros()) / 2;
+    (0..length)
+        .rev()
+        .map(|i| match (key >> (2 * i)) & 3 {
+            0 => 'A',
+            1 => 'C',
+            2 => 'G',
+            _ => 'T',
+        })
+        .collect()
+}